@@ -0,0 +1,494 @@
+// Embeds build metadata into the firmware, see `src/build_info.rs`.
+//
+// GIT_HASH        short commit hash, with `-dirty` for uncommitted changes
+// BUILD_TIMESTAMP UTC build time, `SOURCE_DATE_EPOCH` overrides the clock
+// BUILD_PROFILE   cargo profile (`debug` or `release`)
+//
+// and writes the build-time configuration to `$OUT_DIR/build_config.rs`, see
+// `src/build_config.rs`. Each setting is taken from the environment variable
+// `FW_<key>`, else from `<key> = <value>` in `firmware.cfg` next to this file
+// (or the file `FW_CONFIG` names), else left at its default:
+//
+// TWIS_ADDRESS    default 7-bit TWIS address, 0x08-0x77 (0x1a)
+// TWIM_FREQUENCY  default TWIM frequency in kHz, 100, 250 or 400 (400)
+// BUF_LEN         TWIS and SPIS DMA buffer size, 16-64 bytes (32); frames
+//                 of it are passed between tasks by value
+// TWIS_SCL, TWIS_SDA, TWIM_SCL, TWIM_SDA
+//                 bus pins as P0.n, P1.n or the PSEL number, on the
+//                 chip's P0 and P1 (the board's)
+//
+// It also checks that `proto/twis.proto` numbers its fields the way
+// `src/protobuf.rs` encodes `message::Request` and `Reply`, as that side is
+// written by hand: oneof field opcode + 1 for a request and variant index
+// + 2 for a reply, named after the variant, and submessage fields numbered
+// from 1 in the order and with the names of the variant's fields.
+//
+// With the `ble` feature it writes a `memory.x` that places the firmware
+// above the S140 SoftDevice, see `SOFTDEVICE_FLASH` and `SOFTDEVICE_RAM`.
+// The link search path of this crate comes before those of its
+// dependencies, so it replaces the one of the HAL.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const PINS: [&str; 4] = ["TWIS_SCL", "TWIS_SDA", "TWIM_SCL", "TWIM_SDA"];
+
+// Flash of the MBR and the S140 7.x SoftDevice, and the RAM it is given:
+// enough for one connection and the GATT table of `src/ble.rs`,
+// `Softdevice::enable` logs what it actually needs.
+const SOFTDEVICE_FLASH: u32 = 0x27000;
+const SOFTDEVICE_RAM: u32 = 0x6000;
+
+fn main() {
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp());
+    println!(
+        "cargo:rustc-env=BUILD_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+
+    build_config();
+    check_proto();
+    if env::var_os("CARGO_FEATURE_BLE").is_some() {
+        memory_layout();
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn build_config() {
+    let path = env::var("FW_CONFIG").unwrap_or_else(|_| "firmware.cfg".into());
+    println!("cargo:rerun-if-env-changed=FW_CONFIG");
+    println!("cargo:rerun-if-changed={}", path);
+    let file = match fs::read_to_string(&path) {
+        Ok(text) => parse_config(&path, &text),
+        Err(_) => HashMap::new(),
+    };
+    let setting = |key: &str| {
+        println!("cargo:rerun-if-env-changed=FW_{}", key);
+        env::var(format!("FW_{}", key))
+            .ok()
+            .or_else(|| file.get(key).cloned())
+    };
+
+    let address = setting("TWIS_ADDRESS").map_or(0x1a, |value| {
+        number(&value)
+            .filter(|address| (0x08..=0x77).contains(address))
+            .unwrap_or_else(|| invalid("TWIS_ADDRESS", &value, "0x08-0x77"))
+    });
+    let frequency = setting("TWIM_FREQUENCY").map_or(400, |value| {
+        number(&value)
+            .filter(|khz| [100, 250, 400].contains(khz))
+            .unwrap_or_else(|| invalid("TWIM_FREQUENCY", &value, "100, 250 or 400"))
+    });
+    let buf_len = setting("BUF_LEN").map_or(32, |value| {
+        number(&value)
+            .filter(|len| (16..=64).contains(len))
+            .unwrap_or_else(|| invalid("BUF_LEN", &value, "16-64"))
+    });
+
+    let mut out = format!(
+        "pub const TWIS_ADDRESS: u8 = {:#04x};\n\
+         pub const TWIM_FREQUENCY_KHZ: u32 = {};\n\
+         pub const BUF_LEN: usize = {};\n",
+        address, frequency, buf_len
+    );
+    let p1_pins = p1_pins();
+    let expected = match p1_pins {
+        0 => "P0.0-P0.31".to_owned(),
+        n => format!("P0.0-P0.31 or P1.0-P1.{}", n - 1),
+    };
+    for key in PINS {
+        let pin = setting(key)
+            .map(|value| pin(&value, p1_pins).unwrap_or_else(|| invalid(key, &value, &expected)));
+        out += &format!("pub const {}: Option<usize> = {:?};\n", key, pin);
+    }
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("build_config.rs"), out).unwrap();
+}
+
+// The nRF52840's 1 MB of flash and 256 KB of RAM, less what the SoftDevice
+// takes at the bottom of each.
+fn memory_layout() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory = format!(
+        "MEMORY\n{{\n  FLASH : ORIGIN = {:#x}, LENGTH = {}K\n  RAM : ORIGIN = {:#x}, LENGTH = {}K\n}}\n",
+        SOFTDEVICE_FLASH,
+        (0x10_0000 - SOFTDEVICE_FLASH) / 1024,
+        0x2000_0000 + SOFTDEVICE_RAM,
+        (0x4_0000 - SOFTDEVICE_RAM) / 1024,
+    );
+    fs::write(out_dir.join("memory.x"), memory).unwrap();
+    println!("cargo:rustc-link-search={}", out_dir.display());
+}
+
+// `KEY = value` lines, `#` starts a comment.
+fn parse_config(path: &str, text: &str) -> HashMap<String, String> {
+    let mut settings = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            panic!("{}:{}: expected `KEY = value`", path, i + 1);
+        };
+        let key = key.trim();
+        if !["TWIS_ADDRESS", "TWIM_FREQUENCY", "BUF_LEN"].contains(&key) && !PINS.contains(&key) {
+            panic!("{}:{}: unknown setting `{}`", path, i + 1, key);
+        }
+        settings.insert(key.to_owned(), value.trim().to_owned());
+    }
+    settings
+}
+
+// Decimal or `0x` hexadecimal.
+fn number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// `P0.n`, `P1.n` or the PSEL number, P1.n as 32 + n, on a chip with
+// `p1_pins` pins on P1.
+fn pin(value: &str, p1_pins: usize) -> Option<usize> {
+    let psel = match value.to_ascii_uppercase().strip_prefix('P') {
+        Some(name) => {
+            let (port, pin) = name.split_once('.')?;
+            let (port, pin): (usize, usize) = (port.parse().ok()?, pin.parse().ok()?);
+            (port < 2 && pin < 32).then_some(32 * port + pin)?
+        }
+        None => value.parse().ok()?,
+    };
+    (psel < 32 + p1_pins).then_some(psel)
+}
+
+// `chip::P1_PINS` of the chip the features select.
+fn p1_pins() -> usize {
+    if env::var_os("CARGO_FEATURE_NRF52832").is_some() {
+        0
+    } else if env::var_os("CARGO_FEATURE_NRF52833").is_some() {
+        10
+    } else {
+        16
+    }
+}
+
+fn invalid<T>(key: &str, value: &str, expected: &str) -> T {
+    panic!("FW_{} / {}: `{}` is not {}", key, key, value, expected)
+}
+
+fn check_proto() {
+    let read = |path: &str| {
+        println!("cargo:rerun-if-changed={}", path);
+        fs::read_to_string(path).unwrap_or_else(|error| panic!("{}: {}", path, error))
+    };
+    let proto = messages(&read("proto/twis.proto"));
+    let request = read("src/request.rs");
+    let config = read("src/config.rs");
+    let requests = variants(block(&request, "pub enum Request {"));
+    let replies = variants(block(&read("src/message.rs"), "pub enum Reply {"));
+    let config_fields = struct_fields(block(&config, "pub struct Config {"));
+
+    let fail = |message: String| -> ! { panic!("proto/twis.proto does not match: {}", message) };
+    let check_message = |proto_type: &str, expected: &[String], variant: &str| {
+        let fields = proto
+            .get(proto_type)
+            .unwrap_or_else(|| fail(format!("no `{}`", proto_type)));
+        let names: Vec<_> = fields.iter().map(|(name, _)| name.clone()).collect();
+        if names != expected {
+            fail(format!(
+                "`{}` has fields {:?}, `{}` {:?}",
+                proto_type, names, variant, expected
+            ));
+        }
+        for (i, (name, number)) in fields.iter().enumerate() {
+            if *number != i as u32 + 1 {
+                fail(format!(
+                    "`{}.{}` is {}, not {}",
+                    proto_type,
+                    name,
+                    number,
+                    i + 1
+                ));
+            }
+        }
+    };
+    // The fields a variant is encoded with: a `len` after `data` comes with
+    // the bytes, and `Config` is encoded field by field.
+    let expected = |variant: &Variant| match &variant.fields {
+        Fields::Unit => vec![],
+        Fields::Tuple(ty) if ty == "Config" => config_fields.clone(),
+        Fields::Tuple(_) => vec![],
+        Fields::Named(names) => names
+            .iter()
+            .filter(|name| *name != "len")
+            .cloned()
+            .collect(),
+    };
+    let check_oneof = |message: &str, variants: &[Variant], number: &dyn Fn(usize, &str) -> u32| {
+        let fields = &proto[message];
+        if fields
+            .first()
+            .map(|(name, number)| (name.as_str(), *number))
+            != Some(("version", 1))
+        {
+            fail(format!("`{}` does not start with `version = 1`", message));
+        }
+        let oneof = &fields[1..];
+        if oneof.len() != variants.len() {
+            fail(format!(
+                "`{}` has {} oneof fields, `message::{}` {} variants",
+                message,
+                oneof.len(),
+                message,
+                variants.len()
+            ));
+        }
+        for (i, (variant, (name, field))) in variants.iter().zip(oneof).enumerate() {
+            if *name != snake_case(&variant.name) || *field != number(i, &variant.name) {
+                fail(format!(
+                    "`{}.{} = {}` for `{}::{}`, expected `{} = {}`",
+                    message,
+                    name,
+                    field,
+                    message,
+                    variant.name,
+                    snake_case(&variant.name),
+                    number(i, &variant.name)
+                ));
+            }
+            let ty = &proto[&format!("{}.{}", message, name)][0].0;
+            match &variant.fields {
+                // One unnamed field, whatever the proto calls it.
+                Fields::Tuple(ty_name) if ty_name != "Config" => {
+                    if proto.get(ty.as_str()).map(Vec::len) != Some(1) {
+                        fail(format!("`{}` should have one field", ty));
+                    }
+                }
+                _ => check_message(
+                    ty,
+                    &expected(variant),
+                    &format!("{}::{}", message, variant.name),
+                ),
+            }
+        }
+    };
+    check_oneof("Request", &requests, &|_, name| {
+        let opcode = constant(&request, &screaming_case(name))
+            .unwrap_or_else(|| fail(format!("no opcode for `{}`", name)));
+        opcode + 1
+    });
+    check_oneof("Reply", &replies, &|i, _| i as u32 + 2);
+}
+
+enum Fields {
+    Unit,
+    Tuple(String),
+    Named(Vec<String>),
+}
+
+struct Variant {
+    name: String,
+    fields: Fields,
+}
+
+// The body of the item `header` starts, between its braces.
+fn block<'a>(source: &'a str, header: &str) -> &'a str {
+    let start = source
+        .find(header)
+        .unwrap_or_else(|| panic!("no `{}`", header))
+        + header.len();
+    let mut depth = 1;
+    for (i, c) in source[start..].char_indices() {
+        match c {
+            '{' | '(' | '[' => depth += 1,
+            '}' | ')' | ']' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return &source[start..start + i];
+        }
+    }
+    panic!("`{}` is not closed", header)
+}
+
+// Without comments and attributes, split at the commas outside brackets.
+fn items(body: &str) -> Vec<String> {
+    let code: String = body
+        .lines()
+        .map(|line| line.split("//").next().unwrap().trim())
+        .filter(|line| !line.starts_with("#["))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut items = vec![String::new()];
+    let mut depth = 0;
+    for c in code.chars() {
+        match c {
+            '{' | '(' | '[' | '<' => depth += 1,
+            '}' | ')' | ']' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        items.last_mut().unwrap().push(c);
+    }
+    items
+        .into_iter()
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn variants(body: &str) -> Vec<Variant> {
+    items(body)
+        .into_iter()
+        .map(|item| {
+            let end = item.find(['(', '{']).unwrap_or(item.len());
+            let name = item[..end].trim().to_owned();
+            let fields = match item[end..].chars().next() {
+                None => Fields::Unit,
+                Some('(') => Fields::Tuple(item[end + 1..item.len() - 1].trim().to_owned()),
+                _ => Fields::Named(struct_fields(&item[end + 1..item.len() - 1])),
+            };
+            Variant { name, fields }
+        })
+        .collect()
+}
+
+fn struct_fields(body: &str) -> Vec<String> {
+    items(body)
+        .iter()
+        .map(|field| {
+            let name = field.split(':').next().unwrap();
+            name.trim_start_matches("pub ").trim().to_owned()
+        })
+        .collect()
+}
+
+// `pub const <name>: u8 = <value>;`
+fn constant(source: &str, name: &str) -> Option<u32> {
+    let prefix = format!("pub const {}: u8 = ", name);
+    let line = source.lines().find_map(|line| line.strip_prefix(&prefix))?;
+    number(line.trim_end_matches(';'))
+}
+
+// The fields of every message of a .proto, `(name, number)` in file order.
+// A oneof's fields are listed with its message's, and each oneof field's
+// type under `<message>.<field>`.
+fn messages(proto: &str) -> HashMap<String, Vec<(String, u32)>> {
+    let mut messages: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+    let mut current: Option<String> = None;
+    let mut in_enum = false;
+    for line in proto.lines() {
+        let line = line.split("//").next().unwrap().trim();
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["message", name, ..] => {
+                current = Some(name.to_string());
+                messages.entry(name.to_string()).or_default();
+                if line.ends_with('}') {
+                    current = None;
+                }
+            }
+            ["enum", ..] => in_enum = true,
+            ["}"] if in_enum => in_enum = false,
+            ["}"] => {}
+            [ty, name, "=", number] if !in_enum => {
+                let message = current.clone().expect("field outside a message");
+                let number = number.trim_end_matches(';').parse().expect("field number");
+                messages
+                    .entry(format!("{}.{}", message, name))
+                    .or_default()
+                    .push((ty.to_string(), 0));
+                messages
+                    .get_mut(&message)
+                    .unwrap()
+                    .push((name.to_string(), number));
+            }
+            _ => {}
+        }
+    }
+    messages
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+fn screaming_case(name: &str) -> String {
+    snake_case(name).to_ascii_uppercase()
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn git_hash() -> String {
+    let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) else {
+        return "unknown".into();
+    };
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) if status.is_empty() => hash,
+        _ => hash + "-dirty",
+    }
+}
+
+fn timestamp() -> String {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date, after Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
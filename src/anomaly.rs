@@ -0,0 +1,46 @@
+// Detection of interrupts that should not have happened.
+//
+// Two kinds are counted and reported instead of being silently absorbed:
+// `on_twis` running with no TWIS event pending, and any interrupt without a
+// bound RTIC task landing in the default handler. The latter is also masked
+// so a stuck interrupt source cannot starve the application.
+
+use {
+    crate::{
+        stats::STATS,
+        tracebuf::{self, Event},
+    },
+    cortex_m::peripheral::NVIC,
+    cortex_m_rt::exception,
+};
+
+/// `arg` values of `Event::Anomaly`.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Kind {
+    /// `on_twis` ran with no TWIS event pending.
+    SpuriousTwis = 0x01,
+    /// Unbound interrupt or exception, `value` is the IRQ number.
+    UnexpectedIrq = 0x02,
+}
+
+/// Reports an `on_twis` invocation with nothing to do.
+pub fn spurious_twis() {
+    STATS.spurious.inc();
+    tracebuf::record(Event::Anomaly, Kind::SpuriousTwis as u8, 0);
+    warn!("anomaly: TWIS interrupt with no event pending");
+}
+
+#[exception]
+unsafe fn DefaultHandler(irqn: i16) {
+    STATS.unexpected_irqs.inc();
+    tracebuf::record(Event::Anomaly, Kind::UnexpectedIrq as u8, irqn as u16);
+    if irqn >= 0 {
+        // Negative numbers are system exceptions, which cannot be masked.
+        let irqn = irqn as usize;
+        (*NVIC::PTR).icer[irqn / 32].write(1 << (irqn % 32));
+        warn!("anomaly: unexpected interrupt {}, masked", irqn);
+    } else {
+        warn!("anomaly: unexpected exception {}", irqn);
+    }
+}
@@ -0,0 +1,169 @@
+// SMBus Address Resolution Protocol, device side (`smbus-arp` feature).
+//
+// TWIS answers at the SMBus Device Default Address, `ADDRESS`, as well as at
+// the register map's, and takes the ARP commands there. Every one carries a
+// PEC (see `smbus`), and a command whose PEC does not match is ignored and
+// recorded as a `ProtocolError::BadCrc`:
+//
+//   0x01        PREPARE_TO_ARP  clear AR
+//   0x02        RESET_DEVICE    clear AR and AV, back to the config address
+//   0x03        GET_UDID        read: 17, UDID, address, PEC; only with AR clear
+//   0x04        ASSIGN_ADDRESS  17, UDID, address: take it if the UDID is ours
+//   addr << 1   directed RESET_DEVICE, for the device at `addr`
+//   addr << 1 | 1  directed GET_UDID, whatever AR is
+//
+// AR (address resolved) is set by ASSIGN_ADDRESS, AV (address valid) too.
+// The address byte of GET_UDID is the current address shifted left with
+// bit 0 set, or 0xff without AV. An assigned address moves the register map
+// there until RESET_DEVICE or a reset; it is volatile, the config address
+// is not touched.
+//
+// The UDID is built from FICR, see `udid`. Several devices answer GET_UDID
+// at once and the one sending a 0 wins a bit, but TWIS cannot see that it
+// lost: with other ARP devices on the bus the controller should resolve
+// this one by directed commands instead. Where nothing is to be sent this
+// READ returns 0xff, which leaves the bus to the others.
+
+use {
+    crate::{
+        error::{AppError, ProtocolError},
+        identity, lock, smbus,
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// The SMBus Device Default Address.
+pub const ADDRESS: u8 = 0x61;
+pub const UDID_LEN: usize = 16;
+
+const PREPARE_TO_ARP: u8 = 0x01;
+const RESET_DEVICE: u8 = 0x02;
+const GET_UDID: u8 = 0x03;
+const ASSIGN_ADDRESS: u8 = 0x04;
+
+// Nordic's USB vendor ID, where a PCI one would go.
+const VENDOR_ID: u16 = 0x1915;
+// Address type dynamic and volatile, PEC supported.
+const CAPABILITIES: u8 = 0b1000_0001;
+// UDID version 1 (SMBus 2.0 and later), silicon revision 0.
+const VERSION: u8 = 0b0000_1000;
+// SMBus 3.0, no ASF, IPMI or zone support.
+const INTERFACE: u16 = 0x0004;
+
+struct Arp {
+    // The register map address to return to, the config one.
+    default: u8,
+    // The address assigned, if AV.
+    assigned: Option<u8>,
+    resolved: bool,
+    // The command a following READ answers.
+    pending: Option<u8>,
+}
+
+static ARP: Mutex<RefCell<Arp>> = Mutex::new(RefCell::new(Arp {
+    default: 0,
+    assigned: None,
+    resolved: false,
+    pending: None,
+}));
+
+/// True with the feature, once `init` ran.
+pub fn is_active() -> bool {
+    cfg!(feature = "smbus-arp") && lock::free(|cs| ARP.borrow(cs).borrow().default != 0)
+}
+
+/// Starts ARP with the register map at `default`.
+pub fn init(default: u8) {
+    lock::free(|cs| ARP.borrow(cs).borrow_mut().default = default);
+    info!("SMBus ARP at {:#04x}, UDID {:02x?}", ADDRESS, udid());
+}
+
+/// The UDID, most significant byte first as sent: capabilities, version,
+/// vendor ID, device ID (the low half of INFO.PART), interface, then
+/// DEVICEID in the subsystem vendor and device IDs and the vendor-specific
+/// ID, the top half first.
+pub fn udid() -> [u8; UDID_LEN] {
+    let device_id = u64::from_le_bytes(identity::device_id());
+    let mut udid = [0; UDID_LEN];
+    udid[0] = CAPABILITIES;
+    udid[1] = VERSION;
+    udid[2..4].copy_from_slice(&VENDOR_ID.to_be_bytes());
+    udid[4..6].copy_from_slice(&(identity::part() as u16).to_be_bytes());
+    udid[6..8].copy_from_slice(&INTERFACE.to_be_bytes());
+    udid[8..].copy_from_slice(&device_id.to_be_bytes());
+    udid
+}
+
+/// Takes a WRITE at `ADDRESS`. Returns the address the register map moves
+/// to, if it does.
+pub fn write(data: &[u8]) -> Option<u8> {
+    let (&command, rest) = data.split_first()?;
+    lock::free(|cs| {
+        let mut arp = ARP.borrow(cs).borrow_mut();
+        arp.pending = None;
+        let current = arp.assigned;
+        // A block read follows these, without a PEC of their own.
+        if command == GET_UDID || current.is_some_and(|a| command == a << 1 | 1) {
+            arp.pending = Some(command);
+            return None;
+        }
+        let (&pec, body) = rest.split_last()?;
+        if smbus::write_pec(ADDRESS, &data[..data.len() - 1]) != pec {
+            warn!("ARP command {:#04x} with a wrong PEC, ignored", command);
+            AppError::from(ProtocolError::BadCrc).record();
+            return None;
+        }
+        match (command, body) {
+            (PREPARE_TO_ARP, []) => {
+                arp.resolved = false;
+                None
+            }
+            (RESET_DEVICE, []) => reset(&mut arp),
+            (ASSIGN_ADDRESS, [17, rest @ ..]) if rest.len() == UDID_LEN + 1 => {
+                let (id, address) = rest.split_at(UDID_LEN);
+                let address = address[0] >> 1;
+                if id != udid() || !(0x08..=0x77).contains(&address) || address == ADDRESS {
+                    return None;
+                }
+                arp.assigned = Some(address);
+                arp.resolved = true;
+                info!("ARP assigned {:#04x}", address);
+                Some(address)
+            }
+            (command, []) if current.is_some_and(|a| command == a << 1) => reset(&mut arp),
+            _ => None,
+        }
+    })
+}
+
+fn reset(arp: &mut Arp) -> Option<u8> {
+    arp.resolved = false;
+    arp.assigned.take()?;
+    info!("ARP reset, back at {:#04x}", arp.default);
+    Some(arp.default)
+}
+
+/// Fills `buf` for a READ at `ADDRESS`: the UDID block if a GET_UDID is
+/// pending and due, 0xff otherwise.
+pub fn read(buf: &mut [u8]) {
+    buf.fill(0xff);
+    let answer = lock::free(|cs| {
+        let mut arp = ARP.borrow(cs).borrow_mut();
+        let command = arp.pending.take()?;
+        if command == GET_UDID && arp.resolved {
+            return None;
+        }
+        Some((command, arp.assigned))
+    });
+    let Some((command, assigned)) = answer else {
+        return;
+    };
+    let mut block = [0; UDID_LEN + 3];
+    block[0] = UDID_LEN as u8 + 1;
+    block[1..][..UDID_LEN].copy_from_slice(&udid());
+    block[UDID_LEN + 1] = assigned.map_or(0xff, |a| a << 1 | 1);
+    block[UDID_LEN + 2] = smbus::read_pec(ADDRESS, command, &block[..UDID_LEN + 2]);
+    let len = block.len().min(buf.len());
+    buf[..len].copy_from_slice(&block[..len]);
+}
@@ -0,0 +1,88 @@
+// HMAC authentication of request frames (`hmac` feature).
+//
+// With the feature every frame carrying a request, after the COMMAND,
+// SEQ_COMMAND or MESSAGE register byte or COBS-decoded from STREAM, ends
+// with a `TAG_LEN`-byte tag: the first bytes of
+//
+//   HMAC-SHA256(key, counter || frame)
+//
+// with the key from `key`, the frame without the tag and the counter as
+// AUTH_COUNT reads it, u32 little-endian. A frame that does not verify is
+// refused with `ProtocolError::Unauthenticated` and counted in
+// `STATS.auth_failures`; one that does advances the counter, so a recorded
+// frame is not accepted a second time. The counter starts from a random
+// value, drawn when it is first needed, so frames recorded before a reset
+// do not verify after it either.
+//
+// The tag is truncated to fit the 32-byte TWIS buffer next to the longest
+// requests; FLASH_PROGRAM carries at most 16 bytes with it.
+
+use {
+    crate::{entropy, error::ProtocolError, key, lock, sha256, stats::STATS},
+    core::cell::Cell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const TAG_LEN: usize = 8;
+
+static COUNTER: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// The AUTH_COUNT register.
+pub fn counter() -> u32 {
+    if let Some(counter) = lock::free(|cs| COUNTER.borrow(cs).get()) {
+        return counter;
+    }
+    // Outside the critical section, see `entropy::take`.
+    let mut bytes = [0; 4];
+    entropy::take(&mut bytes);
+    lock::free(|cs| {
+        let counter = COUNTER.borrow(cs);
+        let value = counter.get().unwrap_or(u32::from_le_bytes(bytes));
+        counter.set(Some(value));
+        value
+    })
+}
+
+/// The tag of `frame` for `counter`.
+pub fn tag(counter: u32, frame: &[u8]) -> [u8; TAG_LEN] {
+    let mac = sha256::hmac(&key::get(), &[&counter.to_le_bytes(), frame]);
+    let mut tag = [0; TAG_LEN];
+    tag.copy_from_slice(&mac[..TAG_LEN]);
+    tag
+}
+
+/// Checks the tag at the end of `data`, returns the frame before it. A
+/// no-op without the feature.
+pub fn verify(data: &[u8]) -> Result<&[u8], ProtocolError> {
+    if !cfg!(feature = "hmac") {
+        return Ok(data);
+    }
+    let Some(split) = data.len().checked_sub(TAG_LEN) else {
+        STATS.auth_failures.inc();
+        return Err(ProtocolError::Unauthenticated);
+    };
+    let (frame, tag) = data.split_at(split);
+    let counter = counter();
+    // Compares every byte, so the time taken tells nothing about the tag.
+    let expected = self::tag(counter, frame);
+    let diff = expected
+        .iter()
+        .zip(tag)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    // Advance only if another frame did not get there first.
+    let verified = diff == 0
+        && lock::free(|cs| {
+            let cell = COUNTER.borrow(cs);
+            let same = cell.get() == Some(counter);
+            if same {
+                cell.set(Some(counter.wrapping_add(1)));
+            }
+            same
+        });
+    if verified {
+        Ok(frame)
+    } else {
+        STATS.auth_failures.inc();
+        Err(ProtocolError::Unauthenticated)
+    }
+}
@@ -0,0 +1,151 @@
+// Latency-versus-power benchmark.
+//
+// `run` sweeps the power configurations of the chip, the sub-power mode and
+// whether the HFXO is held, and does `ROUNDS` register reads from TWIM to
+// the local TWIS in each. A round trip is timed with the DWT cycle counter
+// from the start of the pointer write to the end of the read, so it includes
+// the TWIS wake-up and the `on_twis` latency on both transfers. The energy
+// column is the `energy` estimate per TWIS transaction, which does not model
+// the sub-power mode; measure that one with `ppk-markers`.
+//
+// All rounds run inside one burst (`end_burst` cannot run before the
+// benchmark task returns), so the mode set here is not changed by `burst`
+// until the burst ends afterwards.
+//
+// While `thermal` throttles, every configuration gets `THROTTLED_ROUNDS`.
+
+use {
+    crate::{
+        clock::{self, Hfxo},
+        controller, energy,
+        error::Op,
+        hal::{pac::TWIM1, twim::Twim},
+        logging,
+        power::{self, PowerMode},
+        profile, regmap, thermal,
+    },
+    core::fmt,
+};
+
+/// Round trips per configuration.
+pub const ROUNDS: u32 = 32;
+const THROTTLED_ROUNDS: u32 = ROUNDS / 4;
+
+#[derive(Clone, Copy)]
+struct Setup {
+    mode: PowerMode,
+    hfxo: bool,
+}
+
+const SETUPS: [Setup; 4] = [
+    Setup {
+        mode: PowerMode::ConstantLatency,
+        hfxo: true,
+    },
+    Setup {
+        mode: PowerMode::ConstantLatency,
+        hfxo: false,
+    },
+    Setup {
+        mode: PowerMode::LowPower,
+        hfxo: true,
+    },
+    Setup {
+        mode: PowerMode::LowPower,
+        hfxo: false,
+    },
+];
+
+struct Row {
+    setup: Setup,
+    // HFXO seen running after the rounds, `burst` may hold it as well.
+    hfxo_running: bool,
+    failed: u32,
+    min_us: u32,
+    avg_us: u32,
+    max_us: u32,
+    read_nj: u64,
+    write_nj: u64,
+}
+
+/// Runs the sweep against TWIS at `address` and prints the table. Clears
+/// the energy totals.
+pub fn run(twim: &mut Twim<TWIM1>, address: u8) {
+    let rounds = if thermal::throttled() {
+        THROTTLED_ROUNDS
+    } else {
+        ROUNDS
+    };
+    println!(
+        "bench: {} round trips per configuration{}, logging paused",
+        rounds,
+        if thermal::throttled() {
+            " (thermal throttle)"
+        } else {
+            ""
+        }
+    );
+    // The per-transaction log lines would dominate the round trip.
+    logging::set_quiet(true);
+    let rows = SETUPS.map(|setup| measure(twim, address, setup, rounds));
+    logging::set_quiet(false);
+    println!("  mode              hfxo   min/avg/max us      read nJ  write nJ  failed");
+    for row in &rows {
+        println!("{}", row);
+    }
+    energy::reset();
+}
+
+fn measure(twim: &mut Twim<TWIM1>, address: u8, setup: Setup, rounds: u32) -> Row {
+    let _hfxo = setup.hfxo.then(Hfxo::request);
+    let mut buf = [0; regmap::SCRATCH_LEN];
+    // Warm-up: starts the burst, powers TWIM up.
+    controller::read_regs(twim, address, regmap::SCRATCH, &mut buf).ok();
+    power::set_mode(setup.mode);
+    energy::reset();
+    let (mut failed, mut total, mut min, mut max) = (0, 0u64, u32::MAX, 0);
+    for _ in 0..rounds {
+        let start = profile::now();
+        let res = controller::read_regs(twim, address, regmap::SCRATCH, &mut buf);
+        let us = profile::micros(profile::now().wrapping_sub(start));
+        if res.is_err() {
+            failed += 1;
+            continue;
+        }
+        total += us as u64;
+        min = min.min(us);
+        max = max.max(us);
+    }
+    let ok = rounds - failed;
+    Row {
+        setup,
+        hfxo_running: clock::hfxo_running(),
+        failed,
+        min_us: if ok == 0 { 0 } else { min },
+        avg_us: total.checked_div(ok as u64).unwrap_or(0) as u32,
+        max_us: max,
+        read_nj: energy::average(Op::Read),
+        write_nj: energy::average(Op::Write),
+    }
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "  {:<17} {:<6} {:>4}/{:>4}/{:>4}      {:>7}  {:>8}  {:>6}",
+            self.setup.mode.name(),
+            match (self.setup.hfxo, self.hfxo_running) {
+                (true, _) => "on",
+                (false, true) => "burst",
+                (false, false) => "off",
+            },
+            self.min_us,
+            self.avg_us,
+            self.max_us,
+            self.read_nj,
+            self.write_nj,
+            self.failed
+        )
+    }
+}
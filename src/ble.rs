@@ -0,0 +1,217 @@
+// The register map and the statistics over BLE (`ble` feature).
+//
+// `run` takes over the idle loop: it enables the SoftDevice, then polls the
+// SoftDevice's event loop, the GATT server and the refresh by hand, as one
+// future, sleeping as `power::wait_unless` does in between. Whatever wakes
+// one of them (a SoftDevice event through SWI2, `refresh` from `heartbeat`)
+// sets `WOKEN` for the next poll.
+//
+// One vendor service, a single connection at a time:
+//
+//   window     u8, read/write  first register of `registers`, 0 at boot
+//   registers  32 bytes, read/notify  the registers from `window` as a READ
+//                                     would return them, FIFOs as 0
+//   stats      64 bytes, read/notify  the `stats::counter`s, u32 LE each
+//
+// Both are refreshed every `HEARTBEAT_MS` and notified when they changed,
+// once the central has enabled it. Writing `window` refreshes at once.
+
+// The event enum `gatt_service` generates ends every variant in `Write`.
+#![allow(clippy::enum_variant_names)]
+
+use {
+    crate::{
+        build_info,
+        power::{self, IdleStrategy},
+        regmap, softdevice, stats, systrace,
+    },
+    core::{
+        cell::{Cell, RefCell},
+        future::{poll_fn, Future},
+        pin::pin,
+        ptr,
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    },
+    embassy_futures::join::join3,
+    nrf_softdevice::{
+        ble::{
+            advertisement_builder::{
+                AdvertisementBuilder, AdvertisementPayload, Flag, ServiceList,
+            },
+            gatt_server,
+            peripheral::{self, ConnectableAdvertisement},
+            Connection,
+        },
+        Softdevice,
+    },
+};
+
+const REGISTERS_LEN: usize = 32;
+const STATS_LEN: usize = 4 * stats::COUNT;
+
+// 4e5a0000-8a3b-4f6e-9d2c-1a7b3c5d7e9f, the characteristics count up from it.
+const SERVICE_UUID: [u8; 16] = 0x4e5a0000_8a3b_4f6e_9d2c_1a7b3c5d7e9f_u128.to_le_bytes();
+
+static ADVERTISEMENT: AdvertisementPayload<31> = AdvertisementBuilder::new()
+    .flags(&[Flag::GeneralDiscovery, Flag::LE_Only])
+    .services_128(ServiceList::Complete, &[SERVICE_UUID])
+    .build();
+
+static SCAN_RESPONSE: AdvertisementPayload<31> = AdvertisementBuilder::new()
+    .full_name(build_info::NAME)
+    .build();
+
+#[nrf_softdevice::gatt_service(uuid = "4e5a0000-8a3b-4f6e-9d2c-1a7b3c5d7e9f")]
+struct RegmapService {
+    #[characteristic(uuid = "4e5a0001-8a3b-4f6e-9d2c-1a7b3c5d7e9f", read, write)]
+    window: u8,
+    #[characteristic(uuid = "4e5a0002-8a3b-4f6e-9d2c-1a7b3c5d7e9f", read, notify)]
+    registers: [u8; REGISTERS_LEN],
+    #[characteristic(uuid = "4e5a0003-8a3b-4f6e-9d2c-1a7b3c5d7e9f", read, notify)]
+    stats: [u8; STATS_LEN],
+}
+
+#[nrf_softdevice::gatt_server]
+struct Server {
+    regmap: RegmapService,
+}
+
+// The connection, if any, and what its central asked for.
+#[derive(Default)]
+struct Link {
+    conn: RefCell<Option<Connection>>,
+    window: Cell<u8>,
+    notify_registers: Cell<bool>,
+    notify_stats: Cell<bool>,
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(true);
+static REFRESH: AtomicBool = AtomicBool::new(true);
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw_waker(), wake, wake, |_| {});
+
+fn raw_waker() -> RawWaker {
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+fn wake(_: *const ()) {
+    WOKEN.store(true, Ordering::Relaxed);
+}
+
+/// Asks for the characteristics to be brought up to date, from
+/// `heartbeat`.
+pub fn refresh() {
+    REFRESH.store(true, Ordering::Relaxed);
+    WOKEN.store(true, Ordering::Relaxed);
+}
+
+/// The idle loop under `ble`.
+pub fn run(strategy: IdleStrategy) -> ! {
+    let sd = softdevice::enable();
+    let server = match Server::new(sd) {
+        Ok(server) => server,
+        Err(error) => {
+            error!("GATT server not registered: {:?}", error);
+            loop {
+                systrace::idle();
+                power::wait(strategy);
+            }
+        }
+    };
+    // The SoftDevice starts the values as filler.
+    let _ = server.regmap.window_set(&0);
+    let sd: &Softdevice = sd;
+    let link = Link::default();
+    let mut events = pin!(join3(
+        sd.run_with_callback(softdevice::on_soc_event),
+        serve(sd, &server, &link),
+        update(&server, &link),
+    ));
+    // SAFETY: the vtable's functions only touch `WOKEN`, from any context.
+    let waker = unsafe { Waker::from_raw(raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if WOKEN.swap(false, Ordering::Relaxed) {
+            let _ = events.as_mut().poll(&mut cx);
+        }
+        systrace::idle();
+        power::wait_unless(strategy, || WOKEN.load(Ordering::Relaxed));
+    }
+}
+
+// Advertises, serves one connection until it ends, and again.
+async fn serve(sd: &Softdevice, server: &Server, link: &Link) -> ! {
+    loop {
+        let advertisement = ConnectableAdvertisement::ScannableUndirected {
+            adv_data: &ADVERTISEMENT,
+            scan_data: &SCAN_RESPONSE,
+        };
+        let conn =
+            match peripheral::advertise_connectable(sd, advertisement, &Default::default()).await {
+                Ok(conn) => conn,
+                Err(error) => {
+                    warn!("BLE advertising failed: {:?}", error);
+                    continue;
+                }
+            };
+        info!("BLE connected");
+        link.conn.replace(Some(conn.clone()));
+        gatt_server::run(&conn, server, |event| match event {
+            ServerEvent::Regmap(RegmapServiceEvent::WindowWrite(window)) => {
+                link.window.set(window);
+                refresh();
+            }
+            ServerEvent::Regmap(RegmapServiceEvent::RegistersCccdWrite { notifications }) => {
+                link.notify_registers.set(notifications);
+            }
+            ServerEvent::Regmap(RegmapServiceEvent::StatsCccdWrite { notifications }) => {
+                link.notify_stats.set(notifications);
+            }
+        })
+        .await;
+        link.conn.take();
+        link.notify_registers.set(false);
+        link.notify_stats.set(false);
+        info!("BLE disconnected");
+    }
+}
+
+// Brings the characteristics up to date on every `refresh`.
+async fn update(server: &Server, link: &Link) -> ! {
+    let service = &server.regmap;
+    let (mut last_registers, mut last_stats) = ([0; REGISTERS_LEN], [0; STATS_LEN]);
+    loop {
+        poll_fn(|_| {
+            if REFRESH.swap(false, Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        let mut registers = [0; REGISTERS_LEN];
+        regmap::mirror(link.window.get(), &mut registers);
+        let mut counters = [0; STATS_LEN];
+        for (i, bytes) in counters.chunks_exact_mut(4).enumerate() {
+            let value = stats::counter(i).map_or(0, |c| c.get());
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        let _ = service.registers_set(&registers);
+        let _ = service.stats_set(&counters);
+        let conn = link.conn.borrow();
+        if let Some(conn) = conn.as_ref() {
+            if link.notify_registers.get() && registers != last_registers {
+                if let Err(error) = service.registers_notify(conn, &registers) {
+                    trace!("registers not notified: {:?}", error);
+                }
+            }
+            if link.notify_stats.get() && counters != last_stats {
+                if let Err(error) = service.stats_notify(conn, &counters) {
+                    trace!("stats not notified: {:?}", error);
+                }
+            }
+        }
+        (last_registers, last_stats) = (registers, counters);
+    }
+}
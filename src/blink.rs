@@ -0,0 +1,107 @@
+// Error indication on the on-board LED.
+//
+// Each class of error has its own blink code: a burst of N short flashes
+// followed by a long pause, repeated until the button is pressed. A panic
+// takes over the LED with continuous fast flashing since no tasks run after
+// that point. This gives headless boards a visible failure indication when
+// no RTT host is attached.
+
+use {
+    crate::{
+        board,
+        hal::{
+            gpio::{Output, Pin, PushPull},
+            prelude::*,
+        },
+    },
+    core::sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The red LED of the board (active low).
+pub const ERROR_LED_PIN: usize = board::LED_RED;
+
+const FLASH_MS: u64 = 150;
+const PAUSE_MS: u64 = 1000;
+
+// Latched error class; 0 means no error.
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Error classes, in increasing order of severity. The discriminant is the
+/// number of flashes in the blink code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum ErrorClass {
+    /// NACK, overrun or other failure on the I2C bus.
+    Bus = 2,
+    /// TWIS received more bytes than its DMA buffer holds.
+    BufferOverflow = 3,
+    /// The previous reset was caused by the watchdog.
+    WatchdogRecovery = 4,
+}
+
+/// Latches `class` for display. A more severe class already being shown is
+/// kept. Returns true if the blink task needs to be started.
+pub fn report(class: ErrorClass) -> bool {
+    let previous = ACTIVE.fetch_max(class as u8, Ordering::Relaxed);
+    previous == 0
+}
+
+/// Clears the latched error; the LED goes dark at the end of the current flash.
+pub fn clear() {
+    ACTIVE.store(0, Ordering::Relaxed);
+}
+
+/// Drives the LED through the blink code of the latched error class.
+pub struct Blinker {
+    led: Pin<Output<PushPull>>,
+    // Position within the current blink code, counting on and off phases.
+    phase: u8,
+}
+
+impl Blinker {
+    pub fn new(led: Pin<Output<PushPull>>) -> Self {
+        Blinker { led, phase: 0 }
+    }
+
+    /// Advances the blink code by one phase and returns the delay until the
+    /// next call, or `None` once the error has been cleared.
+    pub fn step(&mut self) -> Option<u64> {
+        let flashes = ACTIVE.load(Ordering::Relaxed);
+        if flashes == 0 {
+            self.led.set_high().ok();
+            self.phase = 0;
+            return None;
+        }
+        // `ACTIVE` only grows until cleared, so the code never gets shorter
+        // under our feet.
+        if !debug_check!(self.phase < 2 * flashes) {
+            self.phase = 0;
+        }
+        let phase = self.phase;
+        self.phase = (phase + 1) % (2 * flashes);
+        if phase.is_multiple_of(2) {
+            self.led.set_low().ok();
+            Some(FLASH_MS)
+        } else if self.phase == 0 {
+            self.led.set_high().ok();
+            Some(PAUSE_MS)
+        } else {
+            self.led.set_high().ok();
+            Some(FLASH_MS)
+        }
+    }
+}
+
+/// Flashes the LED forever. Only for the panic handler: takes the pin over
+/// without regard for its owner.
+pub fn panic_loop() -> ! {
+    // SAFETY: interrupts are disabled and nothing else runs after a panic.
+    let (port, bit) = board::port(ERROR_LED_PIN);
+    port.pin_cnf[ERROR_LED_PIN % 32].write(|w| w.dir().output());
+    loop {
+        port.outclr.write(|w| unsafe { w.bits(bit) });
+        cortex_m::asm::delay(4_000_000);
+        port.outset.write(|w| unsafe { w.bits(bit) });
+        cortex_m::asm::delay(4_000_000);
+    }
+}
@@ -0,0 +1,243 @@
+// A Bosch BME280 at TWIS address 1 (`bme280` feature).
+//
+// The register layout of the real part, so BME280 drivers run against it
+// unchanged:
+//
+//   0x88-0xa1  calibration: T1-T3, P1-P9, then H1      r
+//   0xd0       chip ID, 0x60                             r
+//   0xe0       reset: 0xb6 resets the part               w
+//   0xe1-0xe7  calibration: H2-H6                        r
+//   0xf2       ctrl_hum, humidity oversampling           rw
+//   0xf3       status, never measuring                   r
+//   0xf4       ctrl_meas: temperature and pressure
+//              oversampling, mode                        rw
+//   0xf5       config, kept but without effect           rw
+//   0xf7-0xfe  pressure, temperature (20 bits each) and
+//              humidity (16 bits), most significant first  r
+//
+// A WRITE is a register address, then pairs of value and address, as on
+// the part; a READ starts at the last address written and counts up. The
+// READ does not move that address, so the next one starts at the same
+// place.
+//
+// Measurements are instant. A forced mode write to ctrl_meas takes one and
+// goes back to sleep mode, in normal mode every READ takes one. They are
+// raw ADC values which the calibration, the example one of the datasheet
+// for T and P, turns into the die temperature from `thermal`, `PRESSURE_PA`
+// and `HUMIDITY_PERCENT`: the raw values are found by bisection on the
+// datasheet's own compensation formulas. A measurement with oversampling 0
+// (skipped) reads 0x80000, or 0x8000 for the humidity, as on the part.
+
+use {
+    crate::{lock, thermal},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// SDO tied low.
+pub const ADDRESS: u8 = 0x76;
+/// Synthetic pressure, at sea level.
+pub const PRESSURE_PA: i64 = 101_325;
+pub const HUMIDITY_PERCENT: i64 = 45;
+
+const CHIP_ID: u8 = 0x60;
+const RESET_VALUE: u8 = 0xb6;
+
+const CALIB_00: u8 = 0x88;
+const ID: u8 = 0xd0;
+const RESET: u8 = 0xe0;
+const CALIB_26: u8 = 0xe1;
+const CTRL_HUM: u8 = 0xf2;
+const CTRL_MEAS: u8 = 0xf4;
+const CONFIG: u8 = 0xf5;
+const DATA: u8 = 0xf7;
+
+// Mode bits of ctrl_meas.
+const MODE: u8 = 0b11;
+const MODE_NORMAL: u8 = 0b11;
+
+const T: (u16, i16, i16) = (27504, 26435, -1000);
+const P1: u16 = 36477;
+const P: [i16; 8] = [-10685, 3024, 2855, 140, -7, 15500, -14600, 6000];
+const H: (u8, i16, u8, i16, i16, i8) = (75, 362, 0, 313, 50, 30);
+
+const SKIPPED: [u8; 8] = [0x80, 0, 0, 0x80, 0, 0, 0x80, 0];
+
+struct Bme280 {
+    pointer: u8,
+    ctrl_hum: u8,
+    ctrl_meas: u8,
+    config: u8,
+    // Pressure, temperature and humidity as in 0xf7-0xfe.
+    data: [u8; 8],
+}
+
+impl Bme280 {
+    const RESET: Self = Self {
+        pointer: 0,
+        ctrl_hum: 0,
+        ctrl_meas: 0,
+        config: 0,
+        data: SKIPPED,
+    };
+}
+
+static BME280: Mutex<RefCell<Bme280>> = Mutex::new(RefCell::new(Bme280::RESET));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "bme280")
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    let Some((&pointer, pairs)) = data.split_first() else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut bme = BME280.borrow(cs).borrow_mut();
+        bme.pointer = pointer;
+        let mut address = pointer;
+        for pair in pairs.chunks(2) {
+            let value = pair[0];
+            match address {
+                RESET if value == RESET_VALUE => *bme = Bme280::RESET,
+                CTRL_HUM => bme.ctrl_hum = value & 0b111,
+                CTRL_MEAS if value & MODE == 0b01 || value & MODE == 0b10 => {
+                    bme.data = measure(bme.ctrl_hum, value);
+                    bme.ctrl_meas = value & !MODE;
+                }
+                CTRL_MEAS => bme.ctrl_meas = value,
+                CONFIG => bme.config = value & !0b10,
+                _ => {}
+            }
+            let Some(&next) = pair.get(1) else {
+                break;
+            };
+            address = next;
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`, from the last address written.
+pub fn read(buf: &mut [u8]) {
+    let calibration = calibration();
+    lock::free(|cs| {
+        let mut bme = BME280.borrow(cs).borrow_mut();
+        if bme.ctrl_meas & MODE == MODE_NORMAL {
+            bme.data = measure(bme.ctrl_hum, bme.ctrl_meas);
+        }
+        let mut address = bme.pointer;
+        for byte in buf.iter_mut() {
+            *byte = match address {
+                CALIB_00..=0xa1 => calibration[(address - CALIB_00) as usize],
+                ID => CHIP_ID,
+                CALIB_26..=0xe7 => calibration[26 + (address - CALIB_26) as usize],
+                CTRL_HUM => bme.ctrl_hum,
+                CTRL_MEAS => bme.ctrl_meas,
+                CONFIG => bme.config,
+                DATA..=0xfe => bme.data[(address - DATA) as usize],
+                _ => 0,
+            };
+            address = address.wrapping_add(1);
+        }
+    });
+}
+
+// The calibration registers, 0x88-0xa1 then 0xe1-0xe7.
+fn calibration() -> [u8; 33] {
+    let mut calib = [0; 33];
+    calib[0..2].copy_from_slice(&T.0.to_le_bytes());
+    calib[2..4].copy_from_slice(&T.1.to_le_bytes());
+    calib[4..6].copy_from_slice(&T.2.to_le_bytes());
+    calib[6..8].copy_from_slice(&P1.to_le_bytes());
+    for (i, p) in P.iter().enumerate() {
+        calib[8 + 2 * i..][..2].copy_from_slice(&p.to_le_bytes());
+    }
+    calib[25] = H.0;
+    calib[26..28].copy_from_slice(&H.1.to_le_bytes());
+    calib[28] = H.2;
+    // H4 and H5 are 12 bits each, sharing 0xe5.
+    calib[29] = (H.3 >> 4) as u8;
+    calib[30] = (H.4 as u8 & 0x0f) << 4 | (H.3 as u8 & 0x0f);
+    calib[31] = (H.4 >> 4) as u8;
+    calib[32] = H.5 as u8;
+    calib
+}
+
+// A measurement with the oversampling settings of `ctrl_hum` and
+// `ctrl_meas`.
+fn measure(ctrl_hum: u8, ctrl_meas: u8) -> [u8; 8] {
+    let celsius = thermal::quarters() as i32 * 25;
+    let adc_t = search(20, |raw| temperature(raw).0 < celsius);
+    let t_fine = temperature(adc_t).1;
+    let adc_p = search(20, |raw| pressure(raw, t_fine) > PRESSURE_PA << 8);
+    let adc_h = search(16, |raw| humidity(raw, t_fine) < HUMIDITY_PERCENT << 10);
+    let mut data = SKIPPED;
+    if ctrl_meas >> 2 & 0b111 != 0 {
+        data[0..3].copy_from_slice(&(adc_p << 4).to_be_bytes()[1..]);
+    }
+    if ctrl_meas >> 5 != 0 {
+        data[3..6].copy_from_slice(&(adc_t << 4).to_be_bytes()[1..]);
+    }
+    if ctrl_hum != 0 {
+        data[6..8].copy_from_slice(&(adc_h as u16).to_be_bytes());
+    }
+    data
+}
+
+// The first of the `bits`-bit raw values for which `below` is false, or
+// the largest one.
+fn search(bits: u32, below: impl Fn(i32) -> bool) -> i32 {
+    let (mut low, mut high) = (0, 1 << bits);
+    while low < high {
+        let mid = (low + high) / 2;
+        if below(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low.min((1 << bits) - 1)
+}
+
+// The datasheet's compensation: temperature in 0.01 degrees C and t_fine.
+fn temperature(adc: i32) -> (i32, i32) {
+    let (t1, t2, t3) = (T.0 as i32, T.1 as i32, T.2 as i32);
+    let var1 = (((adc >> 3) - (t1 << 1)) * t2) >> 11;
+    let var2 = (((((adc >> 4) - t1) * ((adc >> 4) - t1)) >> 12) * t3) >> 14;
+    let t_fine = var1 + var2;
+    ((t_fine * 5 + 128) >> 8, t_fine)
+}
+
+// Pressure in Pa as Q24.8.
+fn pressure(adc: i32, t_fine: i32) -> i64 {
+    let [p2, p3, p4, p5, p6, p7, p8, p9] = P.map(|p| p as i64);
+    let mut var1 = t_fine as i64 - 128_000;
+    let mut var2 = var1 * var1 * p6;
+    var2 += (var1 * p5) << 17;
+    var2 += p4 << 35;
+    var1 = ((var1 * var1 * p3) >> 8) + ((var1 * p2) << 12);
+    var1 = (((1 << 47) + var1) * P1 as i64) >> 33;
+    if var1 == 0 {
+        return 0;
+    }
+    let mut p = 1_048_576 - adc as i64;
+    p = (((p << 31) - var2) * 3125) / var1;
+    var1 = (p9 * (p >> 13) * (p >> 13)) >> 25;
+    var2 = (p8 * p) >> 19;
+    ((p + var1 + var2) >> 8) + (p7 << 4)
+}
+
+// Relative humidity in % as Q22.10, in 64 bits where the datasheet has 32.
+fn humidity(adc: i32, t_fine: i32) -> i64 {
+    let (h1, h2, h3, h4, h5, h6) = (
+        H.0 as i64, H.1 as i64, H.2 as i64, H.3 as i64, H.4 as i64, H.5 as i64,
+    );
+    let v = t_fine as i64 - 76_800;
+    let v = ((((adc as i64) << 14) - (h4 << 20) - (h5 * v) + 16_384) >> 15)
+        * (((((((v * h6) >> 10) * (((v * h3) >> 11) + 32_768)) >> 10) + 2_097_152) * h2 + 8_192)
+            >> 14);
+    let v = v - (((((v >> 15) * (v >> 15)) >> 7) * h1) >> 4);
+    v.clamp(0, 419_430_400) >> 12
+}
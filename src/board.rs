@@ -0,0 +1,358 @@
+// Pin map of the supported boards, as used by this firmware.
+//
+// The board is selected with a feature: `board-dk` for the nRF52840-DK,
+// `board-dongle` for the nRF52840 Dongle, and the nRF52840-MDK otherwise
+// (`board-mdk`). Built for another `chip`, it is that chip's DK, the
+// nRF52833-DK or the nRF52-DK. The bus, button and LED pins differ between
+// them and are in the PSEL numbering, P0.n as n and P1.n as 32 + n, so they
+// can be on either port; `init` takes them with `take`. The other pins are
+// the same on every board and on P0 unless listed under P1, taken from the
+// HAL by name. This table lists all of them by number, for code that handles
+// every pin at once such as `power::park_unused_pins`. When porting to
+// another board, add a `pins` module for it; a fork that only moves the bus
+// pins can set them at build time instead, see `build_config`.
+
+#[cfg(not(feature = "nrf52832"))]
+use crate::hal::pac::P1;
+use crate::{
+    build_config, chip,
+    hal::{
+        gpio::{Disconnected, Pin},
+        pac::{p0::RegisterBlock, P0},
+    },
+};
+
+/// P1.`pin` in the PSEL numbering.
+pub const fn p1(pin: usize) -> usize {
+    32 + pin
+}
+
+#[cfg(not(any(
+    feature = "board-dk",
+    feature = "board-dongle",
+    feature = "nrf52833",
+    feature = "nrf52832"
+)))]
+mod pins {
+    use super::p1;
+
+    pub const NAME: &str = "nRF52840-MDK";
+    pub const TWIS_SCL: usize = 15;
+    pub const TWIS_SDA: usize = 16;
+    pub const TWIM_SCL: usize = 27;
+    pub const TWIM_SDA: usize = 26;
+    pub const BUTTON: usize = p1(0);
+    /// The RGB LED, active low.
+    pub const LED_GREEN: usize = 22;
+    pub const LED_RED: usize = 23;
+    pub const LED_BLUE: usize = 24;
+    /// Enable input gating TWIS, see `busgate`, on P0.
+    pub const ENABLE_IN: usize = 13;
+    /// Toggled on every error, see `signal`, on P0.
+    pub const ERROR_OUT: usize = 14;
+}
+
+// TWIS on the Arduino header's D8 and D9, TWIM on its SCL and SDA.
+#[cfg(feature = "board-dk")]
+mod pins {
+    use super::p1;
+
+    pub const NAME: &str = "nRF52840-DK";
+    pub const TWIS_SCL: usize = p1(10);
+    pub const TWIS_SDA: usize = p1(11);
+    pub const TWIM_SCL: usize = 27;
+    pub const TWIM_SDA: usize = 26;
+    /// Button 4.
+    pub const BUTTON: usize = 25;
+    /// LED1 to LED3, active low.
+    pub const LED_GREEN: usize = 13;
+    pub const LED_RED: usize = 14;
+    pub const LED_BLUE: usize = 15;
+    /// Button 3: holding it closes the bus.
+    pub const ENABLE_IN: usize = 24;
+    /// LED4.
+    pub const ERROR_OUT: usize = 16;
+}
+
+// All bus pins on the castellated edge.
+#[cfg(feature = "board-dongle")]
+mod pins {
+    use super::p1;
+
+    pub const NAME: &str = "nRF52840 Dongle";
+    pub const TWIS_SCL: usize = 15;
+    pub const TWIS_SDA: usize = 22;
+    pub const TWIM_SCL: usize = p1(13);
+    pub const TWIM_SDA: usize = p1(15);
+    /// SW1.
+    pub const BUTTON: usize = p1(6);
+    /// LED1 as green, the red and blue channels of LED2, active low.
+    pub const LED_GREEN: usize = 6;
+    pub const LED_RED: usize = 8;
+    pub const LED_BLUE: usize = 12;
+    pub const ENABLE_IN: usize = 13;
+    pub const ERROR_OUT: usize = 24;
+}
+
+// As on the nRF52840-DK, but with TWIS on P0: P1 stops at P1.09.
+#[cfg(all(
+    feature = "nrf52833",
+    not(any(feature = "board-dk", feature = "board-dongle"))
+))]
+mod pins {
+    pub const NAME: &str = "nRF52833-DK";
+    pub const TWIS_SCL: usize = 22;
+    pub const TWIS_SDA: usize = 23;
+    pub const TWIM_SCL: usize = 27;
+    pub const TWIM_SDA: usize = 26;
+    /// Button 4.
+    pub const BUTTON: usize = 25;
+    /// LED1 to LED3, active low.
+    pub const LED_GREEN: usize = 13;
+    pub const LED_RED: usize = 14;
+    pub const LED_BLUE: usize = 15;
+    /// Button 3: holding it closes the bus.
+    pub const ENABLE_IN: usize = 24;
+    /// LED4.
+    pub const ERROR_OUT: usize = 16;
+}
+
+// The nRF52-DK (PCA10040), TWIM on the Arduino header's SCL and SDA.
+#[cfg(all(
+    feature = "nrf52832",
+    not(any(feature = "board-dk", feature = "board-dongle"))
+))]
+mod pins {
+    pub const NAME: &str = "nRF52-DK";
+    pub const TWIS_SCL: usize = 22;
+    pub const TWIS_SDA: usize = 23;
+    pub const TWIM_SCL: usize = 27;
+    pub const TWIM_SDA: usize = 26;
+    /// Button 4.
+    pub const BUTTON: usize = 16;
+    /// LED1 to LED3, active low.
+    pub const LED_GREEN: usize = 17;
+    pub const LED_RED: usize = 18;
+    pub const LED_BLUE: usize = 19;
+    /// Button 3: holding it closes the bus.
+    pub const ENABLE_IN: usize = 15;
+    /// LED4.
+    pub const ERROR_OUT: usize = 20;
+}
+
+pub use pins::*;
+
+// The bus pins `build_config` sets instead, shadowing those of `pins`.
+pub const TWIS_SCL: usize = or(build_config::TWIS_SCL, pins::TWIS_SCL);
+pub const TWIS_SDA: usize = or(build_config::TWIS_SDA, pins::TWIS_SDA);
+pub const TWIM_SCL: usize = or(build_config::TWIM_SCL, pins::TWIM_SCL);
+pub const TWIM_SDA: usize = or(build_config::TWIM_SDA, pins::TWIM_SDA);
+
+const fn or(pin: Option<usize>, default: usize) -> usize {
+    match pin {
+        Some(pin) => pin,
+        None => default,
+    }
+}
+
+const _: () = assert!(
+    cfg!(feature = "board-mdk") as u8
+        + cfg!(feature = "board-dk") as u8
+        + cfg!(feature = "board-dongle") as u8
+        <= 1,
+    "more than one board selected"
+);
+
+// The board specific pins in use with every feature set.
+const BOARD_PINS: [usize; 9] = [
+    TWIS_SCL, TWIS_SDA, TWIM_SCL, TWIM_SDA, BUTTON, LED_GREEN, LED_RED, LED_BLUE, ENABLE_IN,
+];
+
+const _: () = assert!(ENABLE_IN < 32 && ERROR_OUT < 32);
+const _: () = assert!(on_chip(&BOARD_PINS), "board pin not on this chip");
+const _: () = assert!(
+    distinct(&[
+        TWIS_SCL, TWIS_SDA, TWIM_SCL, TWIM_SDA, BUTTON, LED_GREEN, LED_RED, LED_BLUE, ENABLE_IN,
+        ERROR_OUT, TRIGGER, ANALOG_IN, RESET,
+    ]),
+    "board pin used twice"
+);
+// Nor one of the pins `init` takes by name for an enabled feature, which a
+// bus pin moved by `build_config` could land on.
+const _: () = assert!(
+    disjoint(
+        port_mask(0, &BOARD_PINS) | mask_if(cfg!(feature = "egu-signals"), &[ERROR_OUT]),
+        FEATURE_P0
+    ) && disjoint(port_mask(1, &BOARD_PINS), FEATURE_P1),
+    "board pin also used by a feature"
+);
+
+/// Takes a board specific pin.
+///
+/// # Safety
+///
+/// Each pin is taken once, and not by name from the HAL as well.
+pub unsafe fn take(pin: usize) -> Pin<Disconnected> {
+    Pin::from_psel_bits(pin as u32)
+}
+
+/// The GPIO port of a pin in the PSEL numbering, and its bit there.
+pub fn port(pin: usize) -> (&'static RegisterBlock, u32) {
+    // SAFETY: callers only touch the bit of the pin, which they own.
+    let port = unsafe {
+        match pin / 32 {
+            #[cfg(not(feature = "nrf52832"))]
+            1 => &*P1::ptr(),
+            _ => &*P0::ptr(),
+        }
+    };
+    (port, 1 << (pin % 32))
+}
+
+/// P0 pins on every board.
+pub const TRIGGER: usize = 3;
+pub const SPIS_SCK: usize = 7;
+pub const SPIS_CSN: usize = 8;
+pub const SPIS_MOSI: usize = 11;
+pub const SPIS_MISO: usize = 12;
+pub const MARKER_ACTIVE: usize = 4;
+pub const MARKER_DMA: usize = 5;
+pub const MARKER_SLEEP: usize = 6;
+/// Rotary encoder phases.
+pub const QDEC_A: usize = 2;
+pub const QDEC_B: usize = 29;
+/// Data line of the WS2812 strip.
+pub const WS2812_DATA: usize = 17;
+/// NFC antenna, with UICR NFCPINS left at its default.
+pub const NFC1: usize = 9;
+pub const NFC2: usize = 10;
+pub const UARTE_RXD: usize = 19;
+pub const UARTE_TXD: usize = 20;
+/// AIN4, the LPCOMP input.
+pub const ANALOG_IN: usize = 28;
+/// PDM microphone.
+pub const PDM_CLK: usize = 30;
+pub const PDM_DIN: usize = 31;
+/// Shunt inputs of `ina219`, AIN6 and AIN7.
+pub const SHUNT_POSITIVE: usize = 30;
+pub const SHUNT_NEGATIVE: usize = 31;
+
+/// P1 pins on every board.
+/// First of the 8 GPIO expander port pins, P1.01-P1.08.
+pub const EXPANDER_PORT: usize = 1;
+pub const EXPANDER_INT: usize = 9;
+/// The QSPI flash.
+pub const QSPI_IO3: usize = 1;
+pub const QSPI_IO2: usize = 2;
+pub const QSPI_SCK: usize = 3;
+pub const QSPI_IO1: usize = 4;
+pub const QSPI_IO0: usize = 5;
+pub const QSPI_CSN: usize = 6;
+
+// Owned by the chip rather than by firmware: the 32.768 kHz crystal and the
+// pin reset.
+const XL1: usize = 0;
+const XL2: usize = 1;
+const RESET: usize = chip::RESET_PIN;
+
+const fn mask(pins: &[usize]) -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < pins.len() {
+        mask |= 1 << pins[i];
+        i += 1;
+    }
+    mask
+}
+
+// The pins of `pins`, in the PSEL numbering, that are on `port`.
+const fn port_mask(port: usize, pins: &[usize]) -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < pins.len() {
+        if pins[i] / 32 == port {
+            mask |= 1 << (pins[i] % 32);
+        }
+        i += 1;
+    }
+    mask
+}
+
+const fn distinct(pins: &[usize]) -> bool {
+    let mut i = 0;
+    while i < pins.len() {
+        let mut j = i + 1;
+        while j < pins.len() {
+            if pins[i] == pins[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn disjoint(a: u32, b: u32) -> bool {
+    a & b == 0
+}
+
+const fn on_chip(pins: &[usize]) -> bool {
+    let mut i = 0;
+    while i < pins.len() {
+        if pins[i] >= 32 + P1_PINS {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn mask_if(on: bool, pins: &[usize]) -> u32 {
+    if on {
+        mask(pins)
+    } else {
+        0
+    }
+}
+
+/// P0 pins in use with the enabled features.
+pub const P0_USED: u32 = mask(&[TRIGGER, ANALOG_IN, RESET])
+    | port_mask(0, &BOARD_PINS)
+    | mask_if(cfg!(feature = "egu-signals"), &[ERROR_OUT])
+    | FEATURE_P0;
+
+/// P1 pins in use with the enabled features.
+pub const P1_USED: u32 = port_mask(1, &BOARD_PINS) | FEATURE_P1;
+
+// The fixed pins of the enabled features, taken from the HAL by name.
+const FEATURE_P0: u32 = mask_if(cfg!(feature = "pdm-mic"), &[PDM_CLK, PDM_DIN])
+    | mask_if(
+        cfg!(feature = "ppk-markers"),
+        &[MARKER_ACTIVE, MARKER_DMA, MARKER_SLEEP],
+    )
+    | mask_if(
+        cfg!(any(feature = "telemetry", feature = "uart-bridge")),
+        &[UARTE_RXD, UARTE_TXD],
+    )
+    | mask_if(
+        cfg!(feature = "spis"),
+        &[SPIS_SCK, SPIS_CSN, SPIS_MOSI, SPIS_MISO],
+    )
+    | mask_if(cfg!(feature = "ws2812"), &[WS2812_DATA])
+    | mask_if(cfg!(feature = "nfc-tag"), &[NFC1, NFC2])
+    | mask_if(cfg!(feature = "qdec"), &[QDEC_A, QDEC_B])
+    | mask_if(cfg!(feature = "ina219"), &[SHUNT_POSITIVE, SHUNT_NEGATIVE])
+    | mask_if(cfg!(feature = "lfclk-xtal"), &[XL1, XL2]);
+
+const FEATURE_P1: u32 = mask_if(
+    cfg!(feature = "qspi-flash"),
+    &[QSPI_IO3, QSPI_IO2, QSPI_SCK, QSPI_IO1, QSPI_IO0, QSPI_CSN],
+) | if cfg!(feature = "gpio-expander") {
+    0xff << EXPANDER_PORT | 1 << EXPANDER_INT
+} else {
+    0
+};
+
+/// Pins on P1; P0 has 32.
+pub const P1_PINS: usize = chip::P1_PINS;
@@ -0,0 +1,47 @@
+// Reset into a bootloader on request, for field updates driven by the
+// controller.
+//
+// A bootloader that stays resident after a reset if GPREGRET holds its
+// magic value is entered by writing that value there and resetting. Those
+// known here:
+//
+//   0xb1  nRF5 SDK secure bootloader, BOOTLOADER_DFU_START
+//   0x57  Adafruit nRF52 bootloader, UF2 drive
+//   0x4e  Adafruit nRF52 bootloader, serial DFU only
+//   0xa8  Adafruit nRF52 bootloader, BLE DFU
+//
+// GPREGRET is retained over a soft reset, so nothing has to go into RAM.
+// Without a bootloader the board simply resets, and a value that stays in
+// GPREGRET is left to the next one to clear. With `ble` the SoftDevice owns
+// POWER, and writes GPREGRET for us.
+
+use crate::hal::pac::POWER;
+#[cfg(feature = "ble")]
+use crate::softdevice;
+
+pub const MAGICS: [u8; 4] = [0xb1, 0x57, 0x4e, 0xa8];
+/// How long after the request the board resets.
+pub const RESET_DELAY_MS: u64 = 50;
+
+/// True for a GPREGRET value some bootloader looks for.
+pub fn is_magic(magic: u8) -> bool {
+    MAGICS.contains(&magic)
+}
+
+/// Sets GPREGRET to `magic` and resets.
+pub fn enter(magic: u8) -> ! {
+    warn!("resetting into the bootloader, GPREGRET {:#04x}", magic);
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        softdevice::set_gpregret(magic);
+        cortex_m::interrupt::disable();
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+    cortex_m::interrupt::disable();
+    // SAFETY: a single write to a retained register, with interrupts off
+    // right before the reset.
+    unsafe {
+        (*POWER::ptr()).gpregret.write(|w| w.gpregret().bits(magic));
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,215 @@
+// UART-to-I2C bridge (`uart-bridge` feature).
+//
+// UARTE1 on the pins of the MDK's DAPLink virtual COM port (RXD P0.19, TXD
+// P0.20, 115200 8N1) takes line-based ASCII commands and runs them on the
+// TWIM bus, so the board doubles as a USB-serial I2C adapter. Numbers are
+// hex, replies are one line:
+//
+//   scan                    found 1a 3c             addresses ACKing a read
+//   r <addr> <len>          ok 01 02 03             read `len` (1..=10) bytes
+//   w <addr> <byte>...      ok                      write up to 16 bytes
+//
+// anything else, or a failed transaction, replies `err <reason>`.
+//
+// Reception runs from the UARTE1 interrupt with a one-byte DMA buffer that
+// the ENDRX_STARTRX short re-arms right away; a complete line goes to the
+// `bridge_cmd` task, which owns the transmitter. A line arriving while the
+// previous one is still being carried out is dropped.
+
+use {
+    crate::{
+        controller,
+        hal::{pac::TWIM1, twim::Twim, uarte::Uarte},
+        stats::STATS,
+    },
+    core::{
+        fmt::{self, Write},
+        ptr::{addr_of, addr_of_mut},
+    },
+};
+
+// The nRF52832 has no UARTE1 and `main` refuses the feature there, so
+// UARTE0 stands in to keep this building.
+#[cfg(feature = "nrf52832")]
+use crate::hal::pac::UARTE0 as UARTE1;
+#[cfg(not(feature = "nrf52832"))]
+use crate::hal::pac::UARTE1;
+
+pub const LINE_LEN: usize = 64;
+// Room for a scan that finds 40 devices.
+const REPLY_LEN: usize = 128;
+
+const MAX_TRANSFER: usize = 16;
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+
+// Target of the receive DMA.
+static mut RX_BYTE: u8 = 0;
+
+/// A received command line, without the terminator.
+pub struct Line {
+    buf: [u8; LINE_LEN],
+    len: usize,
+}
+
+/// Collects received bytes into lines; state of the UARTE1 interrupt.
+pub struct Receiver {
+    line: Line,
+}
+
+pub struct Bridge {
+    uarte: Uarte<UARTE1>,
+}
+
+fn regs() -> &'static crate::hal::pac::uarte0::RegisterBlock {
+    // SAFETY: the receive registers are only used here and from the UARTE1
+    // interrupt; the transmit side belongs to `Bridge::uarte`.
+    unsafe { &*UARTE1::ptr() }
+}
+
+impl Bridge {
+    /// Starts reception on the configured `uarte`.
+    pub fn new(uarte: Uarte<UARTE1>) -> Self {
+        let regs = regs();
+        regs.rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(addr_of_mut!(RX_BYTE) as u32) });
+        regs.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+        regs.shorts.modify(|_, w| w.endrx_startrx().enabled());
+        regs.intenset.write(|w| w.endrx().set());
+        regs.tasks_startrx.write(|w| unsafe { w.bits(1) });
+        Bridge { uarte }
+    }
+
+    /// Carries out `line` and sends the reply.
+    pub fn run(&mut self, twim: &mut Twim<TWIM1>, line: &Line) {
+        let mut reply = Reply {
+            buf: [0; REPLY_LEN],
+            len: 0,
+        };
+        execute(twim, line.as_str(), &mut reply).ok();
+        reply.write_str("\r\n").ok();
+        if self.uarte.write(&reply.buf[..reply.len]).is_err() {
+            STATS.errors.inc();
+        }
+    }
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Receiver {
+            line: Line {
+                buf: [0; LINE_LEN],
+                len: 0,
+            },
+        }
+    }
+
+    /// Handles ENDRX, returns a line once it is complete. Bytes past
+    /// `LINE_LEN` are dropped.
+    pub fn on_interrupt(&mut self) -> Option<Line> {
+        let regs = regs();
+        if regs.events_endrx.read().bits() == 0 {
+            return None;
+        }
+        regs.events_endrx.reset();
+        // SAFETY: the DMA finished writing it, and the next byte takes
+        // ~87 us to arrive.
+        let byte = unsafe { addr_of!(RX_BYTE).read_volatile() };
+        let line = &mut self.line;
+        match byte {
+            b'\n' | b'\r' if line.len > 0 => {
+                let done = Line {
+                    buf: line.buf,
+                    len: line.len,
+                };
+                line.len = 0;
+                Some(done)
+            }
+            b'\n' | b'\r' => None,
+            b if line.len < LINE_LEN => {
+                line.buf[line.len] = b;
+                line.len += 1;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Line {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len])
+            .unwrap_or("")
+            .trim()
+    }
+}
+
+// Formats a reply into a fixed buffer, silently truncating.
+struct Reply {
+    buf: [u8; REPLY_LEN],
+    len: usize,
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(REPLY_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn hex(word: Option<&str>) -> Option<u8> {
+    u8::from_str_radix(word?, 16).ok()
+}
+
+fn execute(twim: &mut Twim<TWIM1>, line: &str, reply: &mut Reply) -> fmt::Result {
+    let mut words = line.split_ascii_whitespace();
+    match words.next() {
+        Some("scan") => {
+            reply.write_str("found")?;
+            for address in FIRST_ADDRESS..=LAST_ADDRESS {
+                if controller::probe(twim, address) {
+                    write!(reply, " {:02x}", address)?;
+                }
+            }
+            Ok(())
+        }
+        Some("r") => match (hex(words.next()), hex(words.next())) {
+            (Some(address), Some(len)) if (1..=MAX_TRANSFER).contains(&(len as usize)) => {
+                let buf = &mut [0; MAX_TRANSFER][..len as usize];
+                match controller::read(twim, address, buf) {
+                    Ok(()) => {
+                        reply.write_str("ok")?;
+                        buf.iter().try_for_each(|b| write!(reply, " {:02x}", b))
+                    }
+                    Err(error) => write!(reply, "err {}", error),
+                }
+            }
+            _ => reply.write_str("err usage: r <addr> <len 01-10>"),
+        },
+        Some("w") => {
+            let address = hex(words.next());
+            let mut data = [0; MAX_TRANSFER];
+            let mut len = 0;
+            for word in words {
+                match hex(Some(word)) {
+                    Some(byte) if len < MAX_TRANSFER => {
+                        data[len] = byte;
+                        len += 1;
+                    }
+                    _ => return reply.write_str("err bad data"),
+                }
+            }
+            match address {
+                Some(address) if len > 0 => match controller::write(twim, address, &data[..len]) {
+                    Ok(()) => reply.write_str("ok"),
+                    Err(error) => write!(reply, "err {}", error),
+                },
+                _ => reply.write_str("err usage: w <addr> <byte>..."),
+            }
+        }
+        _ => reply.write_str("err unknown command"),
+    }
+}
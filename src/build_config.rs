@@ -0,0 +1,9 @@
+// Build-time configuration generated by `build.rs` from `FW_*` environment
+// variables or `firmware.cfg`, for forks that need another address, DMA
+// buffer size or bus pins without editing the code.
+//
+// The address and frequency are only the defaults: a config persisted in
+// UICR still overrides them, see `config`. A pin left unset is the board's,
+// see `board`.
+
+include!(concat!(env!("OUT_DIR"), "/build_config.rs"));
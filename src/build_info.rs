@@ -0,0 +1,22 @@
+// Build metadata generated by `build.rs`, printed at boot and by `version`.
+
+use core::fmt;
+
+pub const NAME: &str = env!("CARGO_PKG_NAME");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("GIT_HASH");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+pub const BUILD_PROFILE: &str = env!("BUILD_PROFILE");
+
+/// `<name> <version> (<git hash>, <build time>, <profile>)`
+pub struct Banner;
+
+impl fmt::Display for Banner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}, {}, {})",
+            NAME, VERSION, GIT_HASH, BUILD_TIMESTAMP, BUILD_PROFILE
+        )
+    }
+}
@@ -0,0 +1,105 @@
+// TWIS transaction bursts and what changes with them.
+//
+// The first TWIS address match (WRITE or READ event) starts a burst, which
+// ends `IDLE_MS` after the last STOPPED, when `end_burst` finds the bus
+// quiet. More transactions are expected during a burst, so the chip runs in
+// constant latency mode then and in low power mode otherwise, see `power`.
+//
+// With the `power-gating` feature the HFXO is off and logging is limited to
+// warnings and errors between bursts, so the chip idles on the LFCLK alone.
+// A burst starts the HFXO, without waiting for it to settle, and brings full
+// logging back.
+
+use {
+    crate::{
+        clock::Hfxo,
+        lock, logging, mono,
+        power::{self, PowerMode},
+        tracebuf::{self, Event},
+    },
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// Bus silence that ends a burst.
+pub const IDLE_MS: u32 = 20;
+
+const IDLE_TICKS: u32 = IDLE_MS * mono::TICK_HZ / 1000;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Held during a burst with `power-gating`.
+static HFXO: Mutex<RefCell<Option<Hfxo>>> = Mutex::new(RefCell::new(None));
+
+// Low 32 bits of the monotonic at the start of the burst and at the last
+// STOPPED.
+static STARTED: AtomicU32 = AtomicU32::new(0);
+static LAST_STOP: AtomicU32 = AtomicU32::new(0);
+
+fn now() -> u32 {
+    crate::app::monotonics::now().ticks() as u32
+}
+
+/// Enters the sleeping state, at the end of `init`.
+pub fn init() {
+    if cfg!(feature = "power-gating") {
+        logging::set_quiet(true);
+    }
+}
+
+/// Called on every address match, starts a burst unless one is running.
+pub fn address_match() {
+    if ACTIVE.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    power::set_mode(PowerMode::ConstantLatency);
+    if cfg!(feature = "power-gating") {
+        // Started outside the critical section, see `clock`.
+        let hfxo = Hfxo::start();
+        lock::free(|cs| HFXO.borrow(cs).replace(Some(hfxo)));
+        logging::set_quiet(false);
+    }
+    STARTED.store(now(), Ordering::Relaxed);
+    tracebuf::record(Event::BurstStart, 0, 0);
+}
+
+/// Called on every STOPPED.
+pub fn stopped() {
+    LAST_STOP.store(now(), Ordering::Relaxed);
+}
+
+/// Ends the burst if the bus has been quiet for `IDLE_MS`. Otherwise returns
+/// the ticks left until it may end.
+pub fn try_end() -> Option<u32> {
+    // Checked and ended in one go, an address match in between would be
+    // lost otherwise.
+    let ended = lock::free(|cs| {
+        let idle = now().wrapping_sub(LAST_STOP.load(Ordering::Relaxed));
+        if idle < IDLE_TICKS {
+            return Err(IDLE_TICKS - idle);
+        }
+        Ok((
+            ACTIVE.swap(false, Ordering::Relaxed),
+            HFXO.borrow(cs).take(),
+        ))
+    });
+    let (active, hfxo) = match ended {
+        Err(left) => return Some(left),
+        Ok(ended) => ended,
+    };
+    // Dropped outside the critical section, see `clock`.
+    drop(hfxo);
+    if active {
+        power::set_mode(PowerMode::LowPower);
+        if cfg!(feature = "power-gating") {
+            logging::set_quiet(true);
+        }
+        let ms = now().wrapping_sub(STARTED.load(Ordering::Relaxed)) as u64 * 1000
+            / mono::TICK_HZ as u64;
+        tracebuf::record(Event::BurstEnd, 0, ms.min(u16::MAX as u64) as u16);
+    }
+    None
+}
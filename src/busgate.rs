@@ -0,0 +1,91 @@
+// Enable input gating the TWIS bus.
+//
+// In a multi-drop system the controller can select the active peripheral
+// with an enable line instead of by address alone. ENABLE (P0.13, active
+// high, pulled up so an open pin means enabled) is that line: while it is
+// low, TWIS is disabled, so it does not ACK its address and ignores the
+// bus. ENABLE senses the opposite of its level, so every change raises the
+// GPIOTE PORT event, shared with the button; `on_gpiote` then switches TWIS
+// right away. It re-enables within the latency of `on_gpiote`: the wake-up
+// from sleep plus at most the longest handler at priority 2 or higher.
+//
+// The SLEEP_FOR sleep window disables TWIS as well, so `hold` keeps track
+// of both and TWIS only runs while neither holds it off.
+
+use {
+    crate::{board, hal::pac::P0},
+    core::sync::atomic::{AtomicU8, Ordering},
+};
+
+/// What holds TWIS off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hold {
+    /// ENABLE is low.
+    Gate = 1 << 0,
+    /// A SLEEP_FOR window is running.
+    SleepWindow = 1 << 1,
+}
+
+const BIT: u32 = 1 << board::ENABLE_IN;
+
+static HELD: AtomicU8 = AtomicU8::new(0);
+
+fn p0() -> &'static crate::hal::pac::p0::RegisterBlock {
+    // SAFETY: only the ENABLE pin, which `init` took over, and its LATCH bit
+    // are written.
+    unsafe { &*P0::ptr() }
+}
+
+/// Configures ENABLE. Returns whether it is asserted.
+pub fn init() -> bool {
+    p0().pin_cnf[board::ENABLE_IN].write(|w| w.dir().input().input().connect().pull().pullup());
+    let open = arm();
+    p0().latch.write(|w| unsafe { w.bits(BIT) });
+    hold(Hold::Gate, !open);
+    info!("ENABLE input {}", if open { "high" } else { "low" });
+    open
+}
+
+// Senses a change from the current level, which it returns.
+fn arm() -> bool {
+    let p0 = p0();
+    let open = p0.in_.read().bits() & BIT != 0;
+    p0.pin_cnf[board::ENABLE_IN].modify(|_, w| {
+        if open {
+            w.sense().low()
+        } else {
+            w.sense().high()
+        }
+    });
+    open
+}
+
+/// Sets or clears `reason`. Returns whether TWIS may run.
+pub fn hold(reason: Hold, held: bool) -> bool {
+    let held = if held {
+        HELD.fetch_or(reason as u8, Ordering::Relaxed) | reason as u8
+    } else {
+        HELD.fetch_and(!(reason as u8), Ordering::Relaxed) & !(reason as u8)
+    };
+    held == 0
+}
+
+/// Handles the ENABLE side of the PORT event: the new level if ENABLE
+/// changed.
+pub fn on_port_event() -> Option<bool> {
+    let p0 = p0();
+    if p0.latch.read().bits() & BIT == 0 {
+        return None;
+    }
+    // Re-armed first, as in `expander::on_port_event`.
+    let open = arm();
+    p0.latch.write(|w| unsafe { w.bits(BIT) });
+    Some(open)
+}
+
+/// Stops sensing, so ENABLE does not wake the chip from System OFF.
+pub fn disarm() {
+    let p0 = p0();
+    p0.pin_cnf[board::ENABLE_IN].modify(|_, w| w.sense().disabled());
+    p0.latch.write(|w| unsafe { w.bits(BIT) });
+}
@@ -0,0 +1,157 @@
+// Drive and pull configuration of the SCL/SDA pins.
+//
+// `Twis::new` and `Twim::new` leave both buses open drain (S0D1: standard
+// drive low, disconnected high) with the internal pull-ups. The peripherals
+// only take over direction and output level, so drive and pull can change
+// at runtime and apply from the next edge:
+//
+// - High drive (H0D1) sinks more current, for fast falling edges on a bus
+//   with a high capacitance at 400 kHz.
+// - The internal pull-ups (~13 kOhm) are too weak for 400 kHz on anything
+//   but a short bus; turn them off when the bus has external resistors.
+// - Standard push-pull (S0S1) is for experiments only: it drives the lines
+//   high against every other device on the bus.
+//
+// The pins are found through the PSEL registers of the peripheral.
+
+use {
+    crate::{
+        board,
+        hal::pac::{p0::PIN_CNF, TWIM1, TWIS0},
+    },
+    core::fmt,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Twis,
+    Twim,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    /// Open drain, standard drive low. The reset default of both buses.
+    S0D1,
+    /// Open drain, high drive low.
+    H0D1,
+    /// Push-pull, standard drive.
+    S0S1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    None,
+    Up,
+}
+
+impl Bus {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "twis" => Some(Bus::Twis),
+            "twim" => Some(Bus::Twim),
+            _ => None,
+        }
+    }
+}
+
+impl Drive {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "s0d1" => Some(Drive::S0D1),
+            "h0d1" => Some(Drive::H0D1),
+            "s0s1" => Some(Drive::S0S1),
+            _ => None,
+        }
+    }
+}
+
+impl Pull {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nopull" => Some(Pull::None),
+            "pullup" => Some(Pull::Up),
+            _ => None,
+        }
+    }
+}
+
+/// Calls `f` with the PIN_CNF register of SCL and SDA of `bus`.
+pub fn for_each(bus: Bus, mut f: impl FnMut(&PIN_CNF)) {
+    // SAFETY: read-only access to the PSEL registers.
+    let psels = unsafe {
+        match bus {
+            Bus::Twis => {
+                let twis = &*TWIS0::ptr();
+                [twis.psel.scl.read().bits(), twis.psel.sda.read().bits()]
+            }
+            Bus::Twim => {
+                let twim = &*TWIM1::ptr();
+                [twim.psel.scl.read().bits(), twim.psel.sda.read().bits()]
+            }
+        }
+    };
+    for psel in psels {
+        // Pin and port, the PSEL numbering of `board`. The pins belong to
+        // the bus; callers only modify the fields they own.
+        let pin = (psel & 0x3f) as usize;
+        f(&board::port(pin).0.pin_cnf[pin % 32]);
+    }
+}
+
+/// Changes drive and pull of both pins of `bus`, keeping the rest.
+pub fn configure(bus: Bus, drive: Drive, pull: Pull) {
+    for_each(bus, |cnf| {
+        cnf.modify(|_, w| {
+            let w = match drive {
+                Drive::S0D1 => w.drive().s0d1(),
+                Drive::H0D1 => w.drive().h0d1(),
+                Drive::S0S1 => w.drive().s0s1(),
+            };
+            match pull {
+                Pull::None => w.pull().disabled(),
+                Pull::Up => w.pull().pullup(),
+            }
+        })
+    });
+}
+
+/// `<bus>: SCL <drive>/<pull>, SDA <drive>/<pull>`
+pub struct Report(pub Bus);
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            Bus::Twis => "twis:",
+            Bus::Twim => "twim:",
+        })?;
+        let mut res = Ok(());
+        let mut names = ["SCL", "SDA"].into_iter();
+        for_each(self.0, |cnf| {
+            let r = cnf.read();
+            let drive = if r.drive().is_s0d1() {
+                "s0d1"
+            } else if r.drive().is_h0d1() {
+                "h0d1"
+            } else if r.drive().is_s0s1() {
+                "s0s1"
+            } else {
+                "other"
+            };
+            let pull = if r.pull().is_pullup() {
+                "pullup"
+            } else if r.pull().is_disabled() {
+                "nopull"
+            } else {
+                "pulldown"
+            };
+            res = res.and(write!(
+                f,
+                " {} {}/{}",
+                names.next().unwrap_or_default(),
+                drive,
+                pull
+            ));
+        });
+        res
+    }
+}
@@ -0,0 +1,219 @@
+// Timing of each TWIS transaction, measured in hardware (`bus-timing`
+// feature).
+//
+// Two GPIOTE channels watch the TWIS bus lines and PPI routes their events
+// to TIMER captures, so no interrupt is involved and the numbers are exact
+// to the 16 MHz timer tick:
+//
+//   PPI 0  SCL rising   TIMER2 CAPTURE[0], then disables itself (first clock)
+//   PPI 1  SCL rising   TIMER2 CAPTURE[1] (last clock), TIMER1 COUNT
+//   PPI 2  TWIS STOPPED TIMER2 CAPTURE[2]
+//   PPI 3  SDA falling  TIMER2 CAPTURE[3], then disables itself (START)
+//
+// `take` reads them at STOPPED and re-arms for the next transaction. The
+// clock rate is averaged over the transaction, so clock stretching and the
+// pauses of a repeated start show up as a lower rate. TIMER2 keeps the
+// HFCLK running, which costs current in sleep.
+
+use {
+    crate::{
+        board,
+        hal::pac::{GPIOTE, PPI, TIMER1, TIMER2, TWIS0},
+    },
+    core::{
+        fmt,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+};
+
+// TIMER2 runs at 16 MHz.
+const TICKS_PER_US: u32 = 16;
+
+// GPIOTE channels and PPI channels and groups used here.
+const SCL_CHANNEL: usize = 0;
+const SDA_CHANNEL: usize = 1;
+const FIRST_CLOCK_GROUP: usize = 0;
+const START_GROUP: usize = 1;
+
+// Capture registers of TIMER2.
+const FIRST_CLOCK: usize = 0;
+const LAST_CLOCK: usize = 1;
+const STOPPED: usize = 2;
+const START: usize = 3;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Measurements of one transaction, in TIMER2 ticks.
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    /// SCL rising edges, address and ACK clocks included.
+    pub clocks: u32,
+    /// START to the first clock, `None` if the START was not seen.
+    pub start: Option<u32>,
+    /// First to last clock.
+    pub clocking: u32,
+    /// Last clock to STOPPED.
+    pub stop: u32,
+}
+
+impl Timing {
+    /// Average SCL rate in kHz.
+    pub fn khz(&self) -> u32 {
+        if self.clocking == 0 {
+            return 0;
+        }
+        ((self.clocks - 1) as u64 * (TICKS_PER_US * 1000) as u64 / self.clocking as u64) as u32
+    }
+
+    /// START (or first clock) to STOPPED, in ticks.
+    pub fn total(&self) -> u32 {
+        self.start.unwrap_or(0) + self.clocking + self.stop
+    }
+}
+
+// Ticks as microseconds with one decimal.
+struct Micros(u32);
+
+impl fmt::Display for Micros {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tenths = self.0 * 10 / TICKS_PER_US;
+        write!(f, "{}.{} us", tenths / 10, tenths % 10)
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} clocks at {} kHz, start ", self.clocks, self.khz())?;
+        match self.start {
+            Some(start) => write!(f, "{}", Micros(start))?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            ", clocking {}, stop {}, total {}",
+            Micros(self.clocking),
+            Micros(self.stop),
+            Micros(self.total())
+        )
+    }
+}
+
+fn timer() -> &'static crate::hal::pac::timer0::RegisterBlock {
+    // SAFETY: TIMER2 is only used here, `init` took ownership of it.
+    unsafe { &*TIMER2::ptr() }
+}
+
+fn counter() -> &'static crate::hal::pac::timer0::RegisterBlock {
+    // SAFETY: as for `timer`.
+    unsafe { &*TIMER1::ptr() }
+}
+
+fn ppi() -> &'static crate::hal::pac::ppi::RegisterBlock {
+    // SAFETY: as for `timer`.
+    unsafe { &*PPI::ptr() }
+}
+
+/// Takes the timers and PPI and starts measuring.
+pub fn init(counter: TIMER1, timer: TIMER2, ppi: PPI) {
+    // SAFETY: the HAL `Gpiote` only uses the PORT event; channels 0 and 1
+    // are configured here only. Their IN events raise no interrupt.
+    let gpiote = unsafe { &*GPIOTE::ptr() };
+    gpiote.config[SCL_CHANNEL].write(|w| {
+        // SAFETY: any pin number of the port is valid.
+        let w = unsafe { w.psel().bits((board::TWIS_SCL % 32) as u8) };
+        #[cfg(not(feature = "nrf52832"))]
+        let w = w.port().bit(board::TWIS_SCL >= 32);
+        w.mode().event().polarity().lo_to_hi()
+    });
+    gpiote.config[SDA_CHANNEL].write(|w| {
+        // SAFETY: any pin number of the port is valid.
+        let w = unsafe { w.psel().bits((board::TWIS_SDA % 32) as u8) };
+        #[cfg(not(feature = "nrf52832"))]
+        let w = w.port().bit(board::TWIS_SDA >= 32);
+        w.mode().event().polarity().hi_to_lo()
+    });
+
+    counter.mode.write(|w| w.mode().counter());
+    counter.bitmode.write(|w| w.bitmode()._32bit());
+    counter.tasks_start.write(|w| unsafe { w.bits(1) });
+    timer.mode.write(|w| w.mode().timer());
+    timer.bitmode.write(|w| w.bitmode()._32bit());
+    timer.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
+    timer.tasks_start.write(|w| unsafe { w.bits(1) });
+
+    // SAFETY: TWIS0 STOPPED is only read as a PPI event endpoint.
+    let stopped = unsafe { &(*TWIS0::ptr()).events_stopped } as *const _ as u32;
+    let scl = &gpiote.events_in[SCL_CHANNEL] as *const _ as u32;
+    let sda = &gpiote.events_in[SDA_CHANNEL] as *const _ as u32;
+    let capture = |i: usize| &timer.tasks_capture[i] as *const _ as u32;
+    let disable = |group: usize| &ppi.tasks_chg[group].dis as *const _ as u32;
+    let channels = [
+        (scl, capture(FIRST_CLOCK), disable(FIRST_CLOCK_GROUP)),
+        (
+            scl,
+            capture(LAST_CLOCK),
+            &counter.tasks_count as *const _ as u32,
+        ),
+        (stopped, capture(STOPPED), 0),
+        (sda, capture(START), disable(START_GROUP)),
+    ];
+    for (i, (event, task, fork)) in channels.into_iter().enumerate() {
+        ppi.ch[i].eep.write(|w| unsafe { w.bits(event) });
+        ppi.ch[i].tep.write(|w| unsafe { w.bits(task) });
+        ppi.fork[i].tep.write(|w| unsafe { w.bits(fork) });
+    }
+    ppi.chg[FIRST_CLOCK_GROUP].write(|w| unsafe { w.bits(1 << 0) });
+    ppi.chg[START_GROUP].write(|w| unsafe { w.bits(1 << 3) });
+    ppi.chenset.write(|w| unsafe { w.bits(1 << 1 | 1 << 2) });
+    arm();
+    ENABLED.store(true, Ordering::Relaxed);
+    info!(
+        "bus timing on SCL P{}.{:02} SDA P{}.{:02}",
+        board::TWIS_SCL / 32,
+        board::TWIS_SCL % 32,
+        board::TWIS_SDA / 32,
+        board::TWIS_SDA % 32
+    );
+}
+
+// Clears the measurements and re-enables the one-shot channels.
+fn arm() {
+    let timer = timer();
+    let counter = counter();
+    timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+    counter.tasks_clear.write(|w| unsafe { w.bits(1) });
+    for cc in timer.cc.iter() {
+        cc.write(|w| unsafe { w.bits(0) });
+    }
+    let ppi = ppi();
+    ppi.tasks_chg[FIRST_CLOCK_GROUP]
+        .en
+        .write(|w| unsafe { w.bits(1) });
+    ppi.tasks_chg[START_GROUP]
+        .en
+        .write(|w| unsafe { w.bits(1) });
+}
+
+/// The transaction that just ended with STOPPED, or `None` without the
+/// feature or if no clock was seen. Re-arms for the next one.
+pub fn take() -> Option<Timing> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let timer = timer();
+    let counter = counter();
+    counter.tasks_capture[0].write(|w| unsafe { w.bits(1) });
+    let clocks = counter.cc[0].read().bits();
+    let cc = |i: usize| timer.cc[i].read().bits();
+    let (first, last, stopped, start) = (cc(FIRST_CLOCK), cc(LAST_CLOCK), cc(STOPPED), cc(START));
+    arm();
+    if clocks < 2 || first == 0 || last < first || stopped < last {
+        return None;
+    }
+    Some(Timing {
+        clocks,
+        start: (start != 0 && start < first).then(|| first - start),
+        clocking: last - first,
+        stop: stopped - last,
+    })
+}
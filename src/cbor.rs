@@ -0,0 +1,177 @@
+// CBOR encoding of the `message` types (`cbor` feature).
+//
+// For hosts with CBOR tooling rather than Rust: a message is a CBOR array
+// of the version, the variant index and the fields, each field an unsigned
+// integer or, for FLASH_PROGRAM data, a byte string. So with Python's
+// `cbor2` SLEEP_FOR 300 ms is `cbor2.dumps([1, 3, 300])`, sent after the
+// MESSAGE register byte, and reading back `[1, 1, 4]` means accepted.
+//
+// Only the subset of RFC 8949 the messages need is decoded: definite
+// lengths and arguments up to 32 bits, in any of their encodings, not just
+// the shortest. A message is told from a postcard one by its first byte, an
+// array header (major type 4) where postcard has the version.
+
+use crate::{
+    error::ProtocolError,
+    wire::{Decoder, Encoder},
+};
+
+const UNSIGNED: u8 = 0;
+const BYTES: u8 = 2;
+const ARRAY: u8 = 4;
+
+/// True if `byte` starts a CBOR array, as a CBOR message does.
+pub fn is_array(byte: u8) -> bool {
+    byte >> 5 == ARRAY
+}
+
+/// CBOR encoder.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, len: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        let end = self.len + bytes.len();
+        let Some(dest) = self.buf.get_mut(self.len..end) else {
+            return Err(ProtocolError::BadLength {
+                len: end as u32,
+                max: self.buf.len(),
+            });
+        };
+        dest.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    // Major type and argument, in the shortest form.
+    fn head(&mut self, major: u8, value: u32) -> Result<(), ProtocolError> {
+        let major = major << 5;
+        match value {
+            0..=23 => self.put(&[major | value as u8]),
+            24..=0xff => self.put(&[major | 24, value as u8]),
+            0x100..=0xffff => {
+                let [hi, lo] = (value as u16).to_be_bytes();
+                self.put(&[major | 25, hi, lo])
+            }
+            _ => {
+                let [b0, b1, b2, b3] = value.to_be_bytes();
+                self.put(&[major | 26, b0, b1, b2, b3])
+            }
+        }
+    }
+}
+
+impl Encoder for Writer<'_> {
+    fn message(&mut self, version: u8, variant: u32, fields: usize) -> Result<(), ProtocolError> {
+        self.head(ARRAY, 2 + fields as u32)?;
+        self.uint(version as u32)?;
+        self.uint(variant)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), ProtocolError> {
+        self.uint(value as u32)
+    }
+
+    fn uint(&mut self, value: u32) -> Result<(), ProtocolError> {
+        self.head(UNSIGNED, value)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        self.head(BYTES, bytes.len() as u32)?;
+        self.put(bytes)
+    }
+
+    fn finish(&mut self) -> Result<usize, ProtocolError> {
+        Ok(self.len)
+    }
+}
+
+/// CBOR decoder.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    // Array items not read yet.
+    items: u32,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, items: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if len > self.data.len() {
+            return Err(ProtocolError::Malformed);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn head(&mut self) -> Result<(u8, u32), ProtocolError> {
+        let initial = self.take(1)?[0];
+        let value = match initial & 0x1f {
+            info @ 0..=23 => info as u32,
+            24 => self.take(1)?[0] as u32,
+            25 => {
+                let bytes = self.take(2)?;
+                u16::from_be_bytes([bytes[0], bytes[1]]) as u32
+            }
+            26 => {
+                let bytes = self.take(4)?;
+                u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            // 64-bit arguments, indefinite lengths and reserved values.
+            _ => return Err(ProtocolError::Malformed),
+        };
+        Ok((initial >> 5, value))
+    }
+
+    // Head of the next array item, which must be of type `major`.
+    fn item(&mut self, major: u8) -> Result<u32, ProtocolError> {
+        if self.items == 0 {
+            return Err(ProtocolError::Malformed);
+        }
+        self.items -= 1;
+        match self.head()? {
+            (found, value) if found == major => Ok(value),
+            _ => Err(ProtocolError::Malformed),
+        }
+    }
+}
+
+impl Decoder for Reader<'_> {
+    fn message(&mut self) -> Result<(u8, u32), ProtocolError> {
+        match self.head()? {
+            (ARRAY, items) if items >= 2 => self.items = items,
+            _ => return Err(ProtocolError::Malformed),
+        }
+        Ok((self.u8()?, self.uint()?))
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        u8::try_from(self.uint()?).map_err(|_| ProtocolError::Malformed)
+    }
+
+    fn uint(&mut self) -> Result<u32, ProtocolError> {
+        self.item(UNSIGNED)
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], ProtocolError> {
+        let len = self.item(BYTES)?;
+        self.take(len as usize)
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.items == 0 && self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtocolError::Malformed)
+        }
+    }
+}
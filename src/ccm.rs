@@ -0,0 +1,130 @@
+// AES-CCM in the CCM peripheral, for `session`.
+//
+// CCM is the Bluetooth LE link encryption: AES-CCM with a 4-byte MIC, a
+// 13-byte nonce of a 39-bit packet counter, a direction bit and an 8-byte
+// IV, and the packet header byte as associated data. Started by hand rather
+// than by the RADIO it works on packets in RAM, by EasyDMA: a header byte
+// (S0), the length, an RFU byte, then the payload, followed by the MIC once
+// encrypted. S0 is always 0 here, so the associated data is one 0x00 byte
+// and a host checks a packet with any AES-CCM implementation.
+//
+// The AES core is shared with ECB, which CCM would abort, so a packet runs
+// with interrupts off like an ECB block does.
+
+use {
+    crate::{ecb::BLOCK_LEN, hal::pac::CCM},
+    core::sync::atomic::{compiler_fence, Ordering},
+};
+
+pub const MIC_LEN: usize = 4;
+pub const IV_LEN: usize = 8;
+/// Longest packet: payload and MIC.
+pub const PACKET_LEN: usize = 64;
+// S0, LENGTH and RFU before the payload.
+const HEADER_LEN: usize = 3;
+
+type Packet = [u8; HEADER_LEN + PACKET_LEN];
+
+// CNFPTR layout.
+#[repr(C)]
+struct Config {
+    key: [u8; BLOCK_LEN],
+    // 39 bits, little-endian.
+    counter: [u8; 8],
+    direction: u8,
+    iv: [u8; IV_LEN],
+}
+
+#[derive(Clone, Copy)]
+pub struct Nonce {
+    pub counter: u64,
+    /// 1 from the controller to this board, 0 the other way.
+    pub direction: u8,
+    pub iv: [u8; IV_LEN],
+}
+
+/// Takes `CCM` so nothing else uses it. Packets are polled, no interrupts.
+pub fn init(ccm: CCM) {
+    ccm.intenclr
+        .write(|w| w.endksgen().clear().endcrypt().clear().error().clear());
+    ccm.shorts.write(|w| w.endksgen_crypt().enabled());
+    // SAFETY: any length from 0x1b to 0xfb is valid. The nRF52832 has no
+    // MAXPACKETSIZE, its extended mode always takes up to 251 bytes.
+    #[cfg(not(feature = "nrf52832"))]
+    ccm.maxpacketsize
+        .write(|w| unsafe { w.bits(PACKET_LEN as u32) });
+}
+
+/// Encrypts `payload` into `out` followed by the MIC, returns the length.
+/// `None` if either does not fit.
+pub fn seal(key: &[u8; BLOCK_LEN], nonce: &Nonce, payload: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = payload.len() + MIC_LEN;
+    if len > PACKET_LEN || len > out.len() {
+        return None;
+    }
+    let mut input: Packet = [0; HEADER_LEN + PACKET_LEN];
+    input[1] = payload.len() as u8;
+    input[HEADER_LEN..][..payload.len()].copy_from_slice(payload);
+    let output = run(key, nonce, false, &input)?;
+    out[..len].copy_from_slice(&output[HEADER_LEN..][..len]);
+    Some(len)
+}
+
+/// Decrypts `packet`, payload and MIC, into `out` and returns the payload
+/// length; `None` if the MIC does not match or the payload does not fit.
+pub fn open(key: &[u8; BLOCK_LEN], nonce: &Nonce, packet: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = packet.len().checked_sub(MIC_LEN)?;
+    if len == 0 || packet.len() > PACKET_LEN || len > out.len() {
+        return None;
+    }
+    let mut input: Packet = [0; HEADER_LEN + PACKET_LEN];
+    input[1] = packet.len() as u8;
+    input[HEADER_LEN..][..packet.len()].copy_from_slice(packet);
+    let output = run(key, nonce, true, &input)?;
+    out[..len].copy_from_slice(&output[HEADER_LEN..][..len]);
+    Some(len)
+}
+
+// Runs one packet through CCM. `None` if decrypting and the MIC was wrong.
+fn run(key: &[u8; BLOCK_LEN], nonce: &Nonce, decrypt: bool, input: &Packet) -> Option<Packet> {
+    let config = Config {
+        key: *key,
+        counter: nonce.counter.to_le_bytes(),
+        direction: nonce.direction,
+        iv: nonce.iv,
+    };
+    let mut output: Packet = [0; HEADER_LEN + PACKET_LEN];
+    let mut scratch = [0u8; 16 + PACKET_LEN];
+    // SAFETY: CCM is only used here, `init` took ownership of it, and a
+    // packet runs with interrupts off so two callers cannot share it, nor
+    // ECB the AES core. The buffers outlive the DMA, which ends at ENDCRYPT.
+    let passed = cortex_m::interrupt::free(|_| unsafe {
+        let ccm = &*CCM::ptr();
+        ccm.enable.write(|w| w.enable().enabled());
+        ccm.mode.write(|w| {
+            if decrypt {
+                w.mode().decryption();
+            } else {
+                w.mode().encryption();
+            }
+            w.datarate()._2mbit().length().extended()
+        });
+        ccm.cnfptr
+            .write(|w| w.bits(&config as *const Config as u32));
+        ccm.inptr.write(|w| w.bits(input.as_ptr() as u32));
+        ccm.outptr.write(|w| w.bits(output.as_mut_ptr() as u32));
+        ccm.scratchptr
+            .write(|w| w.bits(scratch.as_mut_ptr() as u32));
+        ccm.events_endksgen.reset();
+        ccm.events_endcrypt.reset();
+        compiler_fence(Ordering::SeqCst);
+        // KSGEN starts CRYPT through the shortcut.
+        ccm.tasks_ksgen.write(|w| w.bits(1));
+        while ccm.events_endcrypt.read().bits() == 0 {}
+        let passed = !decrypt || ccm.micstatus.read().micstatus().is_check_passed();
+        ccm.enable.write(|w| w.enable().disabled());
+        passed
+    });
+    compiler_fence(Ordering::SeqCst);
+    passed.then_some(output)
+}
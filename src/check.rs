@@ -0,0 +1,50 @@
+// Recoverable invariant checks.
+//
+// `soft_assert!(cond)` evaluates to `cond`. When it is false the condition
+// and its location are logged at `error` level, the `assertions` counter is
+// bumped and an `Event::Assert` is traced. Instead of panicking, the caller
+// then takes its own recovery action, typically dropping the transaction:
+//
+//   if !soft_assert!(send_twi_cmds::spawn().is_ok(), "already pending") {
+//       return;
+//   }
+//
+// `debug_check!` is the same check compiled out of release builds (it then
+// evaluates to true), for invariants only worth verifying during development.
+
+use {
+    crate::{
+        stats::STATS,
+        tracebuf::{self, Event},
+    },
+    core::fmt,
+};
+
+/// Checks `cond`, reporting a failure and evaluating to `cond`. An optional
+/// format string replaces the stringified condition in the log.
+macro_rules! soft_assert {
+    ($cond:expr $(,)?) => {
+        soft_assert!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {{
+        let ok: bool = $cond;
+        if !ok {
+            $crate::check::failed(format_args!($($arg)+), file!(), line!());
+        }
+        ok
+    }};
+}
+
+/// `soft_assert!` in debug builds, always true in release builds.
+macro_rules! debug_check {
+    ($($arg:tt)+) => {
+        !cfg!(debug_assertions) || soft_assert!($($arg)+)
+    };
+}
+
+/// Reports a failed check. Use the macros instead of calling this directly.
+pub fn failed(what: fmt::Arguments, file: &str, line: u32) {
+    STATS.assertions.inc();
+    tracebuf::record(Event::Assert, 0, line as u16);
+    error!("assertion failed: {} at {}:{}", what, file, line);
+}
@@ -0,0 +1,75 @@
+// The nRF52 the firmware is built for.
+//
+// Selected with a feature, exactly one of `nrf52840` (the default),
+// `nrf52833` and `nrf52832`, which also picks the HAL `main` imports as
+// `hal`. All three have TWIS and TWIM with EasyDMA; what they lack is
+// handled where it is used:
+//
+//   nRF52833  no QSPI; P1 has 10 pins
+//   nRF52832  no QSPI, USBD, UARTE1 or P1; RAM0-RAM7 only; no partial
+//             page erase; the pin reset is on P0.21
+//
+// Features that need what a chip lacks are refused in `main`. The flash
+// pages kept at the top of the flash and the DFU slot are placed from
+// `FLASH_LEN`.
+
+use {
+    crate::hal::pac::{Interrupt, NVIC_PRIO_BITS},
+    cortex_m::peripheral::NVIC,
+};
+
+#[cfg(feature = "nrf52840")]
+mod consts {
+    pub const NAME: &str = "nRF52840";
+    pub const FLASH_LEN: usize = 1024 << 10;
+    pub const RAM_LEN: usize = 256 << 10;
+    pub const P1_PINS: usize = 16;
+    pub const RESET_PIN: usize = 18;
+}
+
+#[cfg(feature = "nrf52833")]
+mod consts {
+    pub const NAME: &str = "nRF52833";
+    pub const FLASH_LEN: usize = 512 << 10;
+    pub const RAM_LEN: usize = 128 << 10;
+    pub const P1_PINS: usize = 10;
+    pub const RESET_PIN: usize = 18;
+}
+
+#[cfg(feature = "nrf52832")]
+mod consts {
+    pub const NAME: &str = "nRF52832";
+    pub const FLASH_LEN: usize = 512 << 10;
+    pub const RAM_LEN: usize = 64 << 10;
+    pub const P1_PINS: usize = 0;
+    pub const RESET_PIN: usize = 21;
+}
+
+pub use consts::*;
+
+/// Start of RAM, the same on all of them.
+pub const RAM_START: usize = 0x2000_0000;
+
+// The interrupts `main` binds outside RTIC on this chip, all at priority
+// 2.
+#[cfg(feature = "nrf52840")]
+const INTERRUPTS: [Interrupt; 3] = [Interrupt::USBD, Interrupt::QSPI, Interrupt::UARTE1];
+#[cfg(feature = "nrf52833")]
+const INTERRUPTS: [Interrupt; 2] = [Interrupt::USBD, Interrupt::UARTE1];
+#[cfg(feature = "nrf52832")]
+const INTERRUPTS: [Interrupt; 0] = [];
+
+/// Sets the priority of the interrupts bound outside RTIC and unmasks them.
+/// From `init`, before any of them can fire.
+pub fn unmask(nvic: &mut NVIC) {
+    // Logical priority 2 as RTIC encodes it: higher is more urgent.
+    let priority = ((1 << NVIC_PRIO_BITS) - 2) << (8 - NVIC_PRIO_BITS);
+    for interrupt in INTERRUPTS {
+        // SAFETY: in `init` with interrupts disabled, and the handlers
+        // take no RTIC resources, so no priority ceiling depends on them.
+        unsafe {
+            nvic.set_priority(interrupt, priority);
+            NVIC::unmask(interrupt);
+        }
+    }
+}
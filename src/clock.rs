@@ -0,0 +1,243 @@
+// Clock setup and the on-demand HFXO.
+//
+// The crystal oscillator is not kept running. Between bus transactions only
+// the LFCLK and the RTC monotonic run; the CPU and the peripherals get their
+// HFCLK from the internal RC oscillator (HFINT), which the clock controller
+// starts when they need it and stops again while the CPU sleeps. Code that
+// needs the accurate clock (TWIM timing, UARTE baud rate) holds an `Hfxo`
+// for as long as it does, the crystal runs while at least one is alive.
+//
+// The sources are chosen by features, for boards without the crystals of
+// the nRF52840-MDK: `lfclk-xtal` runs the LFCLK from a 32.768 kHz crystal
+// instead of the RC oscillator, `hfclk-rc` never starts the HFXO. `init`
+// checks that each oscillator actually starts. A crystal that does not is
+// reported and replaced by the RC oscillator rather than hanging the boot.
+//
+// The RC oscillator is only accurate to ~2 % until calibrated against the
+// HFXO, after which it stays within ~500 ppm. While the LFCLK runs from it,
+// `calibrate` is called periodically; each run holds the HFXO until the
+// DONE event, handled by `on_interrupt`. Without an HFXO there is nothing
+// to calibrate against and the RTC keeps the raw RC accuracy.
+//
+// Once the SoftDevice of `ble` runs, it calibrates the RC oscillator itself
+// and the HFXO is requested from it, see `softdevice`. As that is a
+// SoftDevice call, an `Hfxo` must then not be started or dropped with
+// PRIMASK set; the counting here takes a `lock::free` section.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{hal::pac::CLOCK, lock},
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+// Upper bounds for the start-up, in `POLL_CYCLES` steps: the HFXO needs
+// ~0.4 ms, a 32.768 kHz crystal up to ~0.25 s.
+const POLL_CYCLES: u32 = 640;
+const HFXO_TIMEOUT_POLLS: u32 = 1_000;
+const LFXO_TIMEOUT_POLLS: u32 = 100_000;
+
+static USERS: AtomicU8 = AtomicU8::new(0);
+
+// Cleared if the HFXO is disabled by `hfclk-rc` or failed to start.
+static HFXO_AVAILABLE: AtomicBool = AtomicBool::new(!cfg!(feature = "hfclk-rc"));
+
+// Set by `init` if the LFCLK ended up on the RC oscillator.
+static LFCLK_RC: AtomicBool = AtomicBool::new(false);
+
+// The HFXO held by a running calibration.
+static CALIBRATION: Mutex<RefCell<Option<Hfxo>>> = Mutex::new(RefCell::new(None));
+
+static CALIBRATIONS: AtomicU32 = AtomicU32::new(0);
+
+/// Keeps the HFXO running until dropped. Without a usable crystal it does
+/// nothing and the HFCLK stays on HFINT.
+pub struct Hfxo(bool);
+
+impl Hfxo {
+    /// Starts the HFXO if it is not running yet and waits until it is
+    /// stable, ~0.4 ms on the nRF52840.
+    pub fn request() -> Self {
+        let hfxo = Hfxo::start();
+        // Outside the critical section, the wait must not delay the TWIS
+        // interrupt.
+        if hfxo.0 && !poll(HFXO_TIMEOUT_POLLS, hfxo_running) {
+            HFXO_AVAILABLE.store(false, Ordering::Relaxed);
+            error!("HFXO did not start, staying on HFINT");
+        }
+        hfxo
+    }
+
+    /// Like `request`, but returns while the crystal may still be starting.
+    pub fn start() -> Self {
+        if !HFXO_AVAILABLE.load(Ordering::Relaxed) {
+            return Hfxo(false);
+        }
+        lock::free(|_| {
+            if USERS.fetch_add(1, Ordering::Relaxed) == 0 {
+                start();
+            }
+        });
+        Hfxo(true)
+    }
+}
+
+impl Drop for Hfxo {
+    fn drop(&mut self) {
+        if !self.0 {
+            return;
+        }
+        lock::free(|_| {
+            if USERS.fetch_sub(1, Ordering::Relaxed) == 1 {
+                stop();
+            }
+        });
+    }
+}
+
+/// Starts the LFCLK from the configured source and checks that the HFXO
+/// can start. Takes `CLOCK` so nothing else configures it.
+pub fn init(clock: CLOCK) {
+    let xtal = cfg!(feature = "lfclk-xtal");
+    if !start_lfclk(&clock, xtal) && xtal {
+        error!("LFXO did not start, falling back to the RC oscillator");
+        clock.tasks_lfclkstop.write(|w| unsafe { w.bits(1) });
+        start_lfclk(&clock, false);
+    }
+    let stat = clock.lfclkstat.read();
+    if !stat.state().is_running() {
+        error!("LFCLK not running, the monotonic will not advance");
+    }
+    // Start the crystal once so a missing one shows up now rather than
+    // at the first transaction.
+    drop(Hfxo::request());
+    let rc = !stat.src().is_xtal();
+    let hfxo = HFXO_AVAILABLE.load(Ordering::Relaxed);
+    LFCLK_RC.store(rc, Ordering::Relaxed);
+    if rc && hfxo && !cfg!(feature = "ble") {
+        clock.intenset.write(|w| w.done().set());
+    }
+    info!(
+        "clocks: LFCLK from {}, HFXO {}",
+        match (rc, hfxo) {
+            (false, _) => "crystal",
+            (true, true) => "RC, calibrated",
+            (true, false) => "RC, uncalibrated",
+        },
+        if hfxo { "on demand" } else { "unused" }
+    );
+}
+
+/// True if the LFCLK runs from the RC oscillator and can be calibrated here,
+/// not by the SoftDevice.
+pub fn needs_calibration() -> bool {
+    lfclk_rc() && HFXO_AVAILABLE.load(Ordering::Relaxed) && !cfg!(feature = "ble")
+}
+
+/// True if `init` left the LFCLK on the RC oscillator.
+pub fn lfclk_rc() -> bool {
+    LFCLK_RC.load(Ordering::Relaxed)
+}
+
+/// Requests the HFXO from the SoftDevice if it is in use, as the
+/// SoftDevice has just taken over the HFCLK. From `softdevice::enable`.
+#[cfg(feature = "ble")]
+pub fn hand_over() {
+    if USERS.load(Ordering::Relaxed) > 0 {
+        softdevice::hfclk_request();
+    }
+}
+
+/// Starts a calibration of the RC oscillator, unless one is running.
+/// Waits for the HFXO, ~0.4 ms, the calibration itself ends in
+/// `on_interrupt`.
+pub fn calibrate() {
+    if !needs_calibration() || lock::free(|cs| CALIBRATION.borrow(cs).borrow().is_some()) {
+        return;
+    }
+    let hfxo = Hfxo::request();
+    lock::free(|cs| {
+        // SAFETY: the calibration task and event are only used here and in
+        // `on_interrupt`, inside critical sections.
+        let clock = unsafe { &*CLOCK::ptr() };
+        clock.events_done.reset();
+        clock.tasks_cal.write(|w| unsafe { w.bits(1) });
+        CALIBRATION.borrow(cs).replace(Some(hfxo));
+    });
+}
+
+/// Handles the calibration DONE event, from the POWER_CLOCK interrupt.
+pub fn on_interrupt() {
+    let done = lock::free(|cs| {
+        // SAFETY: as in `calibrate`.
+        let clock = unsafe { &*CLOCK::ptr() };
+        if clock.events_done.read().bits() == 0 {
+            return false;
+        }
+        clock.events_done.reset();
+        // Dropping the guard stops the HFXO if nothing else holds it.
+        drop(CALIBRATION.borrow(cs).take());
+        true
+    });
+    if done {
+        let n = CALIBRATIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        trace!("LFRC calibrated ({} runs)", n);
+    }
+}
+
+fn start_lfclk(clock: &CLOCK, xtal: bool) -> bool {
+    clock.events_lfclkstarted.reset();
+    clock
+        .lfclksrc
+        .write(|w| if xtal { w.src().xtal() } else { w.src().rc() });
+    clock.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+    let started = poll(LFXO_TIMEOUT_POLLS, || {
+        clock.events_lfclkstarted.read().bits() != 0
+    });
+    clock.events_lfclkstarted.reset();
+    started
+}
+
+// Polls `done` for up to `polls` steps of `POLL_CYCLES`.
+fn poll(polls: u32, mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..polls {
+        if done() {
+            return true;
+        }
+        cortex_m::asm::delay(POLL_CYCLES);
+    }
+    done()
+}
+
+/// True if the HFCLK currently runs from the crystal.
+pub fn hfxo_running() -> bool {
+    // SAFETY: read-only access to a status register.
+    let clock = unsafe { &*CLOCK::ptr() };
+    let stat = clock.hfclkstat.read();
+    stat.state().is_running() && stat.src().is_xtal()
+}
+
+// `init` consumes CLOCK, so the tasks are triggered through the pointer.
+fn start() {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::hfclk_request();
+    }
+    // SAFETY: the HFCLK tasks are only triggered here, inside a critical section.
+    let clock = unsafe { &*CLOCK::ptr() };
+    clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+}
+
+fn stop() {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::hfclk_release();
+    }
+    // SAFETY: as above.
+    let clock = unsafe { &*CLOCK::ptr() };
+    clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+}
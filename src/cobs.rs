@@ -0,0 +1,51 @@
+// Consistent Overhead Byte Stuffing, for `stream`.
+//
+// COBS removes every 0x00 from a frame at the cost of one byte per 254, so
+// 0x00 is free to delimit frames: each run of non-zero bytes is preceded by
+// a code byte, its length plus one, which for a code below 0xff also stands
+// for the 0x00 the run ended at. The delimiters are left to the caller.
+
+/// Encodes `data` into `out` and returns the length, or `None` if `out` is
+/// too short. The frame never holds a 0x00.
+pub fn encode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut code_at = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for &byte in data {
+        if byte != 0 {
+            *out.get_mut(len)? = byte;
+            len += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xff {
+            *out.get_mut(code_at)? = code;
+            code_at = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    *out.get_mut(code_at)? = code;
+    Some(len)
+}
+
+/// Decodes `frame`, without its delimiter, into `out` and returns the
+/// length, or `None` if the frame is corrupted or `out` is too short.
+pub fn decode(frame: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut at = 0;
+    let mut len = 0;
+    while at < frame.len() {
+        let code = frame[at];
+        let run = frame.get(at + 1..at + code as usize)?;
+        if code == 0 || run.contains(&0) {
+            return None;
+        }
+        out.get_mut(len..len + run.len())?.copy_from_slice(run);
+        len += run.len();
+        at += code as usize;
+        if code != 0xff && at < frame.len() {
+            *out.get_mut(len)? = 0;
+            len += 1;
+        }
+    }
+    Some(len)
+}
@@ -0,0 +1,207 @@
+// Device configuration persisted in UICR.
+//
+// The 32 CUSTOMER words of UICR hold a log of config records, one word
+// each. Flash bits can be cleared but not set without an erase, so a new
+// config goes into the next erased (0xffff_ffff) word and the last record
+// written wins. An all-zero record is a factory reset: it restores the
+// defaults. Once all 32 words are used, further changes are refused until
+// UICR is erased with a debug probe, since erasing it from the firmware would
+// also wipe the reset pin and access port configuration stored next to it.
+//
+// Record layout: | address: u8 | frequency step: u8 | flags: u8 | MAGIC |
+//
+// Changes take effect at the next boot. With `ble` UICR is not writable
+// while the SoftDevice runs, which is always once `idle` started it, and
+// changes are refused.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{
+        build_config,
+        hal::pac::{NVMC, UICR},
+    },
+    core::{
+        fmt,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+};
+
+const MAGIC: u32 = 0x5a << 24;
+const ERASED: u32 = 0xffff_ffff;
+const FACTORY_RESET: u32 = 0;
+
+/// TWIM adapts its frequency to the error rate, see `controller`.
+pub const ADAPTIVE_FREQUENCY: u8 = 1 << 0;
+/// TWIM is powered down when idle, see `controller`.
+pub const TWIM_AUTO_OFF: u8 = 1 << 1;
+/// The GPIO expander drives INT on input changes, see `expander`.
+pub const EXPANDER_INT: u8 = 1 << 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// 7-bit TWIS address, also the address `send_twi_cmds` talks to.
+    pub address: u8,
+    /// Index into the TWIM frequencies, 0 is the fastest.
+    pub frequency_step: u8,
+    pub flags: u8,
+}
+
+/// 0x1a at 400 kHz unless `build_config` sets them.
+pub const DEFAULT: Config = Config {
+    address: build_config::TWIS_ADDRESS,
+    // The steps of `controller`.
+    frequency_step: match build_config::TWIM_FREQUENCY_KHZ {
+        400 => 0,
+        250 => 1,
+        _ => 2,
+    },
+    flags: ADAPTIVE_FREQUENCY | TWIM_AUTO_OFF | EXPANDER_INT,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Address outside 0x08..=0x77 or unknown frequency step.
+    Invalid,
+    /// No erased CUSTOMER word left.
+    Full,
+    /// UICR is not writable while the SoftDevice runs.
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    Locked,
+}
+
+// The active config, as a record.
+static ACTIVE: AtomicU32 = AtomicU32::new(0);
+
+impl Config {
+    fn from_record(record: u32) -> Option<Self> {
+        if record & 0xff00_0000 != MAGIC {
+            return None;
+        }
+        let [address, frequency_step, flags, _] = record.to_le_bytes();
+        let config = Config {
+            address,
+            frequency_step,
+            flags,
+        };
+        config.is_valid().then_some(config)
+    }
+
+    fn to_record(self) -> u32 {
+        u32::from_le_bytes([self.address, self.frequency_step, self.flags, 0]) | MAGIC
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (0x08..=0x77).contains(&self.address) && self.frequency_step < 3
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+fn customer() -> &'static [crate::hal::pac::uicr::CUSTOMER; 32] {
+    // SAFETY: read-only access; UICR is memory mapped flash.
+    unsafe { &(*UICR::ptr()).customer }
+}
+
+/// Loads the last record from UICR, falling back to `DEFAULT`.
+pub fn load() -> Config {
+    let config = stored();
+    ACTIVE.store(config.to_record(), Ordering::Relaxed);
+    config
+}
+
+/// The last record in UICR, which `get` only returns after the next boot.
+pub fn stored() -> Config {
+    let last = customer()
+        .iter()
+        .map(|word| word.read().bits())
+        .take_while(|&word| word != ERASED)
+        .last();
+    match last {
+        Some(record) => Config::from_record(record).unwrap_or_else(|| {
+            if record != FACTORY_RESET {
+                warn!("invalid config record {:#010x}, using defaults", record);
+            }
+            DEFAULT
+        }),
+        None => DEFAULT,
+    }
+}
+
+/// The config loaded at boot.
+pub fn get() -> Config {
+    Config::from_record(ACTIVE.load(Ordering::Relaxed)).unwrap_or(DEFAULT)
+}
+
+/// Appends `config` to UICR.
+pub fn save(config: Config) -> Result<(), Error> {
+    if !config.is_valid() {
+        return Err(Error::Invalid);
+    }
+    append(config.to_record())
+}
+
+/// Appends a factory reset record to UICR.
+pub fn factory_reset() -> Result<(), Error> {
+    append(FACTORY_RESET)
+}
+
+fn append(record: u32) -> Result<(), Error> {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return Err(Error::Locked);
+    }
+    let slot = customer()
+        .iter()
+        .find(|word| word.read().bits() == ERASED)
+        .ok_or(Error::Full)?;
+    // SAFETY: NVMC is only used from priority 1 tasks (this one,
+    // `store_bank` and `heartbeat`), which do not preempt each other.
+    let nvmc = unsafe { &*NVMC::ptr() };
+    nvmc.config.write(|w| w.wen().wen());
+    while nvmc.ready.read().ready().is_busy() {}
+    slot.write(|w| unsafe { w.bits(record) });
+    while nvmc.ready.read().ready().is_busy() {}
+    nvmc.config.write(|w| w.wen().ren());
+    Ok(())
+}
+
+/// Used and total CUSTOMER words.
+pub fn slots_used() -> (usize, usize) {
+    let words = customer();
+    let used = words
+        .iter()
+        .filter(|word| word.read().bits() != ERASED)
+        .count();
+    (used, words.len())
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "address {:#04x}, frequency step {}, adaptive frequency {}, TWIM auto-off {}, expander INT {}",
+            self.address,
+            self.frequency_step,
+            if self.has(ADAPTIVE_FREQUENCY) {
+                "on"
+            } else {
+                "off"
+            },
+            if self.has(TWIM_AUTO_OFF) { "on" } else { "off" },
+            if self.has(EXPANDER_INT) { "on" } else { "off" },
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::Invalid => "invalid config",
+            Error::Full => "UICR config slots used up, erase UICR with a probe",
+            Error::Locked => "UICR not writable while the SoftDevice runs",
+        })
+    }
+}
@@ -0,0 +1,209 @@
+// Line-based command console on RTT down channel 0 and, with `usb-console`,
+// the USB serial port.
+//
+// The host types a command terminated by a newline (the cargo-embed RTT
+// terminal does this for you). Input is polled from a periodic task since
+// RTT has no way to interrupt the target. Both inputs share one line buffer,
+// so type on one at a time.
+
+use crate::{
+    buspins::{Bus, Drive, Pull},
+    hexdump::DumpMode,
+    logging::{DownChannel, Level},
+    regmap,
+    request::{self, Request},
+    trigger::Pattern,
+    twimpoll, usbconsole,
+};
+
+const LINE_LEN: usize = 64;
+
+/// A parsed console command.
+pub enum Command {
+    Help,
+    /// `level` prints the active level, `level <name>` changes it.
+    Level(Option<Level>),
+    /// Dump the trace ring to the data channel.
+    TraceDump,
+    TraceClear,
+    /// Print the transaction statistics summary.
+    Stats,
+    /// Print the per-task execution time table.
+    Profile,
+    ProfileReset,
+    /// Print the TWIS event-to-armed latency histogram.
+    Latency,
+    LatencyReset,
+    /// Print the estimated energy per TWIS transaction.
+    Energy,
+    EnergyReset,
+    /// Turn the verbose TWIS event log on or off.
+    TwisLog(bool),
+    /// `hexwidth` prints the hex-dump line width, `hexwidth <n>` changes it.
+    HexWidth(Option<usize>),
+    /// `dump` prints the payload dump mode, `dump <mode>` changes it.
+    Dump(Option<DumpMode>),
+    /// Print the last wake-up and the HFXO state.
+    Power,
+    /// Run the latency-versus-power benchmark.
+    Bench,
+    /// `poll` prints the hardware-timed TWIM polling, `poll <ms>` starts it,
+    /// `poll off` (`Some(0)`) stops it.
+    Poll(Option<u32>),
+    /// `pins` prints the bus pin drive and pull, `pins <bus> <drive> [pull]`
+    /// changes them.
+    Pins(Option<(Bus, Drive, Pull)>),
+    /// Print the persisted device config.
+    Config,
+    /// Print the firmware version and build metadata.
+    Version,
+    /// `trigger` prints the armed pattern, `trigger <hex bytes>` arms one.
+    Trigger(Option<Pattern>),
+    TriggerOff,
+    /// Send a request, given as COMMAND bytes, to the peripheral as a typed
+    /// message.
+    Send(Request),
+    Unknown,
+}
+
+pub struct Console {
+    channel: DownChannel,
+    line: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Console {
+    pub fn new(channel: DownChannel) -> Self {
+        Console {
+            channel,
+            line: [0; LINE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Drains pending input and returns the next complete command, if any.
+    ///
+    /// Bytes past `LINE_LEN` on a single line are dropped.
+    pub fn poll(&mut self) -> Option<Command> {
+        let mut byte = [0u8; 1];
+        while self.channel.read(&mut byte) == 1 || usbconsole::read(&mut byte) == 1 {
+            match byte[0] {
+                b'\n' | b'\r' => {
+                    if self.len == 0 {
+                        continue;
+                    }
+                    let command = parse(&self.line[..self.len]);
+                    self.len = 0;
+                    return Some(command);
+                }
+                b if self.len < LINE_LEN => {
+                    self.line[self.len] = b;
+                    self.len += 1;
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+fn parse(line: &[u8]) -> Command {
+    let line = match core::str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(_) => return Command::Unknown,
+    };
+    let mut words = line.split_ascii_whitespace();
+    match (words.next(), words.next()) {
+        (Some("help"), None) => Command::Help,
+        (Some("level"), None) => Command::Level(None),
+        (Some("level"), Some(name)) => match Level::from_name(name) {
+            Some(level) => Command::Level(Some(level)),
+            None => Command::Unknown,
+        },
+        (Some("trace"), None) => Command::TraceDump,
+        (Some("trace"), Some("clear")) => Command::TraceClear,
+        (Some("stats"), None) => Command::Stats,
+        (Some("version"), None) => Command::Version,
+        (Some("power"), None) => Command::Power,
+        (Some("bench"), None) => Command::Bench,
+        (Some("poll"), None) => Command::Poll(None),
+        (Some("poll"), Some("off")) => Command::Poll(Some(0)),
+        (Some("poll"), Some(ms)) => match ms.parse() {
+            Ok(ms @ twimpoll::MIN_PERIOD_MS..=twimpoll::MAX_PERIOD_MS) => Command::Poll(Some(ms)),
+            _ => Command::Unknown,
+        },
+        (Some("pins"), None) => Command::Pins(None),
+        (Some("pins"), Some(bus)) => {
+            let bus = Bus::from_name(bus);
+            let drive = words.next().and_then(Drive::from_name);
+            let pull = words.next().map_or(Some(Pull::Up), Pull::from_name);
+            match (bus, drive, pull) {
+                (Some(bus), Some(drive), Some(pull)) => Command::Pins(Some((bus, drive, pull))),
+                _ => Command::Unknown,
+            }
+        }
+        (Some("config"), None) => Command::Config,
+        (Some("profile"), None) => Command::Profile,
+        (Some("profile"), Some("reset")) => Command::ProfileReset,
+        (Some("latency"), None) => Command::Latency,
+        (Some("latency"), Some("reset")) => Command::LatencyReset,
+        (Some("energy"), None) => Command::Energy,
+        (Some("energy"), Some("reset")) => Command::EnergyReset,
+        (Some("twislog"), Some("on")) => Command::TwisLog(true),
+        (Some("twislog"), Some("off")) => Command::TwisLog(false),
+        (Some("hexwidth"), None) => Command::HexWidth(None),
+        (Some("hexwidth"), Some(n)) => match n.parse() {
+            Ok(n) => Command::HexWidth(Some(n)),
+            Err(_) => Command::Unknown,
+        },
+        (Some("dump"), None) => Command::Dump(None),
+        (Some("dump"), Some("full")) => Command::Dump(Some(DumpMode::Full)),
+        (Some("dump"), Some("crc")) => Command::Dump(Some(DumpMode::Checksum)),
+        (Some("dump"), Some("head")) => match words.next().map(str::parse) {
+            Some(Ok(n)) => Command::Dump(Some(DumpMode::Head(n))),
+            _ => Command::Unknown,
+        },
+        (Some("trigger"), None) => Command::Trigger(None),
+        (Some("trigger"), Some("off")) => Command::TriggerOff,
+        (Some("trigger"), Some(first)) => {
+            match Pattern::parse(core::iter::once(first).chain(words)) {
+                Some(pattern) => Command::Trigger(Some(pattern)),
+                None => Command::Unknown,
+            }
+        }
+        (Some("send"), Some(first)) => {
+            let mut bytes = [0; regmap::BUF_LEN];
+            let mut len = 0;
+            for word in core::iter::once(first).chain(words) {
+                match (bytes.get_mut(len), u8::from_str_radix(word, 16)) {
+                    (Some(byte), Ok(value)) => *byte = value,
+                    _ => return Command::Unknown,
+                }
+                len += 1;
+            }
+            request::parse(&bytes[..len]).map_or(Command::Unknown, Command::Send)
+        }
+        _ => Command::Unknown,
+    }
+}
+
+pub const HELP: &str = "\
+commands:
+  help                              this text
+  level [error|warn|info|trace]     show or set the log level
+  hexwidth [n]                      show or set bytes per hex-dump line
+  dump [full|head <n>|crc]          show or set how transfer payloads are logged
+  profile [reset]                   per-task min/avg/max execution time
+  latency [reset]                   TWIS event to DMA-armed latency histogram
+  energy [reset]                    estimated energy per TWIS read/write
+  stats                             print transaction statistics
+  power                             last wake-up reason and power state
+  bench                             round-trip latency and energy per power configuration
+  poll [off|<ms>]                   show, start or stop TIMER3/PPI-timed TWIM reads of the alive counter
+  pins [<bus> <drive> [<pull>]]     show or set bus pin drive and pull: twis|twim, s0d1|h0d1|s0s1, pullup|nopull
+  config                            device config persisted in UICR
+  version                           firmware version, git hash and build time
+  twislog on|off                    log every TWIS event with AMOUNT and timing
+  trigger [off|<hex bytes>]         pulse P0.03 when TWIS receives a byte pattern
+  send <hex bytes>                  send COMMAND bytes (opcode, args) as a typed message
+  trace [clear]                     dump the event trace to the data channel, or clear it";
@@ -0,0 +1,358 @@
+// TWIM (controller role) transactions.
+//
+// Wraps the blocking TWIM calls with bounded retries, statistics and event
+// tracing so `send_twi_cmds` only has to deal with the final result.
+//
+// TWIM is powered down by `power_down_if_idle` once no transaction has been
+// issued for a while: the peripheral is disabled and the input buffers of
+// its pins are disconnected. The pull-ups stay, so the bus keeps idling high
+// for TWIS. The next transaction powers it up again.
+//
+// The bus frequency adapts to the error rate, unless the config turns it
+// off: starting at the configured frequency (400 kHz by default), a window
+// of `WINDOW` transactions with more than `MAX_WINDOW_ERRORS` NACKs or
+// overruns drops it one step (250, then 100 kHz), `CLEAN_WINDOWS` windows
+// without any raise it one step again.
+//
+// With `twim-ppi` TIMER3 may be polling through TWIM in the background;
+// every transaction here pauses it, see `twimpoll`.
+
+use {
+    crate::{
+        auth,
+        buspins::{self, Bus},
+        config::{self, Config},
+        error::{AppError, InternalError, Op},
+        hal::{
+            pac::{twim0::frequency::FREQUENCY_A, TWIM1},
+            twim::{Error, Frequency, Twim},
+        },
+        message::{self, Reply},
+        mono, regmap, regsnap,
+        request::Request,
+        stats::STATS,
+        status,
+        tracebuf::{self, Event},
+        twimpoll,
+    },
+    core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+};
+
+/// Extra attempts made after an address NACK, e.g. while the peripheral is
+/// still re-arming its DMA buffer.
+const MAX_RETRIES: u32 = 2;
+
+/// Bus frequencies, fastest first.
+const FREQUENCIES: [Frequency; 3] = [Frequency::K400, Frequency::K250, Frequency::K100];
+
+// Cleared by `configure` if the config turns adaptation off.
+static ADAPTIVE: AtomicBool = AtomicBool::new(true);
+
+const WINDOW: u32 = 16;
+const MAX_WINDOW_ERRORS: u32 = 2;
+const CLEAN_WINDOWS: u32 = 4;
+
+// Index into `FREQUENCIES`, the one to start at comes from the config.
+static STEP: AtomicU8 = AtomicU8::new(0);
+
+// Transactions and errors in the current window, and clean windows in a row.
+static WINDOW_TRANSACTIONS: AtomicU32 = AtomicU32::new(0);
+static WINDOW_ERRORS: AtomicU32 = AtomicU32::new(0);
+static CLEAN: AtomicU32 = AtomicU32::new(0);
+
+static POWERED: AtomicBool = AtomicBool::new(true);
+
+// Low 32 bits of the monotonic at the end of the last transaction.
+static LAST_USE: AtomicU32 = AtomicU32::new(0);
+
+fn now() -> u32 {
+    crate::app::monotonics::now().ticks() as u32
+}
+
+/// Applies the persisted config, returns the frequency to create TWIM with.
+pub fn configure(config: &Config) -> Frequency {
+    let step = (config.frequency_step as usize).min(FREQUENCIES.len() - 1);
+    STEP.store(step as u8, Ordering::Relaxed);
+    ADAPTIVE.store(config.has(config::ADAPTIVE_FREQUENCY), Ordering::Relaxed);
+    FREQUENCIES[step]
+}
+
+/// Applies the TWIM settings of `config` right away. From priority 1,
+/// between transactions.
+pub fn reconfigure(config: &Config) {
+    let frequency = configure(config);
+    WINDOW_TRANSACTIONS.store(0, Ordering::Relaxed);
+    WINDOW_ERRORS.store(0, Ordering::Relaxed);
+    CLEAN.store(0, Ordering::Relaxed);
+    // SAFETY: as in `adapt_frequency`.
+    let twim = unsafe { &*TWIM1::ptr() };
+    twim.frequency.write(|w| w.frequency().variant(frequency));
+    info!(
+        "TWIM at {} kHz, adaptive frequency {}",
+        khz(frequency),
+        ADAPTIVE.load(Ordering::Relaxed)
+    );
+}
+
+/// Reads `buf.len()` bytes from `address`.
+pub fn read(twim: &mut Twim<TWIM1>, address: u8, buf: &mut [u8]) -> Result<(), AppError> {
+    check_supply()?;
+    let res = powered(|| with_retries(|| twim.read(address, buf)));
+    STATS.twim_reads.inc();
+    match res {
+        Ok(()) => {
+            STATS.twim_bytes.add(buf.len() as u32);
+            tracebuf::record(Event::TwimRead, 0, buf.len() as u16);
+            Ok(())
+        }
+        Err(error) => Err(failed(Op::Read, address, error)),
+    }
+}
+
+/// Writes `buf` to `address`.
+pub fn write(twim: &mut Twim<TWIM1>, address: u8, buf: &[u8]) -> Result<(), AppError> {
+    check_supply()?;
+    let res = powered(|| with_retries(|| twim.write(address, buf)));
+    STATS.twim_writes.inc();
+    match res {
+        Ok(()) => {
+            STATS.twim_bytes.add(buf.len() as u32);
+            tracebuf::record(Event::TwimWrite, 0, buf.len() as u16);
+            Ok(())
+        }
+        Err(error) => Err(failed(Op::Write, address, error)),
+    }
+}
+
+/// True if `address` ACKs a one-byte read. Bypasses the retries and the
+/// adaptive frequency, a bus scan NACKs on nearly every address.
+pub fn probe(twim: &mut Twim<TWIM1>, address: u8) -> bool {
+    if check_supply().is_err() {
+        return false;
+    }
+    let _pause = twimpoll::pause();
+    power_up();
+    let acked = twim.read(address, &mut [0]).is_ok();
+    LAST_USE.store(now(), Ordering::Relaxed);
+    acked
+}
+
+/// Reads `buf.len()` consecutive registers of the TWIS register map,
+/// starting at `reg`.
+pub fn read_regs(
+    twim: &mut Twim<TWIM1>,
+    address: u8,
+    reg: u8,
+    buf: &mut [u8],
+) -> Result<(), AppError> {
+    write(twim, address, &[reg])?;
+    read(twim, address, buf)
+}
+
+/// Reads the u32 register at `reg` of the TWIS register map, in one READ so
+/// the bytes are from the same value, see `multibyte`.
+pub fn read_u32(twim: &mut Twim<TWIM1>, address: u8, reg: u8) -> Result<u32, AppError> {
+    let mut bytes = [0; 4];
+    read_regs(twim, address, reg, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Writes `data` to consecutive registers of the TWIS register map, starting
+/// at `reg`. `data` must leave room for the pointer byte in the TWIS buffer,
+/// longer writes fail with `TxBufferTooLong`.
+pub fn write_regs(
+    twim: &mut Twim<TWIM1>,
+    address: u8,
+    reg: u8,
+    data: &[u8],
+) -> Result<(), AppError> {
+    if !soft_assert!(
+        data.len() < regmap::BUF_LEN,
+        "{} bytes do not fit a TWIS write",
+        data.len()
+    ) {
+        return Err(AppError::Twim {
+            op: Op::Write,
+            address,
+            error: Error::TxBufferTooLong,
+        });
+    }
+    let mut frame = [0; regmap::BUF_LEN];
+    let frame = &mut frame[..data.len() + 1];
+    frame[0] = reg;
+    frame[1..].copy_from_slice(data);
+    write(twim, address, frame)
+}
+
+/// Sends `request` to `address` as a typed message and reads the reply.
+/// With the `hmac` feature the message is tagged for the counter `address`
+/// reports, which takes the same key on both ends, see `key`.
+pub fn send(twim: &mut Twim<TWIM1>, address: u8, request: &Request) -> Result<Reply, AppError> {
+    let mut frame = [0; regmap::BUF_LEN];
+    frame[0] = regmap::MESSAGE;
+    let tag_len = if cfg!(feature = "hmac") {
+        auth::TAG_LEN
+    } else {
+        0
+    };
+    let mut len = 1 + message::encode(request, &mut frame[1..regmap::BUF_LEN - tag_len])?;
+    if tag_len != 0 {
+        let counter = read_u32(twim, address, regmap::AUTH_COUNT)?;
+        let tag = auth::tag(counter, &frame[1..len]);
+        frame[len..len + tag_len].copy_from_slice(&tag);
+        len += tag_len;
+    }
+    write(twim, address, &frame[..len])?;
+    // The pointer stays at MESSAGE.
+    let mut answer = [0; message::REPLY_LEN];
+    read(twim, address, &mut answer)?;
+    Ok(message::decode_reply(&answer)?)
+}
+
+// No new DMA after a power-fail warning.
+fn check_supply() -> Result<(), AppError> {
+    if status::is_set(status::BROWNOUT) {
+        let error = AppError::Internal(InternalError::Brownout);
+        error.record();
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn failed(op: Op, address: u8, error: Error) -> AppError {
+    let error = AppError::Twim { op, address, error };
+    error.record();
+    regsnap::log("twim failed");
+    error
+}
+
+/// Powers TWIM down if no transaction has been issued for `timeout`.
+/// Otherwise returns the time left until it may.
+pub fn power_down_if_idle(timeout: mono::Duration) -> Option<mono::Duration> {
+    let idle = now().wrapping_sub(LAST_USE.load(Ordering::Relaxed)) as u64;
+    if idle < timeout.ticks() {
+        return Some(mono::Duration::from_ticks(timeout.ticks() - idle));
+    }
+    if twimpoll::running() {
+        return Some(timeout);
+    }
+    if POWERED.swap(false, Ordering::Relaxed) {
+        set_powered(false);
+        info!("TWIM powered down");
+    }
+    None
+}
+
+// The HAL `Twim` never disables the peripheral, so doing it behind its back
+// is fine as long as it is enabled again before the next transaction.
+fn set_powered(on: bool) {
+    // SAFETY: only called from the task owning TWIM, between transactions.
+    let twim = unsafe { &*TWIM1::ptr() };
+    if on {
+        twim.enable.write(|w| w.enable().enabled());
+    } else {
+        twim.enable.write(|w| w.enable().disabled());
+    }
+    // Only the input buffer, drive and pull are left as `buspins` set them.
+    buspins::for_each(Bus::Twim, |cnf| {
+        cnf.modify(|_, w| {
+            if on {
+                w.input().connect()
+            } else {
+                w.input().disconnect()
+            }
+        })
+    });
+}
+
+// Runs a transaction with TWIM powered up.
+fn powered<T>(transaction: impl FnOnce() -> T) -> T {
+    let _pause = twimpoll::pause();
+    power_up();
+    let res = transaction();
+    LAST_USE.store(now(), Ordering::Relaxed);
+    adapt_frequency();
+    res
+}
+
+/// Enables TWIM if it was powered down.
+pub fn power_up() {
+    if !POWERED.swap(true, Ordering::Relaxed) {
+        set_powered(true);
+        trace!("TWIM powered up");
+    }
+}
+
+fn khz(frequency: Frequency) -> u32 {
+    match frequency {
+        FREQUENCY_A::K100 => 100,
+        FREQUENCY_A::K250 => 250,
+        FREQUENCY_A::K400 => 400,
+    }
+}
+
+/// Current bus frequency in kHz.
+pub fn frequency_khz() -> u32 {
+    khz(FREQUENCIES[STEP.load(Ordering::Relaxed) as usize])
+}
+
+// Counts one transaction towards the current window and changes the
+// frequency at the end of a window if necessary.
+fn adapt_frequency() {
+    if !ADAPTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if WINDOW_TRANSACTIONS.fetch_add(1, Ordering::Relaxed) + 1 < WINDOW {
+        return;
+    }
+    let errors = WINDOW_ERRORS.swap(0, Ordering::Relaxed);
+    WINDOW_TRANSACTIONS.store(0, Ordering::Relaxed);
+    let step = STEP.load(Ordering::Relaxed) as usize;
+    let new_step = if errors > MAX_WINDOW_ERRORS {
+        CLEAN.store(0, Ordering::Relaxed);
+        (step + 1).min(FREQUENCIES.len() - 1)
+    } else if errors == 0 && CLEAN.fetch_add(1, Ordering::Relaxed) + 1 >= CLEAN_WINDOWS {
+        CLEAN.store(0, Ordering::Relaxed);
+        step.saturating_sub(1)
+    } else {
+        step
+    };
+    if new_step != step {
+        STEP.store(new_step as u8, Ordering::Relaxed);
+        // SAFETY: only called from the task owning TWIM, between transactions.
+        let twim = unsafe { &*TWIM1::ptr() };
+        twim.frequency
+            .write(|w| w.frequency().variant(FREQUENCIES[new_step]));
+        warn!(
+            "TWIM {} errors in {} transactions, {} kHz -> {} kHz",
+            errors,
+            WINDOW,
+            khz(FREQUENCIES[step]),
+            khz(FREQUENCIES[new_step])
+        );
+    }
+}
+
+fn with_retries(mut transaction: impl FnMut() -> Result<(), Error>) -> Result<(), Error> {
+    let mut attempt = 0;
+    loop {
+        let res = transaction();
+        match res {
+            Err(Error::AddressNack) | Err(Error::DataNack) => {
+                STATS.nacks.inc();
+                WINDOW_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(Error::Overrun) => {
+                STATS.overruns.inc();
+                WINDOW_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => STATS.errors.inc(),
+            Ok(()) => {}
+        }
+        if res != Err(Error::AddressNack) || attempt == MAX_RETRIES {
+            return res;
+        }
+        attempt += 1;
+        STATS.retries.inc();
+    }
+}
@@ -0,0 +1,279 @@
+// Firmware update over I2C into the second half of the flash (`dfu`
+// feature).
+//
+// The controller streams a new image into `SLOT` with requests, each
+// carried out by the `run_dfu` task and reported in RESULT:
+//
+//   1. DFU_BEGIN with the manifest: image size and CRC-32
+//   2. DFU_WRITE for every chunk, in order, with its CRC-16; a chunk that
+//      starts a flash page erases it first, which takes 85 ms
+//   3. DFU_VERIFY: the CRC-32 of the slot and the vector table of the image
+//   4. DFU_ACTIVATE: copy the verified image over this one and reset
+//
+// DFU_OFFSET reads where the next chunk goes, so a controller resumes
+// after an error, and resending the last chunk is harmless. A chunk whose
+// CRC-16 does not match is refused with `ProtocolError::BadCrc` before it
+// gets here. DFU_STATE reads `IDLE`, `RECEIVING` or `VERIFIED`.
+//
+// There is no bootloader: the copy runs from RAM, `dfu_install`, with
+// interrupts off, and a power cut during it leaves no firmware to boot;
+// recover with a probe. The running image has to end below `SLOT` and the
+// new one be at most `MAX_SIZE`.
+
+use {
+    crate::{chip, hexdump, lock, nvstore},
+    core::{cell::RefCell, fmt},
+    cortex_m::interrupt::Mutex,
+};
+
+/// Where the new image goes, and the most it may be of: up to the `rolling`
+/// and `nvstore` pages.
+pub const SLOT: usize = chip::FLASH_LEN / 2;
+pub const MAX_SIZE: u32 = (chip::FLASH_LEN - SLOT - 0x2000) as u32;
+/// Most data in a DFU_WRITE.
+pub const CHUNK_LEN: usize = 16;
+const PAGE_LEN: u32 = 4096;
+
+pub const IDLE: u8 = 0;
+pub const RECEIVING: u8 = 1;
+pub const VERIFIED: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The running image reaches into `SLOT`.
+    NoSlot,
+    /// A DFU_WRITE or DFU_VERIFY without a DFU_BEGIN, or a DFU_ACTIVATE
+    /// before DFU_VERIFY passed.
+    State,
+    /// A chunk at another offset than DFU_OFFSET, or past the image.
+    Offset,
+    /// A chunk did not read back as programmed.
+    Flash,
+    /// A DFU_VERIFY before the whole image was written.
+    Incomplete,
+    /// The CRC-32 of the slot is not the one in the manifest.
+    Crc,
+    /// The image does not start with a vector table for this chip.
+    Image,
+}
+
+#[derive(Clone, Copy)]
+struct Manifest {
+    size: u32,
+    crc: u32,
+}
+
+struct Dfu {
+    manifest: Option<Manifest>,
+    // Bytes written, and the length of the last chunk.
+    written: u32,
+    last_len: u32,
+    verified: bool,
+}
+
+static DFU: Mutex<RefCell<Dfu>> = Mutex::new(RefCell::new(Dfu {
+    manifest: None,
+    written: 0,
+    last_len: 0,
+    verified: false,
+}));
+
+fn slot(offset: u32, len: u32) -> &'static [u8] {
+    // SAFETY: memory mapped flash inside `SLOT`, which only `run_dfu`
+    // programs, from the same task as this is called from.
+    unsafe { core::slice::from_raw_parts((SLOT + offset as usize) as *const u8, len as usize) }
+}
+
+fn slot_word(offset: u32) -> u32 {
+    let bytes = slot(offset, 4);
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The DFU_OFFSET register.
+pub fn offset() -> u32 {
+    lock::free(|cs| DFU.borrow(cs).borrow().written)
+}
+
+/// The DFU_STATE register.
+pub fn state() -> u8 {
+    lock::free(|cs| {
+        let dfu = DFU.borrow(cs).borrow();
+        match dfu.manifest {
+            None => IDLE,
+            Some(_) if dfu.verified => VERIFIED,
+            Some(_) => RECEIVING,
+        }
+    })
+}
+
+/// Starts receiving an image of `size` bytes with CRC-32 `crc`, dropping
+/// any earlier one.
+pub fn begin(size: u32, crc: u32) -> Result<(), Error> {
+    if nvstore::image_end() > SLOT {
+        return Err(Error::NoSlot);
+    }
+    lock::free(|cs| {
+        *DFU.borrow(cs).borrow_mut() = Dfu {
+            manifest: Some(Manifest { size, crc }),
+            written: 0,
+            last_len: 0,
+            verified: false,
+        }
+    });
+    Ok(())
+}
+
+/// Programs the chunk `data` at `offset`. Only call from priority 1 tasks,
+/// like `nvstore`.
+pub fn write(offset: u32, data: &[u8]) -> Result<(), Error> {
+    let (manifest, written, last_len) = lock::free(|cs| {
+        let dfu = DFU.borrow(cs).borrow();
+        (dfu.manifest, dfu.written, dfu.last_len)
+    });
+    let manifest = manifest.ok_or(Error::State)?;
+    let len = data.len() as u32;
+    let end = offset.checked_add(len).ok_or(Error::Offset)?;
+    // The last chunk again, its answer was lost.
+    if end == written && len == last_len && slot(offset, len) == data {
+        return Ok(());
+    }
+    if offset != written || end > manifest.size {
+        return Err(Error::Offset);
+    }
+    let page = offset.next_multiple_of(PAGE_LEN);
+    if page < end {
+        nvstore::erase_page(SLOT + page as usize);
+    }
+    for (i, word) in data.chunks_exact(4).enumerate() {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        nvstore::program_word(SLOT + offset as usize + 4 * i, word);
+    }
+    if slot(offset, len) != data {
+        return Err(Error::Flash);
+    }
+    lock::free(|cs| {
+        let mut dfu = DFU.borrow(cs).borrow_mut();
+        dfu.written = end;
+        dfu.last_len = len;
+    });
+    Ok(())
+}
+
+/// Checks the received image against the manifest.
+pub fn verify() -> Result<(), Error> {
+    let (manifest, written) = lock::free(|cs| {
+        let dfu = DFU.borrow(cs).borrow();
+        (dfu.manifest, dfu.written)
+    });
+    let manifest = manifest.ok_or(Error::State)?;
+    if written != manifest.size {
+        return Err(Error::Incomplete);
+    }
+    if hexdump::crc32(slot(0, manifest.size)) != manifest.crc {
+        return Err(Error::Crc);
+    }
+    // The initial stack pointer in RAM, the reset handler a Thumb address
+    // inside the image.
+    let sp = slot_word(0);
+    let reset = slot_word(4);
+    let ram = chip::RAM_START as u32..=(chip::RAM_START + chip::RAM_LEN) as u32;
+    if !ram.contains(&sp) || reset & 1 == 0 || reset & !1 >= manifest.size {
+        return Err(Error::Image);
+    }
+    lock::free(|cs| DFU.borrow(cs).borrow_mut().verified = true);
+    Ok(())
+}
+
+/// Installs the verified image and resets into it.
+pub fn activate() -> Result<(), Error> {
+    let size = lock::free(|cs| {
+        let dfu = DFU.borrow(cs).borrow();
+        dfu.manifest.filter(|_| dfu.verified).map(|m| m.size)
+    })
+    .ok_or(Error::State)?;
+    warn!("installing the new image, {} bytes, and resetting", size);
+    // SAFETY: `verify` checked the image, which ends below `SLOT`, so the
+    // copy never overwrites its own source.
+    unsafe { dfu_install(size, SLOT as u32) }
+}
+
+extern "C" {
+    fn dfu_install(size: u32, slot: u32) -> !;
+}
+
+// `dfu_install` copies from `SLOT` page by page, so it has to start on a
+// page and hold the largest image inside the flash of this chip.
+const _: () = assert!(
+    SLOT.is_multiple_of(PAGE_LEN as usize) && SLOT + MAX_SIZE as usize <= chip::FLASH_LEN,
+    "DFU slot outside the flash"
+);
+
+// Erases the pages from 0 up to `size` (r0), copies the slot at `slot` (r1)
+// over them one word at a time and resets. In `.data`, so it runs from RAM while the
+// flash it came from is erased, and written in assembly, since a debug
+// build would call into flash even for the simplest Rust. NVMC: READY at
+// 0x400, CONFIG at 0x504 (0 read, 1 write, 2 erase), ERASEPAGE at 0x508.
+core::arch::global_asm!(
+    ".pushsection .data.dfu_install, \"awx\"",
+    ".syntax unified",
+    ".thumb",
+    ".p2align 2",
+    ".global dfu_install",
+    ".type dfu_install, %function",
+    ".thumb_func",
+    "dfu_install:",
+    "    cpsid i",
+    "    movw r3, #0xe000",
+    "    movt r3, #0x4001",
+    "    mov r4, r1",
+    "    movs r2, #2",
+    "    str r2, [r3, #0x504]",
+    "    movs r1, #0",
+    "1:  cmp r1, r0",
+    "    bhs 3f",
+    "    str r1, [r3, #0x508]",
+    "2:  ldr r2, [r3, #0x400]",
+    "    cmp r2, #0",
+    "    beq 2b",
+    "    add r1, r1, #0x1000",
+    "    b 1b",
+    "3:  movs r2, #1",
+    "    str r2, [r3, #0x504]",
+    "    movs r1, #0",
+    "4:  cmp r1, r0",
+    "    bhs 6f",
+    "    ldr r2, [r4, r1]",
+    "    str r2, [r1]",
+    "5:  ldr r2, [r3, #0x400]",
+    "    cmp r2, #0",
+    "    beq 5b",
+    "    adds r1, r1, #4",
+    "    b 4b",
+    "6:  movs r2, #0",
+    "    str r2, [r3, #0x504]",
+    "    dsb",
+    // AIRCR: VECTKEY and SYSRESETREQ.
+    "    movw r2, #0xed0c",
+    "    movt r2, #0xe000",
+    "    movw r1, #0x0004",
+    "    movt r1, #0x05fa",
+    "    str r1, [r2]",
+    "    dsb",
+    "7:  b 7b",
+    ".size dfu_install, . - dfu_install",
+    ".popsection",
+);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NoSlot => "firmware image overlaps the DFU slot",
+            Error::State => "request out of sequence",
+            Error::Offset => "chunk out of order or past the image",
+            Error::Flash => "chunk did not read back as programmed",
+            Error::Incomplete => "image not fully written",
+            Error::Crc => "image CRC does not match the manifest",
+            Error::Image => "image has no valid vector table",
+        })
+    }
+}
@@ -0,0 +1,172 @@
+// Description of the register map, read from the DESCRIPTION register.
+//
+// READs starting at DESCRIPTION return a stream of TLV records, a type, a
+// length and that many bytes of value, up to an end record and 0 after it:
+//
+//   0x01  HEADER    4  format version, register count, stream length u16 LE
+//   0x02  REGISTER  8  offset, width, count, access, name hash u32 LE
+//   0xff  END       0
+//
+// A register is `count` values of `width` bytes from `offset`, e.g. STATS
+// is 16 of 4 bytes; the access bits are `READ`, `WRITE` and `FIFO`. Names
+// are sent as the FNV-1a 32-bit hash of the name in `regmap`'s table, e.g.
+// "STATS", so a host looks up the names it knows and shows the others by
+// hash. A host skips records of a type it does not know by their length.
+//
+// Like STREAM, DESCRIPTION only consumes the bytes a READ moved, so any
+// READ length walks the whole stream. It starts over at boot and with a
+// DESCRIBE request.
+
+use {
+    crate::{
+        ccm, events, identity, message, regmap::*, rolling, saadc, stats, unlock, wallclock, ws2812,
+    },
+    core::sync::atomic::{AtomicU16, Ordering},
+};
+
+pub const VERSION: u8 = 1;
+
+/// Access bits of a REGISTER record.
+pub const READ: u8 = 1 << 0;
+pub const WRITE: u8 = 1 << 1;
+/// A READ starting there leaves the pointer in place.
+pub const FIFO: u8 = 1 << 2;
+
+const HEADER: u8 = 0x01;
+const REGISTER: u8 = 0x02;
+const END: u8 = 0xff;
+
+const HEADER_LEN: usize = 2 + 4;
+const RECORD_LEN: usize = 2 + 8;
+/// Length of the whole stream.
+pub const LEN: usize = HEADER_LEN + REGISTERS.len() * RECORD_LEN + 2;
+
+struct Register {
+    name: &'static str,
+    offset: u8,
+    width: u8,
+    count: u8,
+    access: u8,
+}
+
+const fn reg(name: &'static str, offset: u8, width: usize, count: usize, access: u8) -> Register {
+    Register {
+        name,
+        offset,
+        width: width as u8,
+        count: count as u8,
+        access,
+    }
+}
+
+const RW: u8 = READ | WRITE;
+
+// In step with the table in `regmap`.
+const REGISTERS: [Register; 49] = [
+    reg("SCRATCH", SCRATCH, 1, SCRATCH_LEN, RW),
+    reg("DEVICE_ADDR", DEVICE_ADDR, identity::ADDR_LEN, 1, READ),
+    reg("BRIDGE_TARGET", BRIDGE_TARGET, 1, 1, READ),
+    reg("BRIDGE_STATUS", BRIDGE_STATUS, 1, 1, READ),
+    reg("POWER_STATE", POWER_STATE, 1, 1, READ),
+    reg("WAKE_REASON", WAKE_REASON, 1, 1, READ),
+    reg("STATUS", STATUS, 1, 1, RW),
+    reg("ANALOG_THRESHOLD", ANALOG_THRESHOLD, 1, 1, RW),
+    reg("RESET_REASON", RESET_REASON, 4, 1, READ),
+    reg("TEMPERATURE", TEMPERATURE, 1, 1, READ),
+    reg("LED_BRIGHTNESS", LED_BRIGHTNESS, 1, 1, RW),
+    reg("LED_BLINK", LED_BLINK, 1, 1, RW),
+    reg("ADC_COUNT", ADC_COUNT, 1, 1, READ),
+    reg("TEMPERATURE_RAW", TEMPERATURE_RAW, 2, 1, READ),
+    reg("RANDOM", RANDOM, 1, 1, READ | FIFO),
+    reg("FLASH_STATUS", FLASH_STATUS, 1, 1, READ),
+    reg("COMMAND", COMMAND, 1, 1, WRITE),
+    reg("FLASH_DATA", FLASH_DATA, 1, 1, READ | FIFO),
+    reg("TIME", TIME, wallclock::LEN, 1, RW),
+    reg("DEVICE_ID", DEVICE_ID, 8, 1, READ),
+    reg("SAMPLES", SAMPLES, 2, saadc::MAX_SAMPLES, READ),
+    reg("STATS", STATS_BASE, 4, stats::COUNT, READ),
+    reg("AUDIO_STATUS", AUDIO_STATUS, 1, 1, READ),
+    reg("AUDIO_LEN", AUDIO_LEN, 2, 1, READ),
+    reg("AUDIO_DATA", AUDIO_DATA, 1, 1, READ | FIFO),
+    reg("LEDS", LEDS, 1, ws2812::LEN, RW),
+    reg("ENCODER_POSITION", ENCODER_POSITION, 4, 1, READ),
+    reg("ENCODER_VELOCITY", ENCODER_VELOCITY, 2, 1, READ),
+    reg("MESSAGE", MESSAGE, 1, message::REPLY_LEN, RW),
+    reg("STREAM", STREAM, 1, 1, RW | FIFO),
+    reg("SEQ_COMMAND", SEQ_COMMAND, 4, 1, RW),
+    reg("RESULT", RESULT, 4, 1, READ),
+    reg("ENVELOPE", ENVELOPE, 1, 1, WRITE),
+    reg("NONCE", NONCE, 1, unlock::LEN, READ),
+    reg("UNLOCK", UNLOCK, 1, unlock::LEN, WRITE),
+    reg("AUTH_COUNT", AUTH_COUNT, 4, 1, READ),
+    reg("SESSION", SESSION, 1, 1, WRITE),
+    reg("SESSION_IV", SESSION_IV, 1, ccm::IV_LEN, READ),
+    reg("ARM_COUNT", ARM_COUNT, 4, 1, READ),
+    reg("ARM", ARM, 1, rolling::LEN, WRITE),
+    reg("DFU_OFFSET", DFU_OFFSET, 4, 1, READ),
+    reg("DFU_STATE", DFU_STATE, 1, 1, READ),
+    reg("BOOTLOADER", BOOTLOADER, 1, 1, WRITE),
+    reg("MOTION", MOTION, 1, 1, RW),
+    reg("DESCRIPTION", DESCRIPTION, 1, 1, READ | FIFO),
+    reg("BATCH", BATCH, 1, BUF_LEN - 1, RW),
+    reg("EVENT", EVENT, events::RECORD_LEN, 1, READ | FIFO),
+    reg("EVENT_COUNT", EVENT_COUNT, 1, 1, READ),
+    reg("SYNC_OFFSET", SYNC_OFFSET, 8, 1, READ),
+];
+
+const _: () = assert!(LEN <= u16::MAX as usize);
+
+static POSITION: AtomicU16 = AtomicU16::new(0);
+
+/// FNV-1a, 32 bits.
+pub const fn hash(name: &str) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+// Byte `index` of the stream, 0 past the end.
+fn byte(index: usize) -> u8 {
+    let registers = REGISTERS.len() * RECORD_LEN;
+    if index < HEADER_LEN {
+        let len = (LEN as u16).to_le_bytes();
+        [HEADER, 4, VERSION, REGISTERS.len() as u8, len[0], len[1]][index]
+    } else if index < HEADER_LEN + registers {
+        let index = index - HEADER_LEN;
+        let reg = &REGISTERS[index / RECORD_LEN];
+        let hash = hash(reg.name).to_le_bytes();
+        [
+            REGISTER, 8, reg.offset, reg.width, reg.count, reg.access, hash[0], hash[1], hash[2],
+            hash[3],
+        ][index % RECORD_LEN]
+    } else if index < LEN {
+        [END, 0][index - HEADER_LEN - registers]
+    } else {
+        0
+    }
+}
+
+/// Starts the stream over, for DESCRIBE.
+pub fn rewind() {
+    POSITION.store(0, Ordering::Relaxed);
+}
+
+/// Fills `buf` from the current position without consuming it.
+pub fn peek(buf: &mut [u8]) {
+    let position = POSITION.load(Ordering::Relaxed) as usize;
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = self::byte(position + i);
+    }
+}
+
+/// Drops the `count` bytes the controller has read.
+pub fn consume(count: usize) {
+    let position = POSITION.load(Ordering::Relaxed) as usize;
+    POSITION.store((position + count).min(LEN) as u16, Ordering::Relaxed);
+}
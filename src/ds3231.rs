@@ -0,0 +1,321 @@
+// A Maxim DS3231 real-time clock at TWIS address 1 (`ds3231` feature).
+//
+// The register map of the real part, timekept by the RTC monotonic like
+// `wallclock`:
+//
+//   0x00-0x06  seconds, minutes, hours, day, date, month/century, year   rw
+//   0x07-0x0a  alarm 1: seconds, minutes, hours, day/date                rw
+//   0x0b-0x0d  alarm 2: minutes, hours, day/date                         rw
+//   0x0e       control, kept but without effect (no INT/SQW pin)         rw
+//   0x0f       status: OSF, EN32kHz, A2F, A1F                            rw
+//   0x10       aging offset, kept                                        rw
+//   0x11-0x12  temperature, the die one from `thermal`                   r
+//
+// All time fields are BCD; hours are 24-hour, or 12-hour with bit 6 set
+// and bit 5 for PM, in whichever mode was last written. The calendar runs
+// from 2000 to 2199, the century bit of the month register set from 2100.
+// Day is 1-7 and counts up at midnight from whatever was written.
+//
+// A WRITE is a register address and values for it and the ones after,
+// wrapping from 0x12 to 0x00; a READ starts at the last address written
+// and counts up without moving it. A READ takes the time once, so its
+// bytes belong to the same second. Writing any time register sets the
+// clock and restarts the second. OSF is set at boot, when the time is
+// 2000-01-01 00:00:00, until the controller clears it. The status flags
+// are cleared by writing 0 to them, writing 1 leaves them alone.
+//
+// `tick` checks the alarms once a second and sets A1F and A2F on a match,
+// as the part does: each alarm compares the fields whose mask bit (bit 7)
+// is clear, alarm 2 at second 0, and with DY/DT (bit 6 of day/date) the
+// day rather than the date.
+
+use {
+    crate::{lock, mono, thermal},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// The DS3231's fixed address.
+pub const ADDRESS: u8 = 0x68;
+
+const TIME: u8 = 0x00;
+const ALARM1: u8 = 0x07;
+const ALARM2: u8 = 0x0b;
+const CONTROL: u8 = 0x0e;
+const STATUS: u8 = 0x0f;
+const AGING: u8 = 0x10;
+const TEMP_MSB: u8 = 0x11;
+const TEMP_LSB: u8 = 0x12;
+
+// Status bits.
+const OSF: u8 = 1 << 7;
+const EN32KHZ: u8 = 1 << 3;
+const A2F: u8 = 1 << 1;
+const A1F: u8 = 1 << 0;
+
+const MASK: u8 = 1 << 7;
+const TWELVE_HOUR: u8 = 1 << 6;
+const PM: u8 = 1 << 5;
+const DY_DT: u8 = 1 << 6;
+const CENTURY: u8 = 1 << 7;
+
+// 2000-01-01 in days since 1970-01-01.
+const EPOCH_DAYS: i64 = 10_957;
+// 2200-01-01 in seconds since 2000-01-01.
+const END_SECS: i64 = 73_048 * 86_400;
+
+struct Ds3231 {
+    pointer: u8,
+    // Added to the ms since boot for the time in ms since 2000-01-01.
+    offset_ms: i64,
+    twelve_hour: bool,
+    // Added to the days since 2000-01-01 for the day register.
+    weekday_offset: u8,
+    alarms: [u8; 7],
+    control: u8,
+    status: u8,
+    aging: u8,
+    // The last second the alarms were checked for.
+    checked: i64,
+}
+
+static DS3231: Mutex<RefCell<Ds3231>> = Mutex::new(RefCell::new(Ds3231 {
+    pointer: 0,
+    offset_ms: 0,
+    twelve_hour: false,
+    weekday_offset: 0,
+    alarms: [0; 7],
+    control: 0b0001_1100,
+    status: OSF | EN32KHZ,
+    aging: 0,
+    checked: 0,
+}));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "ds3231")
+}
+
+fn uptime_ms() -> i64 {
+    (crate::app::monotonics::now().ticks() * 1000 / mono::TICK_HZ as u64) as i64
+}
+
+impl Ds3231 {
+    // Seconds since 2000-01-01.
+    fn now(&self) -> i64 {
+        ((uptime_ms() + self.offset_ms).div_euclid(1000)).rem_euclid(END_SECS)
+    }
+
+    fn read(&self, address: u8, time: &[u8; 7]) -> u8 {
+        match address {
+            TIME..=0x06 => time[address as usize],
+            ALARM1..=0x0d => self.alarms[(address - ALARM1) as usize],
+            CONTROL => self.control,
+            STATUS => self.status,
+            AGING => self.aging,
+            TEMP_MSB => (thermal::quarters() >> 2) as u8,
+            TEMP_LSB => ((thermal::quarters() & 3) << 6) as u8,
+            _ => 0,
+        }
+    }
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    let Some((&pointer, values)) = data.split_first() else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut rtc = DS3231.borrow(cs).borrow_mut();
+        rtc.pointer = pointer;
+        let mut time = encode(&rtc, rtc.now());
+        let mut set = false;
+        let mut address = pointer;
+        for &value in values {
+            match address {
+                TIME..=0x06 => {
+                    time[address as usize] = value;
+                    set = true;
+                }
+                ALARM1..=0x0d => rtc.alarms[(address - ALARM1) as usize] = value,
+                CONTROL => rtc.control = value,
+                STATUS => {
+                    let flags = OSF | A2F | A1F;
+                    rtc.status = rtc.status & (value | !flags) & !EN32KHZ | value & EN32KHZ;
+                }
+                AGING => rtc.aging = value,
+                _ => {}
+            }
+            address = if address >= TEMP_LSB { 0 } else { address + 1 };
+        }
+        if set {
+            let seconds = decode(&time);
+            rtc.offset_ms = seconds * 1000 - uptime_ms();
+            rtc.twelve_hour = time[2] & TWELVE_HOUR != 0;
+            let weekday = (seconds / 86_400 % 7) as u8;
+            let day = bcd_to_bin(time[3] & 0x07).clamp(1, 7) - 1;
+            rtc.weekday_offset = (day + 7 - weekday) % 7;
+            rtc.checked = seconds - 1;
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`, from the last address written.
+pub fn read(buf: &mut [u8]) {
+    lock::free(|cs| {
+        let rtc = DS3231.borrow(cs).borrow();
+        let time = encode(&rtc, rtc.now());
+        let mut address = rtc.pointer;
+        for byte in buf.iter_mut() {
+            *byte = rtc.read(address, &time);
+            address = if address >= TEMP_LSB { 0 } else { address + 1 };
+        }
+    });
+}
+
+/// Checks the alarms for the seconds since the last call. Returns the ms to
+/// the start of the next second.
+pub fn tick() -> u64 {
+    lock::free(|cs| {
+        let mut rtc = DS3231.borrow(cs).borrow_mut();
+        let now = rtc.now();
+        // Only a few seconds can be missed, after the clock was set.
+        let from = rtc.checked.clamp(now - 3, now);
+        for second in from + 1..=now {
+            let time = encode(&rtc, second);
+            if alarm1(&rtc.alarms, &time) {
+                rtc.status |= A1F;
+            }
+            if alarm2(&rtc.alarms, &time) {
+                rtc.status |= A2F;
+            }
+        }
+        rtc.checked = now;
+        (1000 - (uptime_ms() + rtc.offset_ms).rem_euclid(1000)) as u64
+    })
+}
+
+// Whether the unmasked fields of an alarm, in the layout of the seconds,
+// minutes, hours and day/date registers, match `time`.
+fn alarm_matches(fields: [Option<u8>; 4], time: &[u8; 7]) -> bool {
+    let [seconds, minutes, hours, day_date] = fields;
+    seconds.is_none_or(|s| s & !MASK == time[0])
+        && minutes.is_none_or(|m| m & !MASK == time[1])
+        && hours.is_none_or(|h| hours_24(h) == hours_24(time[2]))
+        && day_date.is_none_or(|d| {
+            if d & DY_DT != 0 {
+                d & 0x0f == time[3]
+            } else {
+                d & 0x3f == time[4]
+            }
+        })
+}
+
+// A field of an alarm, `None` if its mask bit is set.
+fn field(register: u8) -> Option<u8> {
+    (register & MASK == 0).then_some(register)
+}
+
+fn alarm1(alarms: &[u8; 7], time: &[u8; 7]) -> bool {
+    alarm_matches(
+        [
+            field(alarms[0]),
+            field(alarms[1]),
+            field(alarms[2]),
+            field(alarms[3]),
+        ],
+        time,
+    )
+}
+
+fn alarm2(alarms: &[u8; 7], time: &[u8; 7]) -> bool {
+    let [minutes, hours, day_date] =
+        [0, 1, 2].map(|i| field(alarms[(ALARM2 - ALARM1) as usize + i]));
+    time[0] == 0 && alarm_matches([None, minutes, hours, day_date], time)
+}
+
+// The hour of an hours register, 0-23.
+fn hours_24(register: u8) -> u8 {
+    if register & TWELVE_HOUR != 0 {
+        bcd_to_bin(register & 0x1f) % 12 + if register & PM != 0 { 12 } else { 0 }
+    } else {
+        bcd_to_bin(register & 0x3f)
+    }
+}
+
+// The time registers for `seconds` since 2000-01-01.
+fn encode(rtc: &Ds3231, seconds: i64) -> [u8; 7] {
+    let days = seconds / 86_400;
+    let of_day = seconds % 86_400;
+    let (year, month, date) = civil(days + EPOCH_DAYS);
+    let hour = (of_day / 3600) as u8;
+    let hours = if rtc.twelve_hour {
+        let pm = if hour >= 12 { PM } else { 0 };
+        TWELVE_HOUR
+            | pm
+            | bin_to_bcd(match hour % 12 {
+                0 => 12,
+                hour => hour,
+            })
+    } else {
+        bin_to_bcd(hour)
+    };
+    let century = if year >= 2100 { CENTURY } else { 0 };
+    [
+        bin_to_bcd((of_day % 60) as u8),
+        bin_to_bcd((of_day / 60 % 60) as u8),
+        hours,
+        ((days % 7) as u8 + rtc.weekday_offset) % 7 + 1,
+        bin_to_bcd(date),
+        century | bin_to_bcd(month),
+        bin_to_bcd((year % 100) as u8),
+    ]
+}
+
+// Seconds since 2000-01-01 of the time registers, out of range fields
+// clamped.
+fn decode(time: &[u8; 7]) -> i64 {
+    let seconds = bcd_to_bin(time[0] & 0x7f).min(59) as i64;
+    let minutes = bcd_to_bin(time[1] & 0x7f).min(59) as i64;
+    let hours = hours_24(time[2]).min(23) as i64;
+    let date = bcd_to_bin(time[4] & 0x3f).clamp(1, 31);
+    let month = bcd_to_bin(time[5] & 0x1f).clamp(1, 12);
+    let century = if time[5] & CENTURY != 0 { 2100 } else { 2000 };
+    let year = century + bcd_to_bin(time[6]) as i64;
+    let days = days_from_civil(year, month, date) - EPOCH_DAYS;
+    days * 86_400 + hours * 3600 + minutes * 60 + seconds
+}
+
+fn bcd_to_bin(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0f)
+}
+
+fn bin_to_bcd(bin: u8) -> u8 {
+    (bin / 10) << 4 | (bin % 10)
+}
+
+// Year, month and day of `days` since 1970-01-01, after Howard Hinnant's
+// `civil_from_days`.
+fn civil(days: i64) -> (i64, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// Days since 1970-01-01 of a date, after `days_from_civil`.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
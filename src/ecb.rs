@@ -0,0 +1,57 @@
+// AES-128 in the ECB peripheral, one block at a time.
+//
+// ECB encrypts a 16-byte block in about 7 us, reading key and cleartext
+// from RAM and writing the ciphertext back by EasyDMA. It is used for the
+// keyed transforms of `unlock`; without a SoftDevice nothing else wants it.
+
+use {
+    crate::hal::pac::ECB,
+    core::sync::atomic::{compiler_fence, Ordering},
+};
+
+pub const BLOCK_LEN: usize = 16;
+
+// ECBDATAPTR layout.
+#[repr(C)]
+struct Data {
+    key: [u8; BLOCK_LEN],
+    cleartext: [u8; BLOCK_LEN],
+    ciphertext: [u8; BLOCK_LEN],
+}
+
+/// Takes `ECB` so nothing else uses it. Blocks are polled, no interrupts.
+pub fn init(ecb: ECB) {
+    ecb.intenclr
+        .write(|w| w.endecb().clear().errorecb().clear());
+}
+
+/// `block` encrypted with `key`, `None` if ECB aborted it.
+pub fn encrypt(key: &[u8; BLOCK_LEN], block: &[u8; BLOCK_LEN]) -> Option<[u8; BLOCK_LEN]> {
+    let mut data = Data {
+        key: *key,
+        cleartext: *block,
+        ciphertext: [0; BLOCK_LEN],
+    };
+    // SAFETY: ECB is only used here, `init` took ownership of it, and a
+    // block runs with interrupts off so two callers cannot share it. `data`
+    // outlives the DMA, which ends with ENDECB or ERRORECB.
+    let done = cortex_m::interrupt::free(|_| unsafe {
+        let ecb = &*ECB::ptr();
+        ecb.events_endecb.reset();
+        ecb.events_errorecb.reset();
+        ecb.ecbdataptr
+            .write(|w| w.bits(&mut data as *mut Data as u32));
+        compiler_fence(Ordering::SeqCst);
+        ecb.tasks_startecb.write(|w| w.bits(1));
+        loop {
+            if ecb.events_endecb.read().bits() != 0 {
+                break true;
+            }
+            if ecb.events_errorecb.read().bits() != 0 {
+                break false;
+            }
+        }
+    });
+    compiler_fence(Ordering::SeqCst);
+    done.then_some(data.ciphertext)
+}
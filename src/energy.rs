@@ -0,0 +1,124 @@
+// Rough energy estimate per TWIS transaction.
+//
+// A transaction runs from the address match (WRITE or READ event) to its
+// STOPPED. `on_twis` charges the CPU cycles it spends on it; the wall time
+// comes from the RTC. The estimate multiplies those times with typical
+// currents from the nRF52840 product specification:
+//
+//   E = VDD * (I_cpu * t_cpu + (I_twis + I_hfxo if running) * t_wall)
+//
+// It ignores the sleep current and everything outside `on_twis`, so treat
+// it as a way to compare configurations, not as a measurement.
+
+use {
+    crate::{clock, error::Op, lock, mono, profile},
+    core::{cell::RefCell, fmt},
+    cortex_m::interrupt::Mutex,
+};
+
+const VDD_MV: u64 = 3000;
+
+// CPU running from flash at 64 MHz.
+const CPU_UA: u64 = if cfg!(feature = "dcdc") { 3300 } else { 6300 };
+// TWIS with EasyDMA, plus the HFINT it runs from.
+const TWIS_UA: u64 = 400;
+const HFXO_UA: u64 = 250;
+
+#[derive(Clone, Copy)]
+struct Pending {
+    start_ticks: u64,
+    cycles: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Total {
+    count: u32,
+    nanojoules: u64,
+}
+
+struct State {
+    pending: Option<Pending>,
+    // Indexed by `Op as usize`.
+    totals: [Total; 2],
+}
+
+const EMPTY: Total = Total {
+    count: 0,
+    nanojoules: 0,
+};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    pending: None,
+    totals: [EMPTY; 2],
+}));
+
+/// Starts a transaction, on its address match.
+pub fn begin() {
+    let start_ticks = crate::app::monotonics::now().ticks();
+    lock::free(|cs| {
+        STATE.borrow(cs).borrow_mut().pending = Some(Pending {
+            start_ticks,
+            cycles: 0,
+        })
+    });
+}
+
+/// Charges the CPU cycles since `start` (a `profile::now` value) to the
+/// running transaction.
+pub fn charge_cpu(start: u32) {
+    let cycles = profile::now().wrapping_sub(start);
+    lock::free(|cs| {
+        if let Some(pending) = &mut STATE.borrow(cs).borrow_mut().pending {
+            pending.cycles = pending.cycles.saturating_add(cycles);
+        }
+    });
+}
+
+/// Ends the running transaction, on its STOPPED, and returns its energy
+/// in nJ.
+pub fn end(op: Op) -> Option<u64> {
+    let now = crate::app::monotonics::now().ticks();
+    let hfxo = clock::hfxo_running();
+    lock::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let pending = state.pending.take()?;
+        let wall_us = (now - pending.start_ticks) * 1_000_000 / mono::TICK_HZ as u64;
+        let cpu_us = profile::micros(pending.cycles) as u64;
+        let periph_ua = TWIS_UA + if hfxo { HFXO_UA } else { 0 };
+        // mV * uA * us = fJ
+        let nanojoules = VDD_MV * (CPU_UA * cpu_us + periph_ua * wall_us) / 1_000_000;
+        let total = &mut state.totals[op as usize];
+        total.count += 1;
+        total.nanojoules += nanojoules;
+        Some(nanojoules)
+    })
+}
+
+/// Average energy per transaction of `op` in nJ, 0 without any.
+pub fn average(op: Op) -> u64 {
+    let total = lock::free(|cs| STATE.borrow(cs).borrow().totals[op as usize]);
+    total
+        .nanojoules
+        .checked_div(total.count as u64)
+        .unwrap_or(0)
+}
+
+/// Forgets the totals.
+pub fn reset() {
+    lock::free(|cs| STATE.borrow(cs).borrow_mut().totals = [EMPTY; 2]);
+}
+
+/// `energy per transaction: read <n> nJ (<count>), write <n> nJ (<count>)`
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = lock::free(|cs| STATE.borrow(cs).borrow().totals);
+        f.write_str("energy per transaction:")?;
+        for (name, op) in [("read", Op::Read), ("write", Op::Write)] {
+            let count = totals[op as usize].count;
+            write!(f, " {} {} nJ ({})", name, average(op), count)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+// Random data for the RANDOM register, from the hardware RNG.
+//
+// With bias correction the RNG takes ~120 us per byte, far too slow to
+// produce a READ's worth while TWIS waits for its buffer. So it fills
+// `POOL` in the background instead: `on_interrupt` collects a byte per
+// VALRDY and stops the RNG once the pool is full, `take` hands bytes out and
+// starts it again. A READ of up to `POOL_LEN` bytes every few ms always gets
+// fresh ones; bytes asked for while the pool is empty read as 0.
+//
+// Once the SoftDevice of `ble` runs, the RNG is its own and keeps a pool
+// like this one, which `take` draws from instead.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{hal::pac::RNG, lock},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const POOL_LEN: usize = 32;
+
+struct Pool {
+    bytes: [u8; POOL_LEN],
+    len: usize,
+}
+
+static POOL: Mutex<RefCell<Pool>> = Mutex::new(RefCell::new(Pool {
+    bytes: [0; POOL_LEN],
+    len: 0,
+}));
+
+fn regs() -> &'static crate::hal::pac::rng::RegisterBlock {
+    // SAFETY: RNG is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*RNG::ptr() }
+}
+
+/// Takes `RNG` so nothing else uses it and starts filling the pool.
+pub fn init(rng: RNG) {
+    rng.config.write(|w| w.dercen().enabled());
+    rng.intenset.write(|w| w.valrdy().set());
+    rng.tasks_start.write(|w| unsafe { w.bits(1) });
+}
+
+/// Handles VALRDY: adds the byte to the pool.
+pub fn on_interrupt() {
+    let rng = regs();
+    if rng.events_valrdy.read().bits() == 0 {
+        return;
+    }
+    rng.events_valrdy.reset();
+    let value = rng.value.read().value().bits();
+    let full = lock::free(|cs| {
+        let mut pool = POOL.borrow(cs).borrow_mut();
+        if pool.len < POOL_LEN {
+            let len = pool.len;
+            pool.bytes[len] = value;
+            pool.len += 1;
+        }
+        pool.len == POOL_LEN
+    });
+    if full {
+        rng.tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+}
+
+/// Fills `buf` from the pool, 0 for bytes it has run out of. Not with
+/// PRIMASK set: with `ble` this may be a SoftDevice call.
+pub fn take(buf: &mut [u8]) {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        let len = softdevice::random(buf);
+        buf[len..].fill(0);
+        if len < buf.len() {
+            trace!("entropy pool ran dry, {} bytes short", buf.len() - len);
+        }
+        return;
+    }
+    let short = lock::free(|cs| {
+        let mut pool = POOL.borrow(cs).borrow_mut();
+        let mut short = 0;
+        for byte in buf.iter_mut() {
+            *byte = if pool.len > 0 {
+                pool.len -= 1;
+                pool.bytes[pool.len]
+            } else {
+                short += 1;
+                0
+            };
+        }
+        short
+    });
+    regs().tasks_start.write(|w| unsafe { w.bits(1) });
+    if short > 0 {
+        trace!("entropy pool ran dry, {} bytes short", short);
+    }
+}
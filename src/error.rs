@@ -0,0 +1,189 @@
+// Crate-wide error type.
+//
+// Every failure the firmware reports is an `AppError`, so the log, the event
+// trace and the console describe errors the same way. `Display` gives a one
+// line description with context, `record` appends the error to the event
+// trace as `Event::Error` with `arg` = `ErrorSource` and a source specific
+// `value`, and raises the ERROR signal. A checksum that does not match, the
+// CRC-16 of a DFU chunk or an SMBus PEC, is always `ProtocolError::BadCrc`.
+//
+// The errors are formatted with `Display` and `Debug` only, not defmt: the
+// log goes through rtt-target, which takes `core::fmt`.
+
+use {
+    crate::{
+        config,
+        events::{self, Kind},
+        hal::{spis, twim, twis},
+        nvstore,
+        signal::{self, Signal},
+        tracebuf::{self, ErrorSource, Event, TaskId},
+    },
+    core::fmt,
+};
+
+/// Direction of a bus transfer, seen from the controller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppError {
+    /// A TWIM transaction failed, after any retries.
+    Twim {
+        op: Op,
+        address: u8,
+        error: twim::Error,
+    },
+    /// Arming a TWIS DMA transfer failed.
+    Twis {
+        op: Op,
+        error: twis::Error,
+    },
+    /// Arming an SPIS DMA transfer failed.
+    Spis(spis::Error),
+    /// The controller sent something the register map cannot accept.
+    Protocol(ProtocolError),
+    /// Persisting the device config failed.
+    Config(config::Error),
+    /// Persisting the scratch registers to flash failed.
+    Store(nvstore::Error),
+    Internal(InternalError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Checksum mismatch, e.g. the CRC-16 of a DFU_WRITE chunk.
+    BadCrc,
+    /// A WRITE longer than the TWIS buffer; the excess was dropped.
+    BadLength { len: u32, max: usize },
+    /// A WRITE to a register that is not writable. The pointer byte is the
+    /// opcode of the register map protocol.
+    UnknownOpcode(u8),
+    /// A request with arguments out of range, for the given opcode.
+    InvalidArgument(u8),
+    /// A typed message that does not decode, see `message`, or an SMBus
+    /// WRITE of the wrong length, see `smbus`.
+    Malformed,
+    /// A typed message of a version this firmware does not speak.
+    Version(u8),
+    /// A privileged request without an unlock, see `unlock`.
+    Locked(u8),
+    /// A request frame whose tag does not verify, see `auth`.
+    Unauthenticated,
+    /// A one-shot request without a rolling code, see `rolling`.
+    NotArmed(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InternalError {
+    /// A software task could not be spawned because it is still pending.
+    SpawnFailed(TaskId),
+    /// Refused while `status::BROWNOUT` is set.
+    Brownout,
+}
+
+impl AppError {
+    /// Appends the error to the event trace and signals it.
+    pub fn record(&self) {
+        let (source, value) = match *self {
+            AppError::Twim {
+                op: Op::Read,
+                error,
+                ..
+            } => (ErrorSource::TwimRead, error as u16),
+            AppError::Twim {
+                op: Op::Write,
+                error,
+                ..
+            } => (ErrorSource::TwimWrite, error as u16),
+            AppError::Twis { error, .. } => (ErrorSource::TwisDma, error as u16),
+            AppError::Spis(error) => (ErrorSource::SpisDma, error as u16),
+            AppError::Protocol(error) => (ErrorSource::Protocol, error.code()),
+            AppError::Config(error) => (ErrorSource::Config, error as u16),
+            AppError::Store(error) => (ErrorSource::Store, error as u16),
+            AppError::Internal(InternalError::SpawnFailed(task)) => {
+                (ErrorSource::Spawn, task as u16)
+            }
+            AppError::Internal(InternalError::Brownout) => (ErrorSource::Brownout, 0),
+        };
+        tracebuf::record(Event::Error, source as u8, value);
+        events::push(Kind::Error, source as u8, value);
+        signal::raise(Signal::Error);
+    }
+}
+
+impl ProtocolError {
+    /// Kind in the high byte, detail in the low byte.
+    pub fn code(&self) -> u16 {
+        match *self {
+            ProtocolError::BadCrc => 0x0100,
+            ProtocolError::BadLength { len, .. } => 0x0200 | len.min(0xff) as u16,
+            ProtocolError::UnknownOpcode(opcode) => 0x0300 | opcode as u16,
+            ProtocolError::InvalidArgument(opcode) => 0x0400 | opcode as u16,
+            ProtocolError::Malformed => 0x0500,
+            ProtocolError::Version(version) => 0x0600 | version as u16,
+            ProtocolError::Locked(opcode) => 0x0800 | opcode as u16,
+            ProtocolError::Unauthenticated => 0x0900,
+            ProtocolError::NotArmed(opcode) => 0x0a00 | opcode as u16,
+        }
+    }
+}
+
+impl From<ProtocolError> for AppError {
+    fn from(error: ProtocolError) -> Self {
+        AppError::Protocol(error)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Read => "read",
+            Op::Write => "write",
+        })
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Twim { op, address, error } => {
+                write!(f, "TWIM {} at {:#04x} failed: {:?}", op, address, error)
+            }
+            AppError::Twis { op, error } => write!(f, "TWIS {} DMA failed: {:?}", op, error),
+            AppError::Spis(error) => write!(f, "SPIS DMA failed: {:?}", error),
+            AppError::Protocol(error) => write!(f, "protocol error: {}", error),
+            AppError::Config(error) => write!(f, "config error: {}", error),
+            AppError::Store(error) => write!(f, "flash store error: {}", error),
+            AppError::Internal(InternalError::SpawnFailed(task)) => {
+                write!(f, "internal error: {} already pending", task.name())
+            }
+            AppError::Internal(InternalError::Brownout) => {
+                f.write_str("internal error: refused while the supply is low")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadCrc => f.write_str("bad CRC"),
+            ProtocolError::BadLength { len, max } => {
+                write!(f, "bad length, {} bytes for a {} byte buffer", len, max)
+            }
+            ProtocolError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            ProtocolError::InvalidArgument(opcode) => {
+                write!(f, "invalid argument for opcode {:#04x}", opcode)
+            }
+            ProtocolError::Malformed => f.write_str("malformed message"),
+            ProtocolError::Version(version) => write!(f, "unsupported message version {}", version),
+            ProtocolError::Locked(opcode) => write!(f, "opcode {:#04x} locked", opcode),
+            ProtocolError::Unauthenticated => f.write_str("frame not authenticated"),
+            ProtocolError::NotArmed(opcode) => write!(f, "opcode {:#04x} not armed", opcode),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Events for the controller, drained from the EVENT register.
+//
+// Things that happen between two polls are queued as 8-byte records,
+// oldest first, little-endian:
+//
+//   | kind: u8 | arg: u8 | value: u16 | ms: u32 |
+//
+// `ms` is the low 32 bits of the `wallclock` time, the time since boot
+// until the controller sets it, so it lines up with TIME. A READ starting
+// at EVENT returns the queued records back to back and zeroes after them,
+// and removes the ones it moved whole, so a READ of any multiple of 8
+// bytes drains that many. EVENT_COUNT has the number still queued.
+//
+// When the queue is full further events are dropped and counted; the first
+// record queued once there is room again is a `Kind::Overflow` one with
+// that count, so the controller knows what it missed.
+
+use {
+    crate::{lock, wallclock},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// Number of records kept.
+pub const CAPACITY: usize = 32;
+
+pub const RECORD_LEN: usize = 8;
+
+/// The `kind` byte of a record, 0 for none.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Kind {
+    /// The button was pressed.
+    Button = 0x01,
+    /// An error was recorded. `arg`: `tracebuf::ErrorSource`, `value`: its
+    /// code, as in the event trace.
+    Error = 0x02,
+    /// `status` flags were set. `arg`: the flags.
+    Status = 0x03,
+    /// Events were dropped before this one. `value`: how many, saturating.
+    Overflow = 0x04,
+}
+
+struct Queue {
+    records: [[u8; RECORD_LEN]; CAPACITY],
+    // Index of the oldest record.
+    tail: usize,
+    len: usize,
+    lost: u16,
+}
+
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue {
+    records: [[0; RECORD_LEN]; CAPACITY],
+    tail: 0,
+    len: 0,
+    lost: 0,
+}));
+
+impl Queue {
+    fn push(&mut self, record: [u8; RECORD_LEN]) -> bool {
+        if self.len == CAPACITY {
+            return false;
+        }
+        self.records[(self.tail + self.len) % CAPACITY] = record;
+        self.len += 1;
+        true
+    }
+}
+
+fn record(kind: Kind, arg: u8, value: u16, ms: u32) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    record[0] = kind as u8;
+    record[1] = arg;
+    record[2..4].copy_from_slice(&value.to_le_bytes());
+    record[4..8].copy_from_slice(&ms.to_le_bytes());
+    record
+}
+
+/// Queues an event, or counts it as lost if the queue is full.
+pub fn push(kind: Kind, arg: u8, value: u16) {
+    let ms = wallclock::now_ms() as u32;
+    lock::free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        if queue.lost > 0 {
+            let lost = queue.lost;
+            if !queue.push(record(Kind::Overflow, 0, lost, ms)) {
+                queue.lost = lost.saturating_add(1);
+                return;
+            }
+            queue.lost = 0;
+        }
+        if !queue.push(record(kind, arg, value, ms)) {
+            queue.lost = queue.lost.saturating_add(1);
+        }
+    });
+}
+
+/// The EVENT_COUNT register.
+pub fn count() -> u8 {
+    lock::free(|cs| QUEUE.borrow(cs).borrow().len as u8)
+}
+
+/// Fills `buf` with the queued records without removing them.
+pub fn peek(buf: &mut [u8]) {
+    lock::free(|cs| {
+        let queue = QUEUE.borrow(cs).borrow();
+        for (i, chunk) in buf.chunks_mut(RECORD_LEN).enumerate() {
+            if i < queue.len {
+                let record = &queue.records[(queue.tail + i) % CAPACITY];
+                chunk.copy_from_slice(&record[..chunk.len()]);
+            } else {
+                chunk.fill(0);
+            }
+        }
+    });
+}
+
+/// Removes the records of the `count` bytes the controller has read that
+/// were moved whole.
+pub fn consume(count: usize) {
+    lock::free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        let records = (count / RECORD_LEN).min(queue.len);
+        queue.tail = (queue.tail + records) % CAPACITY;
+        queue.len -= records;
+    });
+}
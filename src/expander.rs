@@ -0,0 +1,145 @@
+// PCF8574-style GPIO expander (`gpio-expander` feature).
+//
+// TWIS answers at `ADDRESS` as well, next to the register map, and speaks
+// the PCF8574 protocol there: no registers, a WRITE sets the 8-bit port
+// from its last byte and a READ returns the port pin levels in every byte.
+// The port is P1.01-P1.08 (bit 0 is P1.01), quasi-bidirectional like the
+// PCF8574's: a 0 drives the pin low, a 1 releases it to a pull-up so it can
+// be read as an input. The port starts with all pins released.
+//
+// With the `config::EXPANDER_INT` flag, INT (P1.09, open drain, active low)
+// is asserted when the pin levels differ from the ones last read, and
+// released by the next READ or WRITE. Changes are caught by the GPIO SENSE
+// mechanism: every port pin senses the opposite of its level, so any change
+// raises the GPIOTE PORT event, which `on_gpiote` shares with the button.
+
+use {
+    crate::board,
+    core::sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+/// The PCF8574 with A2..A0 tied low.
+pub const ADDRESS: u8 = 0x20;
+
+const MASK: u32 = 0xff << board::EXPANDER_PORT;
+
+static INT_ENABLED: AtomicBool = AtomicBool::new(false);
+// Pin levels as of the last READ or WRITE.
+static LAST: AtomicU8 = AtomicU8::new(0xff);
+
+// Only the expander pins, which `init` took over, and their LATCH bits are
+// written.
+fn p1() -> &'static crate::hal::pac::p0::RegisterBlock {
+    board::port(board::p1(0)).0
+}
+
+/// Configures the port and INT pins, with interrupt on change if
+/// `interrupt` is set.
+pub fn init(interrupt: bool) {
+    let p1 = p1();
+    p1.outset
+        .write(|w| unsafe { w.bits(MASK | 1 << board::EXPANDER_INT) });
+    for pin in board::EXPANDER_PORT..board::EXPANDER_PORT + 8 {
+        p1.pin_cnf[pin].write(|w| {
+            w.dir()
+                .output()
+                .input()
+                .connect()
+                .pull()
+                .pullup()
+                .drive()
+                .s0d1()
+        });
+    }
+    p1.pin_cnf[board::EXPANDER_INT].write(|w| w.dir().output().input().disconnect().drive().s0d1());
+    INT_ENABLED.store(interrupt, Ordering::Relaxed);
+    LAST.store(levels(), Ordering::Relaxed);
+    arm();
+    info!(
+        "GPIO expander at {:#04x}, INT {}",
+        ADDRESS,
+        if interrupt { "on" } else { "off" }
+    );
+}
+
+fn levels() -> u8 {
+    (p1().in_.read().bits() >> board::EXPANDER_PORT) as u8
+}
+
+// Lets every port pin sense a change from its current level.
+fn arm() {
+    if !INT_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let p1 = p1();
+    let levels = levels();
+    for bit in 0..8 {
+        p1.pin_cnf[board::EXPANDER_PORT + bit].modify(|_, w| {
+            if levels & 1 << bit != 0 {
+                w.sense().low()
+            } else {
+                w.sense().high()
+            }
+        });
+    }
+}
+
+fn set_int(asserted: bool) {
+    let bit = 1 << board::EXPANDER_INT;
+    if asserted {
+        p1().outclr.write(|w| unsafe { w.bits(bit) });
+    } else {
+        p1().outset.write(|w| unsafe { w.bits(bit) });
+    }
+}
+
+/// Pin levels for a READ; releases INT.
+pub fn read() -> u8 {
+    let levels = levels();
+    LAST.store(levels, Ordering::Relaxed);
+    set_int(false);
+    levels
+}
+
+/// Sets the port from a WRITE; releases INT.
+pub fn write(value: u8) {
+    let p1 = p1();
+    let value = (value as u32) << board::EXPANDER_PORT;
+    p1.outset.write(|w| unsafe { w.bits(value) });
+    p1.outclr.write(|w| unsafe { w.bits(!value & MASK) });
+    LAST.store(levels(), Ordering::Relaxed);
+    set_int(false);
+    arm();
+}
+
+/// Handles the expander side of the PORT event. Returns true if a port pin
+/// triggered it.
+pub fn on_port_event() -> bool {
+    if !INT_ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let p1 = p1();
+    let latched = p1.latch.read().bits() & MASK;
+    if latched == 0 {
+        return false;
+    }
+    // Re-armed first: without a sense condition met any more, the LATCH
+    // bits clear and DETECT can rise again on the next change.
+    arm();
+    p1.latch.write(|w| unsafe { w.bits(latched) });
+    let levels = levels();
+    if levels != LAST.load(Ordering::Relaxed) {
+        set_int(true);
+        trace!("expander inputs {:#010b}", levels);
+    }
+    true
+}
+
+/// Stops sensing, so port pins do not wake the chip from System OFF.
+pub fn disarm() {
+    let p1 = p1();
+    for pin in board::EXPANDER_PORT..board::EXPANDER_PORT + 8 {
+        p1.pin_cnf[pin].modify(|_, w| w.sense().disabled());
+    }
+    p1.latch.write(|w| unsafe { w.bits(MASK) });
+}
@@ -0,0 +1,177 @@
+// Hex-dump formatting for logged buffers.
+//
+//   0000: 48 65 6c 6c 6f 00 01 02  Hello...
+//
+// One line per `width` bytes: offset, hex bytes, then the printable ASCII
+// characters with everything else shown as `.`. The line width is shared by
+// all dumps and can be changed at runtime with the `hexwidth` console command.
+//
+// Transfer payloads are logged through `Payload`, which honours the `dump`
+// mode: the full dump, only the first N bytes, or just length and CRC-32 so
+// high-rate runs are not throttled by log output.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+pub const MAX_WIDTH: usize = 64;
+
+static WIDTH: AtomicUsize = AtomicUsize::new(16);
+
+/// Returns the number of bytes per line.
+pub fn width() -> usize {
+    WIDTH.load(Ordering::Relaxed)
+}
+
+/// Sets the number of bytes per line, clamped to `1..=MAX_WIDTH`.
+pub fn set_width(width: usize) {
+    WIDTH.store(width.clamp(1, MAX_WIDTH), Ordering::Relaxed);
+}
+
+/// How much of each transfer payload is logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpMode {
+    Full,
+    /// Only the first N bytes, plus the total length.
+    Head(usize),
+    /// Only the length and the CRC-32 of the payload.
+    Checksum,
+}
+
+// 0 = full, 1 = head, 2 = checksum; `HEAD_LEN` holds N for head.
+static MODE: AtomicU8 = AtomicU8::new(0);
+static HEAD_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn mode() -> DumpMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => DumpMode::Head(HEAD_LEN.load(Ordering::Relaxed)),
+        2 => DumpMode::Checksum,
+        _ => DumpMode::Full,
+    }
+}
+
+pub fn set_mode(mode: DumpMode) {
+    let code = match mode {
+        DumpMode::Full => 0,
+        DumpMode::Head(n) => {
+            HEAD_LEN.store(n, Ordering::Relaxed);
+            1
+        }
+        DumpMode::Checksum => 2,
+    };
+    MODE.store(code, Ordering::Relaxed);
+}
+
+/// A transfer payload, formatted according to the current `DumpMode`.
+pub struct Payload<'a>(pub &'a [u8]);
+
+impl fmt::Display for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = self.0;
+        match mode() {
+            DumpMode::Full => HexDump::new(data).fmt(f),
+            DumpMode::Head(n) if n < data.len() => {
+                if n > 0 {
+                    writeln!(f, "{}", HexDump::new(&data[..n]))?;
+                }
+                write!(f, "... {} bytes total", data.len())
+            }
+            DumpMode::Head(_) => HexDump::new(data).fmt(f),
+            DumpMode::Checksum => write!(f, "{} bytes, crc32 {:08x}", data.len(), crc32(data)),
+        }
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib), bitwise since payloads are short.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// CRC-16/CCITT-FALSE, `binascii.crc_hqx(data, 0xffff)` in Python.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+pub struct HexDump<'a> {
+    data: &'a [u8],
+    width: usize,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        HexDump {
+            data,
+            width: width(),
+        }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.data.is_empty() {
+            return f.write_str("(empty)");
+        }
+        for (i, line) in self.data.chunks(self.width).enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:04x}:", i * self.width)?;
+            for byte in line {
+                write!(f, " {:02x}", byte)?;
+            }
+            // Pad short last lines so the ASCII column stays aligned.
+            for _ in line.len()..self.width {
+                f.write_str("   ")?;
+            }
+            f.write_str("  ")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, the SMBus PEC.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,49 @@
+// Device identity from FICR, for the DEVICE_ID and DEVICE_ADDR registers
+// and the SMBus UDID.
+//
+// Both are programmed by Nordic and unique per chip: DEVICEID is a 64-bit
+// random id, DEVICEADDR the 48-bit (random static) BLE address. With several
+// of these boards on one bus, a controller reads them to tell which board
+// answers at an address.
+
+use crate::hal::pac::FICR;
+
+pub const ADDR_LEN: usize = 6;
+
+fn ficr() -> &'static crate::hal::pac::ficr::RegisterBlock {
+    // SAFETY: read-only, FICR is factory programmed flash.
+    unsafe { &*FICR::ptr() }
+}
+
+/// DEVICEID, little-endian.
+pub fn device_id() -> [u8; 8] {
+    let id = &ficr().deviceid;
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&id[0].read().bits().to_le_bytes());
+    bytes[4..].copy_from_slice(&id[1].read().bits().to_le_bytes());
+    bytes
+}
+
+/// INFO.PART, the part number, e.g. 0x52840.
+pub fn part() -> u32 {
+    ficr().info.part.read().bits()
+}
+
+/// DEVICEADDR, little-endian.
+pub fn device_addr() -> [u8; ADDR_LEN] {
+    let addr = &ficr().deviceaddr;
+    let mut bytes = [0; ADDR_LEN];
+    bytes[..4].copy_from_slice(&addr[0].read().bits().to_le_bytes());
+    bytes[4..].copy_from_slice(&addr[1].read().bits().to_le_bytes()[..2]);
+    bytes
+}
+
+/// NFC-A UID from the NFC tag header: Nordic's manufacturer id, then 6
+/// unique bytes.
+pub fn nfc_uid() -> [u8; 7] {
+    let header0 = ficr().nfc.tagheader0.read().bits().to_le_bytes();
+    let header1 = ficr().nfc.tagheader1.read().bits().to_le_bytes();
+    [
+        header0[0], header0[1], header0[2], header0[3], header1[0], header1[1], header1[2],
+    ]
+}
@@ -0,0 +1,173 @@
+// A TI INA219 power monitor at TWIS address 1 (`ina219` feature).
+//
+// The register set of the real part, big-endian 16-bit each:
+//
+//   0x00  configuration  rw  RST, BRNG, PG, BADC and SADC (kept), MODE
+//   0x01  shunt voltage  r   10 uV per LSB
+//   0x02  bus voltage    r   bits 3-15 in 4 mV, CNVR (bit 1), OVF (bit 0)
+//   0x03  power          r   current * bus voltage / 5000
+//   0x04  current        r   shunt voltage * calibration / 4096
+//   0x05  calibration    rw  0 at reset, so current and power read 0
+//
+// A WRITE of the pointer alone selects the register a READ returns, one of
+// the pointer and two bytes writes it as well. As on the part the pointer
+// does not move; a READ longer than 2 bytes repeats the register.
+//
+// The voltages are measured with the SAADC: the shunt across AIN6 (P0.30,
+// IN+) and AIN7 (P0.31, IN-), differential at +-150 mV, and the bus
+// voltage at `BUS_INPUT`, the board's own VDD unless changed. `sample`
+// takes them every `PERIOD_MS` in the continuous modes, and once after a
+// WRITE of a triggered mode, then sets CNVR, cleared by reading power. A
+// shunt voltage beyond the PGA range clamps and sets OVF; modes 0 and 4
+// stop the conversions.
+
+use {
+    crate::{lock, saadc},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// A0 and A1 tied low.
+pub const ADDRESS: u8 = 0x40;
+pub const PERIOD_MS: u64 = 50;
+/// SAADC input of the bus voltage, 8 for VDD.
+pub const BUS_INPUT: u8 = 8;
+
+const SHUNT_POSITIVE: u8 = 6;
+const SHUNT_NEGATIVE: u8 = 7;
+
+const CONFIGURATION: u8 = 0x00;
+const SHUNT_VOLTAGE: u8 = 0x01;
+const BUS_VOLTAGE: u8 = 0x02;
+const POWER: u8 = 0x03;
+const CURRENT: u8 = 0x04;
+const CALIBRATION: u8 = 0x05;
+
+const CONFIG_RESET: u16 = 0x399f;
+const RST: u16 = 1 << 15;
+const CNVR: u16 = 1 << 1;
+const OVF: u16 = 1 << 0;
+
+struct Ina219 {
+    pointer: u8,
+    config: u16,
+    calibration: u16,
+    // Shunt voltage in 10 uV.
+    shunt: i16,
+    // Bus voltage in mV.
+    bus_mv: u16,
+    flags: u16,
+    // A triggered conversion is due.
+    triggered: bool,
+}
+
+impl Ina219 {
+    const RESET: Self = Self {
+        pointer: 0,
+        config: CONFIG_RESET,
+        calibration: 0,
+        shunt: 0,
+        bus_mv: 0,
+        flags: 0,
+        triggered: false,
+    };
+
+    fn current(&self) -> i16 {
+        (self.shunt as i32 * self.calibration as i32 / 4096) as i16
+    }
+
+    fn register(&mut self, pointer: u8) -> u16 {
+        match pointer {
+            CONFIGURATION => self.config,
+            SHUNT_VOLTAGE => self.shunt as u16,
+            BUS_VOLTAGE => (self.bus_mv / 4) << 3 | self.flags,
+            POWER => {
+                self.flags &= !CNVR;
+                let bus = (self.bus_mv / 4) as i32;
+                (self.current() as i32 * bus / 5000).unsigned_abs() as u16
+            }
+            CURRENT => self.current() as u16,
+            CALIBRATION => self.calibration,
+            _ => 0,
+        }
+    }
+}
+
+static INA219: Mutex<RefCell<Ina219>> = Mutex::new(RefCell::new(Ina219::RESET));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "ina219")
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    lock::free(|cs| {
+        let mut ina = INA219.borrow(cs).borrow_mut();
+        match *data {
+            [pointer] => ina.pointer = pointer,
+            [CONFIGURATION, high, low, ..] => {
+                ina.pointer = CONFIGURATION;
+                let value = u16::from_be_bytes([high, low]);
+                if value & RST != 0 {
+                    *ina = Ina219::RESET;
+                    return;
+                }
+                ina.config = value;
+                ina.flags &= !CNVR;
+                ina.triggered = matches!(value & 0b111, 1..=3);
+            }
+            [CALIBRATION, high, low, ..] => {
+                ina.pointer = CALIBRATION;
+                // Bit 0 is not used.
+                ina.calibration = u16::from_be_bytes([high, low]) & !1;
+            }
+            [pointer, ..] => ina.pointer = pointer,
+            [] => {}
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`: the selected register, repeated.
+pub fn read(buf: &mut [u8]) {
+    let value = lock::free(|cs| {
+        let mut ina = INA219.borrow(cs).borrow_mut();
+        let pointer = ina.pointer;
+        ina.register(pointer)
+    });
+    for (byte, value) in buf.iter_mut().zip(value.to_be_bytes().iter().cycle()) {
+        *byte = *value;
+    }
+}
+
+/// Takes a conversion if one is due, every `PERIOD_MS`.
+pub fn sample() {
+    let due = lock::free(|cs| {
+        let ina = INA219.borrow(cs).borrow();
+        matches!(ina.config & 0b111, 5..=7) || ina.triggered
+    });
+    if !due {
+        return;
+    }
+    // A SAMPLE run in progress delays it to the next period.
+    let (Some(shunt), Some(bus)) = (
+        saadc::convert(SHUNT_POSITIVE, Some(SHUNT_NEGATIVE)),
+        saadc::convert(BUS_INPUT, None),
+    ) else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut ina = INA219.borrow(cs).borrow_mut();
+        // 150 mV in 8192, in 10 uV.
+        let shunt = shunt as i32 * 15_000 / 8192;
+        // PG: +-40 mV times 1, 2, 4 or 8.
+        let range = 4000 << (ina.config >> 11 & 0b11);
+        ina.flags = CNVR;
+        if shunt.abs() > range {
+            ina.flags |= OVF;
+        }
+        ina.shunt = shunt.clamp(-range, range) as i16;
+        ina.bus_mv = (bus.max(0) as u32 * 3600 / 4096) as u16;
+        ina.triggered = false;
+    });
+}
@@ -0,0 +1,156 @@
+// The IPMI commands a BMC on `ssif` answers.
+//
+//   App      0x06  0x01  Get Device ID
+//                  0x04  Get Self Test Results      0x55, all passed
+//                  0x57  Get System Interface Capabilities, SSIF only
+//   Storage  0x0a  0x10  Get FRU Inventory Area Info
+//                  0x11  Read FRU Data
+//
+// FRU device 0 holds a common header and a board info area built from
+// `build_info` and FICR: manufacturer, product name, DEVICEID as the serial
+// number and the part number, so `ipmitool fru print` has something to
+// show. It is longer than one SSIF block, so reading it takes multi-part
+// reads. Anything else is answered with completion code 0xc1.
+
+use crate::{build_info, identity, ssif::MSG_LEN};
+
+const NETFN_APP: u8 = 0x06;
+const NETFN_STORAGE: u8 = 0x0a;
+
+const GET_DEVICE_ID: u8 = 0x01;
+const GET_SELF_TEST_RESULTS: u8 = 0x04;
+const GET_SYSTEM_INTERFACE_CAPABILITIES: u8 = 0x57;
+const GET_FRU_INVENTORY_AREA_INFO: u8 = 0x10;
+const READ_FRU_DATA: u8 = 0x11;
+
+// Completion codes.
+const OK: u8 = 0x00;
+const INVALID_COMMAND: u8 = 0xc1;
+const BAD_LENGTH: u8 = 0xc7;
+const OUT_OF_RANGE: u8 = 0xc9;
+const INVALID_FIELD: u8 = 0xcc;
+const NOT_PRESENT: u8 = 0xcb;
+
+const FRU_LEN: usize = 96;
+
+/// Carries out `request` and writes the response into `response`, returns
+/// its length, 0 for a request too short to answer.
+pub fn handle(request: &[u8], response: &mut [u8; MSG_LEN]) -> usize {
+    let [netfn_lun, command, data @ ..] = request else {
+        return 0;
+    };
+    let (netfn, lun) = (netfn_lun >> 2, netfn_lun & 3);
+    response[0] = (netfn | 1) << 2 | lun;
+    response[1] = *command;
+    let body = &mut response[3..];
+    let (code, len) = match (netfn, *command, data) {
+        (NETFN_APP, GET_DEVICE_ID, []) => {
+            let [major, minor] = version();
+            let part = identity::part() as u16;
+            let id = [
+                0x20, // device ID
+                0x01, // device revision, no SDRs
+                major,
+                minor,
+                0x02,       // IPMI 2.0
+                0b000_1000, // FRU inventory device
+                0x00,
+                0x00,
+                0x00, // manufacturer ID, unspecified
+                part as u8,
+                (part >> 8) as u8,
+            ];
+            body[..id.len()].copy_from_slice(&id);
+            (OK, id.len())
+        }
+        (NETFN_APP, GET_SELF_TEST_RESULTS, []) => {
+            body[..2].copy_from_slice(&[0x55, 0x00]);
+            (OK, 2)
+        }
+        (NETFN_APP, GET_SYSTEM_INTERFACE_CAPABILITIES, [0]) => {
+            // Multi-part reads and writes with middle parts, PEC, SSIF 1.0.
+            body[..4].copy_from_slice(&[0x00, 0b1000_1000, MSG_LEN as u8, MSG_LEN as u8]);
+            (OK, 4)
+        }
+        (NETFN_APP, GET_SYSTEM_INTERFACE_CAPABILITIES, [_]) => (INVALID_FIELD, 0),
+        (NETFN_STORAGE, GET_FRU_INVENTORY_AREA_INFO, [0]) => {
+            body[..3].copy_from_slice(&[FRU_LEN as u8, 0, 0]);
+            (OK, 3)
+        }
+        (NETFN_STORAGE, READ_FRU_DATA, [0, lo, hi, count]) => {
+            let offset = u16::from_le_bytes([*lo, *hi]) as usize;
+            let count = (*count as usize).min(MSG_LEN - 4);
+            match fru().get(offset..) {
+                Some(rest) => {
+                    let count = count.min(rest.len());
+                    body[0] = count as u8;
+                    body[1..][..count].copy_from_slice(&rest[..count]);
+                    (OK, 1 + count)
+                }
+                None => (OUT_OF_RANGE, 0),
+            }
+        }
+        (NETFN_STORAGE, GET_FRU_INVENTORY_AREA_INFO, [_])
+        | (NETFN_STORAGE, READ_FRU_DATA, [_, _, _, _]) => (NOT_PRESENT, 0),
+        (NETFN_APP, GET_DEVICE_ID | GET_SELF_TEST_RESULTS, _)
+        | (NETFN_APP, GET_SYSTEM_INTERFACE_CAPABILITIES, _)
+        | (NETFN_STORAGE, GET_FRU_INVENTORY_AREA_INFO | READ_FRU_DATA, _) => (BAD_LENGTH, 0),
+        _ => (INVALID_COMMAND, 0),
+    };
+    response[2] = code;
+    3 + len
+}
+
+// The crate version as firmware revisions: major, then minor in BCD.
+fn version() -> [u8; 2] {
+    let mut parts = build_info::VERSION
+        .split('.')
+        .map(|part| part.parse::<u8>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0) & 0x7f;
+    let minor = parts.next().unwrap_or(0).min(99);
+    [major, (minor / 10) << 4 | (minor % 10)]
+}
+
+// The FRU data: common header, then the board info area at offset 8.
+fn fru() -> [u8; FRU_LEN] {
+    let mut fru = [0; FRU_LEN];
+    fru[..8].copy_from_slice(&[0x01, 0, 0, 1, 0, 0, 0, 0]);
+    fru[7] = checksum(&fru[..7]);
+    let mut serial = [0; 16];
+    for (i, byte) in identity::device_id().iter().rev().enumerate() {
+        serial[2 * i] = hex(byte >> 4);
+        serial[2 * i + 1] = hex(byte & 0xf);
+    }
+    // Version, length in 8 bytes (filled in below), English, no date.
+    let mut area = [0; FRU_LEN - 8];
+    area[..6].copy_from_slice(&[0x01, 0, 0, 0, 0, 0]);
+    let mut len = 6;
+    for field in [
+        &b"Nordic Semiconductor"[..],
+        build_info::NAME.as_bytes(),
+        &serial,
+        b"nRF52840",
+        b"",
+    ] {
+        // Type 8-bit ASCII and the length.
+        area[len] = 0xc0 | field.len() as u8;
+        area[len + 1..][..field.len()].copy_from_slice(field);
+        len += 1 + field.len();
+    }
+    area[len] = 0xc1;
+    let len = (len + 2).next_multiple_of(8).min(area.len());
+    area[1] = (len / 8) as u8;
+    area[len - 1] = checksum(&area[..len - 1]);
+    fru[8..][..len].copy_from_slice(&area[..len]);
+    fru
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg()
+}
+
+fn hex(nibble: u8) -> u8 {
+    b"0123456789ABCDEF"[nibble as usize]
+}
@@ -0,0 +1,280 @@
+// Transaction journal in QSPI flash (`usb-msc` feature).
+//
+// Every `PERIOD_SECS` the `flush_journal` task appends the `tracebuf`
+// records logged since the last flush to the top `LEN` bytes of the QSPI
+// flash, so the trace of a board in the field survives resets and power
+// loss; `usbdisk` serves it as a file. The region is a ring of 4 KB
+// sectors, oldest overwritten once all are in use:
+//
+//   | magic: u32 | seq: u32 | records: [[u8; RECORD_LEN]; PER_SECTOR] |
+//
+// `seq` counts the sectors ever opened, so at boot the newest is the one
+// with the highest `seq` and the oldest the start of the consecutive run
+// before it. Unwritten records read as 0xff; their event byte never is.
+// Records keep the `tracebuf` layout, timestamps restarting at each boot,
+// which a `JournalOpen` record marks; `TimeSync` records tie them to the
+// controller's time.
+//
+// The QSPI is shared with the flash requests, so each step only starts an
+// operation and `flush_journal` comes back once it is done. Reads go
+// through XIP, which only works while no operation is running.
+
+use {
+    crate::{
+        lock, qspiflash,
+        tracebuf::{self, Event, RECORD_LEN},
+    },
+    core::{
+        cell::RefCell,
+        ptr::{addr_of, addr_of_mut},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// Bytes of flash at the top taken by the journal.
+pub const LEN: u32 = 64 << 10;
+/// Start of the journal in the flash.
+pub const BASE: u32 = qspiflash::SIZE - LEN;
+/// How often new records are written.
+pub const PERIOD_SECS: u64 = 10;
+/// How soon `flush_journal` checks again on a running operation.
+pub const POLL_MS: u32 = 2;
+
+const SECTOR_LEN: u32 = 4 << 10;
+const SECTORS: u32 = LEN / SECTOR_LEN;
+const HEADER_LEN: u32 = 8;
+const PER_SECTOR: u32 = (SECTOR_LEN - HEADER_LEN) / RECORD_LEN as u32;
+/// Most bytes the journal holds.
+pub const CAPACITY: u32 = SECTORS * PER_SECTOR * RECORD_LEN as u32;
+// "JRNL".
+const MAGIC: u32 = 0x4c4e_524a;
+// Where XIP maps the flash.
+const XIP: u32 = 0x1200_0000;
+// Records per program, the QSPI page must not be crossed.
+const BATCH: usize = 4;
+const PAGE_LEN: u32 = 256;
+
+// What the running operation was started for.
+#[derive(Clone, Copy)]
+enum Phase {
+    Idle,
+    Erasing { sector: u32 },
+    Header { sector: u32 },
+    Records { first: u32, count: u32 },
+}
+
+struct Ring {
+    // Oldest sector and sectors in use, the newest last.
+    first: u32,
+    used: u32,
+    // `seq` of the newest sector and records in it.
+    seq: u32,
+    records: u32,
+    // `tracebuf` sequence number of the next record to write.
+    cursor: u32,
+    phase: Phase,
+}
+
+impl Ring {
+    fn newest(&self) -> u32 {
+        (self.first + self.used - 1) % SECTORS
+    }
+
+    fn len(&self) -> u32 {
+        match self.used {
+            0 => 0,
+            used => ((used - 1) * PER_SECTOR + self.records) * RECORD_LEN as u32,
+        }
+    }
+}
+
+static RING: Mutex<RefCell<Option<Ring>>> = Mutex::new(RefCell::new(None));
+
+// EasyDMA source of the running program, word aligned for the QSPI.
+#[repr(align(4))]
+struct Buffer([u8; BATCH * RECORD_LEN]);
+
+static mut BUFFER: Buffer = Buffer([0xff; BATCH * RECORD_LEN]);
+
+fn sector_address(sector: u32) -> u32 {
+    BASE + sector * SECTOR_LEN
+}
+
+// Reads a byte of the flash through XIP.
+fn xip(address: u32) -> u8 {
+    // SAFETY: the XIP region maps the whole flash once the QSPI is active,
+    // callers make sure no operation is running.
+    unsafe { core::ptr::read_volatile((XIP + address) as *const u8) }
+}
+
+fn xip_u32(address: u32) -> u32 {
+    u32::from_le_bytes([0, 1, 2, 3].map(|i| xip(address + i)))
+}
+
+fn busy() -> bool {
+    qspiflash::status() & qspiflash::BUSY != 0
+}
+
+/// Finds the newest sector and the end of its records. Needs the flash,
+/// call after `qspiflash::init`.
+pub fn init() {
+    if qspiflash::status() & qspiflash::PRESENT == 0 {
+        warn!("no QSPI flash, no journal");
+        return;
+    }
+    let seq = |sector: u32| {
+        let address = sector_address(sector);
+        (xip_u32(address) == MAGIC).then(|| xip_u32(address + 4))
+    };
+    let newest = (0..SECTORS)
+        .filter_map(|sector| seq(sector).map(|seq| (seq, sector)))
+        .max();
+    let mut ring = Ring {
+        first: 0,
+        used: 0,
+        seq: 0,
+        records: 0,
+        cursor: 0,
+        phase: Phase::Idle,
+    };
+    if let Some((newest_seq, newest)) = newest {
+        ring.seq = newest_seq;
+        ring.used = 1;
+        ring.first = newest;
+        while ring.used < SECTORS {
+            let previous = (ring.first + SECTORS - 1) % SECTORS;
+            if seq(previous) != Some(newest_seq.wrapping_sub(ring.used)) {
+                break;
+            }
+            ring.first = previous;
+            ring.used += 1;
+        }
+        let records = sector_address(newest) + HEADER_LEN;
+        ring.records = (0..PER_SECTOR)
+            .find(|&i| xip(records + i * RECORD_LEN as u32 + 4) == 0xff)
+            .unwrap_or(PER_SECTOR);
+    }
+    info!("journal: {} sectors, {} bytes", ring.used, ring.len());
+    tracebuf::record(Event::JournalOpen, 0, ring.used as u16);
+    lock::free(|cs| RING.borrow(cs).replace(Some(ring)));
+}
+
+/// Takes the next step of writing out new records, once the previous
+/// operation is done. Returns in how many ms to come back, `None` when
+/// everything is written.
+pub fn step() -> Option<u32> {
+    if busy() {
+        return Some(POLL_MS);
+    }
+    lock::free(|cs| {
+        let mut ring = RING.borrow(cs).borrow_mut();
+        let ring = ring.as_mut()?;
+        // The operation started last time has finished.
+        match ring.phase {
+            Phase::Idle => {}
+            Phase::Erasing { sector } => {
+                let header = [MAGIC, ring.seq.wrapping_add(1)];
+                // SAFETY: no operation is running, the QSPI does not read it.
+                let buffer = unsafe { &mut *addr_of_mut!(BUFFER) };
+                for (bytes, word) in buffer.0.chunks_exact_mut(4).zip(header) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+                // SAFETY: left alone until the next step, when it is done.
+                let header = &unsafe { &*addr_of!(BUFFER) }.0[..HEADER_LEN as usize];
+                if qspiflash::journal_program(sector_address(sector), header) {
+                    ring.phase = Phase::Header { sector };
+                }
+                return Some(POLL_MS);
+            }
+            Phase::Header { sector } => {
+                if ring.used == 0 {
+                    ring.first = sector;
+                }
+                ring.used += 1;
+                ring.seq = ring.seq.wrapping_add(1);
+                ring.records = 0;
+            }
+            Phase::Records { first, count } => {
+                ring.records += count;
+                ring.cursor = first.wrapping_add(count);
+            }
+        }
+        ring.phase = Phase::Idle;
+
+        let mut batch = [[0; RECORD_LEN]; BATCH];
+        let (first, count) = tracebuf::copy_since(ring.cursor, &mut batch);
+        if first != ring.cursor {
+            warn!(
+                "journal: {} records overwritten before they were saved",
+                first.wrapping_sub(ring.cursor)
+            );
+            ring.cursor = first;
+        }
+        if count == 0 {
+            return None;
+        }
+        if ring.used == 0 || ring.records == PER_SECTOR {
+            let sector = if ring.used == 0 {
+                0
+            } else {
+                (ring.newest() + 1) % SECTORS
+            };
+            if qspiflash::journal_erase(sector_address(sector)) {
+                if ring.used == SECTORS {
+                    // The oldest sector goes now, readers must not see it.
+                    ring.first = (ring.first + 1) % SECTORS;
+                    ring.used -= 1;
+                }
+                ring.phase = Phase::Erasing { sector };
+            }
+            return Some(POLL_MS);
+        }
+        let address = sector_address(ring.newest()) + HEADER_LEN + ring.records * RECORD_LEN as u32;
+        let fit = (PAGE_LEN - address % PAGE_LEN) / RECORD_LEN as u32;
+        let count = (count as u32).min(fit).min(PER_SECTOR - ring.records);
+        // SAFETY: no operation is running, the QSPI does not read it.
+        let buffer = unsafe { &mut *addr_of_mut!(BUFFER) };
+        for (bytes, record) in buffer.0.chunks_exact_mut(RECORD_LEN).zip(&batch) {
+            bytes.copy_from_slice(record);
+        }
+        let len = count as usize * RECORD_LEN;
+        // SAFETY: left alone until the next step, when it is done.
+        let data = &unsafe { &*addr_of!(BUFFER) }.0[..len];
+        if qspiflash::journal_program(address, data) {
+            ring.phase = Phase::Records { first, count };
+        }
+        Some(POLL_MS)
+    })
+}
+
+/// Bytes in the journal.
+pub fn len() -> u32 {
+    lock::free(|cs| RING.borrow(cs).borrow().as_ref().map_or(0, Ring::len))
+}
+
+/// Fills `buf` with the journal from byte `offset` on, oldest record first
+/// and 0 past the end. Returns false while the QSPI is busy, the flash
+/// cannot be read then.
+pub fn read(offset: u32, buf: &mut [u8]) -> bool {
+    lock::free(|cs| {
+        if busy() {
+            return false;
+        }
+        let ring = RING.borrow(cs).borrow();
+        let Some(ring) = ring.as_ref() else {
+            buf.fill(0);
+            return true;
+        };
+        let len = ring.len();
+        let sector_bytes = PER_SECTOR * RECORD_LEN as u32;
+        for (at, byte) in (offset..).zip(buf.iter_mut()) {
+            *byte = if at < len {
+                let sector = (ring.first + at / sector_bytes) % SECTORS;
+                xip(sector_address(sector) + HEADER_LEN + at % sector_bytes)
+            } else {
+                0
+            };
+        }
+        true
+    })
+}
@@ -0,0 +1,49 @@
+// The 128-bit key of `unlock` and `auth`.
+//
+// Set `TWIS_KEY` to 32 hex digits at build time to share one key between a
+// controller and any number of boards, e.g. `TWIS_KEY=000102...0f cargo
+// build`; a malformed one fails the build. Without it every chip uses its
+// encryption root ER from FICR, random and different on every chip, which
+// the host reads once over SWD: the 16 bytes at 0x10000080 in memory order.
+
+use crate::hal::pac::FICR;
+
+pub const LEN: usize = 16;
+
+const SHARED: Option<[u8; LEN]> = match option_env!("TWIS_KEY") {
+    Some(hex) => Some(parse(hex.as_bytes())),
+    None => None,
+};
+
+const fn parse(hex: &[u8]) -> [u8; LEN] {
+    assert!(hex.len() == 2 * LEN, "TWIS_KEY must be 32 hex digits");
+    let mut key = [0; LEN];
+    let mut i = 0;
+    while i < LEN {
+        key[i] = nibble(hex[2 * i]) << 4 | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+const fn nibble(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => panic!("TWIS_KEY must be 32 hex digits"),
+    }
+}
+
+pub fn get() -> [u8; LEN] {
+    if let Some(key) = SHARED {
+        return key;
+    }
+    // SAFETY: read-only, FICR is factory programmed flash.
+    let er = unsafe { &(*FICR::ptr()).er };
+    let mut key = [0; LEN];
+    for (word, chunk) in er.iter().zip(key.chunks_exact_mut(4)) {
+        chunk.copy_from_slice(&word.read().bits().to_le_bytes());
+    }
+    key
+}
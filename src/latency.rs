@@ -0,0 +1,56 @@
+// Histogram of the TWIS WRITE/READ to buffer-ready latency.
+//
+// `on_twis` records the DWT cycles from its entry to the moment the RX or TX
+// DMA transfer is armed, i.e. how long the controller is held in clock
+// stretching by the firmware. Buckets are powers of two in microseconds so
+// the tail stays visible next to the common case; `latency` prints them.
+
+use {
+    crate::profile,
+    core::sync::atomic::{AtomicU32, Ordering},
+};
+
+// Bucket 0 counts < 1 us, bucket i counts [2^(i-1), 2^i) us and the last
+// one everything above.
+const BUCKETS: usize = 12;
+
+static HISTOGRAM: [AtomicU32; BUCKETS] = [const { AtomicU32::new(0) }; BUCKETS];
+
+// Largest latency seen, in cycles.
+static WORST: AtomicU32 = AtomicU32::new(0);
+
+static SAMPLES: AtomicU32 = AtomicU32::new(0);
+
+/// Records the time from `start` (a `profile::now` value) until now.
+pub fn record(start: u32) {
+    let cycles = profile::now().wrapping_sub(start);
+    let us = profile::micros(cycles);
+    let bucket = ((u32::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+    HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+    WORST.fetch_max(cycles, Ordering::Relaxed);
+    SAMPLES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn print_report() {
+    println!(
+        "event-to-armed latency, {} samples, worst {} us",
+        SAMPLES.load(Ordering::Relaxed),
+        profile::micros(WORST.load(Ordering::Relaxed))
+    );
+    for (bucket, count) in HISTOGRAM.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        match bucket {
+            0 => println!("  {:>12} us {:>8}", "< 1", count),
+            b if b == BUCKETS - 1 => println!("  >= {:<9} us {:>8}", 1 << (b - 1), count),
+            b => println!("  {:>5}-{:<6} us {:>8}", 1 << (b - 1), 1 << b, count),
+        }
+    }
+}
+
+pub fn reset() {
+    for count in &HISTOGRAM {
+        count.store(0, Ordering::Relaxed);
+    }
+    WORST.store(0, Ordering::Relaxed);
+    SAMPLES.store(0, Ordering::Relaxed);
+}
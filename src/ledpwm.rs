@@ -0,0 +1,85 @@
+// Blue LED brightness and blink rate, set by the controller.
+//
+// LED_BRIGHTNESS (0 off .. 255 full) and LED_BLINK (half period in
+// `BLINK_STEP_MS` steps, 0 steady) are plain registers. A write only stores
+// the value and marks it changed; the bus handler then spawns `drive_led`,
+// which does the actual work at task level: it sets the PWM0 duty cycle and,
+// while blinking, reschedules itself for the next half period.
+//
+// PWM0 keeps the HFCLK running, so it is disabled while the LED is dark.
+
+use {
+    crate::hal::{
+        gpio::{Output, Pin, PushPull},
+        pac::PWM0,
+        pwm::{Channel, Prescaler, Pwm},
+    },
+    core::sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+pub const BLINK_STEP_MS: u32 = 50;
+
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(0);
+static BLINK: AtomicU8 = AtomicU8::new(0);
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub struct Led {
+    pwm: Pwm<PWM0>,
+}
+
+impl Led {
+    /// Drives the active-low `pin` from PWM0, dark until `show`.
+    pub fn new(pwm: PWM0, pin: Pin<Output<PushPull>>) -> Self {
+        let pwm = Pwm::new(pwm);
+        // 1 MHz / 255, ~3.9 kHz: one count per brightness step.
+        pwm.set_prescaler(Prescaler::Div16)
+            .set_max_duty(u8::MAX as u16)
+            .set_output_pin(Channel::C0, pin);
+        pwm.disable();
+        Led { pwm }
+    }
+
+    /// Lights the LED at `level`, 0 turns it and PWM0 off.
+    pub fn show(&self, level: u8) {
+        if level == 0 {
+            self.pwm.disable();
+            return;
+        }
+        self.pwm.enable();
+        // The pin is low, i.e. the LED lit, for `level` counts.
+        self.pwm.set_duty_off(Channel::C0, level as u16);
+    }
+}
+
+pub fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+pub fn blink() -> u8 {
+    BLINK.load(Ordering::Relaxed)
+}
+
+/// Half period of the blink in ms, `None` if the LED is steady.
+pub fn half_period_ms() -> Option<u32> {
+    match blink() {
+        0 => None,
+        steps => Some(steps as u32 * BLINK_STEP_MS),
+    }
+}
+
+pub fn set_brightness(value: u8) -> bool {
+    BRIGHTNESS.store(value, Ordering::Relaxed);
+    CHANGED.store(true, Ordering::Relaxed);
+    true
+}
+
+pub fn set_blink(value: u8) -> bool {
+    BLINK.store(value, Ordering::Relaxed);
+    CHANGED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Returns true once after either register was written.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::Relaxed)
+}
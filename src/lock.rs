@@ -0,0 +1,29 @@
+// Critical sections around the state shared with interrupt handlers.
+//
+// `free` is `interrupt::free` until the SoftDevice of `ble` runs. From then
+// on PRIMASK would hold off the SoftDevice's interrupts too, for as long as
+// a section lasts, and fault its calls; `free` masks the firmware's
+// interrupts in the NVIC instead, through the `critical_section` of
+// nrf-softdevice. That leaves the interrupts the SoftDevice reserves
+// enabled, among them POWER_CLOCK and RNG, but `softdevice::enable` has
+// masked the RTIC tasks bound to those for good by then, so nothing that
+// touches the state here can run inside a section either way.
+//
+// `power::wait_unless` and the panic path stay on PRIMASK.
+
+use cortex_m::interrupt::CriticalSection;
+
+/// Runs `f` with the firmware's interrupts held off, `interrupt::free`
+/// until the SoftDevice runs.
+#[inline]
+pub fn free<R>(f: impl FnOnce(&CriticalSection) -> R) -> R {
+    #[cfg(feature = "ble")]
+    if crate::softdevice::enabled() {
+        return critical_section::with(|_| {
+            // SAFETY: every interrupt whose handler takes a `Mutex` is
+            // masked until the section ends, see above.
+            f(unsafe { &CriticalSection::new() })
+        });
+    }
+    cortex_m::interrupt::free(f)
+}
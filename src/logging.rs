@@ -0,0 +1,359 @@
+// RTT channel setup and leveled logging.
+//
+// Up channel 0 ("Terminal") carries the human-readable log text written with
+// the `error!`/`warn!`/`info!`/`trace!` macros below. Down channel 0 carries
+// console commands from the host (see `console`). Up channel 1 ("Data")
+// carries raw binary records so host tools can consume payloads without
+// parsing log text.
+//
+// Every record on the data channel has the same layout:
+//
+//   | tag: u8 | len: u16 (LE) | payload: [u8; len] |
+//
+// A record is written with a single channel write in `NoBlockSkip` mode, so
+// the host either sees a complete record or nothing at all.
+//
+// Writes never block: without a host draining the buffers (a standalone
+// board) output that does not fit is dropped and counted in
+// `STATS.rtt_drops`. Log lines are formatted into a small buffer before the
+// channel is locked, then written in one piece, so a line is either shown
+// whole or not at all and the critical section only lasts for the copy.
+//
+// Without the `rtt` feature there are no channels: every log call and data
+// record compiles to nothing, the console never receives input, and the
+// firmware reports only through the statistics registers. The exception is
+// `usb-console`, which gets the log lines and console input either way.
+
+use {
+    crate::mono,
+    core::{
+        fmt,
+        sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+};
+
+#[cfg(feature = "rtt")]
+use {
+    crate::{lock, stats::STATS},
+    core::{cell::RefCell, fmt::Write},
+    cortex_m::interrupt::{self, Mutex},
+    rtt_target::{rtt_init, ChannelMode, UpChannel},
+};
+
+#[cfg(feature = "rtt")]
+pub use rtt_target::DownChannel;
+
+/// Stands in for the RTT console channel, never has input.
+#[cfg(not(feature = "rtt"))]
+pub struct DownChannel;
+
+#[cfg(not(feature = "rtt"))]
+impl DownChannel {
+    pub fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+}
+
+/// Logs at the given level if it is currently enabled, prefixed with the
+/// time since boot.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::$level) {
+            $crate::logging::write_line(format_args!(
+                "{} {}",
+                $crate::logging::Timestamp::now(),
+                format_args!($($arg)*)
+            ));
+        }
+    };
+}
+
+/// Prints a line without timestamp or level filtering, for console replies.
+macro_rules! println {
+    ($($arg:tt)*) => {
+        $crate::logging::write_line(format_args!($($arg)*))
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! error {
+    ($($arg:tt)*) => { log!(Error, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => { log!(Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!(Info, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { log!(Trace, $($arg)*) };
+}
+
+/// Largest payload carried by a single data record. Longer payloads are truncated.
+pub const MAX_RECORD_PAYLOAD: usize = 256;
+
+#[cfg(feature = "rtt")]
+const HEADER_LEN: usize = 3;
+
+// Log lines longer than this are cut short, and counted as dropped.
+#[cfg(feature = "rtt")]
+const LINE_BUF_LEN: usize = 256;
+
+#[cfg(feature = "rtt")]
+static TERMINAL_CHANNEL: Mutex<RefCell<Option<UpChannel>>> = Mutex::new(RefCell::new(None));
+#[cfg(feature = "rtt")]
+static DATA_CHANNEL: Mutex<RefCell<Option<UpChannel>>> = Mutex::new(RefCell::new(None));
+
+// Everything is logged by default, matching the demo's original output.
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+// Set by `burst` between transaction bursts.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Log verbosity, from least to most verbose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    /// Per-transfer buffer dumps and other high-rate output.
+    Trace = 3,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Trace,
+        }
+    }
+}
+
+/// Returns the active log level.
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Changes the active log level; takes effect for the next log call.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns true if messages at `level` are currently printed.
+pub fn enabled(level: Level) -> bool {
+    if !cfg!(any(feature = "rtt", feature = "usb-console")) {
+        return false;
+    }
+    if level > Level::Warn && QUIET.load(Ordering::Relaxed) {
+        return false;
+    }
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// While quiet, only warnings and errors are logged, whatever the level.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Milliseconds since boot according to the RTC monotonic, formatted as
+/// `[seconds.millis]`. Reads as zero until `init` has returned.
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let ticks = crate::app::monotonics::now().ticks();
+        Timestamp(ticks * 1000 / mono::TICK_HZ as u64)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>6}.{:03}]", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// Identifies the contents of a binary record on the data channel.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Tag {
+    /// Buffer received by the TWIS peripheral (controller WRITE).
+    TwisRx = 0x01,
+    /// Buffer transmitted by the TWIS peripheral (controller READ).
+    TwisTx = 0x02,
+    /// Buffer read by the TWIM controller.
+    TwimRx = 0x03,
+    /// Buffer written by the TWIM controller.
+    TwimTx = 0x04,
+    /// Buffer received by the SPIS peripheral (`spis` feature, write frame).
+    SpisRx = 0x05,
+    /// Buffer transmitted by the SPIS peripheral (read frame).
+    SpisTx = 0x06,
+    /// Chunk of trace ring records, see `tracebuf`.
+    Trace = 0x10,
+}
+
+/// Sets up the RTT control block, routes log output to channel 0 and keeps
+/// channel 1 around for binary records. Returns the console down channel.
+#[cfg(feature = "rtt")]
+pub fn init() -> DownChannel {
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024
+                mode: NoBlockSkip
+                name: "Terminal"
+            }
+            1: {
+                size: 4096
+                mode: NoBlockSkip
+                name: "Data"
+            }
+        }
+        down: {
+            0: {
+                size: 64
+                name: "Terminal"
+            }
+        }
+    };
+    lock::free(|cs| {
+        TERMINAL_CHANNEL.borrow(cs).replace(Some(channels.up.0));
+        DATA_CHANNEL.borrow(cs).replace(Some(channels.up.1));
+    });
+    channels.down.0
+}
+
+#[cfg(not(feature = "rtt"))]
+pub fn init() -> DownChannel {
+    DownChannel
+}
+
+// Hosts may switch a channel to `BlockIfFull` when they attach; with nobody
+// reading afterwards that would stall the caller forever.
+#[cfg(feature = "rtt")]
+fn ensure_non_blocking(channel: &mut UpChannel) {
+    if channel.mode() != ChannelMode::NoBlockSkip {
+        channel.set_mode(ChannelMode::NoBlockSkip);
+    }
+}
+
+// A log line with its newline, formatted outside any critical section.
+#[cfg(feature = "rtt")]
+struct Line {
+    buf: [u8; LINE_BUF_LEN],
+    len: usize,
+    cut: bool,
+}
+
+#[cfg(feature = "rtt")]
+impl Line {
+    fn format(args: fmt::Arguments) -> Self {
+        let mut line = Line {
+            buf: [0; LINE_BUF_LEN],
+            len: 0,
+            cut: false,
+        };
+        line.write_fmt(args).ok();
+        // The newline goes over the last byte of a full line.
+        line.len = line.len.min(LINE_BUF_LEN - 1);
+        line.buf[line.len] = b'\n';
+        line.len += 1;
+        line
+    }
+}
+
+#[cfg(feature = "rtt")]
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(LINE_BUF_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.cut |= n < s.len();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rtt")]
+fn write_line_to(channel: &mut UpChannel, line: &Line) -> bool {
+    ensure_non_blocking(channel);
+    channel.write(&line.buf[..line.len]) == line.len && !line.cut
+}
+
+/// Writes `args` and a newline to the terminal channel and the USB console.
+/// Use the macros.
+#[cfg(feature = "rtt")]
+pub fn write_line(args: fmt::Arguments) {
+    let line = Line::format(args);
+    lock::free(|cs| {
+        if let Some(channel) = TERMINAL_CHANNEL.borrow(cs).borrow_mut().as_mut() {
+            if !write_line_to(channel, &line) {
+                STATS.rtt_drops.inc();
+            }
+        }
+    });
+    crate::usbconsole::write_line(args);
+}
+
+#[cfg(not(feature = "rtt"))]
+pub fn write_line(args: fmt::Arguments) {
+    crate::usbconsole::write_line(args);
+}
+
+/// Prints the panic message. Only for the panic handler.
+#[cfg(feature = "rtt")]
+pub fn write_panic(args: fmt::Arguments) {
+    let line = Line::format(args);
+    interrupt::free(|cs| match TERMINAL_CHANNEL.borrow(cs).try_borrow_mut() {
+        Ok(mut channel) => {
+            if let Some(channel) = channel.as_mut() {
+                write_line_to(channel, &line);
+            }
+        }
+        // Panicked while logging. SAFETY: interrupts are disabled and the
+        // interrupted writer never resumes.
+        Err(_) => {
+            if let Some(mut channel) = unsafe { UpChannel::conjure(0) } {
+                write_line_to(&mut channel, &line);
+            }
+        }
+    });
+}
+
+/// Writes `payload` as a single tagged record to the data channel.
+#[cfg(feature = "rtt")]
+pub fn dump(tag: Tag, payload: &[u8]) {
+    let len = payload.len().min(MAX_RECORD_PAYLOAD);
+    let mut record = [0u8; HEADER_LEN + MAX_RECORD_PAYLOAD];
+    record[0] = tag as u8;
+    record[1..HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+    record[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&payload[..len]);
+
+    lock::free(|cs| {
+        if let Some(channel) = DATA_CHANNEL.borrow(cs).borrow_mut().as_mut() {
+            ensure_non_blocking(channel);
+            if channel.write(&record[..HEADER_LEN + len]) != HEADER_LEN + len {
+                STATS.rtt_drops.inc();
+            }
+        }
+    });
+}
+
+#[cfg(not(feature = "rtt"))]
+pub fn dump(_tag: Tag, _payload: &[u8]) {}
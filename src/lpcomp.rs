@@ -0,0 +1,85 @@
+// Analog wake source on the low-power comparator.
+//
+// LPCOMP compares AIN4 (P0.28) with a fraction of VDD and raises an event
+// when the input crosses it upwards. It runs on the LFCLK and keeps working
+// in System ON sleep and in System OFF, so a rising input wakes the chip
+// from either. Each crossing sets `status::ANALOG` for the controller.
+//
+// The threshold is set in sixteenths of VDD, 1..=15, through the register
+// map (`ANALOG_THRESHOLD`); 0 turns the comparator off. LPCOMP only takes a
+// new reference while disabled, so a change restarts it.
+
+use {
+    crate::{
+        hal::pac::LPCOMP,
+        status,
+        tracebuf::{self, Event},
+    },
+    core::sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Threshold at boot, in sixteenths of VDD: 1.5 V at 3 V.
+pub const DEFAULT_THRESHOLD: u8 = 8;
+
+static THRESHOLD: AtomicU8 = AtomicU8::new(0);
+
+fn lpcomp() -> &'static crate::hal::pac::lpcomp::RegisterBlock {
+    // SAFETY: LPCOMP is only accessed through this module, from `on_twis`
+    // and `on_lpcomp`, which run at the same priority.
+    unsafe { &*LPCOMP::ptr() }
+}
+
+/// Selects AIN4 and upward crossings, then starts the comparator at
+/// `DEFAULT_THRESHOLD`. Takes `LPCOMP` so nothing else configures it.
+pub fn init(lpcomp: LPCOMP) {
+    lpcomp.psel.write(|w| w.psel().analog_input4());
+    lpcomp.anadetect.write(|w| w.anadetect().up());
+    // 50 mV hysteresis, so a noisy input does not fire repeatedly.
+    lpcomp.hyst.write(|w| w.hyst().set_bit());
+    lpcomp.intenset.write(|w| w.up().set());
+    set_threshold(DEFAULT_THRESHOLD);
+}
+
+/// Current threshold in sixteenths of VDD, 0 if off.
+pub fn threshold() -> u8 {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Restarts the comparator at `sixteenths` of VDD, or stops it for 0.
+/// Returns false, changing nothing, for values above 15.
+pub fn set_threshold(sixteenths: u8) -> bool {
+    if sixteenths > 15 {
+        return false;
+    }
+    let lpcomp = lpcomp();
+    lpcomp.tasks_stop.write(|w| unsafe { w.bits(1) });
+    lpcomp.enable.write(|w| w.enable().disabled());
+    THRESHOLD.store(sixteenths, Ordering::Relaxed);
+    if sixteenths == 0 {
+        return true;
+    }
+    // Odd steps only exist as sixteenths (REFSEL 8..=15), even ones as
+    // eighths (REFSEL 0..=6).
+    let refsel = if sixteenths % 2 == 1 {
+        8 + sixteenths / 2
+    } else {
+        sixteenths / 2 - 1
+    };
+    lpcomp.refsel.write(|w| unsafe { w.bits(refsel as u32) });
+    lpcomp.enable.write(|w| w.enable().enabled());
+    lpcomp.events_up.reset();
+    lpcomp.tasks_start.write(|w| unsafe { w.bits(1) });
+    true
+}
+
+/// Handles an upward crossing, from the LPCOMP interrupt.
+pub fn on_interrupt() {
+    let lpcomp = lpcomp();
+    if lpcomp.events_up.read().bits() == 0 {
+        return;
+    }
+    lpcomp.events_up.reset();
+    status::set(status::ANALOG);
+    tracebuf::record(Event::Analog, 0, threshold() as u16);
+    info!("analog input above {}/16 VDD", threshold());
+}
@@ -4,165 +4,1954 @@
 // Demo of using non-blocking DMA transactions with the
 // TWIS (Two Wire Interface/I2C in peripheral mode) module.
 
-use {core::panic::PanicInfo, nrf52840_hal as hal, rtt_target::rprintln};
+use core::panic::PanicInfo;
+#[cfg(feature = "nrf52832")]
+use nrf52832_hal as hal;
+#[cfg(feature = "nrf52833")]
+use nrf52833_hal as hal;
+#[cfg(feature = "nrf52840")]
+use nrf52840_hal as hal;
+// For the interrupt handlers below `app`.
+#[cfg(not(feature = "nrf52832"))]
+use {
+    bridge::Receiver,
+    core::ptr::addr_of_mut,
+    error::{AppError, InternalError},
+    hal::pac::interrupt,
+    systrace::Span,
+    tracebuf::TaskId,
+};
+
+#[cfg(all(feature = "telemetry", feature = "uart-bridge"))]
+compile_error!("`telemetry` and `uart-bridge` share UARTE pins P0.19/P0.20");
+#[cfg(all(feature = "usb-console", feature = "hfclk-rc"))]
+compile_error!("`usb-console` needs the HFXO, which `hfclk-rc` never starts");
+#[cfg(all(feature = "gpio-expander", feature = "qspi-flash"))]
+compile_error!("`gpio-expander` and `qspi-flash` share pins P1.01-P1.06");
+#[cfg(not(any(feature = "nrf52840", feature = "nrf52833", feature = "nrf52832")))]
+compile_error!("select the chip with `nrf52840`, `nrf52833` or `nrf52832`");
+const _: () = assert!(
+    cfg!(feature = "nrf52840") as u8
+        + cfg!(feature = "nrf52833") as u8
+        + cfg!(feature = "nrf52832") as u8
+        <= 1,
+    "more than one chip selected, build for the others with `--no-default-features`"
+);
+#[cfg(all(
+    not(feature = "nrf52840"),
+    any(feature = "board-mdk", feature = "board-dk", feature = "board-dongle")
+))]
+compile_error!("the `board-*` boards carry an nRF52840");
+#[cfg(all(not(feature = "nrf52840"), feature = "qspi-flash"))]
+compile_error!("`qspi-flash` and `usb-msc` need the QSPI of the nRF52840");
+#[cfg(all(
+    feature = "nrf52832",
+    any(
+        feature = "usb-console",
+        feature = "uart-bridge",
+        feature = "gpio-expander"
+    )
+))]
+compile_error!(
+    "the nRF52832 has no USBD, UARTE1 or P1 for `usb-console`, `uart-bridge` and `gpio-expander`"
+);
+#[cfg(all(feature = "nrf52832", any(feature = "ws2812", feature = "telemetry")))]
+compile_error!("`ws2812` and `telemetry` use the pins of the nRF52-DK's LEDs");
+#[cfg(all(feature = "board-dk", feature = "qspi-flash"))]
+compile_error!("`qspi-flash` only knows the flash pins of the MDK");
+#[cfg(all(
+    feature = "board-dongle",
+    any(
+        feature = "ppk-markers",
+        feature = "spis",
+        feature = "gpio-expander",
+        feature = "qspi-flash"
+    )
+))]
+compile_error!("the Dongle's LEDs and button are on the pins of `ppk-markers`, `spis`, `gpio-expander` and `qspi-flash`");
+// The devices emulated at TWIS address 1, at most one of them.
+const _: () = assert!(
+    cfg!(feature = "gpio-expander") as u8
+        + cfg!(feature = "smbus-arp") as u8
+        + cfg!(feature = "pmbus") as u8
+        + cfg!(feature = "ipmi-ssif") as u8
+        + cfg!(feature = "mctp") as u8
+        + cfg!(feature = "bme280") as u8
+        + cfg!(feature = "mpu6050") as u8
+        + cfg!(feature = "ds3231") as u8
+        + cfg!(feature = "ina219") as u8
+        <= 1,
+    "`gpio-expander`, `smbus-arp`, `pmbus`, `ipmi-ssif`, `mctp`, `bme280`, `mpu6050`, `ds3231` and `ina219` all need TWIS address 1"
+);
+#[cfg(all(feature = "ina219", feature = "pdm-mic"))]
+compile_error!("`ina219` and `pdm-mic` share pins P0.30/P0.31");
+#[cfg(all(feature = "nfc-tag", feature = "hfclk-rc"))]
+compile_error!("`nfc-tag` needs the HFXO, which `hfclk-rc` never starts");
+#[cfg(all(feature = "ble", feature = "hfclk-rc"))]
+compile_error!("the radio of `ble` needs the HFXO, which `hfclk-rc` never starts");
+#[cfg(all(feature = "ble", feature = "dfu"))]
+compile_error!("`dfu` installs from flash 0, where `ble` needs the SoftDevice");
+#[cfg(all(feature = "ble", feature = "usb-console"))]
+compile_error!("`usb-console` needs the USB power events, which the SoftDevice of `ble` owns");
+#[cfg(all(
+    feature = "ble",
+    any(feature = "unlock", feature = "rolling-code", feature = "ccm")
+))]
+compile_error!(
+    "`unlock`, `rolling-code` and `ccm` need ECB and CCM, which the SoftDevice of `ble` owns"
+);
+
+#[macro_use]
+mod logging;
+#[macro_use]
+mod check;
+
+// The modules of a feature are built without it as well, with
+// `allow(dead_code)` where only that feature uses them, so the RTIC app and
+// its resources stay the same with every feature set and the feature checks
+// are `cfg!` at the call sites.
+mod anomaly;
+mod arp;
+mod auth;
+mod bench;
+// Needs nrf-softdevice, only built with `ble`.
+#[cfg(feature = "ble")]
+mod ble;
+mod blink;
+mod bme280;
+mod board;
+mod bootloader;
+#[cfg_attr(not(feature = "uart-bridge"), allow(dead_code))]
+mod bridge;
+mod build_config;
+mod build_info;
+mod burst;
+mod busgate;
+mod buspins;
+#[cfg_attr(not(feature = "bus-timing"), allow(dead_code))]
+mod bustiming;
+mod cbor;
+mod ccm;
+mod chip;
+mod clock;
+mod cobs;
+mod config;
+mod console;
+mod controller;
+mod dfu;
+mod discovery;
+mod ds3231;
+mod ecb;
+mod energy;
+mod entropy;
+mod error;
+mod events;
+#[cfg_attr(not(feature = "gpio-expander"), allow(dead_code))]
+mod expander;
+mod hexdump;
+mod identity;
+mod ina219;
+mod ipmi;
+#[cfg_attr(not(feature = "usb-msc"), allow(dead_code))]
+mod journal;
+mod key;
+mod latency;
+mod ledpwm;
+mod lock;
+mod lpcomp;
+mod markers;
+mod mctp;
+mod message;
+#[cfg_attr(not(feature = "pdm-mic"), allow(dead_code))]
+mod mic;
+mod mono;
+mod mpu6050;
+mod multibyte;
+#[cfg_attr(not(feature = "nfc-tag"), allow(dead_code))]
+mod nfctag;
+mod nvstore;
+#[cfg_attr(not(feature = "oled"), allow(dead_code))]
+mod oled;
+mod outcome;
+mod pmbus;
+mod postmortem;
+mod power;
+mod profile;
+mod protobuf;
+mod qdec;
+#[cfg_attr(not(feature = "qspi-flash"), allow(dead_code))]
+mod qspiflash;
+mod regmap;
+mod regsnap;
+mod repeater;
+mod request;
+mod resetreas;
+mod retain;
+mod rolling;
+mod saadc;
+mod sequence;
+mod session;
+mod sha256;
+mod signal;
+mod smbus;
+#[cfg(feature = "ble")]
+mod softdevice;
+mod spiframe;
+mod ssif;
+mod stats;
+mod status;
+mod stream;
+mod systrace;
+#[cfg_attr(not(feature = "telemetry"), allow(dead_code))]
+mod telemetry;
+mod thermal;
+mod tracebuf;
+mod trigger;
+mod twimpoll;
+mod twislog;
+mod unlock;
+mod usbconsole;
+#[cfg_attr(not(feature = "usb-msc"), allow(dead_code))]
+mod usbdisk;
+// Needs the USB crates, only built with `usb-msc`.
+#[cfg(feature = "usb-msc")]
+mod usbmsc;
+mod wallclock;
+mod wire;
+#[cfg_attr(not(feature = "ws2812"), allow(dead_code))]
+mod ws2812;
 
 #[rtic::app(device = crate::hal::pac, peripherals = true, dispatchers = [SWI0_EGU0])]
 mod app {
 
     use {
+        crate::hal,
+        crate::{
+            anomaly, arp, bench,
+            blink::{self, Blinker, ErrorClass},
+            bme280, board, bootloader,
+            bridge::{Bridge, Line},
+            build_info, burst,
+            busgate::{self, Hold},
+            buspins, bustiming, ccm, chip,
+            clock::{self, Hfxo},
+            config::{self, Config},
+            console::{self, Command, Console},
+            controller, dfu, discovery, ds3231, ecb, energy, entropy,
+            error::{AppError, InternalError, Op, ProtocolError},
+            events, expander,
+            hexdump::{self, Payload},
+            identity, ina219, journal, latency,
+            ledpwm::{self, Led},
+            lock,
+            logging::{self, Tag},
+            lpcomp,
+            markers::{self, Marker},
+            mctp, mic,
+            mono::{self, MonoRtc},
+            mpu6050, nfctag, nvstore,
+            oled::{self, Oled},
+            outcome::{self, Failure},
+            pmbus,
+            postmortem::{self, TransferState},
+            power::{self, IdleStrategy, WakeReason},
+            profile, qdec, qspiflash,
+            regmap::{self, Transport},
+            regsnap,
+            repeater::{self, Frame},
+            request::{self, Request},
+            resetreas, retain, rolling, saadc,
+            signal::{self, Signal},
+            spiframe, ssif,
+            stats::{self, STATS},
+            status, stream,
+            systrace::Span,
+            telemetry::{self, Telemetry},
+            thermal,
+            tracebuf::{self, Event, TaskId},
+            trigger, twimpoll, twislog, wallclock, ws2812,
+        },
+        hal::prelude::*,
         hal::{
-            gpio::{p0::Parts, p1::Parts as Parts1},
+            gpio::{p0::Parts, Level as PinLevel, Output, Pin, PushPull},
             gpiote::Gpiote,
-            pac::{TWIM1, TWIS0},
+            pac::{SPIS2, TWIM1, TWIS0},
+            spis::{self, Spis},
             twim::{Pins as TwimPins, *},
             twis::{Pins as TwisPins, *},
         },
-        nrf52840_hal as hal,
-        rtt_target::{rprintln, rtt_init_print},
     };
 
-    type DmaBuffer = &'static mut [u8; 8];
+    // At the priority of `on_power`, the highest of the tasks, rather than
+    // RTIC's default of the highest the NVIC has: with `ble` the priorities
+    // above 3 include those the SoftDevice reserves.
+    #[monotonic(binds = RTC1, default = true, priority = 3)]
+    type Mono = MonoRtc;
+
+    // How often the RTT console is checked for input.
+    const CONSOLE_POLL_MS: u64 = 100;
+
+    // Interval between periodic statistics summaries.
+    const STATS_PERIOD_SECS: u64 = 10;
+
+    // Interval between UARTE telemetry frames.
+    const TELEMETRY_PERIOD_MS: u64 = 1000;
+
+    // Heartbeat LED toggle interval; each toggle bumps the alive counter.
+    const HEARTBEAT_MS: u64 = 500;
+
+    // TWIM is powered down after this long without a transaction.
+    const TWIM_IDLE_TIMEOUT_MS: u64 = 2000;
+
+    // Interval between calibrations of the LFCLK RC oscillator, as
+    // recommended by Nordic for a stable temperature.
+    const CALIBRATION_PERIOD_SECS: u64 = 4;
+
+    // How `idle` waits for interrupts, see `power::IdleStrategy`.
+    const IDLE_STRATEGY: IdleStrategy = IdleStrategy::Wfi;
+
+    type DmaBuffer = &'static mut [u8; regmap::BUF_LEN];
 
     pub enum TwisTransfer {
         Running(Transfer<TWIS0, DmaBuffer>),
         Idle((DmaBuffer, Twis<TWIS0>)),
     }
 
+    pub enum SpisTransfer {
+        Running(spis::TransferSplit<SPIS2, DmaBuffer, DmaBuffer>),
+        Idle((DmaBuffer, DmaBuffer, Spis<SPIS2>)),
+    }
+
     #[shared]
     struct Shared {
         #[lock_free]
         transfer: Option<TwisTransfer>,
+        // Shared by the tasks driving the bus, all at priority 1.
+        #[lock_free]
+        twim: Twim<TWIM1>,
     }
 
     #[local]
     struct Local {
+        blinker: Blinker,
+        // `None` unless built with the `uart-bridge` feature
+        bridge: Option<Bridge>,
+        console: Console,
         gpiote: Gpiote,
-        twim: Twim<TWIM1>,
+        heartbeat_led: Pin<Output<PushPull>>,
+        led: Led,
+        // `None` unless built with the `spis` feature
+        spis: Option<SpisTransfer>,
+        // `None` unless built with the `telemetry` feature
+        telemetry: Option<Telemetry>,
     }
 
     #[init(local = [
-        BUF: [u8; 8] = [0; 8],
+        BUF: [u8; crate::regmap::BUF_LEN] = [0; crate::regmap::BUF_LEN],
+        SPIS_TX: [u8; crate::regmap::BUF_LEN] = [0; crate::regmap::BUF_LEN],
+        SPIS_RX: [u8; crate::regmap::BUF_LEN] = [0; crate::regmap::BUF_LEN],
     ])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         let BUF = ctx.local.BUF;
+        let mut core = ctx.core;
+        chip::unmask(&mut core.NVIC);
+        #[cfg(feature = "ble")]
+        crate::softdevice::init(&mut core.NVIC);
+
+        let console = Console::new(logging::init());
+        info!("{}", build_info::Banner);
+        info!("board: {} ({})", board::NAME, chip::NAME);
+        // The LFCLK drives the RTC monotonic. The HFXO is only started on
+        // demand, see `clock`.
+        clock::init(ctx.device.CLOCK);
+        postmortem::report();
+        power::init_regulators(&ctx.device.POWER);
+        power::init_pof(&ctx.device.POWER);
+        power::init_usb(&ctx.device.POWER);
+        let reset = resetreas::take(&ctx.device.POWER);
+        info!(
+            "device id {:016x}",
+            u64::from_le_bytes(identity::device_id())
+        );
+        info!("reset reason: {}", reset);
+        if reset.is(resetreas::OFF) {
+            info!("Resumed from System OFF");
+        }
+        nvstore::load();
+        retain::restore(reset.is(resetreas::OFF), BUF);
+        let config = config::load();
+        info!("config: {}", config);
+        info!("Waiting for commands from controller...");
 
-        let _clocks = hal::clocks::Clocks::new(ctx.device.CLOCK).enable_ext_hfosc();
-        rtt_init_print!();
-        rprintln!("Waiting for commands from controller...");
+        let mono = MonoRtc::new(ctx.device.RTC1);
+        profile::init(&mut core.DCB, &mut core.DWT);
+        power::init_idle(IDLE_STRATEGY, &mut core.SCB);
 
+        // Pins taken here must also be in the `board` pin map, the others
+        // are parked at the end of `init`.
         let p0 = Parts::new(ctx.device.P0);
-        let p1 = Parts1::new(ctx.device.P1); // nrf52840_mdk has its button connected to p1_00
 
-        // Configure gpio pins 15 and 16 for TWIS
-        let scl = p0.p0_15.into_floating_input().degrade();
-        let sda = p0.p0_16.into_floating_input().degrade();
+        // SAFETY: each board specific pin is taken once below, and none of
+        // them by name from `p0`.
+        let (scl, sda) = unsafe { (board::take(board::TWIS_SCL), board::take(board::TWIS_SDA)) };
+        let scl = scl.into_floating_input();
+        let sda = sda.into_floating_input();
 
         // create a twis instance
-        let twis = Twis::new(ctx.device.TWIS0, TwisPins { scl, sda }, 0x1A);
+        let twis = Twis::new(ctx.device.TWIS0, TwisPins { scl, sda }, config.address);
         twis.enable_interrupt(TwiEvent::Write)
             .enable_interrupt(TwiEvent::Read)
-            .enable_interrupt(TwiEvent::Stopped)
-            .enable();
+            .enable_interrupt(TwiEvent::Stopped);
+        if cfg!(feature = "gpio-expander") {
+            if config.address == expander::ADDRESS {
+                warn!("TWIS address taken by the register map, no GPIO expander");
+            } else {
+                twis.set_address1(expander::ADDRESS);
+                expander::init(config.has(config::EXPANDER_INT));
+            }
+        }
+        if cfg!(feature = "smbus-arp") {
+            if config.address == arp::ADDRESS {
+                warn!("TWIS address is the SMBus default address, no ARP");
+            } else {
+                twis.set_address1(arp::ADDRESS);
+                arp::init(config.address);
+            }
+        }
+        if cfg!(feature = "pmbus") {
+            if config.address == pmbus::ADDRESS {
+                warn!("TWIS address taken by the register map, no PMBus device");
+            } else {
+                twis.set_address1(pmbus::ADDRESS);
+                info!("PMBus device at {:#04x}", pmbus::ADDRESS);
+            }
+        }
+        if cfg!(feature = "ipmi-ssif") {
+            if config.address == ssif::ADDRESS {
+                warn!("TWIS address taken by the register map, no SSIF BMC");
+            } else {
+                twis.set_address1(ssif::ADDRESS);
+                info!("SSIF BMC at {:#04x}", ssif::ADDRESS);
+            }
+        }
+        if cfg!(feature = "mctp") {
+            if config.address == mctp::ADDRESS {
+                warn!("TWIS address taken by the register map, no MCTP endpoint");
+            } else {
+                twis.set_address1(mctp::ADDRESS);
+                info!("MCTP endpoint at {:#04x}", mctp::ADDRESS);
+            }
+        }
+        if cfg!(feature = "bme280") {
+            if config.address == bme280::ADDRESS {
+                warn!("TWIS address taken by the register map, no BME280");
+            } else {
+                twis.set_address1(bme280::ADDRESS);
+                info!("BME280 at {:#04x}", bme280::ADDRESS);
+            }
+        }
+        if cfg!(feature = "mpu6050") {
+            if config.address == mpu6050::ADDRESS {
+                warn!("TWIS address taken by the register map, no MPU-6050");
+            } else {
+                twis.set_address1(mpu6050::ADDRESS);
+                info!("MPU-6050 at {:#04x}", mpu6050::ADDRESS);
+            }
+        }
+        if cfg!(feature = "ds3231") {
+            if config.address == ds3231::ADDRESS {
+                warn!("TWIS address taken by the register map, no DS3231");
+            } else {
+                twis.set_address1(ds3231::ADDRESS);
+                info!("DS3231 at {:#04x}", ds3231::ADDRESS);
+            }
+        }
+        if cfg!(feature = "ina219") {
+            if config.address == ina219::ADDRESS {
+                warn!("TWIS address taken by the register map, no INA219");
+            } else {
+                twis.set_address1(ina219::ADDRESS);
+                info!("INA219 at {:#04x}", ina219::ADDRESS);
+            }
+        }
+        twis.enable();
+        if !busgate::init() {
+            set_twis_enabled(false);
+        }
+        if cfg!(feature = "bus-timing") {
+            bustiming::init(ctx.device.TIMER1, ctx.device.TIMER2, ctx.device.PPI);
+        }
 
-        // Configure gpio pins 26 and 27 for TWIM
-        let scl = p0.p0_27.into_floating_input().degrade();
-        let sda = p0.p0_26.into_floating_input().degrade();
+        // SAFETY: as for the TWIS pins.
+        let (scl, sda) = unsafe { (board::take(board::TWIM_SCL), board::take(board::TWIM_SDA)) };
+        let scl = scl.into_floating_input();
+        let sda = sda.into_floating_input();
 
         // create a twim instance
-        let twim = Twim::new(ctx.device.TWIM1, TwimPins { scl, sda }, Frequency::K100);
+        let twim = Twim::new(
+            ctx.device.TWIM1,
+            TwimPins { scl, sda },
+            controller::configure(&config),
+        );
+        if cfg!(feature = "twim-ppi") {
+            twimpoll::init(ctx.device.TIMER3);
+        }
 
         // button to reset DMA buffer
-        let btn = p1.p1_00.into_pullup_input().degrade();
+        // SAFETY: as for the TWIS pins.
+        let btn = unsafe { board::take(board::BUTTON) }.into_pullup_input();
 
         // gpio tasks and events instance
         let gpiote = Gpiote::new(ctx.device.GPIOTE);
         gpiote.port().input_pin(&btn).low();
         gpiote.port().enable_interrupt();
 
+        // error LED, see `blink` for the blink codes
+        // SAFETY: as for the TWIS pins.
+        let led = unsafe { board::take(board::LED_RED) }.into_push_pull_output(PinLevel::High);
+        let blinker = Blinker::new(led);
+        if reset.is(resetreas::WATCHDOG) {
+            indicate(ErrorClass::WatchdogRecovery);
+        }
+
+        // logic-analyzer trigger output, see `trigger`
+        trigger::init(p0.p0_03.into_push_pull_output(PinLevel::Low).degrade());
+
+        // analog wake source on AIN4 (P0.28), see `lpcomp`
+        lpcomp::init(ctx.device.LPCOMP);
+
+        // power-profiler markers, see `markers`
+        #[cfg(feature = "ppk-markers")]
+        markers::init(
+            p0.p0_04.into_push_pull_output(PinLevel::Low).degrade(),
+            p0.p0_05.into_push_pull_output(PinLevel::Low).degrade(),
+            p0.p0_06.into_push_pull_output(PinLevel::Low).degrade(),
+        );
+
+        // green LED, toggled by `heartbeat`
+        // SAFETY: as for the TWIS pins.
+        let heartbeat_led =
+            unsafe { board::take(board::LED_GREEN) }.into_push_pull_output(PinLevel::High);
+
+        // blue LED, dimmed and blinked by the controller, see `ledpwm`
+        // SAFETY: as for the TWIS pins.
+        let blue = unsafe { board::take(board::LED_BLUE) };
+        let led = Led::new(ctx.device.PWM0, blue.into_push_pull_output(PinLevel::High));
+
+        // telemetry frames on UARTE0, for boards without a debug probe
+        #[cfg(feature = "telemetry")]
+        let telemetry = {
+            use hal::uarte::{Baudrate, Parity, Pins as UartePins, Uarte};
+            let txd = p0.p0_20.into_push_pull_output(PinLevel::High).degrade();
+            let rxd = p0.p0_19.into_floating_input().degrade();
+            let pins = UartePins {
+                rxd,
+                txd,
+                cts: None,
+                rts: None,
+            };
+            let uarte = Uarte::new(
+                ctx.device.UARTE0,
+                pins,
+                Parity::EXCLUDED,
+                Baudrate::BAUD115200,
+            );
+            send_telemetry::spawn().unwrap();
+            Some(Telemetry::new(uarte))
+        };
+        #[cfg(not(feature = "telemetry"))]
+        let telemetry = None;
+
+        // serial I2C adapter on UARTE1, see `bridge`
+        #[cfg(feature = "uart-bridge")]
+        let bridge = {
+            use hal::uarte::{Baudrate, Parity, Pins as UartePins, Uarte};
+            let txd = p0.p0_20.into_push_pull_output(PinLevel::High).degrade();
+            let rxd = p0.p0_19.into_floating_input().degrade();
+            let pins = UartePins {
+                rxd,
+                txd,
+                cts: None,
+                rts: None,
+            };
+            let uarte = Uarte::new(
+                ctx.device.UARTE1,
+                pins,
+                Parity::EXCLUDED,
+                Baudrate::BAUD115200,
+            );
+            Some(Bridge::new(uarte))
+        };
+        #[cfg(not(feature = "uart-bridge"))]
+        let bridge = None;
+
+        // SPI peripheral on SPIS2, see `on_spis`. SPIS0 and SPIS1 share their
+        // instance with TWIS0 and TWIM1.
+        #[cfg(feature = "spis")]
+        let spis = {
+            use hal::spis::{Mode, Pins as SpisPins, SpisEvent};
+            // Clocked out past the buffer, and while the CPU holds it.
+            const ORC: u8 = 0xff;
+            const DEF: u8 = 0xfe;
+            let pins = SpisPins {
+                sck: p0.p0_07.into_floating_input().degrade(),
+                cs: p0.p0_08.into_floating_input().degrade(),
+                copi: Some(p0.p0_11.into_floating_input().degrade()),
+                cipo: Some(p0.p0_12.into_floating_input().degrade()),
+            };
+            let spis = Spis::new(ctx.device.SPIS2, pins);
+            spis.set_mode(Mode::Mode0)
+                .set_orc(ORC)
+                .set_default_char(DEF)
+                .enable_interrupt(SpisEvent::End);
+            Some(arm_spis(ctx.local.SPIS_TX, ctx.local.SPIS_RX, spis))
+        };
+        #[cfg(not(feature = "spis"))]
+        let spis = None;
+
+        if cfg!(any(feature = "rtt", feature = "usb-console")) {
+            poll_console::spawn().unwrap();
+        }
+        heartbeat::spawn().unwrap();
+        report_stats::spawn_after(mono::Duration::secs(STATS_PERIOD_SECS)).unwrap();
+        if clock::needs_calibration() {
+            calibrate_lfclk::spawn().unwrap();
+        }
+        thermal::init(ctx.device.TEMP);
+        saadc::init(ctx.device.SAADC);
+        entropy::init(ctx.device.RNG);
+        ecb::init(ctx.device.ECB);
+        ccm::init(ctx.device.CCM);
+        rolling::load();
+        reserve_codes();
+        #[cfg(feature = "nrf52840")]
+        if cfg!(feature = "qspi-flash") {
+            qspiflash::init(ctx.device.QSPI);
+        }
+        if cfg!(feature = "usb-msc") {
+            journal::init();
+            flush_journal::spawn_after(mono::Duration::secs(journal::PERIOD_SECS)).unwrap();
+        }
+        if cfg!(feature = "pdm-mic") {
+            mic::init(ctx.device.PDM);
+        }
+        if cfg!(feature = "ws2812") {
+            ws2812::init(ctx.device.PWM1);
+        }
+        if cfg!(feature = "nfc-tag") {
+            nfctag::init(ctx.device.NFCT);
+        }
+        if cfg!(feature = "qdec") {
+            qdec::init(ctx.device.QDEC);
+        }
+        if cfg!(feature = "egu-signals") {
+            signal::init(ctx.device.EGU1);
+        }
+        if cfg!(feature = "oled") {
+            refresh_oled::spawn().unwrap();
+        }
+        if cfg!(feature = "ds3231") {
+            tick_ds3231::spawn().unwrap();
+        }
+        if cfg!(feature = "ina219") {
+            sample_ina219::spawn().unwrap();
+        }
+        check_temp::spawn_after(mono::Duration::secs(thermal::PERIOD_SECS)).unwrap();
+        power::park_unused_pins();
+        burst::init();
+
         (
             Shared {
                 transfer: Some(TwisTransfer::Idle((BUF, twis))),
+                twim,
+            },
+            Local {
+                blinker,
+                bridge,
+                console,
+                gpiote,
+                heartbeat_led,
+                led,
+                spis,
+                telemetry,
             },
-            Local { gpiote, twim },
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
-    #[task(priority = 2, binds = GPIOTE, local = [gpiote], shared = [transfer])]
+    // Above `on_twis`, so a running transfer can be stopped right away.
+    #[task(priority = 3, binds = POWER_CLOCK)]
+    fn on_power(_: on_power::Context) {
+        let _span = Span::isr(TaskId::OnPower);
+        power::on_pofwarn();
+        power::on_usb();
+        clock::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = SAADC)]
+    fn on_saadc(_: on_saadc::Context) {
+        let _span = Span::isr(TaskId::OnSaadc);
+        saadc::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = RNG)]
+    fn on_rng(_: on_rng::Context) {
+        let _span = Span::isr(TaskId::OnRng);
+        entropy::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = PDM)]
+    fn on_pdm(_: on_pdm::Context) {
+        let _span = Span::isr(TaskId::OnPdm);
+        mic::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = PWM1)]
+    fn on_pwm1(_: on_pwm1::Context) {
+        let _span = Span::isr(TaskId::OnPwm1);
+        ws2812::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = NFCT)]
+    fn on_nfct(_: on_nfct::Context) {
+        let _span = Span::isr(TaskId::OnNfct);
+        if nfctag::on_interrupt() && activate_nfc::spawn().is_err() {
+            AppError::Internal(InternalError::SpawnFailed(TaskId::ActivateNfc)).record();
+        }
+    }
+
+    // Waits for the HFXO, which the NFCT needs, at task level.
+    #[task]
+    fn activate_nfc(_: activate_nfc::Context) {
+        let _span = Span::task(TaskId::ActivateNfc);
+        nfctag::activate();
+    }
+
+    // Only the polls of `twimpoll` enable TWIM interrupts.
+    #[task(priority = 2, binds = SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1)]
+    fn on_twim(_: on_twim::Context) {
+        let _span = Span::isr(TaskId::OnTwim);
+        twimpoll::on_interrupt();
+    }
+
+    // Handles the signals raised since it last ran, see `signal`. At the
+    // priority of the software tasks, so it may use TWIM like them.
+    #[task(priority = 1, binds = SWI1_EGU1)]
+    fn on_signal(_: on_signal::Context) {
+        let _span = Span::isr(TaskId::OnSignal);
+        if signal::take(Signal::PayloadReady) {
+            if let Some(payload) = signal::take_payload() {
+                trace!("{}", Payload(payload.bytes()));
+                logging::dump(payload.tag, payload.bytes());
+            }
+        }
+        if signal::take(Signal::Error) {
+            warn!(
+                "{} errors since the last ERROR signal",
+                signal::take_errors()
+            );
+        }
+        if signal::take(Signal::Reconfigure) {
+            controller::reconfigure(&config::stored());
+        }
+    }
+
+    #[task(priority = 2, binds = QDEC)]
+    fn on_qdec(_: on_qdec::Context) {
+        let _span = Span::isr(TaskId::OnQdec);
+        qdec::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = COMP_LPCOMP)]
+    fn on_lpcomp(_: on_lpcomp::Context) {
+        let _span = Span::isr(TaskId::OnLpcomp);
+        power::woke(WakeReason::Analog);
+        lpcomp::on_interrupt();
+    }
+
+    #[task(priority = 2, binds = GPIOTE, local = [gpiote])]
     fn on_gpiote(ctx: on_gpiote::Context) {
+        let _span = Span::isr(TaskId::OnGpiote);
         ctx.local.gpiote.reset_events();
-        rprintln!("Reset buffer");
-        let transfer = ctx.shared.transfer;
-        let (buf, twis) = match transfer.take().unwrap() {
-            TwisTransfer::Running(t) => t.wait(),
-            TwisTransfer::Idle(t) => t,
-        };
-        buf.copy_from_slice(&[0; 8][..]);
-        rprintln!("{:?}", buf);
-        transfer.replace(TwisTransfer::Idle((buf, twis)));
+        // The PORT event is shared with the GPIO expander inputs and ENABLE.
+        let button = take_button_latch();
+        let expander = expander::on_port_event();
+        let gate = busgate::on_port_event();
+        if let Some(open) = gate {
+            hold_twis(Hold::Gate, !open);
+            tracebuf::record(Event::Gate, 0, open as u16);
+            info!("ENABLE {}", if open { "high" } else { "low" });
+        }
+        if (expander || gate.is_some()) && !button {
+            return;
+        }
+        power::woke(WakeReason::Button);
+        info!("Reset buffer");
+        blink::clear();
+        regmap::clear_scratch();
+        trace!("{}", Payload(&regmap::scratch()));
+        tracebuf::record(Event::ButtonReset, 0, 0);
+        events::push(events::Kind::Button, 0, 0);
 
         // spawn `send_twi_cmds` task. This task uses the `twim` to send read and write commands to `twis`.
-        send_twi_cmds::spawn().unwrap();
+        let spawned = send_twi_cmds::spawn().is_ok();
+        if soft_assert!(spawned, "send_twi_cmds already pending") {
+            tracebuf::record(Event::TaskSpawn, TaskId::SendTwiCmds as u8, 0);
+        } else {
+            // The pending run does the same work, so this press is dropped.
+            AppError::Internal(InternalError::SpawnFailed(TaskId::SendTwiCmds)).record();
+        }
     }
 
-    #[task(priority = 2, binds = SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0, shared = [transfer])]
+    #[task(priority = 2, binds = SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0, local = [receiving: bool = false, secondary: bool = false], shared = [transfer])]
     fn on_twis(ctx: on_twis::Context) {
+        let _span = Span::isr(TaskId::OnTwis);
+        let entered = profile::now();
+        let drained = twislog::drain_extra_events();
+        if !twis_event_pending() {
+            if !drained {
+                anomaly::spurious_twis();
+            }
+            return;
+        }
+        power::woke(WakeReason::AddressMatch);
         let transfer = ctx.shared.transfer;
-        let (buf, twis) = match transfer.take().unwrap() {
-            TwisTransfer::Running(t) => t.wait(),
-            TwisTransfer::Idle(t) => t,
+        postmortem::set_transfer_state(TransferState::Taken);
+        let (was_running, (buf, twis)) = match transfer.take().unwrap() {
+            TwisTransfer::Running(mut t) => {
+                // A READ or WRITE without STOPPED in between (repeated start)
+                // would leave `wait` spinning forever.
+                let idle = if soft_assert!(t.is_done(), "TWIS event while a transfer is running") {
+                    t.wait()
+                } else {
+                    abort(t)
+                };
+                markers::set(Marker::Dma, false);
+                tracebuf::record(Event::DmaDone, 0, 0);
+                (true, idle)
+            }
+            TwisTransfer::Idle(t) => (false, t),
         };
         if twis.is_event_triggered(TwiEvent::Read) {
             twis.reset_event(TwiEvent::Read);
-            rprintln!("READ command received");
-            let tx = twis.tx(buf).unwrap();
+            twislog::log(TwiEvent::Read);
+            burst::address_match();
+            energy::begin();
+            tracebuf::record(Event::TwisRead, 0, 0);
+            info!("READ command received");
+            // The WRITE before a repeated start, which got no STOPPED.
+            let written = (was_running && *ctx.local.receiving)
+                .then(|| (twis.amount() as usize).min(buf.len()));
+            *ctx.local.receiving = false;
+            *ctx.local.secondary = twis_matched_address1();
+            if *ctx.local.secondary && repeater::is_active() {
+                repeater::take(&mut buf[..]);
+            } else if *ctx.local.secondary {
+                if let Some(len) = written {
+                    address1_write(&buf[..len]);
+                }
+                address1_read(&mut buf[..]);
+            } else {
+                regmap::fill(Transport::Twis, &mut buf[..]);
+            }
+            tracebuf::record(Event::DmaTxStart, 0, buf.len() as u16);
+            let tx = twis.tx(buf).unwrap_or_else(|error| {
+                twis_dma_failed(AppError::Twis {
+                    op: Op::Read,
+                    error,
+                })
+            });
             transfer.replace(TwisTransfer::Running(tx));
+            markers::set(Marker::Dma, true);
+            postmortem::set_transfer_state(TransferState::Running);
+            latency::record(entered);
+            energy::charge_cpu(entered);
         } else if twis.is_event_triggered(TwiEvent::Write) {
             twis.reset_event(TwiEvent::Write);
-            rprintln!("WRITE command received");
-            let rx = twis.rx(buf).unwrap();
+            twislog::log(TwiEvent::Write);
+            burst::address_match();
+            energy::begin();
+            tracebuf::record(Event::TwisWrite, 0, 0);
+            info!("WRITE command received");
+            *ctx.local.receiving = true;
+            *ctx.local.secondary = twis_matched_address1();
+            tracebuf::record(Event::DmaRxStart, 0, buf.len() as u16);
+            let rx = twis.rx(buf).unwrap_or_else(|error| {
+                twis_dma_failed(AppError::Twis {
+                    op: Op::Write,
+                    error,
+                })
+            });
             transfer.replace(TwisTransfer::Running(rx));
+            markers::set(Marker::Dma, true);
+            postmortem::set_transfer_state(TransferState::Running);
+            latency::record(entered);
+            energy::charge_cpu(entered);
         } else {
+            // STOPPED, the only event left after `twis_event_pending`
             twis.reset_event(TwiEvent::Stopped);
-            rprintln!("{:?}", buf);
+            twislog::log(TwiEvent::Stopped);
+            burst::stopped();
+            if let Some(timing) = bustiming::take() {
+                let clocks = timing.clocks.min(u8::MAX as u32) as u8;
+                tracebuf::record(Event::BusTiming, clocks, timing.khz() as u16);
+                info!("bus timing: {}", timing);
+            }
+            // Already queued if this is not the first STOPPED of the burst.
+            end_burst::spawn_after(mono::Duration::millis(burst::IDLE_MS as u64)).ok();
+            if !soft_assert!(was_running, "TWIS STOPPED with no transfer armed") {
+                // Nothing was moved, the buffer only holds stale data.
+                transfer.replace(TwisTransfer::Idle((buf, twis)));
+                postmortem::set_transfer_state(TransferState::Idle);
+                return;
+            }
+            let (tag, len) = if *ctx.local.receiving {
+                let amount = twis.amount();
+                STATS.twis_writes.inc();
+                STATS.twis_bytes_rx.add(amount);
+                if twis.is_overflow() {
+                    STATS.overruns.inc();
+                    let error = AppError::from(ProtocolError::BadLength {
+                        len: amount,
+                        max: buf.len(),
+                    });
+                    error.record();
+                    warn!("{}", error);
+                    regsnap::log("twis rx overflow");
+                    indicate(ErrorClass::BufferOverflow);
+                }
+                tracebuf::record(Event::TwisStopped, 0, amount as u16);
+                let len = (amount as usize).min(buf.len());
+                if *ctx.local.secondary && repeater::is_active() {
+                    let dropped = twis.is_overflow()
+                        || forward::spawn(Some(Frame::new(&buf[..len]))).is_err();
+                    repeater::set_dropped(dropped);
+                } else if *ctx.local.secondary {
+                    address1_write(&buf[..len]);
+                } else {
+                    trigger::check(&buf[..len]);
+                    apply_write(Transport::Twis, buf, len);
+                }
+                (Tag::TwisRx, len)
+            } else {
+                let amount = twis_tx_amount();
+                STATS.twis_reads.inc();
+                STATS.twis_bytes_tx.add(amount);
+                tracebuf::record(Event::TwisStopped, 0, amount as u16);
+                // Bytes past the buffer were clocked out as ORC.
+                let len = (amount as usize).min(buf.len());
+                if !*ctx.local.secondary {
+                    regmap::advance(Transport::Twis, len);
+                } else if repeater::is_active() {
+                    // Read ahead for the next READ; if a forward is still
+                    // pending it reads ahead itself.
+                    forward::spawn(None).ok();
+                }
+                (Tag::TwisTx, len)
+            };
+            energy::charge_cpu(entered);
+            let op = if *ctx.local.receiving {
+                Op::Write
+            } else {
+                Op::Read
+            };
+            if let Some(nanojoules) = energy::end(op) {
+                trace!("~{} nJ for the {}", nanojoules, op);
+            }
+            telemetry::record_transfer(tag, len);
+            oled::record_transfer(tag, &buf[..len]);
+            if signal::enabled() {
+                signal::post_payload(tag, &buf[..len]);
+            } else {
+                trace!("{}", Payload(&buf[..len]));
+                logging::dump(tag, &buf[..len]);
+            }
             transfer.replace(TwisTransfer::Idle((buf, twis)));
+            postmortem::set_transfer_state(TransferState::Idle);
+        }
+    }
+
+    // SPIS counterpart of `on_twis`, with the `spis` feature: one
+    // transaction per CSN assertion, framed by `spiframe`.
+    #[task(priority = 2, binds = SPIM2_SPIS2_SPI2, local = [spis])]
+    fn on_spis(ctx: on_spis::Context) {
+        let _span = Span::isr(TaskId::OnSpis);
+        let slot = ctx.local.spis;
+        let mut transfer = match slot.take() {
+            Some(SpisTransfer::Running(transfer)) => transfer,
+            idle => {
+                *slot = idle;
+                return;
+            }
+        };
+        if !transfer.is_done() {
+            *slot = Some(SpisTransfer::Running(transfer));
+            return;
+        }
+        power::woke(WakeReason::SpiSelect);
+        // Takes the semaphore back, the buffers are the CPU's until re-armed.
+        let (tx, rx, spis) = transfer.wait();
+        let (overread, overflow) = (spis.is_overread(), spis.is_overflow());
+        let received = spis.amount();
+        // SAFETY: the SPIS2 instance is owned here. Reads the bytes sent and
+        // clears the STATUS flags.
+        let sent = unsafe {
+            let regs = &*SPIS2::ptr();
+            regs.status
+                .write(|w| w.overread().clear().overflow().clear());
+            regs.txd.amount.read().bits()
+        };
+        spis.reset_events();
+        tracebuf::record(
+            Event::SpisEnd,
+            overread as u8 | (overflow as u8) << 1,
+            received as u16,
+        );
+        if overflow {
+            STATS.overruns.inc();
+            warn!(
+                "SPIS transaction past {} bytes, the rest was dropped",
+                rx.len()
+            );
+        }
+        let len = (received as usize).min(rx.len());
+        let (tag, data) = match spiframe::decode(&rx[..len]) {
+            spiframe::Frame::Empty => {
+                *slot = Some(arm_spis(tx, rx, spis));
+                return;
+            }
+            spiframe::Frame::Read => {
+                // Bytes past the buffer were clocked out as ORC.
+                let len = (sent as usize).min(tx.len());
+                info!("SPIS read, {} bytes", len);
+                regmap::advance(Transport::Spis, len);
+                (Tag::SpisTx, &tx[..len])
+            }
+            spiframe::Frame::Write(data) => {
+                info!("SPIS write, {} bytes", data.len());
+                apply_write(Transport::Spis, rx, len);
+                (Tag::SpisRx, &rx[..len])
+            }
+        };
+        telemetry::record_transfer(tag, data.len());
+        oled::record_transfer(tag, data);
+        trace!("{}", Payload(data));
+        logging::dump(tag, data);
+        *slot = Some(arm_spis(tx, rx, spis));
+    }
+
+    // Fills `tx` from the register map and hands both buffers to SPIS for
+    // the next transaction. On failure SPIS stays idle and answers every
+    // transaction with the DEF character.
+    fn arm_spis(tx: DmaBuffer, rx: DmaBuffer, spis: Spis<SPIS2>) -> SpisTransfer {
+        regmap::fill(Transport::Spis, &mut tx[..]);
+        match spis.transfer_split(tx, rx) {
+            Ok(transfer) => SpisTransfer::Running(transfer),
+            Err((error, spis, tx, rx)) => {
+                let error = AppError::Spis(error);
+                error.record();
+                error!("{}", error);
+                SpisTransfer::Idle((tx, rx, spis))
+            }
+        }
+    }
+
+    // Applies a controller WRITE of `len` bytes in `buf`, from either bus.
+    fn apply_write(transport: Transport, buf: &[u8; regmap::BUF_LEN], len: usize) {
+        let applied = if status::is_set(status::BROWNOUT) {
+            // Except a write clearing the flag, see `power`.
+            if buf[..len].first() == Some(&regmap::STATUS) {
+                regmap::apply(transport, &buf[..len])
+            } else {
+                warn!("WRITE dropped, supply low");
+                Ok(None)
+            }
+        } else {
+            regmap::apply(transport, &buf[..len])
+        };
+        let refused = |error| {
+            outcome::refused(error);
+            let error = AppError::from(error);
+            STATS.errors.inc();
+            error.record();
+            warn!("{}", error);
+        };
+        match applied {
+            Ok(None) => {}
+            Ok(Some(request)) => handle_request(request, buf),
+            Err(error) => refused(error),
+        }
+        // Frames the WRITE completed in the stream.
+        while let Some(res) = stream::take() {
+            match res {
+                Ok(request) => handle_request(request, buf),
+                Err(error) => refused(error),
+            }
+        }
+        // A run already pending picks up the new values as well.
+        if ledpwm::take_changed() {
+            drive_led::spawn(false).ok();
+        }
+        ws2812::show();
+    }
+
+    // Shows the LED registers; `tick` is the blink rescheduling itself,
+    // otherwise the registers changed and the blink restarts lit.
+    #[task(capacity = 2, local = [led, next: Option<drive_led::SpawnHandle> = None, lit: bool = false])]
+    fn drive_led(ctx: drive_led::Context, tick: bool) {
+        let _span = Span::task(TaskId::DriveLed);
+        let next = ctx.local.next;
+        let lit = ctx.local.lit;
+        if tick {
+            *lit = !*lit;
+        } else {
+            if let Some(handle) = next.take() {
+                handle.cancel().ok();
+            }
+            *lit = true;
+            info!(
+                "LED brightness {}, blink {}",
+                ledpwm::brightness(),
+                ledpwm::blink()
+            );
+        }
+        let period = ledpwm::half_period_ms();
+        let level = if *lit || period.is_none() {
+            ledpwm::brightness()
+        } else {
+            0
+        };
+        ctx.local.led.show(level);
+        *next = period
+            .and_then(|ms| drive_led::spawn_after(mono::Duration::millis(ms as u64), true).ok());
+    }
+
+    // Carries out a request written to the COMMAND register; `buf` is the
+    // DMA buffer it arrived in.
+    fn handle_request(request: Request, buf: &[u8; regmap::BUF_LEN]) {
+        info!("{:?} requested by controller", request);
+        let opcode = request.opcode();
+        outcome::accepted(opcode);
+        match request {
+            Request::Sleep => {
+                if system_off::spawn().is_ok() {
+                    retain::save(buf);
+                } else {
+                    outcome::failed(opcode, Failure::Internal);
+                    AppError::Internal(InternalError::SpawnFailed(TaskId::SystemOff)).record();
+                }
+            }
+            Request::WriteConfig(config) => spawn_store_config(Some(config)),
+            Request::FactoryReset => spawn_store_config(None),
+            Request::SleepFor(ms) => {
+                hold_twis(Hold::SleepWindow, true);
+                if resume_twis::spawn_after(mono::Duration::millis(ms as u64)).is_ok() {
+                    tracebuf::record(Event::SleepWindow, 0, ms);
+                    outcome::done(opcode);
+                } else {
+                    // Without the resume scheduled TWIS would stay off.
+                    hold_twis(Hold::SleepWindow, false);
+                    outcome::failed(opcode, Failure::Internal);
+                    AppError::Internal(InternalError::SpawnFailed(TaskId::ResumeTwis)).record();
+                }
+            }
+            Request::Store => {
+                if store_bank::spawn().is_err() {
+                    outcome::failed(opcode, Failure::Internal);
+                    AppError::Internal(InternalError::SpawnFailed(TaskId::StoreBank)).record();
+                }
+            }
+            Request::FlashRead(address) => {
+                if !qspiflash::read(address) {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("QSPI flash busy or absent, read dropped");
+                }
+            }
+            Request::FlashProgram { .. } | Request::FlashErase { .. }
+                if journal_reserved(&request) =>
+            {
+                outcome::failed(opcode, Failure::Conflict);
+                warn!("QSPI flash range reserved for the journal, refused");
+            }
+            Request::FlashProgram { address, data, len } => {
+                if !qspiflash::program(address, &data[..len as usize]) {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("QSPI flash busy or absent, program dropped");
+                }
+            }
+            Request::FlashErase { address, size } => {
+                if !qspiflash::erase(address, size) {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("QSPI flash busy or absent, erase dropped");
+                }
+            }
+            Request::Bridge { target, fetch_len } => {
+                if let Some(device) = address1_device() {
+                    outcome::failed(opcode, Failure::Conflict);
+                    warn!("TWIS address 1 taken by the {}, no repeater", device);
+                } else if target == config::get().address {
+                    outcome::failed(opcode, Failure::Conflict);
+                    warn!("repeater target is the register map address, refused");
+                } else {
+                    set_twis_address1(target);
+                    repeater::set_target(target, fetch_len);
+                    outcome::done(opcode);
+                    if target == 0 {
+                        info!("repeater off");
+                    } else {
+                        info!("repeating to {:#04x}", target);
+                    }
+                }
+            }
+            Request::Capture(samples) => {
+                if !mic::start(samples) {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("PDM busy or absent, capture dropped");
+                }
+            }
+            Request::Sample { channel, count } => {
+                if !saadc::start(channel, count) {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("SAADC busy, sample request dropped");
+                }
+            }
+            Request::DfuBegin { .. }
+            | Request::DfuWrite { .. }
+            | Request::DfuVerify
+            | Request::DfuActivate => {
+                if !cfg!(feature = "dfu") {
+                    outcome::failed(opcode, Failure::Unavailable);
+                    warn!("built without the dfu feature, request dropped");
+                } else if run_dfu::spawn(request).is_err() {
+                    outcome::failed(opcode, Failure::Internal);
+                    AppError::Internal(InternalError::SpawnFailed(TaskId::RunDfu)).record();
+                }
+            }
+            Request::Bootloader(magic) => {
+                // After a moment, so the controller can still read RESULT.
+                let after = mono::Duration::millis(bootloader::RESET_DELAY_MS);
+                if enter_bootloader::spawn_after(after, magic).is_ok() {
+                    outcome::done(opcode);
+                } else {
+                    outcome::failed(opcode, Failure::Internal);
+                    AppError::Internal(InternalError::SpawnFailed(TaskId::EnterBootloader))
+                        .record();
+                }
+            }
+            Request::Describe => {
+                discovery::rewind();
+                outcome::done(opcode);
+            }
+            Request::SyncTime { seconds, millis } => {
+                wallclock::sync(seconds, millis);
+                outcome::done(opcode);
+            }
+        }
+    }
+
+    // Ends a sleep window. `on_twis` cannot fire while TWIS is disabled, so
+    // the raw register access does not race with it.
+    #[task]
+    fn resume_twis(_: resume_twis::Context) {
+        let _span = Span::task(TaskId::ResumeTwis);
+        hold_twis(Hold::SleepWindow, false);
+        tracebuf::record(Event::SleepWindowEnd, 0, 0);
+        info!("TWIS back after sleep window");
+    }
+
+    // Whether a flash request reaches into the journal; a chip erase always
+    // does.
+    fn journal_reserved(request: &Request) -> bool {
+        let (address, len) = match *request {
+            Request::FlashProgram { address, len, .. } => (address, len as u32),
+            Request::FlashErase { address, size } => (address, size.len()),
+            _ => return false,
+        };
+        cfg!(feature = "usb-msc") && address + len > journal::BASE
+    }
+
+    fn spawn_store_config(config: Option<Config>) {
+        if store_config::spawn(config).is_err() {
+            outcome::failed(config_opcode(&config), Failure::Internal);
+            AppError::Internal(InternalError::SpawnFailed(TaskId::StoreConfig)).record();
         }
     }
 
-    #[task(local = [twim])]
+    // The request `spawn_store_config` carries out.
+    fn config_opcode(config: &Option<Config>) -> u8 {
+        match config {
+            Some(_) => request::WRITE_CONFIG,
+            None => request::FACTORY_RESET,
+        }
+    }
+
+    // Persists `config`, or the defaults for `None`.
+    #[task]
+    fn store_config(_: store_config::Context, config: Option<Config>) {
+        let _span = Span::task(TaskId::StoreConfig);
+        let res = match config {
+            Some(config) => config::save(config),
+            None => config::factory_reset(),
+        };
+        match res {
+            Ok(()) => {
+                outcome::done(config_opcode(&config));
+                info!(
+                    "config saved: {}, active after reset",
+                    config.unwrap_or(config::DEFAULT)
+                );
+                signal::raise(Signal::Reconfigure);
+            }
+            Err(error) => {
+                outcome::failed(config_opcode(&config), Failure::Internal);
+                let error = AppError::Config(error);
+                error.record();
+                STATS.errors.inc();
+                error!("{}", error);
+            }
+        }
+    }
+
+    // Flash erase and program stall the CPU, so this runs at task level,
+    // where `nvstore` can let interrupts in between.
+    #[task]
+    fn store_bank(_: store_bank::Context) {
+        let _span = Span::task(TaskId::StoreBank);
+        match nvstore::store() {
+            Ok(()) => {
+                outcome::done(request::STORE);
+                info!("scratch registers stored: {}", Payload(&regmap::scratch()))
+            }
+            Err(error) => {
+                outcome::failed(request::STORE, Failure::Internal);
+                let error = AppError::Store(error);
+                error.record();
+                STATS.errors.inc();
+                error!("{}", error);
+            }
+        }
+    }
+
+    // Programs the update slot, which stalls the CPU like `store_bank`.
+    // The controller waits for RESULT before each request, so one at a time.
+    #[task]
+    fn run_dfu(_: run_dfu::Context, request: Request) {
+        let _span = Span::task(TaskId::RunDfu);
+        let res = match request {
+            Request::DfuBegin { size, crc } => dfu::begin(size, crc),
+            Request::DfuWrite {
+                offset, data, len, ..
+            } => dfu::write(offset, &data[..len as usize]),
+            Request::DfuVerify => dfu::verify(),
+            Request::DfuActivate => dfu::activate(),
+            _ => return,
+        };
+        let opcode = request.opcode();
+        match res {
+            Ok(()) => outcome::done(opcode),
+            Err(error) => {
+                let failure = match error {
+                    dfu::Error::NoSlot | dfu::Error::State | dfu::Error::Offset => {
+                        Failure::Conflict
+                    }
+                    dfu::Error::Incomplete | dfu::Error::Crc | dfu::Error::Image => {
+                        Failure::Invalid
+                    }
+                    dfu::Error::Flash => Failure::Internal,
+                };
+                outcome::failed(opcode, failure);
+                warn!("{:?} failed: {}", request, error);
+            }
+        }
+    }
+
+    #[task]
+    fn enter_bootloader(_: enter_bootloader::Context, magic: u8) {
+        let _span = Span::task(TaskId::EnterBootloader);
+        bootloader::enter(magic)
+    }
+
+    #[task]
+    fn system_off(_: system_off::Context) {
+        let _span = Span::task(TaskId::SystemOff);
+        info!("Entering System OFF, press the button to wake up");
+        power::system_off()
+    }
+
+    #[task(shared = [twim])]
     fn send_twi_cmds(ctx: send_twi_cmds::Context) {
-        let twim = ctx.local.twim;
+        let _span = Span::task(TaskId::SendTwiCmds);
+        let _hfxo = Hfxo::request();
+        let twim = ctx.shared.twim;
 
-        // read 8 bytes from TWIS at address 0x1A
-        rprintln!("\nREAD from address 0x1A");
-        let rx_buf = &mut [0; 8][..];
-        let res = twim.read(0x1A, rx_buf);
-        rprintln!("Result: {:?}\n{:?}", res, rx_buf);
+        let address = config::get().address;
 
-        // write 8 bytes to TWIS at address 0x1A
-        rprintln!("\nWRITE to address 0x1A");
+        // read the 8 scratch registers from TWIS (address 0x1A by default)
+        info!(
+            "READ from address {:#04x} at {} kHz",
+            address,
+            controller::frequency_khz()
+        );
+        let rx_buf = &mut [0; regmap::SCRATCH_LEN][..];
+        let res = controller::read_regs(twim, address, regmap::SCRATCH, rx_buf);
+        report(res);
+        trace!("{}", Payload(rx_buf));
+        logging::dump(Tag::TwimRx, rx_buf);
+
+        // write 8 bytes to the scratch registers of TWIS
+        info!("WRITE to address {:#04x}", address);
         let tx_buf = [1, 2, 3, 4, 5, 6, 7, 8];
-        let res = twim.write(0x1A, &tx_buf[..]);
-        rprintln!("Result: {:?}\n{:?}", res, tx_buf);
+        let res = controller::write_regs(twim, address, regmap::SCRATCH, &tx_buf[..]);
+        report(res);
+        trace!("{}", Payload(&tx_buf[..]));
+        logging::dump(Tag::TwimTx, &tx_buf[..]);
+
+        // the alive counter shows the peripheral side is still running
+        match controller::read_u32(twim, address, regmap::ALIVE) {
+            Ok(alive) => info!("alive counter: {}", alive),
+            Err(error) => report(Err(error)),
+        }
+
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            // Already queued if the previous run was less than a timeout ago.
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+    }
+
+    // Sends a request typed on the console as a message, see `message`.
+    #[task(shared = [twim])]
+    fn send_request(ctx: send_request::Context, request: Request) {
+        let _span = Span::task(TaskId::SendRequest);
+        let _hfxo = Hfxo::request();
+        let address = config::get().address;
+        match controller::send(ctx.shared.twim, address, &request) {
+            Ok(reply) => println!("{:?} to {:#04x}: {}", request, address, reply),
+            Err(error) => println!("{:?} not sent: {}", request, error),
+        }
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+    }
+
+    #[task(shared = [twim])]
+    fn run_bench(ctx: run_bench::Context) {
+        let _span = Span::task(TaskId::RunBench);
+        bench::run(ctx.shared.twim, config::get().address);
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+    }
+
+    // Carries a WRITE received for the repeater target over to TWIM, then
+    // reads ahead for the next READ.
+    #[task(shared = [twim])]
+    fn forward(ctx: forward::Context, frame: Option<Frame>) {
+        let _span = Span::task(TaskId::Forward);
+        let _hfxo = Hfxo::request();
+        repeater::forward(ctx.shared.twim, frame);
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+    }
+
+    // Sends an MCTP response as a bus master, see `mctp`.
+    #[task(shared = [twim])]
+    fn send_mctp(ctx: send_mctp::Context) {
+        let _span = Span::task(TaskId::SendMctp);
+        let _hfxo = Hfxo::request();
+        mctp::send(ctx.shared.twim);
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+    }
+
+    // Redraws the status display, see `oled`.
+    #[task(local = [oled: Oled = Oled::new()], shared = [twim])]
+    fn refresh_oled(ctx: refresh_oled::Context) {
+        let _span = Span::task(TaskId::RefreshOled);
+        // For the TWIM timing, as in `send_twi_cmds`.
+        let _hfxo = Hfxo::request();
+        if !ctx.local.oled.refresh(ctx.shared.twim) {
+            return;
+        }
+        if config::get().has(config::TWIM_AUTO_OFF) {
+            twim_idle::spawn_after(mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS)).ok();
+        }
+        refresh_oled::spawn_after(mono::Duration::millis(oled::PERIOD_MS)).unwrap();
+    }
+
+    // Writes new trace records to the flash journal, see `journal`.
+    #[task]
+    fn flush_journal(_: flush_journal::Context) {
+        let _span = Span::task(TaskId::FlushJournal);
+        match journal::step() {
+            Some(ms) => flush_journal::spawn_after(mono::Duration::millis(ms as u64)),
+            None => flush_journal::spawn_after(mono::Duration::secs(journal::PERIOD_SECS)),
+        }
+        .unwrap();
+    }
+
+    #[task]
+    fn twim_idle(_: twim_idle::Context) {
+        let _span = Span::task(TaskId::TwimIdle);
+        let timeout = mono::Duration::millis(TWIM_IDLE_TIMEOUT_MS);
+        if let Some(left) = controller::power_down_if_idle(timeout) {
+            twim_idle::spawn_after(left).ok();
+        }
+    }
+
+    // Keeps rolling codes reserved in flash ahead of the count, from `init`
+    // and the heartbeat.
+    fn reserve_codes() {
+        if let Err(error) = rolling::reserve() {
+            let error = AppError::Store(error);
+            error.record();
+            STATS.errors.inc();
+            error!("rolling codes: {}", error);
+        }
+    }
+
+    // Logs the outcome of a TWIM transaction, showing failures on the LED.
+    fn report(res: Result<(), AppError>) {
+        match res {
+            Ok(()) => info!("Result: Ok"),
+            Err(error) => {
+                error!("Result: {}", error);
+                indicate(ErrorClass::Bus);
+            }
+        }
+    }
+
+    #[task(local = [console])]
+    fn poll_console(ctx: poll_console::Context) {
+        let _span = Span::task(TaskId::PollConsole);
+        while let Some(command) = ctx.local.console.poll() {
+            match command {
+                Command::Help => println!("{}", console::HELP),
+                Command::Level(None) => println!("log level: {:?}", logging::level()),
+                Command::Level(Some(level)) => {
+                    logging::set_level(level);
+                    println!("log level set to {:?}", level);
+                }
+                Command::TraceDump => {
+                    let count = tracebuf::dump();
+                    println!("dumped {} trace records", count);
+                }
+                Command::TraceClear => {
+                    tracebuf::clear();
+                    println!("trace cleared");
+                }
+                Command::TwisLog(on) => {
+                    twislog::set_verbose(on);
+                    println!("twis event log {}", if on { "on" } else { "off" });
+                }
+                Command::Profile => profile::print_report(),
+                Command::ProfileReset => {
+                    profile::reset();
+                    println!("profile cleared");
+                }
+                Command::Latency => latency::print_report(),
+                Command::Energy => println!("{}", energy::Report),
+                Command::EnergyReset => {
+                    energy::reset();
+                    println!("energy totals cleared");
+                }
+                Command::LatencyReset => {
+                    latency::reset();
+                    println!("latency histogram cleared");
+                }
+                Command::Stats => println!("{}", stats::Summary),
+                Command::HexWidth(None) => println!("hex-dump width: {}", hexdump::width()),
+                Command::HexWidth(Some(width)) => {
+                    hexdump::set_width(width);
+                    println!("hex-dump width set to {}", hexdump::width());
+                }
+                Command::Trigger(None) => match trigger::pattern() {
+                    Some(pattern) => println!("trigger on {:02x?}", pattern.bytes()),
+                    None => println!("trigger off"),
+                },
+                Command::Trigger(Some(pattern)) => {
+                    trigger::set_pattern(Some(pattern));
+                    println!("trigger armed on {:02x?}", pattern.bytes());
+                }
+                Command::TriggerOff => {
+                    trigger::set_pattern(None);
+                    println!("trigger off");
+                }
+                Command::Dump(None) => println!("dump mode: {:?}", hexdump::mode()),
+                Command::Dump(Some(mode)) => {
+                    hexdump::set_mode(mode);
+                    println!("dump mode set to {:?}", mode);
+                }
+                Command::Power => println!("{}", power::Status),
+                Command::Pins(None) => {
+                    println!("{}", buspins::Report(buspins::Bus::Twis));
+                    println!("{}", buspins::Report(buspins::Bus::Twim));
+                }
+                Command::Pins(Some((bus, drive, pull))) => {
+                    buspins::configure(bus, drive, pull);
+                    println!("{}", buspins::Report(bus));
+                }
+                Command::Bench => {
+                    if run_bench::spawn().is_err() {
+                        AppError::Internal(InternalError::SpawnFailed(TaskId::RunBench)).record();
+                    }
+                }
+                Command::Send(request) => {
+                    if send_request::spawn(request).is_err() {
+                        AppError::Internal(InternalError::SpawnFailed(TaskId::SendRequest))
+                            .record();
+                    }
+                }
+                Command::Poll(_) if !cfg!(feature = "twim-ppi") => {
+                    println!("built without the `twim-ppi` feature")
+                }
+                Command::Poll(None) => println!("{}", twimpoll::Report),
+                Command::Poll(Some(0)) => twimpoll::stop(),
+                Command::Poll(Some(ms)) => twimpoll::start(config::get().address, ms),
+                Command::Config => {
+                    let (used, total) = config::slots_used();
+                    println!("config: {}", config::get());
+                    println!("UICR config slots: {} of {} used", used, total);
+                }
+                Command::Version => println!("{}", build_info::Banner),
+                Command::Unknown => println!("unknown command, try `help`"),
+            }
+        }
+        poll_console::spawn_after(mono::Duration::millis(CONSOLE_POLL_MS)).unwrap();
+    }
+
+    #[task]
+    fn report_stats(_: report_stats::Context) {
+        let _span = Span::task(TaskId::ReportStats);
+        info!("{}", stats::Summary);
+        report_stats::spawn_after(mono::Duration::secs(STATS_PERIOD_SECS)).unwrap();
+    }
+
+    #[task(local = [heartbeat_led])]
+    fn heartbeat(ctx: heartbeat::Context) {
+        let _span = Span::task(TaskId::Heartbeat);
+        let led = ctx.local.heartbeat_led;
+        if led.is_set_low().unwrap() {
+            led.set_high().unwrap();
+        } else {
+            led.set_low().unwrap();
+        }
+        STATS.alive.inc();
+        reserve_codes();
+        #[cfg(feature = "ble")]
+        crate::ble::refresh();
+        heartbeat::spawn_after(mono::Duration::millis(HEARTBEAT_MS)).unwrap();
+    }
+
+    #[task(local = [telemetry])]
+    fn send_telemetry(ctx: send_telemetry::Context) {
+        let _span = Span::task(TaskId::SendTelemetry);
+        if let Some(telemetry) = ctx.local.telemetry {
+            // The baud rate is only accurate on the crystal.
+            let _hfxo = Hfxo::request();
+            telemetry.send();
+            send_telemetry::spawn_after(mono::Duration::millis(TELEMETRY_PERIOD_MS)).unwrap();
+        }
+    }
+
+    #[task(local = [bridge], shared = [twim])]
+    fn bridge_cmd(ctx: bridge_cmd::Context, line: Line) {
+        let _span = Span::task(TaskId::BridgeCmd);
+        if let Some(bridge) = ctx.local.bridge {
+            // For the TWIM timing and the baud rate of the reply.
+            let _hfxo = Hfxo::request();
+            bridge.run(ctx.shared.twim, &line);
+        }
+    }
+
+    #[task]
+    fn end_burst(_: end_burst::Context) {
+        let _span = Span::task(TaskId::EndBurst);
+        if let Some(left) = burst::try_end() {
+            end_burst::spawn_after(mono::Duration::from_ticks(left as u64)).ok();
+        }
+    }
+
+    #[task]
+    fn calibrate_lfclk(_: calibrate_lfclk::Context) {
+        let _span = Span::task(TaskId::CalibrateLfclk);
+        clock::calibrate();
+        calibrate_lfclk::spawn_after(mono::Duration::secs(CALIBRATION_PERIOD_SECS)).unwrap();
+    }
+
+    // Sets the emulated DS3231's alarm flags, at each of its seconds.
+    #[task]
+    fn tick_ds3231(_: tick_ds3231::Context) {
+        let _span = Span::task(TaskId::TickDs3231);
+        let ms = ds3231::tick();
+        tick_ds3231::spawn_after(mono::Duration::millis(ms)).unwrap();
+    }
+
+    // Measures the emulated INA219's voltages when due, see `ina219`.
+    #[task]
+    fn sample_ina219(_: sample_ina219::Context) {
+        let _span = Span::task(TaskId::SampleIna219);
+        ina219::sample();
+        sample_ina219::spawn_after(mono::Duration::millis(ina219::PERIOD_MS)).unwrap();
+    }
+
+    #[task]
+    fn check_temp(_: check_temp::Context) {
+        let _span = Span::task(TaskId::CheckTemp);
+        thermal::check();
+        check_temp::spawn_after(mono::Duration::secs(thermal::PERIOD_SECS)).unwrap();
+    }
+
+    #[task(local = [blinker])]
+    fn blink_led(ctx: blink_led::Context) {
+        let _span = Span::task(TaskId::BlinkLed);
+        if let Some(delay_ms) = ctx.local.blinker.step() {
+            // A new error may have restarted the pattern in the meantime.
+            blink_led::spawn_after(mono::Duration::millis(delay_ms)).ok();
+        }
+    }
+
+    // Shows `class` on the error LED.
+    fn indicate(class: ErrorClass) {
+        if blink::report(class) {
+            // Already scheduled if the LED was just cleared mid-pattern.
+            blink_led::spawn().ok();
+        }
+    }
+
+    // True if WRITE, READ or STOPPED is pending. The other TWIS events only
+    // raise interrupts for `twislog`.
+    fn twis_event_pending() -> bool {
+        // SAFETY: read-only access to event flags of the TWIS instance owned by `transfer`.
+        let twis = unsafe { &*TWIS0::ptr() };
+        twis.events_write.read().bits() != 0
+            || twis.events_read.read().bits() != 0
+            || twis.events_stopped.read().bits() != 0
+    }
+
+    // TWIS without a DMA transfer cannot serve the bus any more.
+    fn twis_dma_failed(error: AppError) -> ! {
+        error.record();
+        regsnap::log("twis dma failed");
+        panic!("{}", error)
+    }
+
+    // Forces a stuck transfer to end so TWIS can be re-armed. Whatever it
+    // moved so far is dropped.
+    fn abort(transfer: Transfer<TWIS0, DmaBuffer>) -> (DmaBuffer, Twis<TWIS0>) {
+        // SAFETY: triggers STOP on the TWIS instance owned by `transfer`.
+        unsafe { (*TWIS0::ptr()).tasks_stop.write(|w| w.bits(1)) };
+        let (buf, twis) = transfer.wait();
+        twis.reset_event(TwiEvent::Stopped);
+        (buf, twis)
+    }
+
+    // The HAL can only enable TWIS once. Disabled, it does not answer the
+    // bus at all; the DMA buffer stays idle.
+    fn set_twis_enabled(on: bool) {
+        // SAFETY: ENABLE of the TWIS instance owned by `transfer`, which is
+        // idle whenever this is called, unless ENABLE drops in the middle of
+        // a transaction. `on_twis` then aborts that transfer at the next
+        // address match.
+        let twis = unsafe { &*TWIS0::ptr() };
+        if on {
+            twis.enable.write(|w| w.enable().enabled());
+        } else {
+            twis.enable.write(|w| w.enable().disabled());
+        }
+    }
+
+    // Answers at `address` as well, as TWIS address 1, or stops answering
+    // there for 0.
+    fn set_twis_address1(address: u8) {
+        // SAFETY: ADDRESS and CONFIG of the TWIS instance owned by `transfer`.
+        // A transaction in progress has already matched, the change applies
+        // from the next.
+        let twis = unsafe { &*TWIS0::ptr() };
+        twis.address[1].write(|w| unsafe { w.address().bits(address) });
+        twis.config.modify(|_, w| w.address1().bit(address != 0));
+    }
+
+    // The device emulated at TWIS address 1, if any.
+    fn address1_device() -> Option<&'static str> {
+        if cfg!(feature = "gpio-expander") {
+            Some("GPIO expander")
+        } else if cfg!(feature = "smbus-arp") {
+            Some("SMBus ARP")
+        } else if cfg!(feature = "pmbus") {
+            Some("PMBus device")
+        } else if cfg!(feature = "ipmi-ssif") {
+            Some("SSIF BMC")
+        } else if cfg!(feature = "mctp") {
+            Some("MCTP endpoint")
+        } else if cfg!(feature = "bme280") {
+            Some("BME280")
+        } else if cfg!(feature = "mpu6050") {
+            Some("MPU-6050")
+        } else if cfg!(feature = "ds3231") {
+            Some("DS3231")
+        } else if cfg!(feature = "ina219") {
+            Some("INA219")
+        } else {
+            None
+        }
+    }
+
+    // A WRITE at TWIS address 1 when the repeater is off.
+    fn address1_write(data: &[u8]) {
+        if arp::is_active() {
+            // The register map moves to the address assigned or taken back.
+            if let Some(address) = arp::write(data) {
+                // SAFETY: as for `set_twis_address1`.
+                let twis = unsafe { &*TWIS0::ptr() };
+                twis.address[0].write(|w| unsafe { w.address().bits(address) });
+            }
+        } else if pmbus::is_active() {
+            pmbus::write(data);
+        } else if ssif::is_active() {
+            ssif::write(data);
+        } else if mctp::is_active() {
+            if mctp::write(data) && send_mctp::spawn().is_err() {
+                AppError::Internal(InternalError::SpawnFailed(TaskId::SendMctp)).record();
+            }
+        } else if bme280::is_active() {
+            bme280::write(data);
+        } else if mpu6050::is_active() {
+            mpu6050::write(data);
+        } else if ds3231::is_active() {
+            ds3231::write(data);
+        } else if ina219::is_active() {
+            ina219::write(data);
+        } else if let Some(&value) = data.last() {
+            // Like the PCF8574, the last byte of a WRITE wins.
+            expander::write(value);
+        }
+    }
+
+    // A READ at TWIS address 1 when the repeater is off.
+    fn address1_read(buf: &mut [u8]) {
+        if arp::is_active() {
+            arp::read(buf);
+        } else if pmbus::is_active() {
+            pmbus::read(buf);
+        } else if ssif::is_active() {
+            ssif::read(buf);
+        } else if mctp::is_active() {
+            // Nothing is read in MCTP over SMBus.
+            buf.fill(0xff);
+        } else if bme280::is_active() {
+            bme280::read(buf);
+        } else if mpu6050::is_active() {
+            mpu6050::read(buf);
+        } else if ds3231::is_active() {
+            ds3231::read(buf);
+        } else if ina219::is_active() {
+            ina219::read(buf);
+        } else {
+            buf.fill(expander::read());
+        }
+    }
+
+    // Sets or clears a reason to keep TWIS off, see `busgate`.
+    fn hold_twis(reason: Hold, held: bool) {
+        // Together, so a higher priority `hold_twis` cannot slip in between.
+        lock::free(|_| set_twis_enabled(busgate::hold(reason, held)));
+    }
+
+    // The HAL only exposes the RX amount.
+    fn twis_tx_amount() -> u32 {
+        // SAFETY: read-only access to a register of the TWIS instance owned by `transfer`.
+        unsafe { (*TWIS0::ptr()).txd.amount.read().bits() }
+    }
+
+    // Whether the last address match was TWIS address 1, the repeater
+    // target's or the one of the `address1_device`.
+    fn twis_matched_address1() -> bool {
+        // SAFETY: as for `twis_tx_amount`.
+        unsafe { (*TWIS0::ptr()).match_.read().bits() == 1 }
+    }
+
+    // Whether the button raised the PORT event, clearing its LATCH bit.
+    fn take_button_latch() -> bool {
+        // Only the button's LATCH bit is touched, from its handler.
+        let (port, bit) = board::port(board::BUTTON);
+        let latched = port.latch.read().bits() & bit != 0;
+        // SAFETY: LATCH bits are cleared by writing 1, the others are kept.
+        port.latch.write(|w| unsafe { w.bits(bit) });
+        latched
     }
 
     #[idle]
     fn idle(_cx: idle::Context) -> ! {
-        rprintln!("idle");
+        info!("idle");
 
+        // The SoftDevice is enabled from here, with interrupts on, and its
+        // event loop sleeps the same way.
+        #[cfg(feature = "ble")]
+        crate::ble::run(IDLE_STRATEGY);
+        #[cfg(not(feature = "ble"))]
         loop {
-            // Now Wait For Interrupt is used instead of a busy-wait loop
-            // to allow MCU to sleep between interrupts
+            // Now Wait For Interrupt (by default) is used instead of a
+            // busy-wait loop to allow MCU to sleep between interrupts
             // https://developer.arm.com/documentation/ddi0406/c/Application-Level-Architecture/Instruction-Details/Alphabetical-list-of-instructions/WFI
-            rtic::export::wfi()
+            // With no `Hfxo` held and no peripheral requesting it, the HFCLK
+            // stops while asleep and only the LFCLK/RTC keep running.
+            crate::systrace::idle();
+            power::wait(IDLE_STRATEGY);
+        }
+    }
+}
+
+// Handlers of the interrupts that not every `chip` has. RTIC 1 sets up the
+// interrupt of a hardware task even when `#[cfg]` leaves the task out, so
+// these are bound here instead, at priority 2 like the tasks, and unmasked
+// by `chip::unmask` in `init`. They share no resources with the tasks, and
+// `spawn` works from any context.
+#[cfg(not(feature = "nrf52832"))]
+#[interrupt]
+fn USBD() {
+    let _span = Span::isr(TaskId::OnUsbd);
+    usbconsole::on_interrupt();
+}
+
+#[cfg(feature = "nrf52840")]
+#[interrupt]
+fn QSPI() {
+    let _span = Span::isr(TaskId::OnQspi);
+    qspiflash::on_interrupt();
+}
+
+// Line assembly of the UARTE1 handler.
+#[cfg(not(feature = "nrf52832"))]
+static mut RECEIVER: Receiver = Receiver::new();
+
+#[cfg(not(feature = "nrf52832"))]
+#[interrupt]
+fn UARTE1() {
+    let _span = Span::isr(TaskId::OnBridgeRx);
+    // SAFETY: only used here, and the handler does not preempt itself.
+    let receiver = unsafe { &mut *addr_of_mut!(RECEIVER) };
+    if let Some(line) = receiver.on_interrupt() {
+        if app::bridge_cmd::spawn(line).is_err() {
+            AppError::Internal(InternalError::SpawnFailed(TaskId::BridgeCmd)).record();
         }
     }
 }
 
 #[inline(never)]
 #[panic_handler]
+#[cfg_attr(not(feature = "rtt"), allow(unused_variables))]
 fn panic(info: &PanicInfo) -> ! {
     cortex_m::interrupt::disable();
-    rprintln!("{}", info);
-    loop {}
+    #[cfg(feature = "rtt")]
+    {
+        postmortem::capture(postmortem::Reason::Panic, 0, format_args!("{}", info));
+        logging::write_panic(format_args!("{}", info));
+    }
+    // Nobody reads the message, and formatting `info` would pull in the
+    // panic message machinery.
+    #[cfg(not(feature = "rtt"))]
+    postmortem::capture(postmortem::Reason::Panic, 0, format_args!("panic"));
+    blink::panic_loop()
 }
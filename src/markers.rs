@@ -0,0 +1,80 @@
+// Power-profiling markers on GPIO (`ppk-markers` feature).
+//
+// Three pins follow what the firmware is doing, so a current trace taken
+// with the digital inputs of a Nordic Power Profiler Kit II (or any logic
+// analyzer) can be lined up with it:
+//
+//   P0.04 -> D0  high while a task or interrupt handler runs
+//   P0.05 -> D1  high while a TWIS DMA transfer is armed
+//   P0.06 -> D2  high while the idle loop sleeps
+//
+// Writes go straight to OUTSET/OUTCLR, so a marker costs a single store.
+
+use {
+    crate::hal::{
+        gpio::{Output, Pin, PushPull},
+        pac::P0,
+    },
+    core::sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+#[derive(Clone, Copy)]
+pub enum Marker {
+    Active = 0,
+    Dma = 1,
+    Sleep = 2,
+}
+
+// Pin masks on P0, zero until `init`, which makes every marker a no-op.
+static MASKS: [AtomicU32; 3] = [const { AtomicU32::new(0) }; 3];
+
+// Running tasks, `Active` is high while non-zero.
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Takes the marker pins, already configured as low outputs on P0.
+#[cfg_attr(not(feature = "ppk-markers"), allow(dead_code))]
+pub fn init(
+    active: Pin<Output<PushPull>>,
+    dma: Pin<Output<PushPull>>,
+    sleep: Pin<Output<PushPull>>,
+) {
+    for (marker, pin) in [
+        (Marker::Active, active),
+        (Marker::Dma, dma),
+        (Marker::Sleep, sleep),
+    ] {
+        MASKS[marker as usize].store(1 << pin.pin(), Ordering::Relaxed);
+    }
+}
+
+#[inline(always)]
+pub fn set(marker: Marker, high: bool) {
+    if !cfg!(feature = "ppk-markers") {
+        return;
+    }
+    let mask = MASKS[marker as usize].load(Ordering::Relaxed);
+    // SAFETY: OUTSET/OUTCLR only change the bits written, and the marker
+    // pins are not used otherwise.
+    let p0 = unsafe { &*P0::ptr() };
+    if high {
+        p0.outset.write(|w| unsafe { w.bits(mask) });
+    } else {
+        p0.outclr.write(|w| unsafe { w.bits(mask) });
+    }
+}
+
+/// A task started, see `systrace::Span`.
+#[inline(always)]
+pub fn enter() {
+    if cfg!(feature = "ppk-markers") && ACTIVE.fetch_add(1, Ordering::Relaxed) == 0 {
+        set(Marker::Active, true);
+    }
+}
+
+/// A task finished.
+#[inline(always)]
+pub fn exit() {
+    if cfg!(feature = "ppk-markers") && ACTIVE.fetch_sub(1, Ordering::Relaxed) == 1 {
+        set(Marker::Active, false);
+    }
+}
@@ -0,0 +1,290 @@
+// MCTP over SMBus, an endpoint at TWIS address 1 (`mctp` feature).
+//
+// Every MCTP packet is an SMBus block write to the endpoint it is for, with
+// a PEC, see `smbus`:
+//
+//   0x0f, byte count, source address << 1 | 1,
+//   header version 0x01, destination EID, source EID,
+//   SOM | EOM | sequence (2 bits) | TO | tag (3 bits), payload..., PEC
+//
+// A message starts with a SOM packet and ends with an EOM one, the
+// sequence number counting up by one from packet to packet, modulo 4, with
+// the same source and tag for all of them. A packet out of sequence drops
+// the message. TWIS takes WRITEs of up to `regmap::BUF_LEN` bytes, so a
+// packet to this endpoint carries at most 24 bytes of payload; a message
+// may be up to `MSG_LEN`.
+//
+// Only MCTP control messages (type 0) are understood:
+//
+//   0x01  Set Endpoint ID                set or force the EID, see below
+//   0x02  Get Endpoint ID                the EID, simple endpoint, dynamic
+//   0x04  Get MCTP Version Support       1.3.1 for the base spec and control
+//   0x05  Get Message Type Support       none beyond control
+//
+// The EID is 0, the null EID, until the bus owner assigns one with Set
+// Endpoint ID; it is volatile. Packets for the null EID, the broadcast EID
+// or ours are taken. There is no READ in MCTP over SMBus: the response goes
+// back as a block write of our own to the requester's address, `send`
+// on TWIM, so TWIM has to be on the same bus for it to arrive.
+
+use {
+    crate::{
+        controller,
+        error::AppError,
+        hal::{pac::TWIM1, twim::Twim},
+        lock, smbus,
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const ADDRESS: u8 = 0x1d;
+/// Longest message, reassembled or sent.
+pub const MSG_LEN: usize = 64;
+// Payload of a packet sent, the baseline transmission unit.
+const MTU: usize = 64;
+
+// SMBus command code of MCTP packets.
+const COMMAND: u8 = 0x0f;
+const HEADER_VERSION: u8 = 0x01;
+const NULL_EID: u8 = 0x00;
+const BROADCAST_EID: u8 = 0xff;
+
+// Bits of the flags byte.
+const SOM: u8 = 1 << 7;
+const EOM: u8 = 1 << 6;
+const TO: u8 = 1 << 3;
+
+const CONTROL: u8 = 0x00;
+// Bits of the control message header.
+const RQ: u8 = 1 << 7;
+const DATAGRAM: u8 = 1 << 6;
+
+const SET_ENDPOINT_ID: u8 = 0x01;
+const GET_ENDPOINT_ID: u8 = 0x02;
+const GET_VERSION_SUPPORT: u8 = 0x04;
+const GET_MESSAGE_TYPE_SUPPORT: u8 = 0x05;
+
+// Completion codes.
+const SUCCESS: u8 = 0x00;
+const ERROR_INVALID_DATA: u8 = 0x02;
+const ERROR_INVALID_LENGTH: u8 = 0x03;
+const ERROR_UNSUPPORTED_CMD: u8 = 0x05;
+const VERSION_NOT_SUPPORTED: u8 = 0x80;
+
+// 1.3.1 in the version number format.
+const VERSION: [u8; 4] = [0xf1, 0xf3, 0xf1, 0x00];
+
+// A message being reassembled or sent.
+#[derive(Clone, Copy)]
+struct Message {
+    // The SMBus address and EID of the other end.
+    address: u8,
+    eid: u8,
+    // Tag and TO as in the flags byte.
+    tag: u8,
+    data: [u8; MSG_LEN],
+    len: usize,
+}
+
+impl Message {
+    const EMPTY: Self = Self {
+        address: 0,
+        eid: 0,
+        tag: 0,
+        data: [0; MSG_LEN],
+        len: 0,
+    };
+}
+
+struct Mctp {
+    eid: u8,
+    incoming: Message,
+    // The sequence number of the next packet, while reassembling.
+    next: Option<u8>,
+    response: Option<Message>,
+}
+
+static MCTP: Mutex<RefCell<Mctp>> = Mutex::new(RefCell::new(Mctp {
+    eid: NULL_EID,
+    incoming: Message::EMPTY,
+    next: None,
+    response: None,
+}));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "mctp")
+}
+
+/// Takes a WRITE at `ADDRESS`, one packet. True if a response is ready for
+/// `send`.
+pub fn write(data: &[u8]) -> bool {
+    let count = data.get(1).map_or(0, |&count| count as usize);
+    // The PEC is not optional here.
+    let packet = match smbus::strip_pec(ADDRESS, data, 2 + count) {
+        Ok(packet) if packet.len() < data.len() => packet,
+        Ok(_) => {
+            warn!("MCTP packet without a PEC, dropped");
+            return false;
+        }
+        Err(error) => {
+            warn!("MCTP packet dropped: {}", error);
+            AppError::from(error).record();
+            return false;
+        }
+    };
+    let [COMMAND, _, source, version, destination, eid, flags, payload @ ..] = packet else {
+        return false;
+    };
+    if version & 0x0f != HEADER_VERSION || source & 1 == 0 {
+        return false;
+    }
+    lock::free(|cs| {
+        let mut mctp = MCTP.borrow(cs).borrow_mut();
+        if ![NULL_EID, BROADCAST_EID, mctp.eid].contains(destination) {
+            return false;
+        }
+        let sequence = flags >> 4 & 3;
+        let tag = flags & (TO | 7);
+        if flags & SOM != 0 {
+            mctp.incoming = Message {
+                address: source >> 1,
+                eid: *eid,
+                tag,
+                ..Message::EMPTY
+            };
+        } else {
+            let incoming = &mctp.incoming;
+            let same =
+                incoming.address == source >> 1 && incoming.eid == *eid && incoming.tag == tag;
+            if mctp.next != Some(sequence) || !same {
+                if mctp.next.take().is_some() {
+                    warn!("MCTP packet out of sequence, message dropped");
+                }
+                return false;
+            }
+        }
+        let incoming = &mut mctp.incoming;
+        if incoming.len + payload.len() > MSG_LEN {
+            warn!("MCTP message too long, dropped");
+            mctp.next = None;
+            return false;
+        }
+        incoming.data[incoming.len..][..payload.len()].copy_from_slice(payload);
+        incoming.len += payload.len();
+        if flags & EOM == 0 {
+            mctp.next = Some((sequence + 1) & 3);
+            return false;
+        }
+        mctp.next = None;
+        let request = mctp.incoming;
+        let response = handle(&mut mctp, &request);
+        mctp.response = response;
+        response.is_some()
+    })
+}
+
+// Carries out a complete message, returns the response if there is one.
+fn handle(mctp: &mut Mctp, request: &Message) -> Option<Message> {
+    // Only a request with the tag owner bit set has a response.
+    if request.tag & TO == 0 {
+        return None;
+    }
+    let [CONTROL, header, command, data @ ..] = &request.data[..request.len] else {
+        return None;
+    };
+    if header & RQ == 0 || header & DATAGRAM != 0 {
+        return None;
+    }
+    let mut response = Message {
+        address: request.address,
+        eid: request.eid,
+        tag: request.tag & !TO,
+        ..Message::EMPTY
+    };
+    let body = &mut response.data[4..];
+    let (code, len) = match (*command, data) {
+        (SET_ENDPOINT_ID, [operation, eid]) => match operation & 3 {
+            // Set and force alike, there is only one bus owner here.
+            0 | 1 if ![NULL_EID, BROADCAST_EID].contains(eid) => {
+                mctp.eid = *eid;
+                info!("MCTP EID {:#04x} assigned", eid);
+                // Accepted, no EID pool.
+                body[..3].copy_from_slice(&[0x00, *eid, 0]);
+                (SUCCESS, 3)
+            }
+            // Set Discovered Flag, nothing to do without discovery.
+            3 => {
+                body[..3].copy_from_slice(&[0x00, mctp.eid, 0]);
+                (SUCCESS, 3)
+            }
+            // Reset asks for a static EID, which there is none of.
+            _ => (ERROR_INVALID_DATA, 0),
+        },
+        (GET_ENDPOINT_ID, []) => {
+            // Simple endpoint with a dynamic EID.
+            body[..3].copy_from_slice(&[mctp.eid, 0x00, 0]);
+            (SUCCESS, 3)
+        }
+        (GET_VERSION_SUPPORT, [0xff | CONTROL]) => {
+            body[0] = 1;
+            body[1..5].copy_from_slice(&VERSION);
+            (SUCCESS, 5)
+        }
+        (GET_VERSION_SUPPORT, [_]) => (VERSION_NOT_SUPPORTED, 0),
+        (GET_MESSAGE_TYPE_SUPPORT, []) => {
+            body[0] = 0;
+            (SUCCESS, 1)
+        }
+        (SET_ENDPOINT_ID | GET_ENDPOINT_ID | GET_VERSION_SUPPORT | GET_MESSAGE_TYPE_SUPPORT, _) => {
+            (ERROR_INVALID_LENGTH, 0)
+        }
+        _ => (ERROR_UNSUPPORTED_CMD, 0),
+    };
+    response.data[..4].copy_from_slice(&[CONTROL, header & !RQ, *command, code]);
+    response.len = 4 + len;
+    Some(response)
+}
+
+/// Sends the response `write` made ready, as packets of up to `MTU` bytes.
+pub fn send(twim: &mut Twim<TWIM1>) {
+    let Some((response, eid)) = lock::free(|cs| {
+        let mut mctp = MCTP.borrow(cs).borrow_mut();
+        Some((mctp.response.take()?, mctp.eid))
+    }) else {
+        return;
+    };
+    let chunks = response.data[..response.len].chunks(MTU);
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.enumerate() {
+        let mut flags = (i as u8 & 3) << 4 | response.tag;
+        if i == 0 {
+            flags |= SOM;
+        }
+        if i == last {
+            flags |= EOM;
+        }
+        let mut packet = [0; 8 + MTU];
+        let header = [
+            COMMAND,
+            5 + chunk.len() as u8,
+            ADDRESS << 1 | 1,
+            HEADER_VERSION,
+            response.eid,
+            eid,
+            flags,
+        ];
+        packet[..7].copy_from_slice(&header);
+        packet[7..][..chunk.len()].copy_from_slice(chunk);
+        let len = 7 + chunk.len();
+        packet[len] = smbus::write_pec(response.address, &packet[..len]);
+        if let Err(error) = controller::write(twim, response.address, &packet[..len + 1]) {
+            warn!(
+                "MCTP response to {:#04x} not sent: {}",
+                response.address, error
+            );
+            return;
+        }
+    }
+}
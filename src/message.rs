@@ -0,0 +1,372 @@
+// Typed controller requests at the MESSAGE register.
+//
+// A WRITE of `[MESSAGE, message...]` carries a version byte and a
+// `request::Request` in the postcard encoding (see `wire`), and a READ from
+// MESSAGE returns the version and the `Reply` to the last message, in the
+// same encoding. The `cbor` and `protobuf` features add CBOR and Protocol
+// Buffers, told apart by the first byte, see `Encoding`. Both ends use these
+// definitions, `controller::send` on the TWIM side, so they are the
+// protocol; a host with serde declares the same types:
+//
+//   struct Message { version: u8, request: Request }      version 1
+//   enum Request {
+//       Sleep,
+//       WriteConfig { address: u8, frequency_step: u8, flags: u8 },
+//       FactoryReset,
+//       SleepFor(u16),
+//       Sample { channel: u8, count: u8 },
+//       Store,
+//       FlashRead(u32),
+//       FlashProgram { address: u32, data: Vec<u8> },
+//       FlashErase { address: u32, size: EraseSize },   Sector, Block, Chip
+//       Bridge { target: u8, fetch_len: u8 },
+//       Capture(u16),
+//       DfuBegin { size: u32, crc: u32 },
+//       DfuWrite { offset: u32, crc: u16, data: Vec<u8> },
+//       DfuVerify,
+//       DfuActivate,
+//       Bootloader(u8),
+//       Describe,
+//       SyncTime { seconds: u32, millis: u16 },
+//   }
+//   struct Answer { version: u8, reply: Reply }
+//   enum Reply { None, Accepted(u8), Refused(u16) }
+//
+// So the variant index of a request is its COMMAND opcode minus 1, and the
+// arguments are checked by `request::check` as for COMMAND. A reply is
+// `Accepted` with the opcode once the message decoded and passed the
+// checks, whether or not carrying it out then succeeds, or `Refused` with
+// the `ProtocolError` code. A version other than `VERSION` is refused.
+
+use {
+    crate::{
+        auth, cbor,
+        config::Config,
+        dfu,
+        error::ProtocolError,
+        lock, protobuf,
+        qspiflash::{self, EraseSize},
+        request::{self, Request},
+        rolling, unlock,
+        wire::{self, Decoder, Encoder, Wire},
+    },
+    core::{cell::RefCell, fmt},
+    cortex_m::interrupt::Mutex,
+};
+
+pub const VERSION: u8 = 1;
+
+/// Longest answer: a protobuf `Reply` refusing with a 16-bit code.
+pub const REPLY_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// No message since boot.
+    None,
+    /// Decoded and checked, with the opcode of the request.
+    Accepted(u8),
+    /// Refused, with the `ProtocolError` code.
+    Refused(u16),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Postcard,
+    Cbor,
+    Protobuf,
+}
+
+impl Encoding {
+    /// What `controller::send` uses: the one of the features, if any.
+    pub const SEND: Encoding = if cfg!(feature = "protobuf") {
+        Encoding::Protobuf
+    } else if cfg!(feature = "cbor") {
+        Encoding::Cbor
+    } else {
+        Encoding::Postcard
+    };
+
+    /// The encoding of `data`, by its first byte: a CBOR array header, the
+    /// tag of protobuf field 1, or else the postcard version.
+    pub fn of(data: &[u8]) -> Encoding {
+        match data.first() {
+            Some(&byte) if cfg!(feature = "cbor") && cbor::is_array(byte) => Encoding::Cbor,
+            Some(&protobuf::FIRST_BYTE) if cfg!(feature = "protobuf") => Encoding::Protobuf,
+            _ => Encoding::Postcard,
+        }
+    }
+
+    fn encode(self, value: &impl Wire, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+        fn run(value: &impl Wire, encoder: &mut impl Encoder) -> Result<usize, ProtocolError> {
+            value.encode(encoder)?;
+            encoder.finish()
+        }
+        match self {
+            Encoding::Postcard => run(value, &mut wire::Writer::new(buf)),
+            Encoding::Cbor => run(value, &mut cbor::Writer::new(buf)),
+            Encoding::Protobuf => run(value, &mut protobuf::Writer::new(buf)),
+        }
+    }
+
+    // Decodes a `T`; all of `data` unless `padded`.
+    fn decode<T: Wire>(self, data: &[u8], padded: bool) -> Result<T, ProtocolError> {
+        fn run<T: Wire>(decoder: &mut impl Decoder, padded: bool) -> Result<T, ProtocolError> {
+            let value = T::decode(decoder)?;
+            if !padded {
+                decoder.finish()?;
+            }
+            Ok(value)
+        }
+        match self {
+            Encoding::Postcard => run(&mut wire::Reader::new(data), padded),
+            Encoding::Cbor => run(&mut cbor::Reader::new(data), padded),
+            Encoding::Protobuf => run(&mut protobuf::Reader::new(data), padded),
+        }
+    }
+}
+
+// The encoded answer to the last message and its length, as MESSAGE
+// reads.
+static ANSWER: Mutex<RefCell<([u8; REPLY_LEN], usize)>> =
+    Mutex::new(RefCell::new(([VERSION, 0, 0, 0, 0, 0, 0, 0], 2)));
+
+/// Decodes a message written to MESSAGE and stores the reply to it.
+pub fn receive(data: &[u8]) -> Result<Request, ProtocolError> {
+    let encoding = Encoding::of(data);
+    let res = auth::verify(data)
+        .and_then(|data| encoding.decode::<Request>(data, false))
+        .and_then(|request| request::check(&request).map(|()| request))
+        .and_then(|request| unlock::admit(&request).map(|()| request))
+        .and_then(|request| rolling::admit(&request).map(|()| request));
+    let reply = match res {
+        Ok(request) => Reply::Accepted(request.opcode()),
+        Err(error) => Reply::Refused(error.code()),
+    };
+    store_answer(&reply, encoding);
+    res
+}
+
+/// Refuses a message that never got to `receive`, in postcard.
+pub fn refuse(error: ProtocolError) {
+    store_answer(&Reply::Refused(error.code()), Encoding::Postcard);
+}
+
+fn store_answer(reply: &Reply, encoding: Encoding) {
+    let mut answer = [0; REPLY_LEN];
+    if let Ok(len) = encoding.encode(reply, &mut answer) {
+        lock::free(|cs| *ANSWER.borrow(cs).borrow_mut() = (answer, len));
+    }
+}
+
+/// Byte `offset` of the answer to the last message.
+pub fn answer(offset: usize) -> u8 {
+    lock::free(|cs| ANSWER.borrow(cs).borrow().0[offset])
+}
+
+/// The answer to the last message, without the padding.
+pub fn last_answer() -> ([u8; REPLY_LEN], usize) {
+    lock::free(|cs| *ANSWER.borrow(cs).borrow())
+}
+
+// The variant of the next message, if it has our version.
+fn variant(decoder: &mut impl Decoder) -> Result<u32, ProtocolError> {
+    match decoder.message()? {
+        (VERSION, variant) => Ok(variant),
+        (version, _) => Err(ProtocolError::Version(version)),
+    }
+}
+
+/// Encodes `request` as a message into `buf` in `Encoding::SEND`, returns
+/// its length.
+pub fn encode(request: &Request, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+    Encoding::SEND.encode(request, buf)
+}
+
+/// Decodes an answer read from MESSAGE, padding after it is ignored.
+pub fn decode_reply(data: &[u8]) -> Result<Reply, ProtocolError> {
+    Encoding::of(data).decode(data, true)
+}
+
+impl Wire for Request {
+    fn encode(&self, encoder: &mut impl Encoder) -> Result<(), ProtocolError> {
+        let fields = match self {
+            Request::Sleep
+            | Request::FactoryReset
+            | Request::Store
+            | Request::DfuVerify
+            | Request::DfuActivate
+            | Request::Describe => 0,
+            Request::SleepFor(_)
+            | Request::Capture(_)
+            | Request::FlashRead(_)
+            | Request::Bootloader(_) => 1,
+            Request::WriteConfig(_) | Request::DfuWrite { .. } => 3,
+            _ => 2,
+        };
+        encoder.message(VERSION, self.opcode() as u32 - 1, fields)?;
+        match *self {
+            Request::Sleep
+            | Request::FactoryReset
+            | Request::Store
+            | Request::DfuVerify
+            | Request::DfuActivate
+            | Request::Describe => Ok(()),
+            Request::WriteConfig(config) => {
+                encoder.u8(config.address)?;
+                encoder.u8(config.frequency_step)?;
+                encoder.u8(config.flags)
+            }
+            Request::SleepFor(value) | Request::Capture(value) => encoder.uint(value as u32),
+            Request::Sample { channel, count } => {
+                encoder.u8(channel)?;
+                encoder.u8(count)
+            }
+            Request::FlashRead(address) => encoder.uint(address),
+            Request::Bootloader(magic) => encoder.u8(magic),
+            Request::FlashProgram { address, data, len } => {
+                encoder.uint(address)?;
+                encoder.bytes(&data[..len as usize])
+            }
+            Request::FlashErase { address, size } => {
+                encoder.uint(address)?;
+                encoder.uint(size as u32)
+            }
+            Request::Bridge { target, fetch_len } => {
+                encoder.u8(target)?;
+                encoder.u8(fetch_len)
+            }
+            Request::DfuBegin { size, crc } => {
+                encoder.uint(size)?;
+                encoder.uint(crc)
+            }
+            Request::SyncTime { seconds, millis } => {
+                encoder.uint(seconds)?;
+                encoder.uint(millis as u32)
+            }
+            Request::DfuWrite {
+                offset,
+                crc,
+                data,
+                len,
+            } => {
+                encoder.uint(offset)?;
+                encoder.uint(crc as u32)?;
+                encoder.bytes(&data[..len as usize])
+            }
+        }
+    }
+
+    fn decode(decoder: &mut impl Decoder) -> Result<Self, ProtocolError> {
+        let opcode = match u8::try_from(variant(decoder)?) {
+            Ok(index) if index < u8::MAX => index + 1,
+            _ => return Err(ProtocolError::Malformed),
+        };
+        Ok(match opcode {
+            request::SLEEP => Request::Sleep,
+            request::WRITE_CONFIG => Request::WriteConfig(Config {
+                address: decoder.u8()?,
+                frequency_step: decoder.u8()?,
+                flags: decoder.u8()?,
+            }),
+            request::FACTORY_RESET => Request::FactoryReset,
+            request::SLEEP_FOR => Request::SleepFor(decoder.u16()?),
+            request::SAMPLE => Request::Sample {
+                channel: decoder.u8()?,
+                count: decoder.u8()?,
+            },
+            request::STORE => Request::Store,
+            request::FLASH_READ => Request::FlashRead(decoder.uint()?),
+            request::FLASH_PROGRAM => {
+                let address = decoder.uint()?;
+                let bytes = decoder.bytes()?;
+                if bytes.len() > qspiflash::PROGRAM_LEN {
+                    return Err(ProtocolError::InvalidArgument(opcode));
+                }
+                let mut data = [0; qspiflash::PROGRAM_LEN];
+                data[..bytes.len()].copy_from_slice(bytes);
+                Request::FlashProgram {
+                    address,
+                    data,
+                    len: bytes.len() as u8,
+                }
+            }
+            request::FLASH_ERASE => {
+                let address = decoder.uint()?;
+                let size = u8::try_from(decoder.uint()?)
+                    .ok()
+                    .and_then(EraseSize::from_code)
+                    .ok_or(ProtocolError::InvalidArgument(opcode))?;
+                Request::FlashErase { address, size }
+            }
+            request::BRIDGE => Request::Bridge {
+                target: decoder.u8()?,
+                fetch_len: decoder.u8()?,
+            },
+            request::CAPTURE => Request::Capture(decoder.u16()?),
+            request::DFU_BEGIN => Request::DfuBegin {
+                size: decoder.uint()?,
+                crc: decoder.uint()?,
+            },
+            request::DFU_WRITE => {
+                let offset = decoder.uint()?;
+                let crc = decoder.u16()?;
+                let bytes = decoder.bytes()?;
+                if bytes.len() > dfu::CHUNK_LEN {
+                    return Err(ProtocolError::InvalidArgument(opcode));
+                }
+                let mut data = [0; dfu::CHUNK_LEN];
+                data[..bytes.len()].copy_from_slice(bytes);
+                Request::DfuWrite {
+                    offset,
+                    crc,
+                    data,
+                    len: bytes.len() as u8,
+                }
+            }
+            request::DFU_VERIFY => Request::DfuVerify,
+            request::DFU_ACTIVATE => Request::DfuActivate,
+            request::BOOTLOADER => Request::Bootloader(decoder.u8()?),
+            request::DESCRIBE => Request::Describe,
+            request::SYNC_TIME => Request::SyncTime {
+                seconds: decoder.uint()?,
+                millis: decoder.u16()?,
+            },
+            _ => return Err(ProtocolError::UnknownOpcode(opcode)),
+        })
+    }
+}
+
+impl Wire for Reply {
+    fn encode(&self, encoder: &mut impl Encoder) -> Result<(), ProtocolError> {
+        match *self {
+            Reply::None => encoder.message(VERSION, 0, 0),
+            Reply::Accepted(opcode) => {
+                encoder.message(VERSION, 1, 1)?;
+                encoder.u8(opcode)
+            }
+            Reply::Refused(code) => {
+                encoder.message(VERSION, 2, 1)?;
+                encoder.uint(code as u32)
+            }
+        }
+    }
+
+    fn decode(decoder: &mut impl Decoder) -> Result<Self, ProtocolError> {
+        match variant(decoder)? {
+            0 => Ok(Reply::None),
+            1 => Ok(Reply::Accepted(decoder.u8()?)),
+            2 => Ok(Reply::Refused(decoder.u16()?)),
+            _ => Err(ProtocolError::Malformed),
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::None => f.write_str("no reply"),
+            Reply::Accepted(opcode) => write!(f, "accepted, opcode {:#04x}", opcode),
+            Reply::Refused(code) => write!(f, "refused, error {:#06x}", code),
+        }
+    }
+}
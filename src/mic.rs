@@ -0,0 +1,173 @@
+// PDM microphone capture, read out over I2C (`pdm-mic` feature).
+//
+// The CAPTURE request records `samples` 16-bit PCM samples from a PDM
+// microphone (CLK P0.30, DIN P0.31, mono, 16.125 kHz) into `CAPTURE` by
+// EasyDMA and returns right away; AUDIO_STATUS shows it busy until the
+// capture has stopped. The recording is then streamed out through the
+// AUDIO_DATA FIFO, TWIS EasyDMA again, each READ continuing where the last
+// one ended:
+//
+//   write [COMMAND, CAPTURE, samples: u16 LE], poll AUDIO_STATUS until not
+//   busy, read AUDIO_DATA until AUDIO_LEN is 0
+//
+// Only the bytes a READ actually clocked out are consumed, so a READ cut
+// short loses nothing. The PDM keeps converting into the buffer it was
+// given until it is stopped, which takes a moment after END; so once the
+// capture has started, the next buffer is pointed at `DISCARD` and the
+// capture cannot be overwritten.
+
+use {
+    crate::{
+        board,
+        hal::pac::PDM,
+        tracebuf::{self, Event},
+    },
+    core::{
+        ptr::addr_of_mut,
+        sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering},
+    },
+};
+
+/// Most samples one capture holds, 254 ms.
+pub const CAPTURE_LEN: usize = 4096;
+
+/// AUDIO_STATUS bits.
+pub const BUSY: u8 = 1 << 0;
+/// The last request was refused: busy, or no microphone.
+pub const REJECTED: u8 = 1 << 1;
+pub const PRESENT: u8 = 1 << 7;
+
+const DISCARD_LEN: usize = 16;
+
+// Targets of the PDM DMA.
+static mut CAPTURE: [i16; CAPTURE_LEN] = [0; CAPTURE_LEN];
+static mut DISCARD: [i16; DISCARD_LEN] = [0; DISCARD_LEN];
+
+static STATUS: AtomicU8 = AtomicU8::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Samples asked for by the running capture.
+static SAMPLES: AtomicU16 = AtomicU16::new(0);
+// Captured bytes, and how many of them the controller has read.
+static LEN: AtomicU16 = AtomicU16::new(0);
+static CURSOR: AtomicU16 = AtomicU16::new(0);
+
+fn regs() -> &'static crate::hal::pac::pdm::RegisterBlock {
+    // SAFETY: PDM is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*PDM::ptr() }
+}
+
+/// Takes `PDM` and connects the microphone pins; it stays disabled until
+/// `start`.
+pub fn init(pdm: PDM) {
+    pdm.psel
+        .clk
+        .write(|w| unsafe { w.pin().bits(board::PDM_CLK as u8).connect().connected() });
+    pdm.psel
+        .din
+        .write(|w| unsafe { w.pin().bits(board::PDM_DIN as u8).connect().connected() });
+    pdm.pdmclkctrl.write(|w| w.freq().default());
+    // The nRF52832 only has a ratio of 64, without the register.
+    #[cfg(not(feature = "nrf52832"))]
+    pdm.ratio.write(|w| w.ratio().ratio64());
+    pdm.mode
+        .write(|w| w.operation().mono().edge().left_falling());
+    pdm.intenset
+        .write(|w| w.started().set().end().set().stopped().set());
+    STATUS.store(PRESENT, Ordering::Relaxed);
+    info!(
+        "PDM microphone on CLK P0.{:02} DIN P0.{:02}",
+        board::PDM_CLK,
+        board::PDM_DIN
+    );
+}
+
+/// Starts capturing `samples` (1..=`CAPTURE_LEN`). Returns false if
+/// refused.
+pub fn start(samples: u16) -> bool {
+    let status = STATUS.load(Ordering::Relaxed);
+    if status & PRESENT == 0 || RUNNING.swap(true, Ordering::Relaxed) {
+        STATUS.fetch_or(REJECTED, Ordering::Relaxed);
+        return false;
+    }
+    STATUS.store(PRESENT | BUSY, Ordering::Relaxed);
+    SAMPLES.store(samples, Ordering::Relaxed);
+    LEN.store(0, Ordering::Relaxed);
+    CURSOR.store(0, Ordering::Relaxed);
+    let pdm = regs();
+    pdm.sample
+        .ptr
+        .write(|w| unsafe { w.sampleptr().bits(addr_of_mut!(CAPTURE) as u32) });
+    pdm.sample
+        .maxcnt
+        .write(|w| unsafe { w.buffsize().bits(samples) });
+    pdm.enable.write(|w| w.enable().enabled());
+    pdm.tasks_start.write(|w| unsafe { w.bits(1) });
+    true
+}
+
+/// Handles STARTED, END and STOPPED.
+pub fn on_interrupt() {
+    let pdm = regs();
+    if pdm.events_started.read().bits() != 0 {
+        pdm.events_started.reset();
+        // PTR and MAXCNT are latched at STARTED: these are for the buffer
+        // after the current one.
+        pdm.sample
+            .ptr
+            .write(|w| unsafe { w.sampleptr().bits(addr_of_mut!(DISCARD) as u32) });
+        pdm.sample
+            .maxcnt
+            .write(|w| unsafe { w.buffsize().bits(DISCARD_LEN as u16) });
+    }
+    if pdm.events_end.read().bits() != 0 {
+        pdm.events_end.reset();
+        if LEN.load(Ordering::Relaxed) == 0 {
+            // The first END is the capture's.
+            LEN.store(SAMPLES.load(Ordering::Relaxed) * 2, Ordering::Relaxed);
+            pdm.tasks_stop.write(|w| unsafe { w.bits(1) });
+        }
+    }
+    if pdm.events_stopped.read().bits() != 0 {
+        pdm.events_stopped.reset();
+        pdm.enable.write(|w| w.enable().disabled());
+        STATUS.fetch_and(!BUSY, Ordering::Relaxed);
+        RUNNING.store(false, Ordering::Relaxed);
+        let samples = LEN.load(Ordering::Relaxed) / 2;
+        tracebuf::record(Event::Captured, 0, samples);
+        info!("PDM: {} samples captured", samples);
+    }
+}
+
+/// AUDIO_STATUS register.
+pub fn status() -> u8 {
+    STATUS.load(Ordering::Relaxed)
+}
+
+/// AUDIO_LEN register: captured bytes not read yet.
+pub fn remaining() -> u16 {
+    if RUNNING.load(Ordering::Relaxed) {
+        return 0;
+    }
+    LEN.load(Ordering::Relaxed) - CURSOR.load(Ordering::Relaxed)
+}
+
+/// Fills `buf` from the AUDIO_DATA FIFO without consuming anything, 0 past
+/// the end of the capture.
+pub fn peek(buf: &mut [u8]) {
+    let len = (remaining() as usize).min(buf.len());
+    let cursor = CURSOR.load(Ordering::Relaxed) as usize;
+    // SAFETY: `remaining` is 0 while the PDM DMA writes it.
+    let capture = unsafe { &*addr_of_mut!(CAPTURE) };
+    for (i, byte) in buf[..len].iter_mut().enumerate() {
+        let at = cursor + i;
+        *byte = capture[at / 2].to_le_bytes()[at % 2];
+    }
+    buf[len..].fill(0);
+}
+
+/// Consumes the `count` bytes of the last `peek` that were read.
+pub fn consume(count: usize) {
+    let count = (remaining() as usize).min(count) as u16;
+    CURSOR.fetch_add(count, Ordering::Relaxed);
+}
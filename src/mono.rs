@@ -0,0 +1,105 @@
+// RTIC monotonic timer backed by an RTC peripheral.
+//
+// The RTC runs off the 32.768 kHz LFCLK, so it keeps counting (and can wake
+// the core) without the HF clock. The hardware counter is only 24 bits wide;
+// overflows are counted in software to extend it to 64 bits.
+//
+// It is RTC1 in every build, RTC0 belongs to the SoftDevice with `ble`.
+
+use {
+    crate::{
+        hal::pac::RTC1,
+        power::{self, WakeReason},
+    },
+    rtic::Monotonic,
+};
+
+/// RTC counter frequency with a prescaler of 0.
+pub const TICK_HZ: u32 = 32_768;
+
+pub type Instant = fugit::TimerInstantU64<TICK_HZ>;
+pub type Duration = fugit::TimerDurationU64<TICK_HZ>;
+
+const COUNTER_MASK: u64 = 0x00ff_ffff;
+
+// The RTC compare event does not fire if CC is set less than 2 ticks ahead of
+// COUNTER; keep a small margin on top of that.
+const MIN_COMPARE_TICKS: u64 = 3;
+
+pub struct MonoRtc {
+    rtc: RTC1,
+    overflow: u64,
+}
+
+impl MonoRtc {
+    /// Starts `rtc` at the full LFCLK rate. LFCLK must already be running.
+    pub fn new(rtc: RTC1) -> Self {
+        rtc.prescaler.write(|w| unsafe { w.bits(0) });
+        rtc.intenset.write(|w| w.compare0().set().ovrflw().set());
+        rtc.tasks_clear.write(|w| unsafe { w.bits(1) });
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+        MonoRtc { rtc, overflow: 0 }
+    }
+
+    fn is_overflow_pending(&self) -> bool {
+        self.rtc.events_ovrflw.read().bits() != 0
+    }
+}
+
+impl Monotonic for MonoRtc {
+    type Instant = Instant;
+    type Duration = Duration;
+
+    // The overflow interrupt must keep running to extend the counter.
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    fn now(&mut self) -> Instant {
+        // Re-read until the counter and the overflow flag belong to the same epoch.
+        let (counter, pending) = loop {
+            let before = self.is_overflow_pending();
+            let counter = self.rtc.counter.read().bits() as u64;
+            if self.is_overflow_pending() == before {
+                break (counter, before);
+            }
+        };
+        let overflow = self.overflow + pending as u64;
+        Instant::from_ticks((overflow << 24) | counter)
+    }
+
+    fn set_compare(&mut self, instant: Instant) {
+        let now = self.now();
+        let ticks = match instant.checked_duration_since(now) {
+            Some(remaining) if remaining.ticks() < MIN_COMPARE_TICKS => {
+                now.ticks() + MIN_COMPARE_TICKS
+            }
+            Some(remaining) if remaining.ticks() <= COUNTER_MASK => instant.ticks(),
+            // Further out than one counter period: the overflow interrupt will
+            // re-arm the compare once the target is in range.
+            Some(_) => 0,
+            None => now.ticks() + MIN_COMPARE_TICKS,
+        };
+        self.rtc.cc[0].write(|w| unsafe { w.bits((ticks & COUNTER_MASK) as u32) });
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.rtc.events_compare[0].write(|w| unsafe { w.bits(0) });
+    }
+
+    fn zero() -> Instant {
+        Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.rtc.tasks_clear.write(|w| w.bits(1));
+        self.rtc.events_ovrflw.write(|w| w.bits(0));
+        self.overflow = 0;
+    }
+
+    fn on_interrupt(&mut self) {
+        power::woke(WakeReason::Timer);
+        if self.is_overflow_pending() {
+            self.rtc.events_ovrflw.write(|w| unsafe { w.bits(0) });
+            self.overflow += 1;
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// An InvenSense MPU-6050 at TWIS address 1 (`mpu6050` feature).
+//
+// The register map of the real part as far as drivers and fusion code use
+// it, with synthetic motion:
+//
+//   0x19       SMPLRT_DIV    rw  sample rate = 8 kHz (1 kHz with DLPF) / (1 + n)
+//   0x1a       CONFIG        rw  DLPF_CFG in bits 0-2
+//   0x1b       GYRO_CONFIG   rw  FS_SEL in bits 3-4, +-250 to +-2000 deg/s
+//   0x1c       ACCEL_CONFIG  rw  AFS_SEL in bits 3-4, +-2 to +-16 g
+//   0x37-0x38  INT_PIN_CFG, INT_ENABLE  rw  kept, no INT pin
+//   0x3a       INT_STATUS    r   DATA_RDY: a sample since the last read of it
+//   0x3b-0x48  accelerometer, temperature, gyroscope, big-endian i16
+//   0x6a       USER_CTRL     rw  kept
+//   0x6b       PWR_MGMT_1    rw  DEVICE_RESET, SLEEP (set at reset)
+//   0x6c       PWR_MGMT_2    rw  kept
+//   0x75       WHO_AM_I      r   0x68
+//
+// A WRITE is a register address and values for it and the ones after, a
+// READ starts at the last address written and counts up without moving it.
+// Other registers read as 0 and ignore writes.
+//
+// The data is sampled at the configured rate from a `Pattern`, set with the
+// MOTION register of the register map: values change once per sample
+// period, and read as 0 while SLEEP is set. The temperature is the die one
+// from `thermal`.
+
+use {
+    crate::{lock, mono, thermal},
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// AD0 tied low.
+pub const ADDRESS: u8 = 0x68;
+
+const SMPLRT_DIV: u8 = 0x19;
+const CONFIG: u8 = 0x1a;
+const GYRO_CONFIG: u8 = 0x1b;
+const ACCEL_CONFIG: u8 = 0x1c;
+const INT_PIN_CFG: u8 = 0x37;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3a;
+const DATA: u8 = 0x3b;
+const DATA_LEN: u8 = 14;
+const USER_CTRL: u8 = 0x6a;
+const PWR_MGMT_1: u8 = 0x6b;
+const PWR_MGMT_2: u8 = 0x6c;
+const WHO_AM_I: u8 = 0x75;
+
+const DEVICE_RESET: u8 = 1 << 7;
+const SLEEP: u8 = 1 << 6;
+const DATA_RDY: u8 = 1 << 0;
+
+// 1 g and 1 deg/s at the smallest ranges.
+const LSB_PER_G: i32 = 16384;
+const LSB_PER_DPS_X10: i32 = 1310;
+
+// The synthetic motion, per MOTION register value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    // Flat and still: 1 g on Z.
+    Still = 0,
+    // Rocking +-30 degrees about X every 4 s.
+    Tilt = 1,
+    // Turning about Z at 90 deg/s.
+    Spin = 2,
+    // Shaken along X, +-0.5 g at 5 Hz.
+    Shake = 3,
+}
+
+impl Pattern {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Still),
+            1 => Some(Self::Tilt),
+            2 => Some(Self::Spin),
+            3 => Some(Self::Shake),
+            _ => None,
+        }
+    }
+}
+
+static PATTERN: AtomicU8 = AtomicU8::new(Pattern::Still as u8);
+
+struct Mpu6050 {
+    pointer: u8,
+    // The writable registers, by address.
+    regs: [u8; 0x70],
+    // The sample DATA_RDY was last read for.
+    seen: u64,
+}
+
+impl Mpu6050 {
+    const RESET: Self = Self {
+        pointer: 0,
+        regs: reset_regs(),
+        seen: 0,
+    };
+}
+
+const fn reset_regs() -> [u8; 0x70] {
+    let mut regs = [0; 0x70];
+    regs[PWR_MGMT_1 as usize] = SLEEP;
+    regs
+}
+
+static MPU6050: Mutex<RefCell<Mpu6050>> = Mutex::new(RefCell::new(Mpu6050::RESET));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "mpu6050")
+}
+
+/// The MOTION register.
+pub fn pattern() -> u8 {
+    PATTERN.load(Ordering::Relaxed)
+}
+
+/// Writes the MOTION register, false if `value` is no `Pattern`.
+pub fn set_pattern(value: u8) -> bool {
+    let Some(pattern) = Pattern::from_u8(value) else {
+        return false;
+    };
+    PATTERN.store(pattern as u8, Ordering::Relaxed);
+    true
+}
+
+fn writable(address: u8) -> bool {
+    matches!(
+        address,
+        SMPLRT_DIV
+            | CONFIG
+            | GYRO_CONFIG
+            | ACCEL_CONFIG
+            | INT_PIN_CFG
+            | INT_ENABLE
+            | USER_CTRL
+            | PWR_MGMT_1
+            | PWR_MGMT_2
+    )
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    let Some((&pointer, values)) = data.split_first() else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut mpu = MPU6050.borrow(cs).borrow_mut();
+        mpu.pointer = pointer;
+        for (address, &value) in (pointer..=u8::MAX).zip(values) {
+            match address {
+                PWR_MGMT_1 if value & DEVICE_RESET != 0 => *mpu = Mpu6050::RESET,
+                address if writable(address) => mpu.regs[address as usize] = value,
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`, from the last address written.
+pub fn read(buf: &mut [u8]) {
+    let now = now_us();
+    lock::free(|cs| {
+        let mut mpu = MPU6050.borrow(cs).borrow_mut();
+        let period = sample_period_us(&mpu.regs);
+        let index = now / period;
+        let data = if mpu.regs[PWR_MGMT_1 as usize] & SLEEP != 0 {
+            [0; DATA_LEN as usize]
+        } else {
+            sample(&mpu.regs, index * period)
+        };
+        let mut address = mpu.pointer;
+        for byte in buf.iter_mut() {
+            *byte = match address {
+                INT_STATUS => {
+                    let ready = index != mpu.seen;
+                    mpu.seen = index;
+                    if ready {
+                        DATA_RDY
+                    } else {
+                        0
+                    }
+                }
+                DATA..=0x48 => data[(address - DATA) as usize],
+                WHO_AM_I => 0x68,
+                address if writable(address) => mpu.regs[address as usize],
+                _ => 0,
+            };
+            address = address.wrapping_add(1);
+        }
+    });
+}
+
+fn now_us() -> u64 {
+    crate::app::monotonics::now().ticks() * 1_000_000 / mono::TICK_HZ as u64
+}
+
+fn sample_period_us(regs: &[u8; 0x70]) -> u64 {
+    let dlpf = regs[CONFIG as usize] & 0b111;
+    let base_hz = if matches!(dlpf, 1..=6) { 1000 } else { 8000 };
+    (1_000_000 * (1 + regs[SMPLRT_DIV as usize] as u64) / base_hz).max(1)
+}
+
+// The data registers for the sample at `us`.
+fn sample(regs: &[u8; 0x70], us: u64) -> [u8; DATA_LEN as usize] {
+    let ms = (us / 1000) as i32;
+    // Accelerometer in mg, gyroscope in 0.1 deg/s.
+    let (accel, gyro) = match Pattern::from_u8(pattern()).unwrap_or(Pattern::Still) {
+        Pattern::Still => ([0, 0, 1000], [0, 0, 0]),
+        Pattern::Tilt => {
+            // 30 degrees * sin(2 pi t / 4 s), in 0.01 degrees.
+            let phase = ms % 4000 * 360 / 4000;
+            let angle = 3000 * sin(phase) / 32768;
+            // d angle / dt = 30 * 2 pi / 4 * cos, 47.1 deg/s at most.
+            let rate = 471 * sin(phase + 90) / 32768;
+            let tilt = angle.rem_euclid(36000) / 100;
+            (
+                [0, sin(tilt) * 1000 / 32768, sin(tilt + 90) * 1000 / 32768],
+                [rate, 0, 0],
+            )
+        }
+        Pattern::Spin => ([0, 0, 1000], [0, 0, 900]),
+        Pattern::Shake => {
+            let phase = ms % 200 * 360 / 200;
+            ([500 * sin(phase) / 32768, 0, 1000], [0, 0, 0])
+        }
+    };
+    let accel_shift = regs[ACCEL_CONFIG as usize] >> 3 & 0b11;
+    let gyro_shift = regs[GYRO_CONFIG as usize] >> 3 & 0b11;
+    let mut data = [0; DATA_LEN as usize];
+    for (i, mg) in accel.into_iter().enumerate() {
+        let value = (mg * (LSB_PER_G >> accel_shift) / 1000).clamp(-32768, 32767) as i16;
+        data[2 * i..][..2].copy_from_slice(&value.to_be_bytes());
+    }
+    // TEMP_OUT = (T - 36.53 C) * 340.
+    let centi = thermal::quarters() as i32 * 25;
+    let temp = ((centi - 3653) * 340 / 100).clamp(-32768, 32767) as i16;
+    data[6..8].copy_from_slice(&temp.to_be_bytes());
+    for (i, rate) in gyro.into_iter().enumerate() {
+        let value = (rate * LSB_PER_DPS_X10 / (100 << gyro_shift)).clamp(-32768, 32767) as i16;
+        data[8 + 2 * i..][..2].copy_from_slice(&value.to_be_bytes());
+    }
+    data
+}
+
+// sin of `degrees` in Q15, Bhaskara's approximation, within 0.2%.
+fn sin(degrees: i32) -> i32 {
+    let degrees = degrees.rem_euclid(360);
+    let (x, sign) = if degrees < 180 {
+        (degrees, 1)
+    } else {
+        (degrees - 180, -1)
+    };
+    let p = x * (180 - x);
+    sign * 4 * p * 32768 / (40500 - p)
+}
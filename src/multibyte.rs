@@ -0,0 +1,78 @@
+// Multi-byte registers: integers wider than a byte in the register map.
+//
+// Every such register is little-endian, least significant byte at the
+// lowest address, signed ones two's complement. A `Value` is the whole
+// integer, taken at once, so its bytes never mix two readings. `regmap`
+// latches one per transport: reading byte 0 of a register takes a new
+// value, and the bytes read after it, in the same READ or in later ones
+// that continue byte by byte, come from that value, as on a sensor with
+// shadow registers. A READ starting past byte 0 of another register takes a
+// new value too. TIME latches the same way in `wallclock`.
+
+/// Widest integer register.
+pub const MAX_LEN: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct Value {
+    bytes: [u8; MAX_LEN],
+}
+
+impl Value {
+    pub const ZERO: Value = Value {
+        bytes: [0; MAX_LEN],
+    };
+
+    pub fn u16(value: u16) -> Self {
+        Value::u32(value as u32)
+    }
+
+    pub fn i16(value: i16) -> Self {
+        Value::u16(value as u16)
+    }
+
+    pub fn u32(value: u32) -> Self {
+        let mut bytes = [0; MAX_LEN];
+        bytes[..4].copy_from_slice(&value.to_le_bytes());
+        Value { bytes }
+    }
+
+    pub fn i32(value: i32) -> Self {
+        Value::u32(value as u32)
+    }
+
+    pub fn i64(value: i64) -> Self {
+        Value {
+            bytes: value.to_le_bytes(),
+        }
+    }
+
+    /// Byte `offset`, 0 the least significant.
+    pub fn byte(self, offset: usize) -> u8 {
+        self.bytes[offset]
+    }
+}
+
+/// A run of `count` integer registers of `len` bytes each from `base`,
+/// `value(index)` reading one of them.
+pub struct Integers {
+    pub base: u8,
+    pub len: usize,
+    pub count: usize,
+    pub value: fn(usize) -> Value,
+}
+
+impl Integers {
+    /// The address of the register `reg` is part of and the offset of
+    /// `reg` in it, if it is in this run.
+    pub fn locate(&self, reg: u8) -> Option<(u8, usize)> {
+        let offset = reg.checked_sub(self.base)? as usize;
+        if offset >= self.len * self.count {
+            return None;
+        }
+        Some((reg - (offset % self.len) as u8, offset % self.len))
+    }
+
+    pub fn read(&self, base: u8) -> Value {
+        (self.value)((base - self.base) as usize / self.len)
+    }
+}
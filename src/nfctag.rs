@@ -0,0 +1,323 @@
+// NFC Type 2 tag mirroring the register map (`nfc-tag` feature).
+//
+// The NFCT peripheral answers an NFC-A reader on the NFC antenna pins
+// (P0.09/P0.10) as a read-only Type 2 tag, so tapping a phone shows the
+// device status with nothing attached to the I2C bus. The NFCT resolves
+// collisions on its own; this module only answers the frames after
+// SELECTED: READ (16 bytes from a block), HALT, and a NACK for anything
+// else, writes included.
+//
+// The tag memory is built when a reader selects the tag: the UID and
+// capability container, then an NDEF message of two records, a text
+// record summarizing the status and a `MIME_TYPE` record holding registers
+// 0x00..`MIRROR_LEN` as a READ would return them. RANDOM reads as 0 there,
+// the tag must not drain the entropy pool.
+//
+// The NFCT needs the HFXO while activated. Starting it takes too long for
+// the interrupt handler, so the `activate_nfc` task does it when the field
+// appears; it runs until the field is gone.
+
+use {
+    crate::{
+        clock::Hfxo,
+        config,
+        hal::pac::NFCT,
+        identity, lock, regmap, status, thermal,
+        tracebuf::{self, Event},
+    },
+    core::{
+        cell::RefCell,
+        fmt::{self, Write},
+        ptr::addr_of_mut,
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// Registers in the MIME record.
+pub const MIRROR_LEN: usize = 0x20;
+const MIME_TYPE: &[u8] = b"application/x-twis-regmap";
+
+const BLOCK_LEN: usize = 4;
+// Header: UID, lock bytes and capability container, 4 blocks.
+const HEADER_LEN: usize = 4 * BLOCK_LEN;
+const DATA_LEN: usize = 192;
+const TAG_LEN: usize = HEADER_LEN + DATA_LEN;
+const TEXT_LEN: usize = 80;
+
+// Type 2 tag commands.
+const READ: u8 = 0x30;
+const HALT: u8 = 0x50;
+const NACK: u8 = 0x0;
+const READ_LEN: usize = 16;
+
+// EasyDMA buffer of the NFCT, for both directions.
+static mut FRAME: [u8; READ_LEN] = [0; READ_LEN];
+
+static TAG: Mutex<RefCell<[u8; TAG_LEN]>> = Mutex::new(RefCell::new([0; TAG_LEN]));
+// The HFXO held while the field is present.
+static HFXO: Mutex<RefCell<Option<Hfxo>>> = Mutex::new(RefCell::new(None));
+
+fn regs() -> &'static crate::hal::pac::nfct::RegisterBlock {
+    // SAFETY: NFCT is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*NFCT::ptr() }
+}
+
+/// Takes `NFCT` and starts sensing for a field.
+pub fn init(nfct: NFCT) {
+    let uid = identity::nfc_uid();
+    nfct.nfcid1_2nd_last
+        .write(|w| unsafe { w.bits(u32::from_be_bytes([0, uid[0], uid[1], uid[2]])) });
+    nfct.nfcid1_last
+        .write(|w| unsafe { w.bits(u32::from_be_bytes([uid[3], uid[4], uid[5], uid[6]])) });
+    nfct.sensres
+        .write(|w| w.nfcidsize().nfcid1double().bitframesdd().sdd00100());
+    // SAFETY: protocol 0 is a Type 2 tag.
+    nfct.selres.write(|w| unsafe { w.protocol().bits(0) });
+    // Always on on the nRF52832, which lacks the register.
+    #[cfg(not(feature = "nrf52832"))]
+    nfct.autocolresconfig.write(|w| w.mode().enabled());
+    nfct.maxlen
+        .write(|w| unsafe { w.maxlen().bits(READ_LEN as u16) });
+    nfct.packetptr
+        .write(|w| unsafe { w.ptr().bits(addr_of_mut!(FRAME) as u32) });
+    // The nRF52832 has no TXFRAMEEND_ENABLERXDATA short, `on_interrupt`
+    // enables RX at TXFRAMEEND instead.
+    nfct.shorts.write(|w| {
+        let w = w.fieldlost_sense().enabled();
+        #[cfg(not(feature = "nrf52832"))]
+        let w = w.txframeend_enablerxdata().enabled();
+        w
+    });
+    #[cfg(feature = "nrf52832")]
+    nfct.intenset.write(|w| w.txframeend().set());
+    nfct.intenset.write(|w| {
+        w.fielddetected()
+            .set()
+            .fieldlost()
+            .set()
+            .selected()
+            .set()
+            .rxframeend()
+            .set()
+            .rxerror()
+            .set()
+    });
+    nfct.tasks_sense.write(|w| unsafe { w.bits(1) });
+    info!("NFC tag {:02x?}", uid);
+}
+
+/// Activates the NFCT once the HFXO runs. From the `activate_nfc` task.
+pub fn activate() {
+    let hfxo = Hfxo::request();
+    let nfct = regs();
+    // What is not kept is dropped outside the critical section, see `clock`.
+    let _dropped = lock::free(|cs| {
+        // The field may be gone by now.
+        if nfct.fieldpresent.read().fieldpresent().is_field_present() {
+            nfct.tasks_activate.write(|w| unsafe { w.bits(1) });
+            HFXO.borrow(cs).replace(Some(hfxo))
+        } else {
+            Some(hfxo)
+        }
+    });
+}
+
+/// Handles the NFCT events. Returns true if the field appeared and
+/// `activate` should run.
+pub fn on_interrupt() -> bool {
+    let nfct = regs();
+    let mut detected = false;
+    if nfct.events_fielddetected.read().bits() != 0 {
+        nfct.events_fielddetected.reset();
+        detected = true;
+    }
+    if nfct.events_fieldlost.read().bits() != 0 {
+        nfct.events_fieldlost.reset();
+        // FIELDLOST_SENSE has the NFCT sensing again already.
+        lock::free(|cs| HFXO.borrow(cs).replace(None));
+        detected = false;
+    }
+    if nfct.events_selected.read().bits() != 0 {
+        nfct.events_selected.reset();
+        build();
+        tracebuf::record(Event::NfcSelected, 0, 0);
+        info!("NFC reader selected the tag");
+        enable_rx();
+    }
+    if nfct.events_rxerror.read().bits() != 0 {
+        nfct.events_rxerror.reset();
+        nfct.framestatus.rx.write(|w| unsafe { w.bits(0xf) });
+        enable_rx();
+    }
+    if nfct.events_rxframeend.read().bits() != 0 {
+        nfct.events_rxframeend.reset();
+        respond();
+    }
+    #[cfg(feature = "nrf52832")]
+    if nfct.events_txframeend.read().bits() != 0 {
+        nfct.events_txframeend.reset();
+        enable_rx();
+    }
+    detected
+}
+
+fn enable_rx() {
+    let nfct = regs();
+    nfct.rxd
+        .frameconfig
+        .write(|w| w.parity().parity().sof().so_f().crcmoderx().crc16rx());
+    nfct.tasks_enablerxdata.write(|w| unsafe { w.bits(1) });
+}
+
+// Answers the frame in FRAME; TXFRAMEEND_ENABLERXDATA waits for the next.
+fn respond() {
+    let nfct = regs();
+    // SAFETY: the NFCT is done receiving into it and transmits only once
+    // STARTTX is triggered below.
+    let frame = unsafe { &mut *addr_of_mut!(FRAME) };
+    let crc_ok = nfct.framestatus.rx.read().crcerror().is_crccorrect();
+    match (crc_ok, frame[0]) {
+        (true, READ) => {
+            let start = frame[1] as usize * BLOCK_LEN;
+            lock::free(|cs| {
+                let tag = TAG.borrow(cs).borrow();
+                for (i, byte) in frame.iter_mut().enumerate() {
+                    *byte = tag.get(start + i).copied().unwrap_or(0);
+                }
+            });
+            nfct.txd.frameconfig.write(|w| {
+                w.parity()
+                    .parity()
+                    .discardmode()
+                    .discard_start()
+                    .sof()
+                    .so_f()
+                    .crcmodetx()
+                    .crc16tx()
+            });
+            nfct.txd
+                .amount
+                .write(|w| unsafe { w.txdatabytes().bits(READ_LEN as u16).txdatabits().bits(0) });
+        }
+        (true, HALT) => {
+            nfct.tasks_gosleep.write(|w| unsafe { w.bits(1) });
+            return;
+        }
+        _ => {
+            // A 4-bit NACK without CRC, also for writes: the tag is
+            // read-only.
+            frame[0] = NACK;
+            nfct.txd.frameconfig.write(|w| {
+                w.parity()
+                    .parity()
+                    .discardmode()
+                    .discard_start()
+                    .sof()
+                    .so_f()
+                    .crcmodetx()
+                    .no_crctx()
+            });
+            nfct.txd
+                .amount
+                .write(|w| unsafe { w.txdatabytes().bits(0).txdatabits().bits(4) });
+        }
+    }
+    nfct.tasks_starttx.write(|w| unsafe { w.bits(1) });
+}
+
+// Writes into a byte slice, for the text record.
+struct Cursor<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Appends an NDEF short record to `out` at `*at`.
+fn record(out: &mut [u8], at: &mut usize, flags: u8, kind: &[u8], payload: &[&[u8]]) {
+    let len: usize = payload.iter().map(|part| part.len()).sum();
+    // SR: short record, payload length in one byte.
+    out[*at..*at + 3].copy_from_slice(&[flags | 0x10, kind.len() as u8, len as u8]);
+    *at += 3;
+    for part in [kind].into_iter().chain(payload.iter().copied()) {
+        out[*at..*at + part.len()].copy_from_slice(part);
+        *at += part.len();
+    }
+}
+
+// Lays out the tag memory with the current register values.
+fn build() {
+    let uid = identity::nfc_uid();
+    let mut tag = [0; TAG_LEN];
+    let bcc0 = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+    let bcc1 = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+    tag[..HEADER_LEN].copy_from_slice(&[
+        uid[0],
+        uid[1],
+        uid[2],
+        bcc0,
+        uid[3],
+        uid[4],
+        uid[5],
+        uid[6],
+        bcc1,
+        0x48,
+        // Static lock bytes: all pages read-only.
+        0xff,
+        0xff,
+        // Capability container: NDEF 1.0, data area size / 8, no write
+        // access.
+        0xe1,
+        0x10,
+        (DATA_LEN / 8) as u8,
+        0x0f,
+    ]);
+
+    let mut text = [0; TEXT_LEN];
+    let mut cursor = Cursor {
+        buf: &mut text,
+        len: 0,
+    };
+    write!(
+        cursor,
+        "TWIS {:#04x}, {} C, status {:#04x}, up {} s, id {:016x}",
+        config::get().address,
+        thermal::celsius(),
+        status::get(),
+        crate::app::monotonics::now().ticks() / crate::mono::TICK_HZ as u64,
+        u64::from_le_bytes(identity::device_id())
+    )
+    .ok();
+    let text_len = cursor.len;
+    let mut mirror = [0; MIRROR_LEN];
+    regmap::mirror(0, &mut mirror);
+
+    // NDEF message TLV, filled in once its length is known.
+    let data = &mut tag[HEADER_LEN..];
+    let mut at = 2;
+    // MB, TNF well-known: text in English, UTF-8.
+    record(
+        data,
+        &mut at,
+        0x80 | 0x01,
+        b"T",
+        &[&[2], b"en", &text[..text_len]],
+    );
+    // ME, TNF MIME type.
+    record(data, &mut at, 0x40 | 0x02, MIME_TYPE, &[&mirror]);
+    data[0] = 0x03;
+    data[1] = (at - 2) as u8;
+    data[at] = 0xfe;
+    lock::free(|cs| *TAG.borrow(cs).borrow_mut() = tag);
+}
@@ -0,0 +1,192 @@
+// Scratch registers persisted in internal flash.
+//
+// The STORE request writes the scratch registers, as received from the
+// controller, to `PAGE`, the last 4 KB flash page; `load` restores them at
+// boot. So unlike `retain`, which only survives System OFF, the data
+// survives resets and power cycles.
+//
+// The CPU stalls while the NVMC erases or programs, interrupts included. A
+// page erase takes up to 85 ms, far longer than the TWIS and SPIS handlers
+// may be held off, so `store` erases in `ERASE_CHUNK_MS` partial erases and
+// the interrupts that came in run in between. The nRF52832 has no partial
+// erase and stalls for the whole page. It is only called from the
+// `store_bank` task. With `ble` the SoftDevice programs the flash once it
+// runs, in between its radio events, and the task waits for it.
+//
+// Record layout: | MAGIC | size | scratch | CRC-32 |, in words.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{chip, hal::pac::NVMC, hexdump, regmap},
+    core::{fmt, mem::size_of, ptr::addr_of},
+};
+
+/// Reserved for the record, the last page of the flash: the firmware image
+/// must end below it, which `store` checks.
+const PAGE: usize = chip::FLASH_LEN - 0x1000;
+
+const MAGIC: u32 = 0x5c4a_7c40;
+
+// tERASEPAGE of the nRF52840, split into partial erases.
+#[cfg(not(feature = "nrf52832"))]
+const ERASE_MS: u32 = 85;
+#[cfg(not(feature = "nrf52832"))]
+const ERASE_CHUNK_MS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The firmware image reaches into `PAGE`.
+    NoPage,
+    /// The record read back differs from the one written.
+    Verify,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NoPage => "firmware image overlaps the store page",
+            Error::Verify => "store page did not read back as written",
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    size: u32,
+    scratch: [u8; regmap::SCRATCH_LEN],
+    // CRC-32 of everything above.
+    crc: u32,
+}
+
+const WORDS: usize = size_of::<Record>() / 4;
+
+impl Record {
+    fn new(scratch: [u8; regmap::SCRATCH_LEN]) -> Self {
+        let mut record = Record {
+            magic: MAGIC,
+            size: size_of::<Record>() as u32,
+            scratch,
+            crc: 0,
+        };
+        record.crc = record.crc();
+        record
+    }
+
+    fn crc(&self) -> u32 {
+        // SAFETY: `Record` is `repr(C)` integers without padding.
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const Record as *const u8,
+                size_of::<Record>() - size_of::<u32>(),
+            )
+        };
+        hexdump::crc32(bytes)
+    }
+
+    fn words(&self) -> [u32; WORDS] {
+        // SAFETY: as above, and a multiple of 4 bytes.
+        unsafe { core::mem::transmute(*self) }
+    }
+}
+
+fn stored() -> Record {
+    // SAFETY: `PAGE` is memory mapped flash, every bit pattern is a valid
+    // `Record`.
+    unsafe { (PAGE as *const Record).read_volatile() }
+}
+
+/// End of the firmware in flash: code and constants, then the `.data`
+/// initializers.
+pub fn image_end() -> usize {
+    // Linker symbols, only their addresses are used.
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+    addr_of!(__sidata) as usize + (addr_of!(__edata) as usize - addr_of!(__sdata) as usize)
+}
+
+/// Persists the scratch registers.
+pub fn store() -> Result<(), Error> {
+    if image_end() > PAGE {
+        return Err(Error::NoPage);
+    }
+    let record = Record::new(regmap::scratch());
+    if stored() == record {
+        return Ok(());
+    }
+    erase_page(PAGE);
+    for (i, word) in record.words().into_iter().enumerate() {
+        program_word(PAGE + 4 * i, word);
+    }
+    if stored() != record {
+        return Err(Error::Verify);
+    }
+    Ok(())
+}
+
+/// Erases the flash page at `page` in partial erases, so interrupts get
+/// in between, or in one on the nRF52832. Only call from priority 1 tasks.
+pub fn erase_page(page: usize) {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::erase_page(page);
+    }
+    // SAFETY: NVMC is only used from priority 1 tasks (`store_bank`,
+    // `store_config` and `heartbeat` for `rolling`), which do not preempt
+    // each other.
+    let nvmc = unsafe { &*NVMC::ptr() };
+    nvmc.config.write(|w| w.wen().een());
+    // SAFETY: the page address is all ERASEPAGE and ERASEPAGEPARTIAL take.
+    #[cfg(feature = "nrf52832")]
+    {
+        nvmc.erasepage().write(|w| unsafe { w.bits(page as u32) });
+        while nvmc.ready.read().ready().is_busy() {}
+    }
+    #[cfg(not(feature = "nrf52832"))]
+    {
+        nvmc.erasepagepartialcfg
+            .write(|w| unsafe { w.duration().bits(ERASE_CHUNK_MS as u8) });
+        for _ in 0..ERASE_MS.div_ceil(ERASE_CHUNK_MS) {
+            nvmc.erasepagepartial
+                .write(|w| unsafe { w.bits(page as u32) });
+            while nvmc.ready.read().ready().is_busy() {}
+        }
+    }
+    nvmc.config.write(|w| w.wen().ren());
+}
+
+/// Programs `word` at `addr`, word aligned and erased. Only call from
+/// priority 1 tasks.
+pub fn program_word(addr: usize, word: u32) {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::program_word(addr, word);
+    }
+    // SAFETY: as in `erase_page`.
+    let nvmc = unsafe { &*NVMC::ptr() };
+    nvmc.config.write(|w| w.wen().wen());
+    // SAFETY: the caller passes an erased, aligned word outside the image.
+    unsafe { (addr as *mut u32).write_volatile(word) };
+    while nvmc.ready.read().ready().is_busy() {}
+    nvmc.config.write(|w| w.wen().ren());
+}
+
+/// Restores the scratch registers from flash, if a record is there. Call
+/// once from `init`.
+pub fn load() {
+    let record = stored();
+    if record.magic != MAGIC || record.size != size_of::<Record>() as u32 {
+        return;
+    }
+    if record.crc != record.crc() {
+        error!("stored scratch registers corrupted, not restored");
+        return;
+    }
+    regmap::set_scratch(record.scratch);
+    info!("scratch registers restored from flash");
+}
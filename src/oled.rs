@@ -0,0 +1,333 @@
+// Status display on an SSD1306 OLED (`oled` feature).
+//
+// The nRF52840 has two TWI instances and this demo uses both, TWIS0 for
+// the register map and TWIM1 as controller, so the display cannot get a bus
+// of its own: it sits on the TWIM bus (P0.26/P0.27) at `ADDRESS`, next to
+// whatever the controller talks to. A 128x64 module shows eight lines of
+// text:
+//
+//   TWIS 1A    UP 1234 S
+//   LAST WR 9 B 3 S AGO
+//   20 01 02 03 04 05 06
+//   RD 12        WR 34
+//   NACK 0       OVR 0
+//   RETRY 0      ERR 0
+//   TEMP 24 C    STAT 00
+//   TWIM 400 KHZ
+//
+// The last transaction is the most recent TWIS (or SPIS) transfer, with up
+// to `FIRST_LEN` of its bytes. `refresh_oled` redraws once a second and only
+// sends the lines that changed; the display's own writes show in the TWIM
+// counters. Without a display, the first refresh finds no ACK and the
+// display stays off until the next boot.
+
+use {
+    crate::{
+        app::monotonics,
+        config, controller,
+        error::AppError,
+        hal::{pac::TWIM1, twim::Twim},
+        lock,
+        logging::Tag,
+        mono,
+        stats::STATS,
+        status, thermal,
+    },
+    core::{
+        cell::RefCell,
+        fmt::{self, Write},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// I2C address of the SSD1306 with SA0 low.
+pub const ADDRESS: u8 = 0x3c;
+/// How often the display is redrawn.
+pub const PERIOD_MS: u64 = 1000;
+
+const COLUMNS: usize = 128;
+const LINES: usize = 8;
+// 5x7 glyphs and a blank column.
+const GLYPH_WIDTH: usize = 6;
+const LINE_LEN: usize = COLUMNS / GLYPH_WIDTH;
+const FIRST_LEN: usize = 7;
+
+// Control bytes: the rest of the write is commands or display data.
+const COMMANDS: u8 = 0x00;
+const DATA: u8 = 0x40;
+
+// Display off, clock, 64 rows, no offset, start line 0, charge pump on,
+// horizontal addressing, flipped to the usual orientation, COM pins,
+// contrast, precharge, VCOMH, show RAM, not inverted, display on.
+const INIT: &[u8] = &[
+    COMMANDS, 0xae, 0xd5, 0x80, 0xa8, 0x3f, 0xd3, 0x00, 0x40, 0x8d, 0x14, 0x20, 0x00, 0xa1, 0xc8,
+    0xda, 0x12, 0x81, 0xcf, 0xd9, 0xf1, 0xdb, 0x40, 0xa4, 0xa6, 0xaf,
+];
+
+// Columns of the glyphs from ' ' to '_', bit 0 at the top. Lower case is
+// shown as upper case, anything else as a space.
+const FONT: [[u8; 5]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5f, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14],
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50],
+    [0x00, 0x00, 0x07, 0x00, 0x00],
+    [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00],
+    [0x2a, 0x1c, 0x7f, 0x1c, 0x2a],
+    [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46],
+    [0x21, 0x41, 0x49, 0x4d, 0x33],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x31],
+    [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x46, 0x49, 0x49, 0x29, 0x1e],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x59, 0x09, 0x06],
+    [0x3e, 0x41, 0x5d, 0x59, 0x4e],
+    [0x7c, 0x12, 0x11, 0x12, 0x7c],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x41, 0x51, 0x73],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32],
+    [0x03, 0x01, 0x7f, 0x01, 0x03],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03],
+    [0x61, 0x59, 0x49, 0x4d, 0x43],
+    [0x00, 0x7f, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x41, 0x41, 0x41, 0x7f, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+];
+
+// The most recent TWIS or SPIS transfer.
+#[derive(Clone, Copy)]
+struct Last {
+    tag: Tag,
+    len: usize,
+    first: [u8; FIRST_LEN],
+    at: u64,
+}
+
+static LAST: Mutex<RefCell<Option<Last>>> = Mutex::new(RefCell::new(None));
+
+/// Remembers a finished TWIS or SPIS transfer for the display, next to
+/// `telemetry::record_transfer`.
+pub fn record_transfer(tag: Tag, data: &[u8]) {
+    let mut first = [0; FIRST_LEN];
+    let shown = data.len().min(FIRST_LEN);
+    first[..shown].copy_from_slice(&data[..shown]);
+    let last = Last {
+        tag,
+        len: data.len(),
+        first,
+        at: monotonics::now().ticks(),
+    };
+    lock::free(|cs| LAST.borrow(cs).replace(Some(last)));
+}
+
+type Text = [[u8; LINE_LEN]; LINES];
+
+// Writes one line, cutting off what does not fit.
+struct Line<'a> {
+    buf: &'a mut [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Write for Line<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < LINE_LEN {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State of the display, owned by `refresh_oled`.
+pub struct Oled {
+    // `None` until the first refresh has found the display, then whether
+    // it did.
+    present: Option<bool>,
+    // What the display shows, to only send the lines that changed.
+    shown: Text,
+}
+
+impl Oled {
+    pub const fn new() -> Self {
+        Oled {
+            present: None,
+            shown: [[0; LINE_LEN]; LINES],
+        }
+    }
+
+    /// Redraws the lines that changed. Returns false once the display
+    /// turned out to be missing.
+    pub fn refresh(&mut self, twim: &mut Twim<TWIM1>) -> bool {
+        match self.present {
+            Some(present) => {
+                if !present {
+                    return false;
+                }
+            }
+            None => {
+                // EasyDMA cannot read from flash.
+                let mut init = [0; INIT.len()];
+                init.copy_from_slice(INIT);
+                let present = controller::probe(twim, ADDRESS)
+                    && controller::write(twim, ADDRESS, &init).is_ok();
+                self.present = Some(present);
+                if !present {
+                    warn!("no SSD1306 at {:#04x}, display off", ADDRESS);
+                    return false;
+                }
+                info!("SSD1306 display at {:#04x}", ADDRESS);
+            }
+        }
+        let text = render();
+        for (page, line) in text.iter().enumerate() {
+            if *line == self.shown[page] {
+                continue;
+            }
+            if let Err(error) = draw(twim, page, line) {
+                warn!("display: {}", error);
+                return true;
+            }
+            self.shown[page] = *line;
+        }
+        true
+    }
+}
+
+// Sends `line` to page `page`, the full width.
+fn draw(twim: &mut Twim<TWIM1>, page: usize, line: &[u8; LINE_LEN]) -> Result<(), AppError> {
+    let window = [
+        COMMANDS,
+        0x21,
+        0,
+        COLUMNS as u8 - 1,
+        0x22,
+        page as u8,
+        page as u8,
+    ];
+    controller::write(twim, ADDRESS, &window)?;
+    let mut frame = [0; 1 + COLUMNS];
+    frame[0] = DATA;
+    for (i, &byte) in line.iter().enumerate() {
+        let index = match byte.to_ascii_uppercase() {
+            byte @ b' '..=b'_' => (byte - b' ') as usize,
+            _ => 0,
+        };
+        let column = 1 + i * GLYPH_WIDTH;
+        frame[column..column + 5].copy_from_slice(&FONT[index]);
+    }
+    controller::write(twim, ADDRESS, &frame)
+}
+
+// The lines the display should show now.
+fn render() -> Text {
+    let mut text = [[b' '; LINE_LEN]; LINES];
+    let now = monotonics::now().ticks();
+    let secs = |ticks: u64| ticks / mono::TICK_HZ as u64;
+    let last = lock::free(|cs| *LAST.borrow(cs).borrow());
+    let mut lines = text.iter_mut().map(|buf| Line { buf, len: 0 });
+    let mut line = || lines.next().unwrap();
+    write!(
+        line(),
+        "TWIS {:02X}    UP {} S",
+        config::get().address,
+        secs(now)
+    )
+    .ok();
+    match last {
+        Some(last) => {
+            let op = match last.tag {
+                Tag::TwisRx => "WR",
+                Tag::SpisRx => "SPI WR",
+                Tag::SpisTx => "SPI RD",
+                _ => "RD",
+            };
+            write!(
+                line(),
+                "LAST {} {} B {} S AGO",
+                op,
+                last.len,
+                secs(now.saturating_sub(last.at))
+            )
+            .ok();
+            let mut bytes = line();
+            for byte in &last.first[..last.len.min(FIRST_LEN)] {
+                write!(bytes, "{:02X} ", byte).ok();
+            }
+        }
+        None => {
+            write!(line(), "LAST -").ok();
+            line();
+        }
+    }
+    write!(
+        line(),
+        "RD {:<9} WR {}",
+        STATS.twis_reads.get(),
+        STATS.twis_writes.get()
+    )
+    .ok();
+    write!(
+        line(),
+        "NACK {:<7} OVR {}",
+        STATS.nacks.get(),
+        STATS.overruns.get()
+    )
+    .ok();
+    write!(
+        line(),
+        "RETRY {:<6} ERR {}",
+        STATS.retries.get(),
+        STATS.errors.get()
+    )
+    .ok();
+    write!(
+        line(),
+        "TEMP {:<3} C    STAT {:02X}",
+        thermal::celsius(),
+        status::get()
+    )
+    .ok();
+    write!(line(), "TWIM {} KHZ", controller::frequency_khz()).ok();
+    text
+}
@@ -0,0 +1,140 @@
+// Outcome of the last command, for the RESULT register and the READ
+// envelope.
+//
+// Every request, whichever register it came through, is refused, or
+// accepted and then carried out, at once or in the background. The state
+// of the last one is tracked here, so a controller can tell success from
+// failure and finished from still running without interpreting the data
+// registers:
+//
+//   0  OK       carried out, or no command since boot
+//   1  BUSY     accepted, still being carried out
+//   2  REFUSED  not accepted; the code is the `ProtocolError` code
+//   3  FAILED   accepted but not carried out; the code is a `Failure`
+//
+// RESULT reads as a u32: the state, the opcode (0 if refused), then the
+// code as u16. With ENVELOPE set for a transport
+// every READ on it starts with the state byte, then the data as usual.
+//
+// Flash, SAADC and PDM work is BUSY while the peripheral is, the rest
+// until the code carrying it out reports back; a report for another opcode
+// than the last command's is a late one for an earlier command and dropped.
+
+use {
+    crate::{error::ProtocolError, lock, mic, qspiflash, request, saadc},
+    core::cell::Cell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const OK: u8 = 0;
+pub const BUSY: u8 = 1;
+pub const REFUSED: u8 = 2;
+pub const FAILED: u8 = 3;
+
+/// Why an accepted command was not carried out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The peripheral is busy or absent.
+    Unavailable = 1,
+    /// Conflicts with the configuration, e.g. a reserved flash range.
+    Conflict = 2,
+    /// The task could not be spawned or the work itself failed.
+    Internal = 3,
+    /// The data sent does not check out, e.g. a firmware image CRC.
+    Invalid = 4,
+}
+
+impl Failure {
+    pub fn code(self) -> u16 {
+        0x0700 | self as u16
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Outcome {
+    state: u8,
+    opcode: u8,
+    code: u16,
+}
+
+static LAST: Mutex<Cell<Outcome>> = Mutex::new(Cell::new(Outcome {
+    state: OK,
+    opcode: 0,
+    code: 0,
+}));
+
+fn set(outcome: Outcome) {
+    lock::free(|cs| LAST.borrow(cs).set(outcome));
+}
+
+// Sets the outcome of `opcode` if it is still the last command's.
+fn report(opcode: u8, state: u8, code: u16) {
+    lock::free(|cs| {
+        let last = LAST.borrow(cs);
+        if last.get().opcode == opcode {
+            last.set(Outcome {
+                state,
+                opcode,
+                code,
+            });
+        }
+    });
+}
+
+/// A WRITE was refused.
+pub fn refused(error: ProtocolError) {
+    set(Outcome {
+        state: REFUSED,
+        opcode: 0,
+        code: error.code(),
+    });
+}
+
+/// A request for `opcode` was accepted and is being carried out.
+pub fn accepted(opcode: u8) {
+    set(Outcome {
+        state: BUSY,
+        opcode,
+        code: 0,
+    });
+}
+
+pub fn done(opcode: u8) {
+    report(opcode, OK, 0);
+}
+
+pub fn failed(opcode: u8, failure: Failure) {
+    report(opcode, FAILED, failure.code());
+}
+
+// True while the peripheral `opcode` hands its work to is still at it.
+fn running(opcode: u8) -> bool {
+    match opcode {
+        request::FLASH_READ | request::FLASH_PROGRAM | request::FLASH_ERASE => {
+            qspiflash::status() & qspiflash::BUSY != 0
+        }
+        request::SAMPLE => saadc::count() == 0,
+        request::CAPTURE => mic::status() & mic::BUSY != 0,
+        // Reported by the task.
+        _ => true,
+    }
+}
+
+/// The state byte, for the READ envelope.
+pub fn state() -> u8 {
+    result() as u8
+}
+
+/// The RESULT register.
+pub fn result() -> u32 {
+    let outcome = lock::free(|cs| {
+        let last = LAST.borrow(cs);
+        let mut outcome = last.get();
+        if outcome.state == BUSY && !running(outcome.opcode) {
+            outcome.state = OK;
+            last.set(outcome);
+        }
+        outcome
+    });
+    outcome.state as u32 | (outcome.opcode as u32) << 8 | (outcome.code as u32) << 16
+}
@@ -0,0 +1,152 @@
+// A small PMBus device at TWIS address 1 (`pmbus` feature).
+//
+// PMBus is SMBus with standard command codes, see `smbus`. This one is an
+// ideal regulator: READ_VOUT is whatever VOUT_COMMAND was set to, and the
+// temperature is the die one from `thermal`.
+//
+//   0x03  CLEAR_FAULTS        send byte  clears STATUS_CML
+//   0x19  CAPABILITY          r byte     PEC, 400 kHz, no SMBALERT#
+//   0x20  VOUT_MODE           r byte     linear, exponent `VOUT_EXPONENT`
+//   0x21  VOUT_COMMAND        rw word    LINEAR16, starts at `VOUT_MV`
+//   0x78  STATUS_BYTE         r byte     TEMPERATURE (throttled), CML
+//   0x79  STATUS_WORD         r word     STATUS_BYTE in the low byte
+//   0x7e  STATUS_CML          r byte     invalid command, PEC failed
+//   0x8b  READ_VOUT           r word     LINEAR16
+//   0x8d  READ_TEMPERATURE_1  r word     LINEAR11, degrees C
+//   0x98  PMBUS_REVISION      r byte     1.3 for Parts I and II
+//
+// LINEAR11 is a 5-bit exponent N over an 11-bit mantissa Y, both two's
+// complement, for Y * 2^N; LINEAR16 the mantissa alone, with N from
+// VOUT_MODE. Words are little-endian. A READ answers the command of the
+// WRITE before it, followed by its PEC, which a host not using PEC does
+// not read. A WRITE may end in a PEC; a wrong one, or an unknown command,
+// sets a STATUS_CML bit and is ignored, and a wrong PEC is recorded as a
+// `ProtocolError::BadCrc` as well.
+
+use {
+    crate::{error::AppError, lock, smbus, thermal},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const ADDRESS: u8 = 0x58;
+/// VOUT_COMMAND at boot.
+pub const VOUT_MV: u32 = 3300;
+/// VOUT_MODE exponent, so a LINEAR16 step is 1/4096 V.
+pub const VOUT_EXPONENT: i8 = -12;
+
+const CLEAR_FAULTS: u8 = 0x03;
+const CAPABILITY: u8 = 0x19;
+const VOUT_MODE: u8 = 0x20;
+const VOUT_COMMAND: u8 = 0x21;
+const STATUS_BYTE: u8 = 0x78;
+const STATUS_WORD: u8 = 0x79;
+const STATUS_CML: u8 = 0x7e;
+const READ_VOUT: u8 = 0x8b;
+const READ_TEMPERATURE_1: u8 = 0x8d;
+const PMBUS_REVISION: u8 = 0x98;
+
+// STATUS_BYTE bits.
+const STATUS_TEMPERATURE: u8 = 1 << 2;
+const STATUS_CML_FAULT: u8 = 1 << 1;
+// STATUS_CML bits.
+const CML_INVALID_COMMAND: u8 = 1 << 7;
+const CML_PEC_FAILED: u8 = 1 << 5;
+
+struct Pmbus {
+    command: Option<u8>,
+    vout: u16,
+    cml: u8,
+}
+
+static PMBUS: Mutex<RefCell<Pmbus>> = Mutex::new(RefCell::new(Pmbus {
+    command: None,
+    vout: (VOUT_MV << -VOUT_EXPONENT as u32).div_ceil(1000) as u16,
+    cml: 0,
+}));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "pmbus")
+}
+
+/// `value` * 2^`exponent` in LINEAR11.
+pub fn linear11(value: i16, exponent: i8) -> u16 {
+    (exponent as u16 & 0x1f) << 11 | value as u16 & 0x7ff
+}
+
+fn status_byte(cml: u8) -> u8 {
+    let mut status = 0;
+    if thermal::throttled() {
+        status |= STATUS_TEMPERATURE;
+    }
+    if cml != 0 {
+        status |= STATUS_CML_FAULT;
+    }
+    status
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    let Some(&command) = data.first() else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut pmbus = PMBUS.borrow(cs).borrow_mut();
+        pmbus.command = None;
+        let len = match command {
+            CLEAR_FAULTS => 1,
+            VOUT_COMMAND if data.len() > 1 => 3,
+            CAPABILITY | VOUT_MODE | VOUT_COMMAND | STATUS_BYTE | STATUS_WORD | STATUS_CML
+            | READ_VOUT | READ_TEMPERATURE_1 | PMBUS_REVISION => 1,
+            _ => {
+                pmbus.cml |= CML_INVALID_COMMAND;
+                return;
+            }
+        };
+        let data = match smbus::strip_pec(ADDRESS, data, len) {
+            Ok(data) => data,
+            Err(error) => {
+                pmbus.cml |= CML_PEC_FAILED;
+                AppError::from(error).record();
+                return;
+            }
+        };
+        match *data {
+            [CLEAR_FAULTS] => pmbus.cml = 0,
+            [VOUT_COMMAND, lo, hi] => pmbus.vout = u16::from_le_bytes([lo, hi]),
+            _ => pmbus.command = Some(command),
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`: the answer to the last command and
+/// its PEC, 0xff after it or without one.
+pub fn read(buf: &mut [u8]) {
+    buf.fill(0xff);
+    let answer = lock::free(|cs| {
+        let pmbus = PMBUS.borrow(cs).borrow();
+        let command = pmbus.command?;
+        let word = |value: u16| Some((command, value.to_le_bytes(), 2));
+        let byte = |value: u8| Some((command, [value, 0], 1));
+        match command {
+            CAPABILITY => byte(0b1010_0000),
+            VOUT_MODE => byte(VOUT_EXPONENT as u8 & 0x1f),
+            VOUT_COMMAND | READ_VOUT => word(pmbus.vout),
+            STATUS_BYTE => byte(status_byte(pmbus.cml)),
+            STATUS_WORD => word(status_byte(pmbus.cml) as u16),
+            STATUS_CML => byte(pmbus.cml),
+            READ_TEMPERATURE_1 => word(linear11(thermal::quarters(), -2)),
+            PMBUS_REVISION => byte(0x33),
+            _ => None,
+        }
+    });
+    let Some((command, value, len)) = answer else {
+        return;
+    };
+    let mut frame = [0; 3];
+    frame[..len].copy_from_slice(&value[..len]);
+    frame[len] = smbus::read_pec(ADDRESS, command, &value[..len]);
+    let len = (len + 1).min(buf.len());
+    buf[..len].copy_from_slice(&frame[..len]);
+}
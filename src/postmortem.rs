@@ -0,0 +1,165 @@
+// Post-mortem record in RAM that survives a reset.
+//
+// On a fatal error (panic or HardFault) the TWIS/TWIM register snapshots, the
+// state of the shared TWIS transfer and the start of the panic message are
+// copied into a `.uninit` section, which the startup code neither zeroes nor
+// initializes. The next boot logs the record and invalidates it, so a crash
+// that took RTT down with it can still be analyzed after a reset.
+//
+// The record only survives resets that keep RAM powered (pin reset, soft
+// reset, watchdog, lockup), not a power cycle.
+
+use {
+    crate::{
+        blink,
+        regsnap::{TwimRegs, TwisRegs},
+    },
+    core::{
+        fmt::{self, Write},
+        mem::{size_of, MaybeUninit},
+        ptr::{addr_of, addr_of_mut},
+        sync::atomic::{AtomicU8, Ordering},
+    },
+    cortex_m_rt::{exception, ExceptionFrame},
+};
+
+const MAGIC: u32 = 0xdead_c0de;
+const MESSAGE_LEN: usize = 80;
+
+/// State of the shared TWIS transfer, as seen by the post-mortem record.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum TransferState {
+    /// Taken out of the shared resource by a running handler.
+    Taken = 0,
+    Idle = 1,
+    Running = 2,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Reason {
+    Panic = 1,
+    HardFault = 2,
+}
+
+// Only integers, so any bit pattern left in RAM is a valid value. `magic`
+// comes first so it can be cleared on its own.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    // `size_of::<Record>()`, so a record written by a different build is
+    // not misread.
+    size: u32,
+    reason: u8,
+    transfer: u8,
+    // Faulting PC for `Reason::HardFault`.
+    pc: u32,
+    twis: TwisRegs,
+    twim: TwimRegs,
+    message_len: u8,
+    message: [u8; MESSAGE_LEN],
+}
+
+#[link_section = ".uninit.POSTMORTEM"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+static TRANSFER: AtomicU8 = AtomicU8::new(TransferState::Idle as u8);
+
+/// Notes the current state of the TWIS transfer.
+pub fn set_transfer_state(state: TransferState) {
+    TRANSFER.store(state as u8, Ordering::Relaxed);
+}
+
+/// True while a TWIS DMA transfer is armed.
+pub fn transfer_running() -> bool {
+    TRANSFER.load(Ordering::Relaxed) == TransferState::Running as u8
+}
+
+// Appends to a fixed buffer, silently truncating.
+struct MessageWriter<'a> {
+    buf: &'a mut [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Writes the post-mortem record. Only for the panic and fault handlers,
+/// with interrupts disabled.
+pub fn capture(reason: Reason, pc: u32, message: fmt::Arguments) {
+    let mut record = Record {
+        magic: MAGIC,
+        size: size_of::<Record>() as u32,
+        reason: reason as u8,
+        transfer: TRANSFER.load(Ordering::Relaxed),
+        pc,
+        twis: TwisRegs::capture(),
+        twim: TwimRegs::capture(),
+        message_len: 0,
+        message: [0; MESSAGE_LEN],
+    };
+    let mut writer = MessageWriter {
+        buf: &mut record.message,
+        len: 0,
+    };
+    writer.write_fmt(message).ok();
+    record.message_len = writer.len as u8;
+    // SAFETY: nothing else runs once a fatal error is being handled.
+    unsafe { addr_of_mut!(RECORD).write_volatile(MaybeUninit::new(record)) };
+}
+
+/// Logs the record left by the previous run, if any, and invalidates it.
+/// Call once from `init`, after logging is up.
+pub fn report() {
+    // SAFETY: runs in `init` before any handler that could write the record.
+    // Every bit pattern is a valid `Record`.
+    let record = unsafe { addr_of!(RECORD).read_volatile().assume_init() };
+    if record.magic != MAGIC || record.size != size_of::<Record>() as u32 {
+        return;
+    }
+    let reason = match record.reason {
+        1 => "panic",
+        2 => "HardFault",
+        _ => "unknown",
+    };
+    let transfer = match record.transfer {
+        0 => "taken",
+        1 => "idle",
+        2 => "running",
+        _ => "unknown",
+    };
+    let len = (record.message_len as usize).min(MESSAGE_LEN);
+    let message = &record.message[..len];
+    // Truncation may have split a character.
+    let message = match core::str::from_utf8(message) {
+        Ok(message) => message,
+        Err(e) => core::str::from_utf8(&message[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    error!(
+        "post-mortem: previous run died of {} (pc {:#010x}), transfer {}",
+        reason, record.pc, transfer
+    );
+    error!("post-mortem: {}", message);
+    error!("post-mortem: {}", record.twim);
+    error!("post-mortem: {}", record.twis);
+    // SAFETY: as above.
+    unsafe { (addr_of_mut!(RECORD) as *mut u32).write_volatile(0) };
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    capture(
+        Reason::HardFault,
+        frame.pc(),
+        format_args!("HardFault, lr {:#010x}", frame.lr()),
+    );
+    blink::panic_loop()
+}
@@ -0,0 +1,471 @@
+// POWER peripheral setup and power-state bookkeeping.
+//
+// With the `dcdc` feature the DC/DC converters replace the LDOs, which cuts
+// the active current roughly in half. They need the external inductors of
+// the reference layout (fitted on the nRF52840-MDK); without them the chip
+// browns out as soon as a converter is enabled. REG0 only runs when the
+// chip is supplied through VDDH (high voltage mode).
+//
+// The idle loop notes which state it sleeps in, and the first handler to run
+// after a wake-up notes why the chip woke. Both are served by the register
+// map (`POWER_STATE`, `WAKE_REASON`) for the controller.
+//
+// The power-fail comparator warns when VDD drops below `POF_THRESHOLD`. The
+// warning sets `status::BROWNOUT` and quiesces DMA: a running TWIS transfer
+// is stopped and, until the controller clears the flag, neither received
+// data is applied to the register map nor TWIM transactions are started, so
+// a collapsing supply cannot corrupt state half-way through a transfer.
+//
+// VBUS detection follows the USB supply: `status::VBUS` is set while it is
+// present, so code can tell bus-powered from battery-powered operation.
+// With `usb-console`, USBPWRRDY hands over to the USBD interrupt, which
+// brings up the USB device once its supply is ready.
+//
+// `park_unused_pins` leaves every GPIO not in the `board` pin map as an
+// input with the buffer disconnected and no pull, the reset state, in case a
+// bootloader left one driven or pulled; peripherals the firmware does not
+// use are disabled for the same reason.
+//
+// `system_off` puts the chip into System OFF, the deepest sleep: everything
+// but the GPIO SENSE logic is off and waking up resets the chip.
+//
+// The `PowerMode` trades wake-up latency against sleep current: constant
+// latency keeps enough of the chip powered for a short, fixed wake-up
+// latency, at a higher sleep current; low power lets it shut down.
+//
+// With `ble` the SoftDevice owns POWER once it is enabled: the mode, System
+// OFF and the power-fail and VBUS events go through it, see `softdevice`.
+//
+// How the idle loop waits is an `IdleStrategy`. WFE with SEVONPEND also
+// wakes on interrupts that are pending but masked and on events sent with
+// SEV; some debug probes lose the connection in WFI or WFE, `Busy` keeps the
+// core awake for them.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+#[cfg(not(feature = "nrf52832"))]
+use {crate::hal::pac::Interrupt, cortex_m::peripheral::NVIC};
+use {
+    crate::{
+        board, busgate, clock, expander,
+        hal::pac::{P0, POWER, TWIM1, TWIS0, UARTE0},
+        markers::{self, Marker},
+        postmortem, status,
+        tracebuf::{self, Event},
+    },
+    core::{
+        fmt,
+        sync::atomic::{AtomicU8, Ordering},
+    },
+    cortex_m::peripheral::SCB,
+};
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleStrategy {
+    Wfi,
+    /// WFE with SEVONPEND set.
+    Wfe,
+    /// Spin without sleeping.
+    Busy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerMode {
+    /// Reset default.
+    LowPower = 0,
+    ConstantLatency = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    /// The CPU is running, or has not slept since boot.
+    Active = 0,
+    /// WFI with the HFXO running.
+    Idle = 1,
+    /// WFI with the HFXO off, only the LFCLK/RTC running.
+    Sleep = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WakeReason {
+    /// Not woken up since boot.
+    None = 0,
+    /// TWIS event: address match, or the end of a transfer.
+    AddressMatch = 1,
+    Button = 2,
+    /// RTC compare or overflow, i.e. a scheduled task.
+    Timer = 3,
+    /// LPCOMP crossing, see `lpcomp`.
+    Analog = 4,
+    /// SPIS transaction, with the `spis` feature.
+    SpiSelect = 5,
+}
+
+// State the idle loop is sleeping in, `Active` once a handler has claimed
+// the wake-up.
+static CURRENT: AtomicU8 = AtomicU8::new(PowerState::Active as u8);
+static WOKE_FROM: AtomicU8 = AtomicU8::new(PowerState::Active as u8);
+static WAKE_REASON: AtomicU8 = AtomicU8::new(WakeReason::None as u8);
+static MODE: AtomicU8 = AtomicU8::new(PowerMode::LowPower as u8);
+
+/// Enables the DC/DC converters if built with the `dcdc` feature, and logs
+/// the regulator setup.
+pub fn init_regulators(power: &POWER) {
+    // The nRF52832 has no VDDH supply.
+    #[cfg(not(feature = "nrf52832"))]
+    let high_voltage = power.mainregstatus.read().mainregstatus().is_high();
+    #[cfg(feature = "nrf52832")]
+    let high_voltage = false;
+    if cfg!(feature = "dcdc") {
+        power.dcdcen.write(|w| w.dcdcen().enabled());
+        // REG0, the stage from VDDH, is only in the nRF52840's PAC.
+        #[cfg(feature = "nrf52840")]
+        if high_voltage {
+            power.dcdcen0.write(|w| w.dcdcen().enabled());
+        }
+    }
+    info!(
+        "regulators: {} mode, {}",
+        if high_voltage {
+            "high voltage"
+        } else {
+            "normal"
+        },
+        if cfg!(feature = "dcdc") {
+            "DC/DC"
+        } else {
+            "LDO"
+        }
+    );
+}
+
+/// Switches between constant latency and low power mode.
+pub fn set_mode(mode: PowerMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::power_mode(mode == PowerMode::ConstantLatency);
+    }
+    // SAFETY: the tasks only switch the sub-power mode.
+    let power = unsafe { &*POWER::ptr() };
+    match mode {
+        PowerMode::LowPower => power.tasks_lowpwr.write(|w| unsafe { w.bits(1) }),
+        PowerMode::ConstantLatency => power.tasks_constlat.write(|w| unsafe { w.bits(1) }),
+    }
+}
+
+impl PowerMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerMode::LowPower => "low power",
+            PowerMode::ConstantLatency => "constant latency",
+        }
+    }
+}
+
+pub fn mode() -> PowerMode {
+    if MODE.load(Ordering::Relaxed) == PowerMode::ConstantLatency as u8 {
+        PowerMode::ConstantLatency
+    } else {
+        PowerMode::LowPower
+    }
+}
+
+// SCB->SCR
+const SCR_SEVONPEND: u32 = 1 << 4;
+
+/// Prepares the core for `strategy`.
+pub fn init_idle(strategy: IdleStrategy, scb: &mut SCB) {
+    if strategy == IdleStrategy::Wfe {
+        // SAFETY: only changes which events wake the core from WFE.
+        unsafe { scb.scr.modify(|scr| scr | SCR_SEVONPEND) };
+    }
+}
+
+/// One pass of the idle loop: waits for the next interrupt as `strategy`
+/// says and returns once it has been handled.
+pub fn wait(strategy: IdleStrategy) {
+    wait_unless(strategy, || false);
+}
+
+/// As `wait`, but returns at once if `ready` says so once interrupts are
+/// masked, so work a handler queued just before is not slept through.
+pub fn wait_unless(strategy: IdleStrategy, ready: impl Fn() -> bool) {
+    match strategy {
+        // Interrupts stay masked until the state is noted, WFI and WFE (with
+        // SEVONPEND) still wake on a pending one.
+        IdleStrategy::Wfi => cortex_m::interrupt::free(|_| {
+            if ready() {
+                return;
+            }
+            sleep();
+            markers::set(Marker::Sleep, true);
+            cortex_m::asm::wfi();
+            markers::set(Marker::Sleep, false);
+        }),
+        IdleStrategy::Wfe => cortex_m::interrupt::free(|_| {
+            if ready() {
+                return;
+            }
+            sleep();
+            markers::set(Marker::Sleep, true);
+            cortex_m::asm::wfe();
+            markers::set(Marker::Sleep, false);
+        }),
+        IdleStrategy::Busy => cortex_m::asm::nop(),
+    }
+}
+
+// Notes the state the idle loop is about to sleep in.
+fn sleep() {
+    let state = if clock::hfxo_running() {
+        PowerState::Idle
+    } else {
+        PowerState::Sleep
+    };
+    CURRENT.store(state as u8, Ordering::Relaxed);
+}
+
+/// Called at the start of every handler that can wake the chip. Only the
+/// first one after a sleep is recorded.
+pub fn woke(reason: WakeReason) {
+    let state = CURRENT.swap(PowerState::Active as u8, Ordering::Relaxed);
+    if state != PowerState::Active as u8 {
+        WOKE_FROM.store(state, Ordering::Relaxed);
+        WAKE_REASON.store(reason as u8, Ordering::Relaxed);
+    }
+}
+
+/// State the chip was in until the last wake-up, `POWER_STATE` register.
+pub fn woke_from() -> u8 {
+    WOKE_FROM.load(Ordering::Relaxed)
+}
+
+/// Cause of the last wake-up, `WAKE_REASON` register.
+pub fn wake_reason() -> u8 {
+    WAKE_REASON.load(Ordering::Relaxed)
+}
+
+/// `last wake: <reason> from <state>, HFXO <on|off>, <mode>`
+pub struct Status;
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match woke_from() {
+            1 => "idle",
+            2 => "sleep",
+            _ => "active",
+        };
+        let reason = match wake_reason() {
+            1 => "address match",
+            2 => "button",
+            3 => "timer",
+            4 => "analog input",
+            5 => "SPI select",
+            _ => "none",
+        };
+        write!(
+            f,
+            "last wake: {} from {}, HFXO {}, {}, {}",
+            reason,
+            state,
+            if clock::hfxo_running() { "on" } else { "off" },
+            mode().name(),
+            if status::is_set(status::VBUS) {
+                "USB powered"
+            } else {
+                "no VBUS"
+            }
+        )
+    }
+}
+
+// Bus pins of TWIS and TWIM, in the PSEL numbering.
+const BUS_PINS: [usize; 4] = [
+    board::TWIS_SCL,
+    board::TWIS_SDA,
+    board::TWIM_SDA,
+    board::TWIM_SCL,
+];
+
+/// Puts the pins outside the `board` pin map and the unused peripherals
+/// into their lowest-power state. Call at the end of `init`.
+pub fn park_unused_pins() {
+    // SAFETY: only pins and peripherals nothing else owns are written.
+    let p0 = unsafe { &*P0::ptr() };
+    let mut parked = 0;
+    for pin in (0..32).filter(|pin| board::P0_USED >> pin & 1 == 0) {
+        p0.pin_cnf[pin].write(|w| w.dir().input().input().disconnect().pull().disabled());
+        parked += 1;
+    }
+    for pin in (0..board::P1_PINS).filter(|pin| board::P1_USED >> pin & 1 == 0) {
+        let p1 = board::port(board::p1(pin)).0;
+        p1.pin_cnf[pin].write(|w| w.dir().input().input().disconnect().pull().disabled());
+        parked += 1;
+    }
+    // SAFETY: as above, UARTE0 is only owned with `telemetry` and USBD with
+    // `usb-console`.
+    unsafe {
+        #[cfg(not(feature = "nrf52832"))]
+        if !cfg!(feature = "usb-console") {
+            (*crate::hal::pac::USBD::ptr())
+                .enable
+                .write(|w| w.enable().disabled());
+        }
+        if !cfg!(feature = "telemetry") {
+            (*UARTE0::ptr()).enable.write(|w| w.enable().disabled());
+        }
+    }
+    info!("parked {} unused pins", parked);
+}
+
+/// Releases the bus and enters System OFF. The button wakes the chip, which
+/// then boots with `resetreas::OFF` set.
+pub fn system_off() -> ! {
+    // The SoftDevice enters System OFF itself, and needs SVC for it: only
+    // the application's interrupts are masked.
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        // SAFETY: never released, the chip is going down.
+        let _ = unsafe { critical_section::acquire() };
+        release_pins();
+        softdevice::system_off();
+    }
+    cortex_m::interrupt::disable();
+    release_pins();
+    // SAFETY: as in `release_pins`.
+    unsafe { (*POWER::ptr()).systemoff.write(|w| w.systemoff().enter()) };
+    // With a debugger attached System OFF is only emulated and execution
+    // carries on here.
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+// Releases the bus and arms the button to wake the chip from System OFF.
+fn release_pins() {
+    // SAFETY: the chip is going down; nothing else touches these peripherals
+    // any more.
+    unsafe {
+        (*TWIS0::ptr()).enable.write(|w| w.enable().disabled());
+        (*TWIM1::ptr()).enable.write(|w| w.enable().disabled());
+        // Inputs with the input buffer disconnected: no drive, no pull, no
+        // leakage, so the controller sees the lines released.
+        for pin in BUS_PINS {
+            board::port(pin).0.pin_cnf[pin % 32]
+                .write(|w| w.dir().input().input().disconnect().pull().disabled());
+        }
+        busgate::disarm();
+        if cfg!(feature = "gpio-expander") {
+            expander::disarm();
+        }
+        let (port, bit) = board::port(board::BUTTON);
+        port.pin_cnf[board::BUTTON % 32].write(|w| {
+            w.dir()
+                .input()
+                .input()
+                .connect()
+                .pull()
+                .pullup()
+                .sense()
+                .low()
+        });
+        // LPCOMP, if enabled, keeps running and wakes the chip as well.
+        // A DETECT still latched from the last press would wake the chip
+        // right away.
+        port.latch.write(|w| w.bits(bit));
+    }
+}
+
+/// Enables the power-fail warning at 2.7 V (VDD) and its interrupt.
+pub fn init_pof(power: &POWER) {
+    #[cfg(not(feature = "nrf52832"))]
+    power
+        .pofcon
+        .write(|w| w.pof().enabled().threshold().v27().thresholdvddh().v27());
+    #[cfg(feature = "nrf52832")]
+    power.pofcon.write(|w| w.pof().enabled().threshold().v27());
+    power.intenset.write(|w| w.pofwarn().set());
+}
+
+/// Enables the VBUS detection interrupts and notes the current state.
+#[cfg(not(feature = "nrf52832"))]
+pub fn init_usb(power: &POWER) {
+    power.intenset.write(|w| {
+        w.usbdetected()
+            .set()
+            .usbremoved()
+            .set()
+            .usbpwrrdy()
+            .bit(cfg!(feature = "usb-console"))
+    });
+    set_vbus(power.usbregstatus.read().vbusdetect().is_vbus_present());
+    if cfg!(feature = "usb-console") {
+        // In case the supply was ready before the interrupt was enabled.
+        NVIC::pend(Interrupt::USBD);
+    }
+}
+
+/// Handles the VBUS events, from the POWER_CLOCK interrupt.
+#[cfg(not(feature = "nrf52832"))]
+pub fn on_usb() {
+    // SAFETY: the USB events are only handled here.
+    let power = unsafe { &*POWER::ptr() };
+    if power.events_usbpwrrdy.read().bits() != 0 {
+        power.events_usbpwrrdy.reset();
+        NVIC::pend(Interrupt::USBD);
+    }
+    let detected = power.events_usbdetected.read().bits() != 0;
+    let removed = power.events_usbremoved.read().bits() != 0;
+    if !detected && !removed {
+        return;
+    }
+    power.events_usbdetected.reset();
+    power.events_usbremoved.reset();
+    // Both events may be pending after a short glitch, the status register
+    // has the final word.
+    set_vbus(power.usbregstatus.read().vbusdetect().is_vbus_present());
+}
+
+// The nRF52832 has no USB, VBUS is never present.
+#[cfg(feature = "nrf52832")]
+pub fn init_usb(_: &POWER) {}
+
+#[cfg(feature = "nrf52832")]
+pub fn on_usb() {}
+
+/// Notes whether VBUS is present, from the POWER events or the SoftDevice's.
+#[cfg_attr(feature = "nrf52832", allow(dead_code))]
+pub fn set_vbus(present: bool) {
+    status::set_live(status::VBUS, present);
+    tracebuf::record(Event::Vbus, 0, present as u16);
+    info!("VBUS {}", if present { "present" } else { "removed" });
+}
+
+/// Handles the power-fail warning, from the POWER_CLOCK interrupt.
+pub fn on_pofwarn() {
+    // SAFETY: the POFWARN event is only handled here.
+    let power = unsafe { &*POWER::ptr() };
+    if power.events_pofwarn.read().bits() == 0 {
+        return;
+    }
+    power.events_pofwarn.reset();
+    brownout();
+}
+
+/// Quiesces DMA for a power-fail warning, from the POWER event or the
+/// SoftDevice's.
+pub fn brownout() {
+    status::set(status::BROWNOUT);
+    if postmortem::transfer_running() {
+        // The STOPPED that follows is handled by `on_twis` as usual.
+        // SAFETY: triggering STOP is harmless whatever TWIS is doing.
+        unsafe { (*TWIS0::ptr()).tasks_stop.write(|w| w.bits(1)) };
+    }
+    tracebuf::record(Event::Brownout, 0, 0);
+    warn!("supply below 2.7 V, DMA quiesced");
+}
@@ -0,0 +1,95 @@
+// Per-task execution time, measured with the DWT cycle counter.
+//
+// Every `systrace::Span` reports the cycles between task entry and exit.
+// Times are inclusive: a task preempted by a higher priority one is charged
+// for the preempting task as well. The cycle counter wraps after ~67 s at
+// 64 MHz, far longer than any task runs.
+
+use {
+    crate::{lock, tracebuf::TaskId},
+    core::cell::RefCell,
+    cortex_m::{
+        interrupt::Mutex,
+        peripheral::{DCB, DWT},
+    },
+};
+
+const CPU_HZ: u32 = 64_000_000;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    count: u32,
+    total: u64,
+    min: u32,
+    max: u32,
+}
+
+const EMPTY: Entry = Entry {
+    count: 0,
+    total: 0,
+    min: u32::MAX,
+    max: 0,
+};
+
+static TABLE: Mutex<RefCell<[Entry; TaskId::ALL.len()]>> =
+    Mutex::new(RefCell::new([EMPTY; TaskId::ALL.len()]));
+
+/// Starts the cycle counter.
+pub fn init(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+/// Current cycle count, the start value for `record`.
+#[inline(always)]
+pub fn now() -> u32 {
+    DWT::cycle_count()
+}
+
+/// Charges the cycles since `start` to `task`.
+pub fn record(task: TaskId, start: u32) {
+    let cycles = now().wrapping_sub(start);
+    lock::free(|cs| {
+        let mut table = TABLE.borrow(cs).borrow_mut();
+        let entry = &mut table[index(task)];
+        entry.count += 1;
+        entry.total += cycles as u64;
+        entry.min = entry.min.min(cycles);
+        entry.max = entry.max.max(cycles);
+    });
+}
+
+/// Prints min/avg/max execution time of every task that has run.
+pub fn print_report() {
+    let table = lock::free(|cs| *TABLE.borrow(cs).borrow());
+    println!("task            runs     min us     avg us     max us");
+    for task in TaskId::ALL {
+        let entry = &table[index(task)];
+        if entry.count == 0 {
+            continue;
+        }
+        let avg = (entry.total / entry.count as u64) as u32;
+        println!(
+            "{:<14} {:>6} {:>10} {:>10} {:>10}",
+            task.name(),
+            entry.count,
+            micros(entry.min),
+            micros(avg),
+            micros(entry.max),
+        );
+    }
+}
+
+/// Forgets all measurements.
+pub fn reset() {
+    lock::free(|cs| *TABLE.borrow(cs).borrow_mut() = [EMPTY; TaskId::ALL.len()]);
+}
+
+fn index(task: TaskId) -> usize {
+    task as usize - 1
+}
+
+/// Converts DWT cycles to microseconds.
+pub fn micros(cycles: u32) -> u32 {
+    cycles / (CPU_HZ / 1_000_000)
+}
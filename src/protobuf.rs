@@ -0,0 +1,270 @@
+// Protocol Buffers encoding of the `message` types (`protobuf` feature).
+//
+// For hosts whose tooling is protobuf based: `proto/twis.proto` declares the
+// messages for nanopb, micropb, protoc and the like, and this is the
+// firmware side of it, written by hand rather than generated, so it shares
+// `message`'s types with the other encodings. A message is a `Request` (or
+// `Reply`) with the version as field 1 and the variant as a oneof of
+// submessages numbered from 2 in variant order, whose fields are numbered
+// from 1 in the order `message` lists them; `build.rs` fails the build if
+// the .proto numbers or names them otherwise.
+//
+// Decoding follows the protobuf rules: fields come in any order, unknown
+// ones are skipped, a missing field is 0 (or empty) and the last of a
+// repeated one wins. The version must be encoded first, as a message is
+// told from a postcard or CBOR one by its first byte, the tag of field 1.
+// A 0x00 where a tag would be ends the message, as the padding after a
+// reply read from MESSAGE does; no field has number 0.
+
+use crate::{
+    error::ProtocolError,
+    wire::{Decoder, Encoder},
+};
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LEN: u8 = 2;
+const FIXED32: u8 = 5;
+
+/// Tag of field 1 as a varint, the first byte of a message.
+pub const FIRST_BYTE: u8 = 1 << 3 | VARINT;
+
+// Fields of a submessage kept by `Reader`, the most any variant has.
+const FIELDS: usize = 3;
+
+/// Protocol Buffers encoder.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    // Where the length of the variant submessage goes, and the number of
+    // the next field in it.
+    sub_at: Option<usize>,
+    field: u32,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Writer {
+            buf,
+            len: 0,
+            sub_at: None,
+            field: 0,
+        }
+    }
+
+    fn put(&mut self, byte: u8) -> Result<(), ProtocolError> {
+        let Some(dest) = self.buf.get_mut(self.len) else {
+            return Err(ProtocolError::BadLength {
+                len: self.len as u32 + 1,
+                max: self.buf.len(),
+            });
+        };
+        *dest = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn varint(&mut self, mut value: u32) -> Result<(), ProtocolError> {
+        while value >= 0x80 {
+            self.put(value as u8 | 0x80)?;
+            value >>= 7;
+        }
+        self.put(value as u8)
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u8) -> Result<(), ProtocolError> {
+        self.varint(field << 3 | wire_type as u32)
+    }
+
+    fn next_field(&mut self) -> u32 {
+        self.field += 1;
+        self.field
+    }
+}
+
+impl Encoder for Writer<'_> {
+    fn message(&mut self, version: u8, variant: u32, _: usize) -> Result<(), ProtocolError> {
+        self.tag(1, VARINT)?;
+        self.varint(version as u32)?;
+        self.tag(variant + 2, LEN)?;
+        // The length is patched in by `finish`, one byte is plenty.
+        self.sub_at = Some(self.len);
+        self.put(0)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), ProtocolError> {
+        self.uint(value as u32)
+    }
+
+    // Zero is the default and left out, as protobuf encoders do.
+    fn uint(&mut self, value: u32) -> Result<(), ProtocolError> {
+        let field = self.next_field();
+        if value == 0 {
+            return Ok(());
+        }
+        self.tag(field, VARINT)?;
+        self.varint(value)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        let field = self.next_field();
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.tag(field, LEN)?;
+        self.varint(bytes.len() as u32)?;
+        bytes.iter().try_for_each(|&byte| self.put(byte))
+    }
+
+    fn finish(&mut self) -> Result<usize, ProtocolError> {
+        if let Some(at) = self.sub_at {
+            let len = self.len - at - 1;
+            if len >= 0x80 {
+                return Err(ProtocolError::BadLength {
+                    len: len as u32,
+                    max: 0x7f,
+                });
+            }
+            self.buf[at] = len as u8;
+        }
+        Ok(self.len)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Field<'a> {
+    Absent,
+    Uint(u32),
+    Bytes(&'a [u8]),
+}
+
+/// Protocol Buffers decoder. Parses the whole message in `message`, then
+/// hands out the submessage fields in order.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    fields: [Field<'a>; FIELDS],
+    next: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            fields: [Field::Absent; FIELDS],
+            next: 0,
+        }
+    }
+
+    fn next_field(&mut self) -> Field<'a> {
+        let field = self.fields.get(self.next).copied().unwrap_or(Field::Absent);
+        self.next += 1;
+        field
+    }
+}
+
+// One field of `data`, from `at` on: number and value, and where the next
+// one starts. `None` at the end or where a 0x00 tag is.
+fn field(data: &[u8], at: usize) -> Result<Option<(u32, Field<'_>, usize)>, ProtocolError> {
+    if data.get(at).is_none_or(|&byte| byte == 0) {
+        return Ok(None);
+    }
+    let (tag, at) = varint(data, at)?;
+    let number = u32::try_from(tag >> 3).map_err(|_| ProtocolError::Malformed)?;
+    let (value, at) = match tag as u8 & 0x07 {
+        VARINT => {
+            let (value, at) = varint(data, at)?;
+            // Too big for any field: a uint32 never is, nor a negative int32.
+            let value = u32::try_from(value).map_err(|_| ProtocolError::Malformed)?;
+            (Field::Uint(value), at)
+        }
+        LEN => {
+            let (len, at) = varint(data, at)?;
+            let end = usize::try_from(len)
+                .ok()
+                .and_then(|len| at.checked_add(len))
+                .filter(|&end| end <= data.len())
+                .ok_or(ProtocolError::Malformed)?;
+            (Field::Bytes(&data[at..end]), end)
+        }
+        FIXED64 if at + 8 <= data.len() => (Field::Absent, at + 8),
+        FIXED32 if at + 4 <= data.len() => (Field::Absent, at + 4),
+        // Groups, reserved wire types or a truncated fixed field.
+        _ => return Err(ProtocolError::Malformed),
+    };
+    Ok(Some((number, value, at)))
+}
+
+fn varint(data: &[u8], mut at: usize) -> Result<(u64, usize), ProtocolError> {
+    let mut value = 0;
+    for shift in (0..70).step_by(7) {
+        let byte = *data.get(at).ok_or(ProtocolError::Malformed)?;
+        at += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, at));
+        }
+    }
+    Err(ProtocolError::Malformed)
+}
+
+impl Decoder for Reader<'_> {
+    fn message(&mut self) -> Result<(u8, u32), ProtocolError> {
+        let mut version = 0;
+        let mut variant = None;
+        let mut at = 0;
+        while let Some((number, value, next)) = field(self.data, at)? {
+            match (number, value) {
+                (1, Field::Uint(value)) => {
+                    version = u8::try_from(value).map_err(|_| ProtocolError::Malformed)?
+                }
+                (2.., Field::Bytes(sub)) => variant = Some((number - 2, sub)),
+                _ => {}
+            }
+            at = next;
+        }
+        let (variant, sub) = variant.ok_or(ProtocolError::Malformed)?;
+        self.fields = [Field::Absent; FIELDS];
+        let mut at = 0;
+        loop {
+            match field(sub, at)? {
+                Some((number, value, next)) => {
+                    let slot = (number as usize)
+                        .checked_sub(1)
+                        .and_then(|index| self.fields.get_mut(index));
+                    if let (Some(slot), Field::Uint(_) | Field::Bytes(_)) = (slot, value) {
+                        *slot = value;
+                    }
+                    at = next;
+                }
+                // A 0x00 inside the submessage is a tag of field 0.
+                None if at < sub.len() => return Err(ProtocolError::Malformed),
+                None => break,
+            }
+        }
+        Ok((version, variant))
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        u8::try_from(self.uint()?).map_err(|_| ProtocolError::Malformed)
+    }
+
+    fn uint(&mut self) -> Result<u32, ProtocolError> {
+        match self.next_field() {
+            Field::Absent => Ok(0),
+            Field::Uint(value) => Ok(value),
+            Field::Bytes(_) => Err(ProtocolError::Malformed),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], ProtocolError> {
+        match self.next_field() {
+            Field::Absent => Ok(&[]),
+            Field::Bytes(bytes) => Ok(bytes),
+            Field::Uint(_) => Err(ProtocolError::Malformed),
+        }
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+}
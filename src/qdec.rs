@@ -0,0 +1,113 @@
+// Rotary encoder on the QDEC (`qdec` feature).
+//
+// The QDEC samples phases A (P0.02) and B (P0.29) every 1024 us, debounced,
+// and accumulates the steps in hardware. Every `REPORT_SAMPLES` samples
+// with movement it raises REPORTRDY, and the READCLRACC short hands the
+// count over; `on_interrupt` adds it to the position, turns it into a
+// velocity and sets STATUS bit 2, so the CPU sleeps while the knob is
+// still. The pins have pull-ups for an encoder switching to ground.
+//
+// The velocity is that of the last report, which only comes with movement,
+// so it reads as 0 once no report has come for two report periods.
+
+use {
+    crate::{
+        board,
+        hal::pac::QDEC,
+        status,
+        tracebuf::{self, Event},
+    },
+    core::sync::atomic::{AtomicI16, AtomicI32, AtomicU32, Ordering},
+};
+
+const SAMPLE_US: u32 = 1024;
+const REPORT_SAMPLES: u32 = 10;
+const REPORT_US: u32 = SAMPLE_US * REPORT_SAMPLES;
+// Two report periods in RTC ticks.
+const STALE_TICKS: u32 = (2 * REPORT_US as u64 * crate::mono::TICK_HZ as u64 / 1_000_000) as u32;
+
+static POSITION: AtomicI32 = AtomicI32::new(0);
+// Counts per second from the last report, and when it came.
+static VELOCITY: AtomicI16 = AtomicI16::new(0);
+static REPORTED: AtomicU32 = AtomicU32::new(0);
+
+fn now() -> u32 {
+    crate::app::monotonics::now().ticks() as u32
+}
+
+fn regs() -> &'static crate::hal::pac::qdec::RegisterBlock {
+    // SAFETY: QDEC is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*QDEC::ptr() }
+}
+
+/// Takes `QDEC` and starts sampling the encoder.
+pub fn init(qdec: QDEC) {
+    // SAFETY: A and B are owned by this module via `board`.
+    let p0 = unsafe { &*crate::hal::pac::P0::ptr() };
+    for pin in [board::QDEC_A, board::QDEC_B] {
+        p0.pin_cnf[pin].write(|w| w.dir().input().input().connect().pull().pullup());
+    }
+    qdec.psel
+        .a
+        .write(|w| unsafe { w.pin().bits(board::QDEC_A as u8).connect().connected() });
+    qdec.psel
+        .b
+        .write(|w| unsafe { w.pin().bits(board::QDEC_B as u8).connect().connected() });
+    qdec.psel.led.write(|w| w.connect().disconnected());
+    qdec.sampleper.write(|w| w.sampleper()._1024us());
+    qdec.reportper.write(|w| w.reportper()._10smpl());
+    qdec.dbfen.write(|w| w.dbfen().enabled());
+    qdec.shorts.write(|w| w.reportrdy_readclracc().enabled());
+    qdec.intenset.write(|w| w.reportrdy().set());
+    qdec.enable.write(|w| w.enable().enabled());
+    qdec.tasks_start.write(|w| unsafe { w.bits(1) });
+    info!(
+        "encoder on A P0.{:02} B P0.{:02}",
+        board::QDEC_A,
+        board::QDEC_B
+    );
+}
+
+/// Handles REPORTRDY: takes the steps of the report.
+pub fn on_interrupt() {
+    let qdec = regs();
+    if qdec.events_reportrdy.read().bits() == 0 {
+        return;
+    }
+    qdec.events_reportrdy.reset();
+    let steps = qdec.accread.read().bits() as i32;
+    // Samples with both phases changed, counted as no step.
+    let doubles = qdec.accdblread.read().bits();
+    if doubles != 0 {
+        trace!("encoder: {} double transitions", doubles);
+    }
+    if steps == 0 {
+        return;
+    }
+    let position = POSITION
+        .fetch_add(steps, Ordering::Relaxed)
+        .wrapping_add(steps);
+    let velocity = steps as i64 * 1_000_000 / REPORT_US as i64;
+    VELOCITY.store(
+        velocity.clamp(i16::MIN as i64, i16::MAX as i64) as i16,
+        Ordering::Relaxed,
+    );
+    REPORTED.store(now(), Ordering::Relaxed);
+    status::set(status::ENCODER);
+    tracebuf::record(Event::EncoderMoved, 0, position as u16);
+    trace!("encoder at {}, {} steps/s", position, velocity);
+}
+
+/// Steps since boot. Which way counts up depends on the wiring of A and B.
+pub fn position() -> i32 {
+    POSITION.load(Ordering::Relaxed)
+}
+
+/// Steps per second, 0 once the encoder has stopped.
+pub fn velocity() -> i16 {
+    if now().wrapping_sub(REPORTED.load(Ordering::Relaxed)) > STALE_TICKS {
+        return 0;
+    }
+    VELOCITY.load(Ordering::Relaxed)
+}
@@ -0,0 +1,328 @@
+// The board's QSPI flash, read, programmed and erased over I2C
+// (`qspi-flash` feature).
+//
+// The nRF52840-MDK carries an 8 MB MX25R6435F on the QSPI pins. The
+// FLASH_READ, FLASH_PROGRAM and FLASH_ERASE requests start a QSPI EasyDMA
+// operation on it and return right away; FLASH_STATUS shows it busy until
+// the READY event, which the QSPI only raises once the flash itself has
+// finished a program or erase. A controller programs an image chunk by
+// chunk, polling FLASH_STATUS in between, and reads it back the same way
+// from the FLASH_DATA FIFO:
+//
+//   write [COMMAND, FLASH_READ, addr: u24 LE], poll FLASH_STATUS until not
+//   busy, read `CHUNK_LEN` bytes from FLASH_DATA
+//
+// The QSPI moves whole words, so addresses and program lengths are
+// multiples of 4. Only single-line opcodes are used, which work without
+// setting the flash's quad enable bit.
+//
+// With `usb-msc`, `journal` keeps its records at the top of the flash and
+// shares the QSPI: its operations go through `journal_program` and
+// `journal_erase`, which leave FLASH_DATA alone, and it reads through XIP.
+//
+// Only the nRF52840 has a QSPI. On the other chips there is never a flash,
+// so every request is refused before one would be started.
+
+#[cfg(feature = "nrf52840")]
+use crate::{
+    board,
+    hal::pac::QSPI,
+    tracebuf::{self, Event},
+};
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering},
+};
+
+/// Flash size in bytes.
+pub const SIZE: u32 = 8 << 20;
+/// Bytes read by FLASH_READ, the length of the FLASH_DATA FIFO.
+pub const CHUNK_LEN: usize = 32;
+/// Most bytes one FLASH_PROGRAM carries, with the pointer, opcode and
+/// address still fitting the TWIS buffer.
+pub const PROGRAM_LEN: usize = 24;
+/// A program may not cross a page of the flash.
+pub const PAGE_LEN: u32 = 256;
+
+/// FLASH_STATUS bits.
+pub const BUSY: u8 = 1 << 0;
+/// The last request was refused: busy, or no flash.
+pub const REJECTED: u8 = 1 << 1;
+pub const PRESENT: u8 = 1 << 7;
+
+// 32 MHz / (SCK_DIV + 1), 8 MHz.
+const SCK_DIV: u8 = 3;
+const READ_ID: u8 = 0x9f;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EraseSize {
+    Sector = 0,
+    Block = 1,
+    Chip = 2,
+}
+
+impl EraseSize {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(EraseSize::Sector),
+            1 => Some(EraseSize::Block),
+            2 => Some(EraseSize::Chip),
+            _ => None,
+        }
+    }
+
+    /// Alignment the address must have.
+    pub fn len(self) -> u32 {
+        match self {
+            EraseSize::Sector => 4 << 10,
+            EraseSize::Block => 64 << 10,
+            EraseSize::Chip => SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Read = 1,
+    Program = 2,
+    Erase = 3,
+    // Not traced, it would end up in the journal itself.
+    Journal = 4,
+}
+
+// EasyDMA buffer of the QSPI, word aligned as it requires.
+#[repr(align(4))]
+struct Chunk([u8; CHUNK_LEN]);
+
+static mut CHUNK: Chunk = Chunk([0xff; CHUNK_LEN]);
+
+static STATUS: AtomicU8 = AtomicU8::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// `Operation` and 4 KB sector of the running operation, for the trace.
+static OPERATION: AtomicU8 = AtomicU8::new(0);
+static SECTOR: AtomicU16 = AtomicU16::new(0);
+
+#[cfg(feature = "nrf52840")]
+fn regs() -> &'static crate::hal::pac::qspi::RegisterBlock {
+    // SAFETY: QSPI is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*QSPI::ptr() }
+}
+
+#[cfg(feature = "nrf52840")]
+fn wait_ready() {
+    let qspi = regs();
+    while qspi.events_ready.read().bits() == 0 {}
+    qspi.events_ready.reset();
+}
+
+/// Takes `QSPI`, activates it and checks for the flash.
+#[cfg(feature = "nrf52840")]
+pub fn init(qspi: QSPI) {
+    // Pin number and port 1, connected.
+    let psel = |pin: usize| pin as u32 | 1 << 5;
+    // SAFETY: pins of P1 owned by this module via `board`.
+    unsafe {
+        qspi.psel.sck.write(|w| w.bits(psel(board::QSPI_SCK)));
+        qspi.psel.csn.write(|w| w.bits(psel(board::QSPI_CSN)));
+        qspi.psel.io0.write(|w| w.bits(psel(board::QSPI_IO0)));
+        qspi.psel.io1.write(|w| w.bits(psel(board::QSPI_IO1)));
+        qspi.psel.io2.write(|w| w.bits(psel(board::QSPI_IO2)));
+        qspi.psel.io3.write(|w| w.bits(psel(board::QSPI_IO3)));
+    }
+    qspi.ifconfig0.write(|w| {
+        w.readoc()
+            .fastread()
+            .writeoc()
+            .pp()
+            .addrmode()
+            ._24bit()
+            .dpmenable()
+            .disable()
+            .ppsize()
+            ._256bytes()
+    });
+    qspi.ifconfig1.write(|w| unsafe {
+        w.sckdelay()
+            .bits(1)
+            .spimode()
+            .mode0()
+            .sckfreq()
+            .bits(SCK_DIV)
+    });
+    qspi.enable.write(|w| w.enable().enabled());
+    qspi.events_ready.reset();
+    qspi.tasks_activate.write(|w| unsafe { w.bits(1) });
+    wait_ready();
+
+    // IO2 and IO3 are WP# and HOLD# in single-line mode, kept high.
+    qspi.cinstrconf.write(|w| unsafe {
+        w.opcode()
+            .bits(READ_ID)
+            .length()
+            ._4b()
+            .lio2()
+            .set_bit()
+            .lio3()
+            .set_bit()
+    });
+    wait_ready();
+    let id = qspi.cinstrdat0.read().bits().to_le_bytes();
+    let [manufacturer, kind, capacity, _] = id;
+    if manufacturer == 0x00 || manufacturer == 0xff {
+        warn!("no QSPI flash found");
+        return;
+    }
+    info!(
+        "QSPI flash {:02x} {:02x} {:02x}",
+        manufacturer, kind, capacity
+    );
+    STATUS.store(PRESENT, Ordering::Relaxed);
+    qspi.intenset.write(|w| w.ready().set());
+}
+
+// Claims the QSPI for an operation, or records the request as rejected.
+fn begin(operation: Operation, address: u32) -> bool {
+    let status = STATUS.load(Ordering::Relaxed);
+    if status & PRESENT == 0 || RUNNING.swap(true, Ordering::Relaxed) {
+        STATUS.fetch_or(REJECTED, Ordering::Relaxed);
+        return false;
+    }
+    OPERATION.store(operation as u8, Ordering::Relaxed);
+    SECTOR.store((address >> 12) as u16, Ordering::Relaxed);
+    STATUS.store(PRESENT | BUSY, Ordering::Relaxed);
+    true
+}
+
+/// Starts reading `CHUNK_LEN` bytes at `address` into FLASH_DATA. Returns
+/// false if refused.
+pub fn read(address: u32) -> bool {
+    if !begin(Operation::Read, address) {
+        return false;
+    }
+    start_read(address, addr_of_mut!(CHUNK) as u32, CHUNK_LEN);
+    true
+}
+
+/// Starts programming `data` at `address`. Returns false if refused.
+pub fn program(address: u32, data: &[u8]) -> bool {
+    if !begin(Operation::Program, address) {
+        return false;
+    }
+    // SAFETY: the QSPI is idle until the write starts below.
+    let chunk = unsafe { &mut (*addr_of_mut!(CHUNK)).0 };
+    chunk[..data.len()].copy_from_slice(data);
+    start_write(address, addr_of!(CHUNK) as u32, data.len());
+    true
+}
+
+/// Starts programming from `data` for `journal`. EasyDMA reads it in place,
+/// so it must stay untouched until the operation is done. Returns false if
+/// busy.
+pub fn journal_program(address: u32, data: &'static [u8]) -> bool {
+    if !begin(Operation::Journal, address) {
+        return false;
+    }
+    start_write(address, data.as_ptr() as u32, data.len());
+    true
+}
+
+/// Starts erasing the sector at `address` for `journal`. Returns false if
+/// busy.
+pub fn journal_erase(address: u32) -> bool {
+    if !begin(Operation::Journal, address) {
+        return false;
+    }
+    start_erase(address, EraseSize::Sector);
+    true
+}
+
+/// Starts erasing `size` at `address`. Returns false if refused.
+pub fn erase(address: u32, size: EraseSize) -> bool {
+    if !begin(Operation::Erase, address) {
+        return false;
+    }
+    start_erase(address, size);
+    true
+}
+
+// Starts reading `len` bytes at `address` into RAM at `dst`.
+#[cfg(feature = "nrf52840")]
+fn start_read(address: u32, dst: u32, len: usize) {
+    let qspi = regs();
+    qspi.read.src.write(|w| unsafe { w.src().bits(address) });
+    qspi.read.dst.write(|w| unsafe { w.dst().bits(dst) });
+    qspi.read.cnt.write(|w| unsafe { w.cnt().bits(len as u32) });
+    qspi.tasks_readstart.write(|w| unsafe { w.bits(1) });
+}
+
+// Starts programming `len` bytes from RAM at `src` to `address`.
+#[cfg(feature = "nrf52840")]
+fn start_write(address: u32, src: u32, len: usize) {
+    let qspi = regs();
+    qspi.write.dst.write(|w| unsafe { w.dst().bits(address) });
+    qspi.write.src.write(|w| unsafe { w.src().bits(src) });
+    qspi.write
+        .cnt
+        .write(|w| unsafe { w.cnt().bits(len as u32) });
+    qspi.tasks_writestart.write(|w| unsafe { w.bits(1) });
+}
+
+#[cfg(feature = "nrf52840")]
+fn start_erase(address: u32, size: EraseSize) {
+    let qspi = regs();
+    qspi.erase.ptr.write(|w| unsafe { w.ptr().bits(address) });
+    qspi.erase.len.write(|w| match size {
+        EraseSize::Sector => w.len()._4kb(),
+        EraseSize::Block => w.len()._64kb(),
+        EraseSize::Chip => w.len().all(),
+    });
+    qspi.tasks_erasestart.write(|w| unsafe { w.bits(1) });
+}
+
+#[cfg(not(feature = "nrf52840"))]
+fn start_read(_: u32, _: u32, _: usize) {}
+
+#[cfg(not(feature = "nrf52840"))]
+fn start_write(_: u32, _: u32, _: usize) {}
+
+#[cfg(not(feature = "nrf52840"))]
+fn start_erase(_: u32, _: EraseSize) {}
+
+/// Handles READY: the operation has finished.
+#[cfg(feature = "nrf52840")]
+pub fn on_interrupt() {
+    let qspi = regs();
+    if qspi.events_ready.read().bits() == 0 {
+        return;
+    }
+    qspi.events_ready.reset();
+    STATUS.fetch_and(!BUSY, Ordering::Relaxed);
+    RUNNING.store(false, Ordering::Relaxed);
+    let operation = OPERATION.load(Ordering::Relaxed);
+    if operation == Operation::Journal as u8 {
+        return;
+    }
+    let sector = SECTOR.load(Ordering::Relaxed);
+    tracebuf::record(Event::FlashDone, operation, sector);
+    trace!("QSPI operation {} done in sector {}", operation, sector);
+}
+
+/// FLASH_STATUS register.
+pub fn status() -> u8 {
+    STATUS.load(Ordering::Relaxed)
+}
+
+/// Fills `buf` from the FLASH_DATA FIFO: the last chunk read (or
+/// programmed), 0xff while an operation is running.
+pub fn take_data(buf: &mut [u8]) {
+    if RUNNING.load(Ordering::Relaxed) {
+        buf.fill(0xff);
+        return;
+    }
+    // SAFETY: no operation is running, so the QSPI does not access it.
+    let chunk = unsafe { &(*addr_of!(CHUNK)).0 };
+    let len = buf.len().min(CHUNK_LEN);
+    buf[..len].copy_from_slice(&chunk[..len]);
+    buf[len..].fill(0xff);
+}
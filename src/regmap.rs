@@ -0,0 +1,659 @@
+// Register map served by TWIS and, with the `spis` feature, SPIS.
+//
+// The peripheral behaves like a typical I2C sensor: the first byte of a
+// controller WRITE sets the register pointer and any following bytes are
+// stored in consecutive registers. A controller READ returns the registers
+// starting at the pointer. Both advance the pointer by the number of bytes
+// moved, so a controller can also stream through a block.
+//
+// The registers are shared, the framing is up to each `Transport`: TWIS
+// maps its WRITE and READ transactions directly, SPIS frames them as in
+// `spiframe`. Every transport has its own pointer, so a controller on one
+// bus does not move the pointer of the other.
+//
+//   0x00..=0x07  SCRATCH      rw  echo buffer, cleared by the button
+//   0x08..=0x0d  DEVICE_ADDR  r   FICR DEVICEADDR, 48-bit LE, see `identity`
+//   0x0e         BRIDGE_TARGET r  `repeater` target address, 0 while off
+//   0x0f         BRIDGE_STATUS r  `repeater` per-hop error bits
+//   0x10         POWER_STATE  r   `power::PowerState` before the last wake-up
+//   0x11         WAKE_REASON  r   `power::WakeReason` of the last wake-up
+//   0x12         STATUS       rw  `status` flags, write 1 to clear (bit 7 live)
+//   0x13         ANALOG_THRESHOLD rw  LPCOMP threshold in 1/16 VDD, 0 = off
+//   0x14..=0x17  RESET_REASON r   RESETREAS at boot, u32 little-endian
+//   0x18         TEMPERATURE  r   die temperature in degrees C, i8
+//   0x19         LED_BRIGHTNESS rw  blue LED PWM level, see `ledpwm`
+//   0x1a         LED_BLINK    rw  blue LED blink half period in 50 ms steps
+//   0x1b         ADC_COUNT    r   samples in SAMPLES, 0 while a SAADC run is on
+//   0x1c..=0x1d  TEMPERATURE_RAW r  die temperature in 0.25 degrees C, i16 LE
+//   0x1e         RANDOM       r   random bytes from `entropy`, FIFO-style
+//   0x1f         FLASH_STATUS r   `qspiflash` status bits
+//   0x20         COMMAND      w   controller requests, see `request`
+//   0x21         FLASH_DATA   r   last QSPI flash chunk, FIFO-style
+//   0x22..=0x27  TIME         rw  `wallclock` seconds u32 LE and ms u16 LE
+//   0x28..=0x2f  DEVICE_ID    r   FICR DEVICEID, 64-bit LE
+//   0x30..=0x3f  SAMPLES      r   last SAADC run, i16 little-endian each
+//   0x40..=0x7f  STATS        r   `stats` counters, u32 little-endian each
+//   0x80         AUDIO_STATUS r   `mic` status bits
+//   0x81..=0x82  AUDIO_LEN    r   captured bytes not read yet, u16 LE
+//   0x83         AUDIO_DATA   r   PDM capture, i16 LE samples, FIFO-style
+//   0x84..=0x9b  LEDS         rw  WS2812 strip, RGB per LED, see `ws2812`
+//   0x9c..=0x9f  ENCODER_POSITION r  `qdec` steps since boot, i32 LE
+//   0xa0..=0xa1  ENCODER_VELOCITY r  `qdec` steps per second, i16 LE
+//   0xa2..=0xa9  MESSAGE      rw  typed requests and their replies, see `message`
+//   0xaa         STREAM       rw  COBS-framed messages and replies, see `stream`
+//   0xab..=0xae  SEQ_COMMAND  rw  sequenced requests and their response, see `sequence`
+//   0xaf..=0xb2  RESULT       r   outcome of the last request, see `outcome`
+//   0xb3         ENVELOPE     w   1: READs on this transport start with the state
+//   0xb4..=0xbb  NONCE        r   unlock challenge, see `unlock`
+//   0xbc..=0xc3  UNLOCK       w   unlock response, checked at the last byte
+//   0xc4..=0xc7  AUTH_COUNT   r   counter of authenticated frames, see `auth`
+//   0xc8         SESSION      w   1: start a new encrypted STREAM session
+//   0xc9..=0xd0  SESSION_IV   r   IV of the session, see `session`
+//   0xd1..=0xd4  ARM_COUNT    r   index of the next rolling code, see `rolling`
+//   0xd5..=0xdc  ARM          w   rolling code, checked at the last byte
+//   0xdd..=0xe0  DFU_OFFSET   r   where the next firmware chunk goes, see `dfu`
+//   0xe1         DFU_STATE    r   0 idle, 1 receiving, 2 verified
+//   0xe2         BOOTLOADER   w   magic: reset into a bootloader, see `bootloader`
+//   0xe3         MOTION       rw  synthetic motion of `mpu6050`, a `Pattern`
+//   0xe4         DESCRIPTION  r   this table as TLV records, FIFO-style, see `discovery`
+//   0xe5         BATCH        rw  registers by offset list, snapshot in one READ
+//   0xe6         EVENT        r   queued events, 8-byte records, FIFO-style, see `events`
+//   0xe7         EVENT_COUNT  r   records queued in EVENT
+//   0xe8..=0xef  SYNC_OFFSET  r   step of the last time setting in ms, i64 LE, see `wallclock`
+//
+// Unmapped registers read as 0. Writes to them are ignored and reported as
+// `ProtocolError::UnknownOpcode`. COMMAND reads as 0 and is not a register
+// as such: a WRITE starting there is decoded as a `request::Request`. A
+// WRITE starting at MESSAGE is a typed message instead, and one at
+// SEQ_COMMAND a request with a sequence number; both leave the pointer
+// there for the READ of the reply. A WRITE of a magic to BOOTLOADER is the
+// BOOTLOADER request, so it takes the same checks as one at COMMAND.
+// RANDOM and FLASH_DATA are FIFOs like the data register of a sensor: a
+// READ starting there returns data for its whole length and leaves the
+// pointer in place. So are AUDIO_DATA, STREAM, DESCRIPTION and EVENT,
+// which also only consume the bytes the READ actually moved; they read as
+// 0 unless a READ starts there. A WRITE starting at STREAM goes to the
+// stream whole.
+// A WRITE of `[BATCH, offset...]` takes a snapshot of the registers at
+// those offsets, in that order, all at the same instant, and a READ
+// starting at BATCH returns it: integer registers whole as of the
+// snapshot, FIFOs as 0. The pointer stays at BATCH, each transport has its
+// own list, and a READ takes the same snapshot again until the next WRITE.
+// Registers wider than a byte are little-endian and read without tearing:
+// reading byte 0 latches the whole value for the transport, see
+// `multibyte`; `INTEGERS` lists them, TIME latches in `wallclock`.
+
+use {
+    crate::{
+        auth, build_config, ccm, dfu, discovery, entropy,
+        error::ProtocolError,
+        events, identity, ledpwm, lock, lpcomp, message, mic, mpu6050,
+        multibyte::{Integers, Value},
+        outcome, power, qdec, qspiflash, repeater,
+        request::{self, Request},
+        resetreas, rolling, saadc, sequence, session, stats, status, stream, thermal, unlock,
+        wallclock, ws2812,
+    },
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// Size of the TWIS and SPIS DMA buffers: the longest WRITE (pointer plus
+/// data) or READ served in one transaction, 32 unless `build_config` sets it.
+pub const BUF_LEN: usize = build_config::BUF_LEN;
+
+pub const SCRATCH: u8 = 0x00;
+pub const SCRATCH_LEN: usize = 8;
+pub const DEVICE_ADDR: u8 = 0x08;
+pub const BRIDGE_TARGET: u8 = 0x0e;
+pub const BRIDGE_STATUS: u8 = 0x0f;
+
+pub const POWER_STATE: u8 = 0x10;
+pub const WAKE_REASON: u8 = 0x11;
+pub const STATUS: u8 = 0x12;
+pub const ANALOG_THRESHOLD: u8 = 0x13;
+pub const RESET_REASON: u8 = 0x14;
+pub const TEMPERATURE: u8 = 0x18;
+pub const LED_BRIGHTNESS: u8 = 0x19;
+pub const LED_BLINK: u8 = 0x1a;
+pub const ADC_COUNT: u8 = 0x1b;
+pub const TEMPERATURE_RAW: u8 = 0x1c;
+pub const RANDOM: u8 = 0x1e;
+pub const FLASH_STATUS: u8 = 0x1f;
+
+pub const COMMAND: u8 = 0x20;
+pub const FLASH_DATA: u8 = 0x21;
+pub const TIME: u8 = 0x22;
+pub const DEVICE_ID: u8 = 0x28;
+
+pub const SAMPLES: u8 = 0x30;
+
+pub const STATS_BASE: u8 = 0x40;
+/// `STATS.alive`, bumped by the heartbeat.
+pub const ALIVE: u8 = STATS_BASE;
+
+pub const AUDIO_STATUS: u8 = 0x80;
+pub const AUDIO_LEN: u8 = 0x81;
+pub const AUDIO_DATA: u8 = 0x83;
+pub const LEDS: u8 = 0x84;
+pub const ENCODER_POSITION: u8 = 0x9c;
+pub const ENCODER_VELOCITY: u8 = 0xa0;
+pub const MESSAGE: u8 = 0xa2;
+pub const STREAM: u8 = 0xaa;
+pub const SEQ_COMMAND: u8 = 0xab;
+pub const RESULT: u8 = 0xaf;
+pub const ENVELOPE: u8 = 0xb3;
+pub const NONCE: u8 = 0xb4;
+pub const UNLOCK: u8 = 0xbc;
+pub const AUTH_COUNT: u8 = 0xc4;
+pub const SESSION: u8 = 0xc8;
+pub const SESSION_IV: u8 = 0xc9;
+pub const ARM_COUNT: u8 = 0xd1;
+pub const ARM: u8 = 0xd5;
+pub const DFU_OFFSET: u8 = 0xdd;
+pub const DFU_STATE: u8 = 0xe1;
+pub const BOOTLOADER: u8 = 0xe2;
+pub const MOTION: u8 = 0xe3;
+pub const DESCRIPTION: u8 = 0xe4;
+pub const BATCH: u8 = 0xe5;
+pub const EVENT: u8 = 0xe6;
+pub const EVENT_COUNT: u8 = 0xe7;
+pub const SYNC_OFFSET: u8 = 0xe8;
+
+/// Bus the register map is accessed through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Twis = 0,
+    Spis = 1,
+}
+
+static POINTERS: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+static ENVELOPES: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+// Integer registers the last fill of a transport read, and the one its
+// READs continue in, see `multibyte`.
+struct Snapshot {
+    values: [(u8, Value); SNAPSHOT_LEN],
+    count: usize,
+    latched: Option<(u8, Value)>,
+}
+
+// A fill of 2-byte registers, partial ones at both ends.
+const SNAPSHOT_LEN: usize = BUF_LEN / 2 + 1;
+
+const NO_SNAPSHOT: Snapshot = Snapshot {
+    values: [(0, Value::ZERO); SNAPSHOT_LEN],
+    count: 0,
+    latched: None,
+};
+
+static SNAPSHOTS: Mutex<RefCell<[Snapshot; 2]>> =
+    Mutex::new(RefCell::new([NO_SNAPSHOT, NO_SNAPSHOT]));
+
+// The integer registers, little-endian each.
+const INTEGERS: [Integers; 13] = [
+    Integers {
+        base: RESET_REASON,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(resetreas::raw()),
+    },
+    Integers {
+        base: TEMPERATURE_RAW,
+        len: 2,
+        count: 1,
+        value: |_| Value::i16(thermal::quarters()),
+    },
+    Integers {
+        base: SAMPLES,
+        len: 2,
+        count: saadc::MAX_SAMPLES,
+        value: |index| Value::i16(saadc::sample(index)),
+    },
+    Integers {
+        base: STATS_BASE,
+        len: 4,
+        count: stats::COUNT,
+        value: |index| Value::u32(stats::counter(index).map_or(0, |c| c.get())),
+    },
+    Integers {
+        base: AUDIO_LEN,
+        len: 2,
+        count: 1,
+        value: |_| Value::u16(mic::remaining()),
+    },
+    Integers {
+        base: ENCODER_POSITION,
+        len: 4,
+        count: 1,
+        value: |_| Value::i32(qdec::position()),
+    },
+    Integers {
+        base: ENCODER_VELOCITY,
+        len: 2,
+        count: 1,
+        value: |_| Value::i16(qdec::velocity()),
+    },
+    Integers {
+        base: SEQ_COMMAND,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(sequence::response()),
+    },
+    Integers {
+        base: RESULT,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(outcome::result()),
+    },
+    Integers {
+        base: AUTH_COUNT,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(auth::counter()),
+    },
+    Integers {
+        base: ARM_COUNT,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(rolling::count()),
+    },
+    Integers {
+        base: DFU_OFFSET,
+        len: 4,
+        count: 1,
+        value: |_| Value::u32(dfu::offset()),
+    },
+    Integers {
+        base: SYNC_OFFSET,
+        len: 8,
+        count: 1,
+        value: |_| Value::i64(wallclock::adjustment()),
+    },
+];
+
+// The integer register `reg` is a byte of: its run, its address and the
+// offset of `reg` in it.
+fn integer(reg: u8) -> Option<(&'static Integers, u8, usize)> {
+    INTEGERS.iter().find_map(|integers| {
+        integers
+            .locate(reg)
+            .map(|(base, offset)| (integers, base, offset))
+    })
+}
+
+impl Transport {
+    fn pointer(self) -> &'static AtomicU8 {
+        &POINTERS[self as usize]
+    }
+
+    fn envelope(self) -> &'static AtomicBool {
+        &ENVELOPES[self as usize]
+    }
+}
+
+// The last BATCH snapshot of each transport.
+struct Batch {
+    values: [u8; BUF_LEN - 1],
+    len: usize,
+}
+
+const NO_BATCH: Batch = Batch {
+    values: [0; BUF_LEN - 1],
+    len: 0,
+};
+
+static BATCHES: Mutex<RefCell<[Batch; 2]>> = Mutex::new(RefCell::new([NO_BATCH, NO_BATCH]));
+
+static SCRATCH_REGS: Mutex<RefCell<[u8; SCRATCH_LEN]>> = Mutex::new(RefCell::new([0; SCRATCH_LEN]));
+
+fn read(reg: u8) -> u8 {
+    let reg = reg as usize;
+    let scratch = SCRATCH as usize;
+    if (scratch..scratch + SCRATCH_LEN).contains(&reg) {
+        lock::free(|cs| SCRATCH_REGS.borrow(cs).borrow()[reg - scratch])
+    } else if (DEVICE_ADDR as usize..DEVICE_ADDR as usize + identity::ADDR_LEN).contains(&reg) {
+        identity::device_addr()[reg - DEVICE_ADDR as usize]
+    } else if reg == BRIDGE_TARGET as usize {
+        repeater::target()
+    } else if reg == BRIDGE_STATUS as usize {
+        repeater::status()
+    } else if reg == POWER_STATE as usize {
+        power::woke_from()
+    } else if reg == WAKE_REASON as usize {
+        power::wake_reason()
+    } else if reg == STATUS as usize {
+        status::get()
+    } else if reg == ANALOG_THRESHOLD as usize {
+        lpcomp::threshold()
+    } else if reg == TEMPERATURE as usize {
+        thermal::celsius() as u8
+    } else if reg == LED_BRIGHTNESS as usize {
+        ledpwm::brightness()
+    } else if reg == LED_BLINK as usize {
+        ledpwm::blink()
+    } else if reg == ADC_COUNT as usize {
+        saadc::count()
+    } else if reg == RANDOM as usize {
+        let mut byte = [0];
+        entropy::take(&mut byte);
+        byte[0]
+    } else if reg == FLASH_STATUS as usize {
+        qspiflash::status()
+    } else if reg == FLASH_DATA as usize {
+        let mut byte = [0];
+        qspiflash::take_data(&mut byte);
+        byte[0]
+    } else if (TIME as usize..TIME as usize + wallclock::LEN).contains(&reg) {
+        wallclock::read(reg - TIME as usize)
+    } else if (DEVICE_ID as usize..DEVICE_ID as usize + 8).contains(&reg) {
+        identity::device_id()[reg - DEVICE_ID as usize]
+    } else if reg == AUDIO_STATUS as usize {
+        mic::status()
+    } else if (LEDS as usize..LEDS as usize + ws2812::LEN).contains(&reg) {
+        ws2812::read(reg - LEDS as usize)
+    } else if (MESSAGE as usize..MESSAGE as usize + message::REPLY_LEN).contains(&reg) {
+        message::answer(reg - MESSAGE as usize)
+    } else if (NONCE as usize..NONCE as usize + unlock::LEN).contains(&reg) {
+        unlock::challenge(reg - NONCE as usize)
+    } else if (SESSION_IV as usize..SESSION_IV as usize + ccm::IV_LEN).contains(&reg) {
+        session::iv(reg - SESSION_IV as usize)
+    } else if reg == DFU_STATE as usize {
+        dfu::state()
+    } else if reg == MOTION as usize {
+        mpu6050::pattern()
+    } else if reg == EVENT_COUNT as usize {
+        events::count()
+    } else {
+        0
+    }
+}
+
+// Returns false if `reg` is not writable.
+fn write(reg: u8, value: u8) -> bool {
+    let reg = reg as usize;
+    let scratch = SCRATCH as usize;
+    if (scratch..scratch + SCRATCH_LEN).contains(&reg) {
+        lock::free(|cs| SCRATCH_REGS.borrow(cs).borrow_mut()[reg - scratch] = value);
+        true
+    } else if reg == STATUS as usize {
+        status::clear(value);
+        true
+    } else if reg == ANALOG_THRESHOLD as usize {
+        lpcomp::set_threshold(value)
+    } else if reg == LED_BRIGHTNESS as usize {
+        ledpwm::set_brightness(value)
+    } else if reg == LED_BLINK as usize {
+        ledpwm::set_blink(value)
+    } else if (TIME as usize..TIME as usize + wallclock::LEN).contains(&reg) {
+        wallclock::write(reg - TIME as usize, value)
+    } else if (LEDS as usize..LEDS as usize + ws2812::LEN).contains(&reg) {
+        ws2812::write(reg - LEDS as usize, value)
+    } else if (UNLOCK as usize..UNLOCK as usize + unlock::LEN).contains(&reg) {
+        unlock::write(reg - UNLOCK as usize, value)
+    } else if (ARM as usize..ARM as usize + rolling::LEN).contains(&reg) {
+        rolling::write(reg - ARM as usize, value)
+    } else if reg == MOTION as usize {
+        mpu6050::set_pattern(value)
+    } else if reg == SESSION as usize {
+        if value == 1 {
+            session::restart();
+        }
+        value == 1
+    } else {
+        false
+    }
+}
+
+/// Fills `buf` with the registers starting at the pointer, for a READ.
+/// The pointer is left alone until `advance` reports how much was sent.
+pub fn fill(transport: Transport, buf: &mut [u8]) {
+    let start = transport.pointer().load(Ordering::Relaxed);
+    let buf = match buf.split_first_mut() {
+        Some((state, data)) if transport.envelope().load(Ordering::Relaxed) => {
+            *state = outcome::state();
+            data
+        }
+        _ => buf,
+    };
+    match start {
+        RANDOM => return entropy::take(buf),
+        FLASH_DATA => return qspiflash::take_data(buf),
+        AUDIO_DATA => return mic::peek(buf),
+        STREAM => return stream::peek(buf),
+        DESCRIPTION => return discovery::peek(buf),
+        EVENT => return events::peek(buf),
+        BATCH => {
+            return lock::free(|cs| {
+                let batch = &BATCHES.borrow(cs).borrow()[transport as usize];
+                let len = batch.len.min(buf.len());
+                buf[..len].copy_from_slice(&batch.values[..len]);
+                buf[len..].fill(0);
+            })
+        }
+        _ => {}
+    }
+    let latched = lock::free(|cs| {
+        SNAPSHOTS.borrow(cs).borrow_mut()[transport as usize]
+            .latched
+            .take()
+    });
+    let mut values = [(0, Value::ZERO); SNAPSHOT_LEN];
+    let mut count = 0;
+    fill_from(
+        start,
+        buf,
+        |base, offset, integers| {
+            let value = match latched {
+                Some((at, value)) if at == base && offset > 0 => value,
+                _ => integers.read(base),
+            };
+            if count < SNAPSHOT_LEN {
+                values[count] = (base, value);
+                count += 1;
+            }
+            value
+        },
+        read,
+    );
+    lock::free(|cs| {
+        let snapshot = &mut SNAPSHOTS.borrow(cs).borrow_mut()[transport as usize];
+        snapshot.values = values;
+        snapshot.count = count;
+    });
+}
+
+// Fills `buf` from `start`, with a value from `take` for each integer
+// register it reaches, by address, offset and run, and every other byte
+// from `other`.
+fn fill_from(
+    start: u8,
+    buf: &mut [u8],
+    mut take: impl FnMut(u8, usize, &Integers) -> Value,
+    other: impl Fn(u8) -> u8,
+) {
+    let mut current: Option<(u8, Value)> = None;
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let reg = start.wrapping_add(i as u8);
+        *byte = match integer(reg) {
+            Some((integers, base, offset)) => {
+                let value = match current {
+                    Some((at, value)) if at == base => value,
+                    _ => take(base, offset, integers),
+                };
+                current = Some((base, value));
+                value.byte(offset)
+            }
+            None => other(reg),
+        };
+    }
+}
+
+/// The registers from `start` for `nfctag` and `ble`, as a READ would
+/// return them but with the FIFOs left alone, reading as 0.
+pub fn mirror(start: u8, buf: &mut [u8]) {
+    fill_from(
+        start,
+        buf,
+        |base, _, integers| integers.read(base),
+        |reg| match reg {
+            RANDOM | FLASH_DATA | AUDIO_DATA | STREAM | DESCRIPTION | EVENT => 0,
+            reg => read(reg),
+        },
+    );
+}
+
+/// Moves the pointer past `count` registers read by the controller, except
+/// from a FIFO.
+pub fn advance(transport: Transport, count: usize) {
+    let pointer = transport.pointer();
+    let count = if transport.envelope().load(Ordering::Relaxed) {
+        count.saturating_sub(1)
+    } else {
+        count
+    };
+    match pointer.load(Ordering::Relaxed) {
+        RANDOM | FLASH_DATA | BATCH => return,
+        AUDIO_DATA => return mic::consume(count),
+        STREAM => return stream::consume(count),
+        DESCRIPTION => return discovery::consume(count),
+        EVENT => return events::consume(count),
+        _ => {}
+    }
+    let end = pointer.load(Ordering::Relaxed).wrapping_add(count as u8);
+    pointer.store(end, Ordering::Relaxed);
+    // Latch the integer register the READ stopped inside of, if any.
+    lock::free(|cs| {
+        let snapshot = &mut SNAPSHOTS.borrow(cs).borrow_mut()[transport as usize];
+        snapshot.latched = match integer(end) {
+            Some((_, base, offset)) if offset > 0 => snapshot.values[..snapshot.count]
+                .iter()
+                .copied()
+                .find(|&(at, _)| at == base),
+            _ => None,
+        };
+    });
+}
+
+// Takes the BATCH snapshot of `offsets` into `batch`.
+fn snapshot(offsets: &[u8], batch: &mut Batch) {
+    // One value per integer register, however many of its bytes are listed.
+    let mut values = [(0, Value::ZERO); BUF_LEN - 1];
+    let mut count = 0;
+    for (byte, &reg) in batch.values.iter_mut().zip(offsets) {
+        *byte = match integer(reg) {
+            Some((integers, base, offset)) => {
+                let value = match values[..count].iter().find(|&&(at, _)| at == base) {
+                    Some(&(_, value)) => value,
+                    None => {
+                        let value = integers.read(base);
+                        values[count] = (base, value);
+                        count += 1;
+                        value
+                    }
+                };
+                value.byte(offset)
+            }
+            None => match reg {
+                RANDOM | FLASH_DATA | AUDIO_DATA | STREAM | DESCRIPTION | EVENT => 0,
+                reg => read(reg),
+            },
+        };
+    }
+    batch.len = offsets.len().min(batch.values.len());
+}
+
+/// Applies a WRITE: sets the pointer from the first byte and stores the rest.
+/// Bytes for registers that are not writable are dropped. A WRITE to COMMAND,
+/// MESSAGE, SEQ_COMMAND or BOOTLOADER returns the request for the caller to
+/// carry out.
+pub fn apply(transport: Transport, data: &[u8]) -> Result<Option<Request>, ProtocolError> {
+    let Some((&start, values)) = data.split_first() else {
+        return Ok(None);
+    };
+    lock::free(|cs| SNAPSHOTS.borrow(cs).borrow_mut()[transport as usize].latched = None);
+    if start == COMMAND {
+        transport.pointer().store(start, Ordering::Relaxed);
+        let request = request::parse(auth::verify(values)?)?;
+        unlock::admit(&request)?;
+        rolling::admit(&request)?;
+        return Ok(Some(request));
+    }
+    if start == BOOTLOADER {
+        transport.pointer().store(start, Ordering::Relaxed);
+        let request = match auth::verify(values)? {
+            [magic] => Request::Bootloader(*magic),
+            other => {
+                return Err(ProtocolError::BadLength {
+                    len: other.len() as u32,
+                    max: 1,
+                })
+            }
+        };
+        request::check(&request)?;
+        unlock::admit(&request)?;
+        rolling::admit(&request)?;
+        return Ok(Some(request));
+    }
+    if start == MESSAGE {
+        transport.pointer().store(start, Ordering::Relaxed);
+        return message::receive(values).map(Some);
+    }
+    if start == SEQ_COMMAND {
+        transport.pointer().store(start, Ordering::Relaxed);
+        return sequence::receive(values);
+    }
+    if start == STREAM {
+        transport.pointer().store(start, Ordering::Relaxed);
+        stream::feed(values);
+        return Ok(None);
+    }
+    if start == BATCH {
+        transport.pointer().store(start, Ordering::Relaxed);
+        lock::free(|cs| {
+            snapshot(
+                values,
+                &mut BATCHES.borrow(cs).borrow_mut()[transport as usize],
+            );
+        });
+        return Ok(None);
+    }
+    let mut all_written = true;
+    for (i, &value) in values.iter().enumerate() {
+        let reg = start.wrapping_add(i as u8);
+        all_written &= if reg == ENVELOPE {
+            transport
+                .envelope()
+                .store(value & 1 != 0, Ordering::Relaxed);
+            value <= 1
+        } else {
+            write(reg, value)
+        };
+    }
+    transport
+        .pointer()
+        .store(start.wrapping_add(values.len() as u8), Ordering::Relaxed);
+    if all_written {
+        Ok(None)
+    } else {
+        Err(ProtocolError::UnknownOpcode(start))
+    }
+}
+
+/// Zeroes the scratch registers.
+pub fn clear_scratch() {
+    lock::free(|cs| *SCRATCH_REGS.borrow(cs).borrow_mut() = [0; SCRATCH_LEN]);
+}
+
+/// Overwrites the scratch registers.
+pub fn set_scratch(values: [u8; SCRATCH_LEN]) {
+    lock::free(|cs| *SCRATCH_REGS.borrow(cs).borrow_mut() = values);
+}
+
+/// Copy of the scratch registers, for logging.
+pub fn scratch() -> [u8; SCRATCH_LEN] {
+    lock::free(|cs| *SCRATCH_REGS.borrow(cs).borrow())
+}
@@ -0,0 +1,114 @@
+// Register snapshots for diagnosing bus-level failures.
+//
+// Error paths capture the TWIS and TWIM registers that describe the state
+// of the bus (ENABLE, ERRORSRC, ADDRESS, AMOUNT, PSEL) and log them:
+//
+//   twis0 enable=9 errorsrc=0x1 address=0x1a match=0 rxd=8 txd=0 scl=0xf sda=0x10
+//   twim1 enable=6 errorsrc=0x2 address=0x1a rxd=0 txd=0 scl=0x1b sda=0x1a
+//
+// ERRORSRC is shown as read, before anything clears it. PSEL values are raw:
+// bits 0..=4 are the pin, bit 5 the port and bit 31 set means disconnected.
+
+use {
+    crate::hal::pac::{TWIM1, TWIS0},
+    core::fmt,
+};
+
+#[derive(Clone, Copy)]
+pub struct TwisRegs {
+    enable: u32,
+    errorsrc: u32,
+    address: u32,
+    match_: u32,
+    rxd_amount: u32,
+    txd_amount: u32,
+    psel_scl: u32,
+    psel_sda: u32,
+}
+
+impl TwisRegs {
+    pub fn capture() -> Self {
+        // SAFETY: read-only access; ERRORSRC is write-one-to-clear, so reading
+        // it has no side effects.
+        let twis = unsafe { &*TWIS0::ptr() };
+        TwisRegs {
+            enable: twis.enable.read().bits(),
+            errorsrc: twis.errorsrc.read().bits(),
+            address: twis.address[0].read().bits(),
+            match_: twis.match_.read().bits(),
+            rxd_amount: twis.rxd.amount.read().bits(),
+            txd_amount: twis.txd.amount.read().bits(),
+            psel_scl: twis.psel.scl.read().bits(),
+            psel_sda: twis.psel.sda.read().bits(),
+        }
+    }
+}
+
+impl fmt::Display for TwisRegs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "twis0 enable={} errorsrc={:#x} address={:#x} match={} rxd={} txd={} scl={:#x} sda={:#x}",
+            self.enable,
+            self.errorsrc,
+            self.address,
+            self.match_,
+            self.rxd_amount,
+            self.txd_amount,
+            self.psel_scl,
+            self.psel_sda,
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct TwimRegs {
+    enable: u32,
+    errorsrc: u32,
+    address: u32,
+    rxd_amount: u32,
+    txd_amount: u32,
+    psel_scl: u32,
+    psel_sda: u32,
+}
+
+impl TwimRegs {
+    pub fn capture() -> Self {
+        // SAFETY: read-only access, see `TwisRegs::capture`.
+        let twim = unsafe { &*TWIM1::ptr() };
+        TwimRegs {
+            enable: twim.enable.read().bits(),
+            errorsrc: twim.errorsrc.read().bits(),
+            address: twim.address.read().bits(),
+            rxd_amount: twim.rxd.amount.read().bits(),
+            txd_amount: twim.txd.amount.read().bits(),
+            psel_scl: twim.psel.scl.read().bits(),
+            psel_sda: twim.psel.sda.read().bits(),
+        }
+    }
+}
+
+impl fmt::Display for TwimRegs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "twim1 enable={} errorsrc={:#x} address={:#x} rxd={} txd={} scl={:#x} sda={:#x}",
+            self.enable,
+            self.errorsrc,
+            self.address,
+            self.rxd_amount,
+            self.txd_amount,
+            self.psel_scl,
+            self.psel_sda,
+        )
+    }
+}
+
+/// Logs both peripherals. Controller and peripheral share the bus, so either
+/// side's registers can explain a failure seen by the other.
+pub fn log(context: &str) {
+    let twim = TwimRegs::capture();
+    let twis = TwisRegs::capture();
+    error!("{}: {}", context, twim);
+    error!("{}: {}", context, twis);
+}
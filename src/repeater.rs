@@ -0,0 +1,170 @@
+// I2C repeater: TWIS transactions forwarded to a target on the TWIM bus.
+//
+// The BRIDGE request makes TWIS answer at the target's own address as
+// well, as its second address, so a controller on the TWIS bus talks to the
+// target as if it was on the same bus. The register map stays at the
+// configured address, for control and status.
+//
+// A WRITE is forwarded once it has ended, by the `forward` task, as a TWIM
+// write of the same bytes. A READ cannot wait for the TWIM bus, TWIS needs
+// its buffer at once, so the target is read ahead: after every forwarded
+// WRITE and every served READ, `forward` reads `fetch_len` bytes from the
+// target and the next READ returns them. For a register device that is a
+// WRITE of the register address followed by READs, as usual; each READ
+// returns the `fetch_len` bytes after the previous one.
+//
+// BRIDGE_STATUS reports each hop separately:
+//
+//   bit 0    downstream: the last WRITE was dropped (too long, or the
+//            previous one still being forwarded)
+//   bit 1    upstream: forwarding the last WRITE failed
+//   bit 2    upstream: the last read-ahead failed
+//   bit 3    downstream: the last READ got no fresh data, it read 0xff
+//   bit 4-7  upstream: `twim::Error` of the last failure, 0xf if refused
+
+use {
+    crate::{
+        controller,
+        error::AppError,
+        hal::{pac::TWIM1, twim::Twim},
+        lock, regmap,
+    },
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+pub const DROPPED: u8 = 1 << 0;
+pub const WRITE_FAILED: u8 = 1 << 1;
+pub const FETCH_FAILED: u8 = 1 << 2;
+pub const STALE: u8 = 1 << 3;
+const ERROR_SHIFT: u8 = 4;
+
+/// A WRITE received for the target.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    data: [u8; regmap::BUF_LEN],
+    len: u8,
+}
+
+impl Frame {
+    pub fn new(data: &[u8]) -> Self {
+        let mut frame = Frame {
+            data: [0; regmap::BUF_LEN],
+            len: data.len() as u8,
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        frame
+    }
+}
+
+struct Cache {
+    data: [u8; regmap::BUF_LEN],
+    len: usize,
+    fresh: bool,
+}
+
+static CACHE: Mutex<RefCell<Cache>> = Mutex::new(RefCell::new(Cache {
+    data: [0xff; regmap::BUF_LEN],
+    len: 0,
+    fresh: false,
+}));
+
+// 0 while off.
+static TARGET: AtomicU8 = AtomicU8::new(0);
+static FETCH_LEN: AtomicU8 = AtomicU8::new(0);
+static STATUS: AtomicU8 = AtomicU8::new(0);
+
+/// Forwards to `target` from now on, 0 turns the repeater off.
+pub fn set_target(target: u8, fetch_len: u8) {
+    TARGET.store(target, Ordering::Relaxed);
+    FETCH_LEN.store(fetch_len, Ordering::Relaxed);
+    STATUS.store(0, Ordering::Relaxed);
+    lock::free(|cs| CACHE.borrow(cs).borrow_mut().fresh = false);
+}
+
+/// BRIDGE_TARGET register.
+pub fn target() -> u8 {
+    TARGET.load(Ordering::Relaxed)
+}
+
+pub fn is_active() -> bool {
+    target() != 0
+}
+
+/// BRIDGE_STATUS register.
+pub fn status() -> u8 {
+    STATUS.load(Ordering::Relaxed)
+}
+
+fn update(clear: u8, set: u8) {
+    let status = STATUS.load(Ordering::Relaxed);
+    STATUS.store(status & !clear | set, Ordering::Relaxed);
+}
+
+// The error bits for a failed upstream transaction.
+fn failure(error: AppError) -> u8 {
+    let code = match error {
+        AppError::Twim { error, .. } => error as u8,
+        _ => 0xf,
+    };
+    code << ERROR_SHIFT
+}
+
+/// Records whether a WRITE for the target was dropped on the way in.
+pub fn set_dropped(dropped: bool) {
+    update(DROPPED, if dropped { DROPPED } else { 0 });
+}
+
+/// Fills `buf` for a READ with the data read ahead, 0xff if there is none.
+pub fn take(buf: &mut [u8]) {
+    let fresh = lock::free(|cs| {
+        let mut cache = CACHE.borrow(cs).borrow_mut();
+        let len = if cache.fresh { cache.len } else { 0 };
+        buf[..len].copy_from_slice(&cache.data[..len]);
+        buf[len..].fill(0xff);
+        core::mem::replace(&mut cache.fresh, false)
+    });
+    update(STALE, if fresh { 0 } else { STALE });
+}
+
+/// Forwards `frame`, if any, and reads ahead. From the `forward` task.
+pub fn forward(twim: &mut Twim<TWIM1>, frame: Option<Frame>) {
+    let target = target();
+    if target == 0 {
+        return;
+    }
+    if let Some(frame) = frame {
+        match controller::write(twim, target, &frame.data[..frame.len as usize]) {
+            Ok(()) => update(WRITE_FAILED, 0),
+            Err(error) => {
+                warn!("repeater: {}", error);
+                update(WRITE_FAILED | 0xf0, WRITE_FAILED | failure(error));
+                return;
+            }
+        }
+    }
+    let len = FETCH_LEN.load(Ordering::Relaxed) as usize;
+    if len == 0 {
+        return;
+    }
+    let mut data = [0xff; regmap::BUF_LEN];
+    match controller::read(twim, target, &mut data[..len]) {
+        Ok(()) => {
+            lock::free(|cs| {
+                *CACHE.borrow(cs).borrow_mut() = Cache {
+                    data,
+                    len,
+                    fresh: true,
+                }
+            });
+            update(FETCH_FAILED, 0);
+        }
+        Err(error) => {
+            warn!("repeater: {}", error);
+            update(FETCH_FAILED | 0xf0, FETCH_FAILED | failure(error));
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// Requests issued by the controller through the COMMAND register.
+//
+// A WRITE of `[COMMAND, opcode, args...]` asks the peripheral to do
+// something instead of storing bytes. The request is carried out once the
+// WRITE has ended (STOPPED), so the controller always sees a complete
+// transaction.
+//
+//   0x01  SLEEP          no args               enter System OFF, wake on the button
+//   0x02  WRITE_CONFIG   address, step, flags  persist a `config::Config`
+//   0x03  FACTORY_RESET  no args               persist the default config
+//   0x04  SLEEP_FOR      ms: u16 LE            disable TWIS for `ms`, then re-enable
+//   0x05  SAMPLE         input, count          start a SAADC run, see `saadc`
+//   0x06  STORE          no args               persist the scratch registers, see `nvstore`
+//   0x07  FLASH_READ     addr: u24 LE          read a QSPI flash chunk, see `qspiflash`
+//   0x08  FLASH_PROGRAM  addr: u24 LE, data    program 4..=24 bytes, within a page
+//   0x09  FLASH_ERASE    addr: u24 LE, size    erase a 4 KB sector (0), 64 KB block (1), all (2)
+//   0x0a  BRIDGE         target, fetch_len     repeat to `target` (0: off), see `repeater`
+//   0x0b  CAPTURE        samples: u16 LE       record 1..=4096 PDM samples, see `mic`
+//   0x0c  DFU_BEGIN      size: u24, crc: u32   start a firmware update, see `dfu`
+//   0x0d  DFU_WRITE      offset: u24, crc: u16, data  program 4..=16 bytes of it
+//   0x0e  DFU_VERIFY     no args               check the update against its manifest
+//   0x0f  DFU_ACTIVATE   no args               install the verified update and reset
+//   0x10  BOOTLOADER     magic                 reset into a bootloader, see `bootloader`
+//   0x11  DESCRIBE       no args               restart DESCRIPTION, see `discovery`
+//   0x12  SYNC_TIME      s: u32, ms: u16       set the clock to the controller's, see `wallclock`
+//
+// Flash addresses and program lengths are multiples of 4, erase addresses
+// multiples of the size. A BRIDGE target is a 7-bit address outside the
+// reserved ones, and reads ahead up to `regmap::BUF_LEN` bytes. DFU sizes,
+// offsets and chunk lengths are multiples of 4, and a chunk's CRC is the
+// CRC-16/CCITT-FALSE of its data (`hexdump::crc16`), DFU integers are
+// little-endian like the rest. A BOOTLOADER magic is one of
+// `bootloader::MAGICS`.
+//
+// The same requests also arrive as typed messages at MESSAGE, see
+// `message`; `check` holds the argument rules for both.
+
+use crate::{
+    bootloader,
+    config::Config,
+    dfu,
+    error::ProtocolError,
+    hexdump, mic,
+    qspiflash::{self, EraseSize},
+    regmap, saadc,
+};
+
+pub const SLEEP: u8 = 0x01;
+pub const WRITE_CONFIG: u8 = 0x02;
+pub const FACTORY_RESET: u8 = 0x03;
+pub const SLEEP_FOR: u8 = 0x04;
+pub const SAMPLE: u8 = 0x05;
+pub const STORE: u8 = 0x06;
+pub const FLASH_READ: u8 = 0x07;
+pub const FLASH_PROGRAM: u8 = 0x08;
+pub const FLASH_ERASE: u8 = 0x09;
+pub const BRIDGE: u8 = 0x0a;
+pub const CAPTURE: u8 = 0x0b;
+pub const DFU_BEGIN: u8 = 0x0c;
+pub const DFU_WRITE: u8 = 0x0d;
+pub const DFU_VERIFY: u8 = 0x0e;
+pub const DFU_ACTIVATE: u8 = 0x0f;
+pub const BOOTLOADER: u8 = 0x10;
+pub const DESCRIBE: u8 = 0x11;
+pub const SYNC_TIME: u8 = 0x12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    Sleep,
+    WriteConfig(Config),
+    FactoryReset,
+    /// Sleep window in ms, at least 1.
+    SleepFor(u16),
+    /// SAADC input (0..=`saadc::MAX_CHANNEL`) and sample count
+    /// (1..=`saadc::MAX_SAMPLES`).
+    Sample {
+        channel: u8,
+        count: u8,
+    },
+    Store,
+    /// Chunk address.
+    FlashRead(u32),
+    /// `data[..len]` to program at `address`.
+    FlashProgram {
+        address: u32,
+        data: [u8; qspiflash::PROGRAM_LEN],
+        len: u8,
+    },
+    FlashErase {
+        address: u32,
+        size: EraseSize,
+    },
+    /// Repeater target, 0 for off, and bytes read ahead for each READ.
+    Bridge {
+        target: u8,
+        fetch_len: u8,
+    },
+    /// PDM samples to record, 1..=`mic::CAPTURE_LEN`.
+    Capture(u16),
+    /// The manifest of a firmware update: image size and CRC-32.
+    DfuBegin {
+        size: u32,
+        crc: u32,
+    },
+    /// `data[..len]` of the image at `offset`, with its CRC-16.
+    DfuWrite {
+        offset: u32,
+        crc: u16,
+        data: [u8; dfu::CHUNK_LEN],
+        len: u8,
+    },
+    DfuVerify,
+    DfuActivate,
+    /// The GPREGRET value of the bootloader to reset into.
+    Bootloader(u8),
+    Describe,
+    /// The controller's time, as written to TIME.
+    SyncTime {
+        seconds: u32,
+        millis: u16,
+    },
+}
+
+impl Request {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Request::Sleep => SLEEP,
+            Request::WriteConfig(_) => WRITE_CONFIG,
+            Request::FactoryReset => FACTORY_RESET,
+            Request::SleepFor(_) => SLEEP_FOR,
+            Request::Sample { .. } => SAMPLE,
+            Request::Store => STORE,
+            Request::FlashRead(_) => FLASH_READ,
+            Request::FlashProgram { .. } => FLASH_PROGRAM,
+            Request::FlashErase { .. } => FLASH_ERASE,
+            Request::Bridge { .. } => BRIDGE,
+            Request::Capture(_) => CAPTURE,
+            Request::DfuBegin { .. } => DFU_BEGIN,
+            Request::DfuWrite { .. } => DFU_WRITE,
+            Request::DfuVerify => DFU_VERIFY,
+            Request::DfuActivate => DFU_ACTIVATE,
+            Request::Bootloader(_) => BOOTLOADER,
+            Request::Describe => DESCRIBE,
+            Request::SyncTime { .. } => SYNC_TIME,
+        }
+    }
+}
+
+fn u24(bytes: &[u8; 3]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+/// Decodes the bytes written to the COMMAND register.
+pub fn parse(data: &[u8]) -> Result<Request, ProtocolError> {
+    let request = match data {
+        [SLEEP] => Request::Sleep,
+        [WRITE_CONFIG, address, frequency_step, flags] => Request::WriteConfig(Config {
+            address: *address,
+            frequency_step: *frequency_step,
+            flags: *flags,
+        }),
+        [FACTORY_RESET] => Request::FactoryReset,
+        [STORE] => Request::Store,
+        [FLASH_READ, a0, a1, a2] => Request::FlashRead(u24(&[*a0, *a1, *a2])),
+        [FLASH_PROGRAM, a0, a1, a2, bytes @ ..]
+            if (1..=qspiflash::PROGRAM_LEN).contains(&bytes.len()) =>
+        {
+            let mut data = [0; qspiflash::PROGRAM_LEN];
+            data[..bytes.len()].copy_from_slice(bytes);
+            Request::FlashProgram {
+                address: u24(&[*a0, *a1, *a2]),
+                data,
+                len: bytes.len() as u8,
+            }
+        }
+        [FLASH_ERASE, a0, a1, a2, size] => match EraseSize::from_code(*size) {
+            Some(size) => Request::FlashErase {
+                address: u24(&[*a0, *a1, *a2]),
+                size,
+            },
+            None => return Err(ProtocolError::InvalidArgument(FLASH_ERASE)),
+        },
+        [BRIDGE, target, fetch_len] => Request::Bridge {
+            target: *target,
+            fetch_len: *fetch_len,
+        },
+        [CAPTURE, lo, hi] => Request::Capture(u16::from_le_bytes([*lo, *hi])),
+        [SLEEP_FOR, lo, hi] => Request::SleepFor(u16::from_le_bytes([*lo, *hi])),
+        [SAMPLE, channel, count] => Request::Sample {
+            channel: *channel,
+            count: *count,
+        },
+        [DFU_BEGIN, s0, s1, s2, c0, c1, c2, c3] => Request::DfuBegin {
+            size: u24(&[*s0, *s1, *s2]),
+            crc: u32::from_le_bytes([*c0, *c1, *c2, *c3]),
+        },
+        [DFU_WRITE, o0, o1, o2, k0, k1, bytes @ ..]
+            if (1..=dfu::CHUNK_LEN).contains(&bytes.len()) =>
+        {
+            let mut data = [0; dfu::CHUNK_LEN];
+            data[..bytes.len()].copy_from_slice(bytes);
+            Request::DfuWrite {
+                offset: u24(&[*o0, *o1, *o2]),
+                crc: u16::from_le_bytes([*k0, *k1]),
+                data,
+                len: bytes.len() as u8,
+            }
+        }
+        [DFU_VERIFY] => Request::DfuVerify,
+        [DFU_ACTIVATE] => Request::DfuActivate,
+        [BOOTLOADER, magic] => Request::Bootloader(*magic),
+        [DESCRIBE] => Request::Describe,
+        [SYNC_TIME, s0, s1, s2, s3, m0, m1] => Request::SyncTime {
+            seconds: u32::from_le_bytes([*s0, *s1, *s2, *s3]),
+            millis: u16::from_le_bytes([*m0, *m1]),
+        },
+        [opcode @ (SLEEP | WRITE_CONFIG | FACTORY_RESET | SLEEP_FOR | SAMPLE | STORE
+        | FLASH_READ | FLASH_PROGRAM | FLASH_ERASE | BRIDGE | CAPTURE | DFU_BEGIN
+        | DFU_WRITE | DFU_VERIFY | DFU_ACTIVATE | BOOTLOADER | DESCRIBE | SYNC_TIME), ..] => {
+            return Err(ProtocolError::BadLength {
+                len: data.len() as u32,
+                max: match *opcode {
+                    WRITE_CONFIG | FLASH_READ => 4,
+                    SLEEP_FOR | SAMPLE | BRIDGE | CAPTURE => 3,
+                    BOOTLOADER => 2,
+                    FLASH_PROGRAM => 4 + qspiflash::PROGRAM_LEN,
+                    FLASH_ERASE => 5,
+                    DFU_BEGIN => 8,
+                    SYNC_TIME => 7,
+                    DFU_WRITE => 6 + dfu::CHUNK_LEN,
+                    _ => 1,
+                },
+            })
+        }
+        [opcode, ..] => return Err(ProtocolError::UnknownOpcode(*opcode)),
+        [] => return Err(ProtocolError::BadLength { len: 0, max: 1 }),
+    };
+    check(&request)?;
+    Ok(request)
+}
+
+/// Checks the arguments of `request`, whichever way it was encoded.
+pub fn check(request: &Request) -> Result<(), ProtocolError> {
+    let valid = match *request {
+        Request::Sleep
+        | Request::FactoryReset
+        | Request::Store
+        | Request::DfuVerify
+        | Request::DfuActivate
+        | Request::Describe => true,
+        Request::WriteConfig(config) => config.is_valid(),
+        Request::SleepFor(ms) => ms != 0,
+        Request::Sample { channel, count } => {
+            channel <= saadc::MAX_CHANNEL && (1..=saadc::MAX_SAMPLES).contains(&(count as usize))
+        }
+        Request::FlashRead(address) => {
+            address.is_multiple_of(4) && address <= qspiflash::SIZE - qspiflash::CHUNK_LEN as u32
+        }
+        Request::FlashProgram { address, len, .. } => {
+            (1..=qspiflash::PROGRAM_LEN).contains(&(len as usize))
+                && address.is_multiple_of(4)
+                && len.is_multiple_of(4)
+                && address.checked_add(len as u32).is_some_and(|end| {
+                    end <= qspiflash::SIZE
+                        && address / qspiflash::PAGE_LEN == (end - 1) / qspiflash::PAGE_LEN
+                })
+        }
+        Request::FlashErase { address, size } => {
+            address.is_multiple_of(size.len()) && address < qspiflash::SIZE
+        }
+        Request::Bridge { target, fetch_len } => {
+            (target == 0 || (0x08..=0x77).contains(&target))
+                && fetch_len as usize <= regmap::BUF_LEN
+        }
+        Request::Capture(samples) => samples != 0 && samples as usize <= mic::CAPTURE_LEN,
+        Request::Bootloader(magic) => bootloader::is_magic(magic),
+        Request::SyncTime { millis, .. } => millis < 1000,
+        Request::DfuBegin { size, .. } => {
+            size != 0 && size.is_multiple_of(4) && size <= dfu::MAX_SIZE
+        }
+        Request::DfuWrite { offset, len, .. } => {
+            (1..=dfu::CHUNK_LEN).contains(&(len as usize))
+                && offset.is_multiple_of(4)
+                && len.is_multiple_of(4)
+                && offset
+                    .checked_add(len as u32)
+                    .is_some_and(|end| end <= dfu::MAX_SIZE)
+        }
+    };
+    if !valid {
+        return Err(ProtocolError::InvalidArgument(request.opcode()));
+    }
+    // Corruption on the way, told apart from a bad offset or length.
+    match *request {
+        Request::DfuWrite { crc, data, len, .. }
+            if hexdump::crc16(&data[..len as usize]) != crc =>
+        {
+            Err(ProtocolError::BadCrc)
+        }
+        _ => Ok(()),
+    }
+}
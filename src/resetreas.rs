@@ -0,0 +1,79 @@
+// Reset reason, decoded from POWER.RESETREAS.
+//
+// RESETREAS accumulates until cleared, so `take` reads it once at boot and
+// clears it; each boot then only sees the causes of its own reset. No bit
+// set means a power-on or brown-out reset. The raw value stays available
+// for the RESET_REASON register.
+
+use {
+    crate::hal::pac::POWER,
+    core::{
+        fmt,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+};
+
+pub const RESET_PIN: u32 = 1 << 0;
+pub const WATCHDOG: u32 = 1 << 1;
+pub const SOFT_RESET: u32 = 1 << 2;
+pub const LOCKUP: u32 = 1 << 3;
+/// Woken from System OFF by a GPIO.
+pub const OFF: u32 = 1 << 16;
+pub const LPCOMP: u32 = 1 << 17;
+/// Woken from System OFF by the debug interface.
+pub const DEBUG: u32 = 1 << 18;
+pub const NFC: u32 = 1 << 19;
+pub const VBUS: u32 = 1 << 20;
+
+const NAMES: [(u32, &str); 9] = [
+    (RESET_PIN, "reset pin"),
+    (WATCHDOG, "watchdog"),
+    (SOFT_RESET, "soft reset"),
+    (LOCKUP, "lockup"),
+    (OFF, "System OFF wake (GPIO)"),
+    (LPCOMP, "System OFF wake (LPCOMP)"),
+    (DEBUG, "System OFF wake (debugger)"),
+    (NFC, "System OFF wake (NFC)"),
+    (VBUS, "System OFF wake (VBUS)"),
+];
+
+static RAW: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+pub struct ResetReason(u32);
+
+impl ResetReason {
+    pub fn is(&self, bits: u32) -> bool {
+        self.0 & bits != 0
+    }
+}
+
+/// Reads and clears RESETREAS. Call once, from `init`.
+pub fn take(power: &POWER) -> ResetReason {
+    let bits = power.resetreas.read().bits();
+    // Write 1 to clear.
+    power.resetreas.write(|w| unsafe { w.bits(bits) });
+    RAW.store(bits, Ordering::Relaxed);
+    ResetReason(bits)
+}
+
+/// RESETREAS as read at boot.
+pub fn raw() -> u32 {
+    RAW.load(Ordering::Relaxed)
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("power-on");
+        }
+        let mut names = NAMES.iter().filter(|(bit, _)| self.is(*bit));
+        if let Some((_, name)) = names.next() {
+            f.write_str(name)?;
+        }
+        for (_, name) in names {
+            write!(f, ", {}", name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+// State kept across System OFF.
+//
+// System OFF powers RAM down unless its retention bits are set, and the wake
+// is a reset, after which the startup code reinitializes every static. So
+// before System OFF `save` copies the scratch registers, the statistics and
+// the TWIS DMA buffer into a record in `.uninit`, seals it with a magic word
+// and a CRC, and sets retention for the RAM sections holding it. After a wake
+// from System OFF `restore` checks the record and copies the state back.
+// Anything else (no record, a record from another build, a corrupted one) is
+// reported and the defaults are kept. With `ble` the SoftDevice sets the
+// retention bits.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{chip, hal::pac::POWER, hexdump, regmap, stats},
+    core::{
+        mem::{size_of, MaybeUninit},
+        ptr::{addr_of, addr_of_mut},
+    },
+};
+
+const MAGIC: u32 = 0x0ff5_7a7e;
+
+// RAM0..RAM7 have two 4 KB sections each, RAM8 (not on the nRF52832) up
+// to six of 32 KB.
+const RAM_START: usize = chip::RAM_START;
+const SMALL_BLOCKS_LEN: usize = 8 * 0x2000;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    size: u32,
+    stats: [u32; stats::COUNT],
+    scratch: [u8; regmap::SCRATCH_LEN],
+    buf: [u8; regmap::BUF_LEN],
+    // CRC-32 of everything above.
+    crc: u32,
+}
+
+impl Record {
+    fn crc(&self) -> u32 {
+        // SAFETY: `Record` is `repr(C)` integers without padding.
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const Record as *const u8,
+                size_of::<Record>() - size_of::<u32>(),
+            )
+        };
+        hexdump::crc32(bytes)
+    }
+}
+
+#[link_section = ".uninit.RETAINED"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// Saves the state and enables retention for it. `buf` is the DMA buffer.
+/// Call once it is certain that System OFF follows.
+pub fn save(buf: &[u8; regmap::BUF_LEN]) {
+    let mut record = Record {
+        magic: MAGIC,
+        size: size_of::<Record>() as u32,
+        stats: [0; stats::COUNT],
+        scratch: regmap::scratch(),
+        buf: *buf,
+        crc: 0,
+    };
+    for (i, value) in record.stats.iter_mut().enumerate() {
+        *value = stats::counter(i).map_or(0, |c| c.get());
+    }
+    record.crc = record.crc();
+    // SAFETY: only written here, right before System OFF, and read once by
+    // `restore` at boot.
+    unsafe { addr_of_mut!(RECORD).write_volatile(MaybeUninit::new(record)) };
+    retain(addr_of!(RECORD) as usize, size_of::<Record>());
+}
+
+/// Restores the state saved by `save` into the statics and `buf`, and
+/// invalidates the record. `woke_from_off` is `resetreas::OFF`; any other
+/// reset discards the record. Call once from `init`.
+pub fn restore(woke_from_off: bool, buf: &mut [u8; regmap::BUF_LEN]) {
+    // SAFETY: runs in `init` before `save` can. Every bit pattern is a valid
+    // `Record`.
+    let record = unsafe { addr_of!(RECORD).read_volatile().assume_init() };
+    // SAFETY: as above.
+    unsafe { (addr_of_mut!(RECORD) as *mut u32).write_volatile(0) };
+    if !woke_from_off {
+        return;
+    }
+    if record.magic != MAGIC || record.size != size_of::<Record>() as u32 {
+        warn!("no retained state, starting from defaults");
+        return;
+    }
+    if record.crc != record.crc() {
+        error!("retained state corrupted, starting from defaults");
+        return;
+    }
+    for (i, &value) in record.stats.iter().enumerate() {
+        if let Some(counter) = stats::counter(i) {
+            counter.set(value);
+        }
+    }
+    regmap::set_scratch(record.scratch);
+    *buf = record.buf;
+    info!("retained state restored");
+}
+
+// Sets the System OFF retention bits of the RAM sections overlapping
+// `start..start + len`.
+fn retain(start: usize, len: usize) {
+    // SAFETY: the RAM power registers are only written here.
+    let power = unsafe { &*POWER::ptr() };
+    let mut addr = start;
+    while addr < start + len {
+        let offset = addr - RAM_START;
+        let (block, section, section_len) = if offset < SMALL_BLOCKS_LEN {
+            (offset / 0x2000, offset % 0x2000 / 0x1000, 0x1000)
+        } else {
+            (8, (offset - SMALL_BLOCKS_LEN) / 0x8000, 0x8000)
+        };
+        addr = (addr & !(section_len - 1)) + section_len;
+        #[cfg(feature = "ble")]
+        if softdevice::enabled() {
+            softdevice::retain_ram(block as u8, 1 << (16 + section));
+            continue;
+        }
+        let ram = match block {
+            0 => &power.ram0,
+            1 => &power.ram1,
+            2 => &power.ram2,
+            3 => &power.ram3,
+            4 => &power.ram4,
+            5 => &power.ram5,
+            6 => &power.ram6,
+            #[cfg(feature = "nrf52832")]
+            _ => &power.ram7,
+            #[cfg(not(feature = "nrf52832"))]
+            7 => &power.ram7,
+            #[cfg(not(feature = "nrf52832"))]
+            _ => &power.ram8,
+        };
+        ram.powerset
+            .write(|w| unsafe { w.bits(1 << (16 + section)) });
+    }
+}
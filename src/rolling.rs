@@ -0,0 +1,215 @@
+// Rolling-code arming of one-shot requests (`rolling-code` feature).
+//
+// SLEEP, FACTORY_RESET, FLASH_ERASE, DFU_ACTIVATE and BOOTLOADER act once
+// and cannot be taken back, so with the feature each has to be armed first
+// by writing the next code of a sequence derived from the key in `key` to
+// ARM:
+//
+//   code n = first 8 bytes of AES-128(key, n u32 LE, 4 zero bytes, 8 0xff)
+//
+// ARM_COUNT reads the n the next code has; one up to `WINDOW` ahead of it
+// is accepted as well, for a controller that used codes this board never
+// saw, and moves the count past it. So a code goes through at most once
+// and a replayed capture of the bus is refused. A right code lets the next
+// one-shot request within `ARMED_MS` through, however it arrives; without
+// one it is refused with `ProtocolError::NotArmed`, and a wrong code
+// disarms.
+//
+// The count survives resets and power cycles. `PAGE` holds a log of marks,
+// one word each, the last programmed one the count may not go past before
+// a new mark is written; `reserve`, from the heartbeat, appends one
+// `BLOCK` codes ahead, so flash is programmed once every `BLOCK` codes.
+// After a reset the count resumes at the last mark, skipping the codes it
+// had reserved. Once the page is full it is erased and the mark written
+// again; a power cut in between resets the count to 0.
+
+use {
+    crate::{
+        chip,
+        ecb::{self, BLOCK_LEN},
+        error::ProtocolError,
+        key, lock, mono, nvstore,
+        request::{self, Request},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const LEN: usize = 8;
+/// How far ahead of ARM_COUNT a code may be.
+pub const WINDOW: u32 = 16;
+/// How long an arming waits for its request.
+pub const ARMED_MS: u64 = 10_000;
+
+// Codes reserved by a mark, past the window.
+const BLOCK: u32 = 64;
+// The page below the `nvstore` one.
+const PAGE: usize = chip::FLASH_LEN - 0x2000;
+const PAGE_WORDS: usize = 1024;
+const ERASED: u32 = 0xffff_ffff;
+
+struct Rolling {
+    next: u32,
+    // The last mark written, codes from here on are refused.
+    reserved: u32,
+    staged: [u8; LEN],
+    // Uptime in ms when an arming expires, if armed.
+    until: Option<u64>,
+    // `reserve` failed, and will not try again.
+    failed: bool,
+}
+
+static ROLLING: Mutex<RefCell<Rolling>> = Mutex::new(RefCell::new(Rolling {
+    next: 0,
+    reserved: 0,
+    staged: [0; LEN],
+    until: None,
+    failed: false,
+}));
+
+fn now_ms() -> u64 {
+    crate::app::monotonics::now().ticks() * 1000 / mono::TICK_HZ as u64
+}
+
+fn mark(index: usize) -> u32 {
+    // SAFETY: `PAGE` is memory mapped flash, `index` within it.
+    unsafe { (PAGE as *const u32).add(index).read_volatile() }
+}
+
+// Marks in `PAGE` and the last of them, 0 if there is none.
+fn last_mark() -> (usize, u32) {
+    let used = (0..PAGE_WORDS).take_while(|&i| mark(i) != ERASED).count();
+    (used, used.checked_sub(1).map_or(0, mark))
+}
+
+fn code(key: &[u8; BLOCK_LEN], n: u32) -> Option<[u8; LEN]> {
+    let mut block = [0xff; BLOCK_LEN];
+    block[..4].copy_from_slice(&n.to_le_bytes());
+    block[4..8].fill(0);
+    let cipher = ecb::encrypt(key, &block)?;
+    let mut code = [0; LEN];
+    code.copy_from_slice(&cipher[..LEN]);
+    Some(code)
+}
+
+/// True for the requests that need arming.
+pub fn is_one_shot(request: &Request) -> bool {
+    matches!(
+        request.opcode(),
+        request::SLEEP
+            | request::FACTORY_RESET
+            | request::FLASH_ERASE
+            | request::DFU_ACTIVATE
+            | request::BOOTLOADER
+    )
+}
+
+/// Resumes the count at the last mark. Call once from `init`, then
+/// `reserve`.
+pub fn load() {
+    if !cfg!(feature = "rolling-code") {
+        return;
+    }
+    let (_, mark) = last_mark();
+    lock::free(|cs| {
+        let mut rolling = ROLLING.borrow(cs).borrow_mut();
+        rolling.next = mark;
+        rolling.reserved = mark;
+    });
+}
+
+/// Writes a new mark once the reserved codes run low. The first error is
+/// returned, after it no more marks are written. Only call from priority 1
+/// tasks, like `nvstore`.
+pub fn reserve() -> Result<(), nvstore::Error> {
+    if !cfg!(feature = "rolling-code") {
+        return Ok(());
+    }
+    let (next, reserved, failed) = lock::free(|cs| {
+        let rolling = ROLLING.borrow(cs).borrow();
+        (rolling.next, rolling.reserved, rolling.failed)
+    });
+    if failed || reserved.saturating_sub(next) >= WINDOW + BLOCK / 2 {
+        return Ok(());
+    }
+    let res = write_mark(next.saturating_add(WINDOW + BLOCK));
+    lock::free(|cs| {
+        let mut rolling = ROLLING.borrow(cs).borrow_mut();
+        match res {
+            Ok(new) => rolling.reserved = new,
+            Err(_) => rolling.failed = true,
+        }
+    });
+    res.map(|_| ())
+}
+
+fn write_mark(new: u32) -> Result<u32, nvstore::Error> {
+    if nvstore::image_end() > PAGE {
+        return Err(nvstore::Error::NoPage);
+    }
+    let (mut used, _) = last_mark();
+    if used == PAGE_WORDS {
+        nvstore::erase_page(PAGE);
+        used = 0;
+    }
+    nvstore::program_word(PAGE + 4 * used, new);
+    if mark(used) != new {
+        return Err(nvstore::Error::Verify);
+    }
+    Ok(new)
+}
+
+/// The ARM_COUNT register.
+pub fn count() -> u32 {
+    lock::free(|cs| ROLLING.borrow(cs).borrow().next)
+}
+
+/// Stages byte `index` of ARM; the last one checks the code.
+pub fn write(index: usize, value: u8) -> bool {
+    let (staged, next, reserved) = lock::free(|cs| {
+        let mut rolling = ROLLING.borrow(cs).borrow_mut();
+        rolling.staged[index] = value;
+        (rolling.staged, rolling.next, rolling.reserved)
+    });
+    if index < LEN - 1 {
+        return true;
+    }
+    let key = key::get();
+    let end = next.saturating_add(WINDOW).min(reserved);
+    let found = (next..end).find(|&n| code(&key, n) == Some(staged));
+    let until = now_ms() + ARMED_MS;
+    let armed = lock::free(|cs| {
+        let mut rolling = ROLLING.borrow(cs).borrow_mut();
+        match found {
+            // Unless another WRITE got past it meanwhile.
+            Some(n) if n >= rolling.next => {
+                rolling.next = n + 1;
+                rolling.until = Some(until);
+                true
+            }
+            _ => {
+                rolling.until = None;
+                false
+            }
+        }
+    });
+    if armed {
+        info!("armed for one one-shot request");
+    } else {
+        warn!("wrong rolling code");
+    }
+    true
+}
+
+/// Lets `request` through if it is not one-shot or takes the arming.
+pub fn admit(request: &Request) -> Result<(), ProtocolError> {
+    if !cfg!(feature = "rolling-code") || !is_one_shot(request) {
+        return Ok(());
+    }
+    let now = now_ms();
+    let until = lock::free(|cs| ROLLING.borrow(cs).borrow_mut().until.take());
+    match until {
+        Some(until) if now <= until => Ok(()),
+        _ => Err(ProtocolError::NotArmed(request.opcode())),
+    }
+}
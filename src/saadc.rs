@@ -0,0 +1,224 @@
+// SAADC sampling on request of the controller.
+//
+// The SAMPLE request starts `count` conversions of one input, spaced by the
+// SAADC's own sample timer (`RATE_HZ`) and written by EasyDMA into
+// `RESULTS`; the CPU only steps in at the start and at END, when
+// `on_interrupt` copies them into the SAMPLES registers. So a controller
+// WRITE, moved by the TWIS DMA, chains into a second DMA peripheral, and a
+// later READ picks up the results the same way:
+//
+//   write [COMMAND, SAMPLE, channel, count], poll ADC_COUNT until it reads
+//   `count`, read 2 * `count` bytes from SAMPLES
+//
+// Inputs 0-7 are AIN0-AIN7, 8 is VDD. Samples are 12-bit single-ended with
+// gain 1/6 against the internal 0.6 V reference, i.e. a full scale of 3.6 V:
+// mV = raw * 3600 / 4096. Inputs on pins the firmware drives (AIN1 is the
+// trigger output, AIN4 the LPCOMP input, ...) read the pin voltage.
+
+use {
+    crate::{
+        hal::pac::SAADC,
+        lock,
+        tracebuf::{self, Event},
+    },
+    core::{
+        cell::RefCell,
+        ptr::addr_of_mut,
+        sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+pub const MAX_SAMPLES: usize = 8;
+/// Highest input number, VDD.
+pub const MAX_CHANNEL: u8 = 8;
+
+const RATE_HZ: u32 = 10_000;
+
+// Target of the SAADC DMA while sampling.
+static mut RESULTS: [i16; MAX_SAMPLES] = [0; MAX_SAMPLES];
+// And for `convert`.
+static mut CONVERSION: i16 = 0;
+
+static SAMPLES: Mutex<RefCell<[i16; MAX_SAMPLES]>> = Mutex::new(RefCell::new([0; MAX_SAMPLES]));
+// Valid entries in SAMPLES, 0 while sampling.
+static COUNT: AtomicU8 = AtomicU8::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Takes `SAADC` so nothing else uses it; it stays disabled until `start`.
+pub fn init(saadc: SAADC) {
+    saadc.intenset.write(|w| w.end().set());
+}
+
+fn regs() -> &'static crate::hal::pac::saadc::RegisterBlock {
+    // SAFETY: SAADC is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*SAADC::ptr() }
+}
+
+/// Starts `count` (1..=`MAX_SAMPLES`) samples of `channel`. Returns false
+/// if a run is still in progress.
+pub fn start(channel: u8, count: u8) -> bool {
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    COUNT.store(0, Ordering::Relaxed);
+    let saadc = regs();
+    saadc.enable.write(|w| w.enable().enabled());
+    saadc.resolution.write(|w| w.val()._12bit());
+    saadc.ch[0].config.write(|w| {
+        w.gain()
+            .gain1_6()
+            .refsel()
+            .internal()
+            .tacq()
+            ._10us()
+            .mode()
+            .se()
+    });
+    // SAFETY: 1..=9 are AIN0-AIN7 and VDD.
+    saadc.ch[0]
+        .pselp
+        .write(|w| unsafe { w.bits(channel as u32 + 1) });
+    saadc
+        .samplerate
+        .write(|w| unsafe { w.cc().bits((16_000_000 / RATE_HZ) as u16).mode().timers() });
+    saadc
+        .result
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(addr_of_mut!(RESULTS) as u32) });
+    saadc
+        .result
+        .maxcnt
+        .write(|w| unsafe { w.maxcnt().bits(count as u16) });
+    saadc.events_started.reset();
+    saadc.events_end.reset();
+    saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+    // Takes a few cycles; SAMPLE before STARTED would be lost.
+    while saadc.events_started.read().bits() == 0 {}
+    saadc.events_started.reset();
+    // With the sample timer, one SAMPLE task runs until MAXCNT is reached.
+    saadc.tasks_sample.write(|w| unsafe { w.bits(1) });
+    true
+}
+
+/// One conversion, waited for: of `channel` as in SAMPLE, or, with
+/// `negative`, of `channel` against it with gain 4 and 14 bits, averaged
+/// over 16 samples, so +-0.15 V in +-8192. For `ina219`; `None` while a
+/// run is in progress.
+pub fn convert(channel: u8, negative: Option<u8>) -> Option<i16> {
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    let saadc = regs();
+    // END is handled here, not by `on_interrupt`.
+    saadc.intenclr.write(|w| w.end().clear());
+    saadc.enable.write(|w| w.enable().enabled());
+    match negative {
+        Some(negative) => {
+            saadc.resolution.write(|w| w.val()._14bit());
+            saadc.oversample.write(|w| w.oversample().over16x());
+            saadc.ch[0].config.write(|w| {
+                w.gain()
+                    .gain4()
+                    .refsel()
+                    .internal()
+                    .tacq()
+                    ._40us()
+                    .mode()
+                    .diff()
+                    .burst()
+                    .enabled()
+            });
+            // SAFETY: as for `start`.
+            saadc.ch[0]
+                .pseln
+                .write(|w| unsafe { w.bits(negative as u32 + 1) });
+        }
+        None => {
+            saadc.resolution.write(|w| w.val()._12bit());
+            saadc.ch[0].config.write(|w| {
+                w.gain()
+                    .gain1_6()
+                    .refsel()
+                    .internal()
+                    .tacq()
+                    ._10us()
+                    .mode()
+                    .se()
+            });
+        }
+    }
+    // SAFETY: as for `start`.
+    saadc.ch[0]
+        .pselp
+        .write(|w| unsafe { w.bits(channel as u32 + 1) });
+    saadc.samplerate.write(|w| w.mode().task());
+    saadc
+        .result
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(addr_of_mut!(CONVERSION) as u32) });
+    saadc.result.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+    saadc.events_started.reset();
+    saadc.events_end.reset();
+    saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+    while saadc.events_started.read().bits() == 0 {}
+    saadc.events_started.reset();
+    saadc.tasks_sample.write(|w| unsafe { w.bits(1) });
+    while saadc.events_end.read().bits() == 0 {}
+    saadc.events_end.reset();
+    saadc.events_stopped.reset();
+    saadc.tasks_stop.write(|w| unsafe { w.bits(1) });
+    while saadc.events_stopped.read().bits() == 0 {}
+    saadc.events_stopped.reset();
+    // Back to what `start` expects.
+    saadc.oversample.write(|w| w.oversample().bypass());
+    saadc.ch[0].pseln.write(|w| w.pseln().nc());
+    saadc.enable.write(|w| w.enable().disabled());
+    saadc.intenset.write(|w| w.end().set());
+    RUNNING.store(false, Ordering::Relaxed);
+    // SAFETY: the DMA has stopped writing it.
+    Some(unsafe { *addr_of_mut!(CONVERSION) })
+}
+
+/// Handles END: publishes the samples and turns the SAADC off.
+pub fn on_interrupt() {
+    let saadc = regs();
+    if saadc.events_end.read().bits() == 0 {
+        return;
+    }
+    saadc.events_end.reset();
+    saadc.events_stopped.reset();
+    saadc.tasks_stop.write(|w| unsafe { w.bits(1) });
+    while saadc.events_stopped.read().bits() == 0 {}
+    saadc.events_stopped.reset();
+    let count = saadc.result.amount.read().bits() as usize;
+    let channel = saadc.ch[0].pselp.read().bits() as u8 - 1;
+    saadc.enable.write(|w| w.enable().disabled());
+    // SAFETY: the DMA has stopped writing it.
+    let results = unsafe { *addr_of_mut!(RESULTS) };
+    lock::free(|cs| {
+        let mut samples = SAMPLES.borrow(cs).borrow_mut();
+        *samples = [0; MAX_SAMPLES];
+        samples[..count].copy_from_slice(&results[..count]);
+    });
+    COUNT.store(count as u8, Ordering::Relaxed);
+    RUNNING.store(false, Ordering::Relaxed);
+    tracebuf::record(Event::Sampled, channel, count as u16);
+    info!(
+        "SAADC: {} samples of input {}: {:?}",
+        count,
+        channel,
+        &results[..count]
+    );
+}
+
+/// ADC_COUNT register.
+pub fn count() -> u8 {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Sample `index` of the last run, for the SAMPLES registers.
+pub fn sample(index: usize) -> i16 {
+    lock::free(|cs| SAMPLES.borrow(cs).borrow()[index])
+}
@@ -0,0 +1,81 @@
+// Sequenced commands at the SEQ_COMMAND register, safe to retransmit.
+//
+// A WRITE of `[SEQ_COMMAND, seq, opcode, args...]` is a COMMAND request with
+// a sequence number the controller picks, e.g. one more than the last. The
+// response to it reads from SEQ_COMMAND as a u32, see `response`. A WRITE
+// with the sequence number and bytes of the last one is a retransmission,
+// as a controller sends when it missed the ACK of the first or the
+// response: it is not carried out again and the response stays as it was,
+// so a retried SLEEP_FOR, FLASH_PROGRAM or CAPTURE happens once. Anything
+// else is a new command, so a controller that restarts its numbering does
+// not lose its first command unless it is the same as the last one.
+
+use {
+    crate::{
+        auth,
+        error::ProtocolError,
+        lock, regmap,
+        request::{self, Request},
+        rolling, unlock,
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+// `response` states.
+const NONE: u8 = 0;
+const ACCEPTED: u8 = 1;
+const REFUSED: u8 = 2;
+
+// The last command, sequence number first.
+const FRAME_LEN: usize = regmap::BUF_LEN - 1;
+
+struct Last {
+    frame: [u8; FRAME_LEN],
+    len: usize,
+    response: u32,
+}
+
+static LAST: Mutex<RefCell<Last>> = Mutex::new(RefCell::new(Last {
+    frame: [0; FRAME_LEN],
+    len: 0,
+    response: (NONE as u32) << 8,
+}));
+
+/// Decodes a sequenced command, `None` for a retransmission of the last.
+pub fn receive(data: &[u8]) -> Result<Option<Request>, ProtocolError> {
+    let Some(&seq) = data.first() else {
+        return Err(ProtocolError::BadLength { len: 0, max: 2 });
+    };
+    let duplicate = lock::free(|cs| {
+        let last = LAST.borrow(cs).borrow();
+        last.len != 0 && &last.frame[..last.len] == data
+    });
+    if duplicate {
+        info!("sequenced command {} repeated, not carried out again", seq);
+        return Ok(None);
+    }
+    let res = auth::verify(data)
+        .and_then(|frame| request::parse(frame.get(1..).unwrap_or_default()))
+        .and_then(|request| unlock::admit(&request).map(|()| request))
+        .and_then(|request| rolling::admit(&request).map(|()| request));
+    let (state, code) = match res {
+        Ok(_) => (ACCEPTED, 0),
+        Err(error) => (REFUSED, error.code()),
+    };
+    lock::free(|cs| {
+        let mut last = LAST.borrow(cs).borrow_mut();
+        let len = data.len().min(FRAME_LEN);
+        last.frame[..len].copy_from_slice(&data[..len]);
+        last.len = len;
+        last.response = seq as u32 | (state as u32) << 8 | (code as u32) << 16;
+    });
+    res.map(Some)
+}
+
+/// Response to the last sequenced command: its sequence number, then 0 for
+/// none since boot, 1 accepted or 2 refused, then the `ProtocolError` code
+/// as u16, least significant byte first.
+pub fn response() -> u32 {
+    lock::free(|cs| LAST.borrow(cs).borrow().response)
+}
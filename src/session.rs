@@ -0,0 +1,143 @@
+// Encrypted STREAM frames (`ccm` feature).
+//
+// With the feature every frame in the STREAM byte stream, requests and
+// replies alike, is sealed in the CCM peripheral before COBS framing: the
+// message encrypted, with its `auth` tag under `hmac`, then a 4-byte MIC
+// (see `ccm`). The nonce is a packet counter, the direction, 1 for requests
+// and 0 for replies, and the IV of the session:
+//
+//   1. write 1 to SESSION to start a session, both counters at 0
+//   2. read the IV from SESSION_IV
+//   3. seal request n with counter n, open reply n with counter n
+//
+// with the key from `key`. A request whose MIC does not match is refused
+// with `ProtocolError::Unauthenticated` and counted in `STATS.auth_failures`,
+// and does not advance the request counter; every reply, refusals too,
+// advances the reply counter. A controller that lost track, after a frame
+// dropped on the bus say, starts a new session.
+//
+// An IV is 4 random bytes and the number of sessions since boot, so no
+// nonce repeats before a reset. It is drawn when first needed, so the
+// frames before any write to SESSION belong to a session as well.
+
+use {
+    crate::{
+        ccm::{self, Nonce, IV_LEN},
+        entropy,
+        error::ProtocolError,
+        key, lock,
+        stats::STATS,
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub use crate::ccm::MIC_LEN;
+
+struct Session {
+    iv: Option<[u8; IV_LEN]>,
+    sessions: u32,
+    requests: u64,
+    replies: u64,
+}
+
+static SESSION: Mutex<RefCell<Session>> = Mutex::new(RefCell::new(Session {
+    iv: None,
+    sessions: 0,
+    requests: 0,
+    replies: 0,
+}));
+
+impl Session {
+    fn iv(&mut self, random: [u8; 4]) -> [u8; IV_LEN] {
+        *self.iv.get_or_insert_with(|| {
+            self.sessions = self.sessions.wrapping_add(1);
+            let mut iv = [0; IV_LEN];
+            iv[..4].copy_from_slice(&random);
+            iv[4..].copy_from_slice(&self.sessions.to_le_bytes());
+            iv
+        })
+    }
+}
+
+// Random bytes for the IV if the session has none yet, drawn outside the
+// critical section, see `entropy::take`.
+fn draw() -> [u8; 4] {
+    let mut random = [0; 4];
+    if lock::free(|cs| SESSION.borrow(cs).borrow().iv.is_none()) {
+        entropy::take(&mut random);
+    }
+    random
+}
+
+/// Starts a new session: a new IV, the counters back at 0.
+pub fn restart() {
+    lock::free(|cs| {
+        let mut session = SESSION.borrow(cs).borrow_mut();
+        session.iv = None;
+        session.requests = 0;
+        session.replies = 0;
+    });
+}
+
+/// Byte `index` of SESSION_IV.
+pub fn iv(index: usize) -> u8 {
+    let random = draw();
+    lock::free(|cs| SESSION.borrow(cs).borrow_mut().iv(random)[index])
+}
+
+/// Decrypts a request frame into `out`, returns the message. Copies it
+/// unchanged without the feature.
+pub fn open<'a>(frame: &[u8], out: &'a mut [u8]) -> Result<&'a [u8], ProtocolError> {
+    if !cfg!(feature = "ccm") {
+        let max = out.len();
+        let out = out.get_mut(..frame.len()).ok_or(ProtocolError::BadLength {
+            len: frame.len() as u32,
+            max,
+        })?;
+        out.copy_from_slice(frame);
+        return Ok(out);
+    }
+    let key = key::get();
+    let random = draw();
+    let len = lock::free(|cs| {
+        let mut session = SESSION.borrow(cs).borrow_mut();
+        let nonce = Nonce {
+            counter: session.requests,
+            direction: 1,
+            iv: session.iv(random),
+        };
+        let len = ccm::open(&key, &nonce, frame, out)?;
+        session.requests += 1;
+        Some(len)
+    });
+    match len {
+        Some(len) => Ok(&out[..len]),
+        None => {
+            STATS.auth_failures.inc();
+            Err(ProtocolError::Unauthenticated)
+        }
+    }
+}
+
+/// Encrypts a reply into `out`, MIC included, returns its length. Copies it
+/// unchanged without the feature.
+pub fn seal(reply: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !cfg!(feature = "ccm") {
+        out.get_mut(..reply.len())?.copy_from_slice(reply);
+        return Some(reply.len());
+    }
+    let key = key::get();
+    let random = draw();
+    lock::free(|cs| {
+        let mut session = SESSION.borrow(cs).borrow_mut();
+        let nonce = Nonce {
+            counter: session.replies,
+            direction: 0,
+            iv: session.iv(random),
+        };
+        let len = ccm::seal(&key, &nonce, reply, out)?;
+        session.replies += 1;
+        Some(len)
+    })
+}
@@ -0,0 +1,212 @@
+// SHA-256 (FIPS 180-4) in software, for the HMAC of `auth`.
+//
+// The nRF52840's CryptoCell CC310 computes SHA-256 and HMAC in hardware,
+// but only through Nordic's closed `nrf_cc310` library; its registers are
+// undocumented. So this is the plain algorithm, some 10 us per 64-byte
+// block at 64 MHz, plenty for frames of at most `regmap::BUF_LEN` bytes.
+
+pub const LEN: usize = 32;
+pub const BLOCK_LEN: usize = 64;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_LEN],
+    // Bytes in `block`, and hashed before it.
+    len: usize,
+    total: u64,
+}
+
+// `const fn` throughout, with `while` loops, so the known answers below are
+// checked at compile time.
+impl Sha256 {
+    pub const fn new() -> Self {
+        Sha256 {
+            state: H0,
+            block: [0; BLOCK_LEN],
+            len: 0,
+            total: 0,
+        }
+    }
+
+    pub const fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = if BLOCK_LEN - self.len < data.len() {
+                BLOCK_LEN - self.len
+            } else {
+                data.len()
+            };
+            let (head, rest) = data.split_at(take);
+            let (_, free) = self.block.split_at_mut(self.len);
+            free.split_at_mut(take).0.copy_from_slice(head);
+            self.len += take;
+            data = rest;
+            if self.len == BLOCK_LEN {
+                self.compress();
+            }
+        }
+    }
+
+    pub const fn finish(mut self) -> [u8; LEN] {
+        let bits = (self.total + self.len as u64) * 8;
+        self.update(&[0x80]);
+        if self.len > BLOCK_LEN - 8 {
+            self.update([0; BLOCK_LEN].split_at(BLOCK_LEN - self.len).0);
+        }
+        let pad = BLOCK_LEN - 8 - self.len;
+        self.update([0; BLOCK_LEN].split_at(pad).0);
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0; LEN];
+        let mut i = 0;
+        while i < 8 {
+            let bytes = self.state[i].to_be_bytes();
+            let mut j = 0;
+            while j < 4 {
+                digest[4 * i + j] = bytes[j];
+                j += 1;
+            }
+            i += 1;
+        }
+        digest
+    }
+
+    const fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        let mut i = 0;
+        while i < 16 {
+            let b = &self.block;
+            w[i] = u32::from_be_bytes([b[4 * i], b[4 * i + 1], b[4 * i + 2], b[4 * i + 3]]);
+            i += 1;
+        }
+        while i < 64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+            i += 1;
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        let mut i = 0;
+        while i < 64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+            i += 1;
+        }
+        let values = [a, b, c, d, e, f, g, h];
+        let mut i = 0;
+        while i < 8 {
+            self.state[i] = self.state[i].wrapping_add(values[i]);
+            i += 1;
+        }
+        self.len = 0;
+        self.total += BLOCK_LEN as u64;
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104) of `parts`, concatenated, with a key of at most
+/// `BLOCK_LEN` bytes.
+pub const fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; LEN] {
+    let mut inner_pad = [0x36; BLOCK_LEN];
+    let mut outer_pad = [0x5c; BLOCK_LEN];
+    let mut i = 0;
+    while i < key.len() {
+        inner_pad[i] ^= key[i];
+        outer_pad[i] ^= key[i];
+        i += 1;
+    }
+    let mut inner = Sha256::new();
+    inner.update(&inner_pad);
+    let mut i = 0;
+    while i < parts.len() {
+        inner.update(parts[i]);
+        i += 1;
+    }
+    let mut outer = Sha256::new();
+    outer.update(&outer_pad);
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+const fn digest(data: &[u8]) -> [u8; LEN] {
+    let mut sha = Sha256::new();
+    sha.update(data);
+    sha.finish()
+}
+
+const fn eq(a: &[u8; LEN], b: &[u8; LEN]) -> bool {
+    let mut i = 0;
+    while i < LEN {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Known answers: FIPS 180-2 appendix B.1, the empty message, the two-block
+// message of B.2, and RFC 4231 test case 2.
+const _: () = assert!(eq(
+    &digest(b"abc"),
+    &[
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22,
+        0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00,
+        0x15, 0xad,
+    ]
+));
+const _: () = assert!(eq(
+    &digest(b""),
+    &[
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9,
+        0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52,
+        0xb8, 0x55,
+    ]
+));
+const _: () = assert!(eq(
+    &digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+    &[
+        0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60,
+        0x39, 0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb,
+        0x06, 0xc1,
+    ]
+));
+const _: () = assert!(eq(
+    &hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]),
+    &[
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75,
+        0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec,
+        0x38, 0x43,
+    ]
+));
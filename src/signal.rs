@@ -0,0 +1,153 @@
+// Software events on EGU1 (`egu-signals` feature).
+//
+// An EGU turns a write to one of its TRIGGER tasks into an event and an
+// interrupt, like a peripheral finishing something. Raising a signal is one
+// register write from any priority; `on_signal` runs at priority 1 and
+// handles whatever has been raised since it last ran. Unlike a spawn
+// nothing is queued: a signal raised again before `on_signal` gets to it is
+// handled once, and data goes along in a mailbox holding the latest value.
+//
+//   0  PAYLOAD_READY  `on_twis` ended a transfer; the payload is logged and
+//                     dumped from `on_signal` instead of the TWIS interrupt
+//   1  ERROR          `AppError::record`; `on_signal` logs how many errors
+//                     came since it last ran
+//   2  RECONFIGURE    `store_config` saved a config; its TWIM settings are
+//                     applied at once, the rest at the next boot
+//
+// The events are PPI endpoints like any other. PPI 5 routes ERROR to GPIOTE
+// channel 2, which toggles ERROR_OUT (P0.14) on every one without the CPU.
+
+use {
+    crate::{
+        board,
+        hal::pac::{EGU1, GPIOTE, PPI},
+        lock,
+        logging::Tag,
+        regmap,
+    },
+    core::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum Signal {
+    PayloadReady = 0,
+    Error = 1,
+    Reconfigure = 2,
+}
+
+// GPIOTE and PPI channels used here, `bustiming` has the ones below.
+const GPIOTE_CHANNEL: usize = 2;
+const PPI_CHANNEL: usize = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Errors recorded since `on_signal` last took the count.
+static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// The payload of the last TWIS transfer.
+#[derive(Clone, Copy)]
+pub struct Payload {
+    pub tag: Tag,
+    buf: [u8; regmap::BUF_LEN],
+    len: usize,
+}
+
+impl Payload {
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+static PAYLOAD: Mutex<RefCell<Option<Payload>>> = Mutex::new(RefCell::new(None));
+
+fn regs() -> &'static crate::hal::pac::egu0::RegisterBlock {
+    // SAFETY: EGU1 is only used here and from its interrupt, `init` took
+    // ownership of it. TRIGGER writes are atomic.
+    unsafe { &*EGU1::ptr() }
+}
+
+/// Takes `EGU1`, enables its interrupt for the signals and connects ERROR
+/// to ERROR_OUT.
+pub fn init(egu: EGU1) {
+    egu.inten.write(|w| {
+        w.triggered0()
+            .set_bit()
+            .triggered1()
+            .set_bit()
+            .triggered2()
+            .set_bit()
+    });
+    // SAFETY: the HAL `Gpiote` only uses the PORT event, GPIOTE and PPI
+    // channels `GPIOTE_CHANNEL` and `PPI_CHANNEL` are configured here only.
+    let (gpiote, ppi) = unsafe { (&*GPIOTE::ptr(), &*PPI::ptr()) };
+    gpiote.config[GPIOTE_CHANNEL].write(|w| unsafe {
+        w.mode()
+            .task()
+            .psel()
+            .bits(board::ERROR_OUT as u8)
+            .polarity()
+            .toggle()
+            .outinit()
+            .low()
+    });
+    let event = &egu.events_triggered[Signal::Error as usize] as *const _ as u32;
+    let task = &gpiote.tasks_out[GPIOTE_CHANNEL] as *const _ as u32;
+    ppi.ch[PPI_CHANNEL].eep.write(|w| unsafe { w.bits(event) });
+    ppi.ch[PPI_CHANNEL].tep.write(|w| unsafe { w.bits(task) });
+    ppi.chenset.write(|w| unsafe { w.bits(1 << PPI_CHANNEL) });
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("EGU1 signals, ERROR on P0.{:02}", board::ERROR_OUT);
+}
+
+/// Whether signals are raised at all, `init` has run.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Raises `signal`, from any priority. Does nothing before `init`.
+pub fn raise(signal: Signal) {
+    if !enabled() {
+        return;
+    }
+    if let Signal::Error = signal {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    regs().tasks_trigger[signal as usize].write(|w| unsafe { w.bits(1) });
+}
+
+/// Clears `signal` and returns whether it was raised. From `on_signal`.
+pub fn take(signal: Signal) -> bool {
+    let event = &regs().events_triggered[signal as usize];
+    let raised = event.read().bits() != 0;
+    if raised {
+        event.reset();
+    }
+    raised
+}
+
+/// Stores the payload of a TWIS transfer and raises PAYLOAD_READY. An
+/// earlier payload not taken yet is replaced.
+pub fn post_payload(tag: Tag, bytes: &[u8]) {
+    let len = bytes.len().min(regmap::BUF_LEN);
+    let mut payload = Payload {
+        tag,
+        buf: [0; regmap::BUF_LEN],
+        len,
+    };
+    payload.buf[..len].copy_from_slice(&bytes[..len]);
+    lock::free(|cs| PAYLOAD.borrow(cs).replace(Some(payload)));
+    raise(Signal::PayloadReady);
+}
+
+pub fn take_payload() -> Option<Payload> {
+    lock::free(|cs| PAYLOAD.borrow(cs).take())
+}
+
+/// Errors raised since the last call.
+pub fn take_errors() -> u32 {
+    ERRORS.swap(0, Ordering::Relaxed)
+}
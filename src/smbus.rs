@@ -0,0 +1,42 @@
+// SMBus framing shared by the devices emulated at TWIS address 1 (`arp`,
+// `pmbus`, `ssif`).
+//
+// SMBus transactions are I2C ones with a command code first: a WRITE of
+// `[command, data...]`, or a WRITE of `[command]` and a repeated-start READ
+// of the answer. Either may end in a PEC, the CRC-8 of every byte on the
+// bus, the address bytes included. A WRITE whose PEC does not match is a
+// `ProtocolError::BadCrc`, which the devices record as an error before
+// dropping it.
+
+use crate::{error::ProtocolError, hexdump};
+
+// CRC-8 of `parts` one after the other. Without reflection or a final XOR
+// the CRC of a single byte is one step of it.
+fn crc8(parts: &[&[u8]]) -> u8 {
+    parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0, |crc, &byte| hexdump::crc8(&[crc ^ byte]))
+}
+
+/// PEC of a WRITE of `data` to `address`.
+pub fn write_pec(address: u8, data: &[u8]) -> u8 {
+    crc8(&[&[address << 1], data])
+}
+
+/// PEC of the READ of `data` after the command `command` to `address`.
+pub fn read_pec(address: u8, command: u8, data: &[u8]) -> u8 {
+    crc8(&[&[address << 1, command, address << 1 | 1], data])
+}
+
+/// Splits off and checks the PEC of a WRITE of `data` to `address` that is
+/// `len` bytes without it. The data without PEC if there is none or it
+/// matches, `Malformed` if `data` is neither length.
+pub fn strip_pec(address: u8, data: &[u8], len: usize) -> Result<&[u8], ProtocolError> {
+    match data.len() {
+        n if n == len => Ok(data),
+        n if n == len + 1 && write_pec(address, &data[..len]) == data[len] => Ok(&data[..len]),
+        n if n == len + 1 => Err(ProtocolError::BadCrc),
+        _ => Err(ProtocolError::Malformed),
+    }
+}
@@ -0,0 +1,271 @@
+// The S140 SoftDevice under `ble`.
+//
+// `enable` starts it from `idle`, once `init` is done with the peripherals
+// it takes over: CLOCK, POWER, RNG, TEMP, ECB, CCM, RADIO, TIMER0, RTC0 and
+// the NVMC. From then on the firmware reaches them through the calls
+// wrapped here, which the modules that used the registers switch to when
+// `enabled`. A SoftDevice call is an SVC, which faults with PRIMASK set, so
+// none of them may run inside `interrupt::free`; the sections of `lock`
+// mask the firmware's interrupts in the NVIC instead while it runs.
+//
+// The SoftDevice keeps NVIC priorities 0, 1 and 4 to itself. The RTIC tasks
+// run at logical 1 to 3, hardware 7 to 5, and the handlers outside RTIC at
+// logical 2; SWI2, which signals its events, is put at logical 1, so the
+// event loop in `ble` is never woken ahead of a bus transaction.
+//
+// Its SoC events arrive in `on_soc_event`: the power-fail warning and the
+// VBUS events are handed to `power` as the POWER interrupt would. Flash
+// operations end with an event too, which `erase_page` and `program_word`
+// wait for.
+
+use {
+    crate::{
+        build_info, clock,
+        hal::pac::{Interrupt, NVIC, NVIC_PRIO_BITS},
+        power,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    nrf_softdevice::{raw, SocEvent, Softdevice},
+};
+
+// The power-fail threshold of `power::init_pof`.
+const POF_THRESHOLD: u8 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V27 as u8;
+const POF_THRESHOLD_VDDH: u8 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V27 as u8;
+
+// A failed flash operation, the radio got in the way, is tried again this
+// often before the caller's read-back reports it.
+const FLASH_ATTEMPTS: u32 = 3;
+
+const FLASH_PAGE_LEN: usize = 0x1000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// True once `enable` has started the SoftDevice.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Puts SWI2 at logical priority 1. From `init`; the SoftDevice unmasks it.
+pub fn init(nvic: &mut NVIC) {
+    let priority = ((1 << NVIC_PRIO_BITS) - 1) << (8 - NVIC_PRIO_BITS);
+    // SAFETY: in `init` with interrupts disabled, no RTIC resource is
+    // shared with the SWI2 handler of nrf-softdevice.
+    unsafe { nvic.set_priority(Interrupt::SWI2_EGU2, priority) };
+}
+
+/// Starts the SoftDevice. Once, from `idle`, with interrupts enabled.
+pub fn enable() -> &'static mut Softdevice {
+    // The SoftDevice takes over the POWER_CLOCK and RNG interrupts, the
+    // RTIC tasks bound to them would otherwise touch its registers.
+    NVIC::mask(Interrupt::POWER_CLOCK);
+    NVIC::mask(Interrupt::RNG);
+    let rc = clock::lfclk_rc();
+    let config = nrf_softdevice::Config {
+        clock: Some(raw::nrf_clock_lf_cfg_t {
+            source: if rc {
+                raw::NRF_CLOCK_LF_SRC_RC
+            } else {
+                raw::NRF_CLOCK_LF_SRC_XTAL
+            } as u8,
+            // Every 4 s, and at least every 8 s for a change of 0.5 C.
+            rc_ctiv: if rc { 16 } else { 0 },
+            rc_temp_ctiv: if rc { 2 } else { 0 },
+            accuracy: if rc {
+                raw::NRF_CLOCK_LF_ACCURACY_500_PPM
+            } else {
+                raw::NRF_CLOCK_LF_ACCURACY_20_PPM
+            } as u8,
+        }),
+        conn_gap: Some(raw::ble_gap_conn_cfg_t {
+            conn_count: 1,
+            event_length: raw::BLE_GAP_EVENT_LENGTH_DEFAULT as u16,
+        }),
+        // Room for the statistics in one notification.
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 67 }),
+        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
+            attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT,
+        }),
+        gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
+            adv_set_count: 1,
+            periph_role_count: 1,
+            central_role_count: 0,
+            central_sec_count: 0,
+            _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
+        }),
+        gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
+            p_value: build_info::NAME.as_ptr() as *mut u8,
+            current_len: build_info::NAME.len() as u16,
+            max_len: build_info::NAME.len() as u16,
+            // SAFETY: all zero is "no access", the name is not writable.
+            write_perm: unsafe { core::mem::zeroed() },
+            _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(
+                raw::BLE_GATTS_VLOC_STACK as u8,
+            ),
+        }),
+        ..Default::default()
+    };
+    // Started and the HFXO handed over in one go, so an `Hfxo` taken in
+    // between is neither lost nor released twice.
+    let sd = critical_section::with(|_| {
+        let sd = Softdevice::enable(&config);
+        clock::hand_over();
+        ENABLED.store(true, Ordering::Relaxed);
+        sd
+    });
+    // SAFETY: plain SoftDevice calls, with valid arguments.
+    unsafe {
+        check(
+            raw::sd_power_pof_threshold_set(POF_THRESHOLD),
+            "pof threshold",
+        );
+        check(
+            raw::sd_power_pof_thresholdvddh_set(POF_THRESHOLD_VDDH),
+            "pof VDDH threshold",
+        );
+        check(raw::sd_power_pof_enable(1), "pof enable");
+        check(raw::sd_power_usbdetected_enable(1), "usbdetected enable");
+        check(raw::sd_power_usbremoved_enable(1), "usbremoved enable");
+        let mut status = 0;
+        check(raw::sd_power_usbregstatus_get(&mut status), "usbregstatus");
+        // USBREGSTATUS.VBUSDETECT
+        power::set_vbus(status & 1 != 0);
+    }
+    info!(
+        "SoftDevice enabled, LFCLK from {}",
+        if rc { "RC" } else { "crystal" }
+    );
+    sd
+}
+
+fn check(ret: u32, call: &str) -> bool {
+    soft_assert!(ret == raw::NRF_SUCCESS, "sd {}: {:#x}", call, ret)
+}
+
+/// Handles a SoC event, from the event loop in `ble`.
+pub fn on_soc_event(event: SocEvent) {
+    match event {
+        SocEvent::PowerFailureWarning => power::brownout(),
+        SocEvent::PowerUsbDetected => power::set_vbus(true),
+        SocEvent::PowerUsbRemoved => power::set_vbus(false),
+        event => trace!("SoC event {:?}", event),
+    }
+}
+
+pub fn hfclk_request() {
+    // SAFETY: a plain SoftDevice call.
+    check(unsafe { raw::sd_clock_hfclk_request() }, "hfclk request");
+}
+
+pub fn hfclk_release() {
+    // SAFETY: a plain SoftDevice call.
+    check(unsafe { raw::sd_clock_hfclk_release() }, "hfclk release");
+}
+
+/// The die temperature in 0.25 C, as the TEMP register reads.
+pub fn temperature() -> i32 {
+    let mut quarters = 0;
+    // SAFETY: the SoftDevice writes the one i32.
+    check(unsafe { raw::sd_temp_get(&mut quarters) }, "temp");
+    quarters
+}
+
+/// Fills `buf` from the SoftDevice's random pool as far as it has bytes,
+/// returns how many it filled.
+pub fn random(buf: &mut [u8]) -> usize {
+    let mut available = 0;
+    // SAFETY: the SoftDevice writes the one u8.
+    unsafe { raw::sd_rand_application_bytes_available_get(&mut available) };
+    let len = buf.len().min(available as usize);
+    // SAFETY: `buf` holds `len` bytes, at most 255.
+    let ret = unsafe { raw::sd_rand_application_vector_get(buf.as_mut_ptr(), len as u8) };
+    if check(ret, "rand") {
+        len
+    } else {
+        0
+    }
+}
+
+/// Constant latency or low power, `power::set_mode`.
+pub fn power_mode(constant_latency: bool) {
+    let mode = if constant_latency {
+        raw::NRF_POWER_MODES_NRF_POWER_MODE_CONSTLAT
+    } else {
+        raw::NRF_POWER_MODES_NRF_POWER_MODE_LOWPWR
+    };
+    // SAFETY: a plain SoftDevice call.
+    check(unsafe { raw::sd_power_mode_set(mode as u8) }, "power mode");
+}
+
+/// Enters System OFF, `power::system_off`.
+pub fn system_off() -> ! {
+    // SAFETY: a plain SoftDevice call, which only returns in debug
+    // interface mode.
+    unsafe { raw::sd_power_system_off() };
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+/// Sets GPREGRET to `value`, `bootloader::enter`.
+pub fn set_gpregret(value: u8) {
+    // SAFETY: plain SoftDevice calls on GPREGRET, register 0.
+    unsafe {
+        check(raw::sd_power_gpregret_clr(0, 0xff), "gpregret clr");
+        check(raw::sd_power_gpregret_set(0, value as u32), "gpregret set");
+    }
+}
+
+/// Sets the `powerset` bits of RAM block `block`, `retain`.
+pub fn retain_ram(block: u8, powerset: u32) {
+    // SAFETY: a plain SoftDevice call.
+    check(
+        unsafe { raw::sd_power_ram_power_set(block, powerset) },
+        "ram power",
+    );
+}
+
+/// Erases the flash page at `page`, `nvstore::erase_page`.
+pub fn erase_page(page: usize) {
+    flash(|| {
+        // SAFETY: the caller passes a page outside the image.
+        unsafe { raw::sd_flash_page_erase((page / FLASH_PAGE_LEN) as u32) }
+    });
+}
+
+/// Programs `word` at `addr`, `nvstore::program_word`.
+pub fn program_word(addr: usize, word: u32) {
+    flash(|| {
+        // SAFETY: as in `nvstore::program_word`; `word` outlives the
+        // operation, `flash` waits for its end.
+        unsafe { raw::sd_flash_write(addr as *mut u32, &word, 1) }
+    });
+}
+
+// Runs the flash operation `start`, waiting for its end, up to
+// `FLASH_ATTEMPTS` times until it succeeds. The task it runs from keeps the
+// event loop from taking the event first.
+fn flash(start: impl Fn() -> u32) {
+    for _ in 0..FLASH_ATTEMPTS {
+        if !check(start(), "flash") {
+            continue;
+        }
+        loop {
+            let mut event = 0;
+            // SAFETY: the SoftDevice writes the one u32.
+            match unsafe { raw::sd_evt_get(&mut event) } {
+                raw::NRF_SUCCESS => {}
+                _ => continue,
+            }
+            match event {
+                raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_SUCCESS => return,
+                raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_ERROR => break,
+                event => {
+                    if let Ok(event) = SocEvent::try_from(event) {
+                        on_soc_event(event);
+                    }
+                }
+            }
+        }
+        warn!("flash operation failed in the SoftDevice, retrying");
+    }
+}
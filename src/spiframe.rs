@@ -0,0 +1,35 @@
+// SPI framing of the register map (`spis` feature).
+//
+// SPIS moves data both ways at once, from buffers armed before CSN goes
+// low, so a transaction cannot answer a register address sent in it. The
+// two TWIS transactions become two SPI transactions instead, told apart by
+// the first MOSI byte:
+//
+//   MOSI  ptr data...   write: sets the pointer and stores the data, like a
+//                       TWIS WRITE; MISO is don't care
+//   MOSI  0xff ...      read: MISO carries the registers from the pointer,
+//                       like a TWIS READ, the pointer advances past them
+//
+// so reading `n` registers from `r` takes `[r]`, then `n` bytes of `0xff`.
+// MISO is filled when a transaction is armed, right after the previous one
+// ended: a read returns the registers as of then.
+
+/// First MOSI byte of a read. Not a register address, the map ends at 0x7f.
+pub const READ: u8 = 0xff;
+
+pub enum Frame<'a> {
+    /// CSN went low without any byte clocked.
+    Empty,
+    Read,
+    /// Pointer and data, for `regmap::apply`.
+    Write(&'a [u8]),
+}
+
+/// Decodes the bytes received in one transaction.
+pub fn decode(mosi: &[u8]) -> Frame<'_> {
+    match mosi.first() {
+        None => Frame::Empty,
+        Some(&READ) => Frame::Read,
+        Some(_) => Frame::Write(mosi),
+    }
+}
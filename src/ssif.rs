@@ -0,0 +1,183 @@
+// IPMI SSIF, the SMBus System Interface of a BMC, at TWIS address 1
+// (`ipmi-ssif` feature).
+//
+// The host writes an IPMI request with an SMBus block write and reads the
+// response with a block read, both of them commands to `ADDRESS` as in
+// `smbus`, a PEC accepted on writes and sent on reads:
+//
+//   0x02  SINGLE_WRITE  request of up to 32 bytes
+//   0x06  START_WRITE   first 32 bytes of a longer request
+//   0x07  MIDDLE_WRITE  next 32 bytes
+//   0x08  END_WRITE     the rest, which completes the request
+//   0x03  READ_START    response, or its first part
+//   0x09  READ_MIDDLE   next part of a response
+//   0x0a  READ_RETRY    the last part again
+//
+// A request or response is netfn << 2 | LUN, command, then the data, with
+// the completion code first in a response, see `ipmi`. One over 32 bytes is
+// read in parts: READ_START answers 32 bytes, 0x00 0x01 and the first 30 of
+// the response; each READ_MIDDLE then a block number, 0 onwards, and the
+// next 31, and the last one the block number 0xff and what is left. The
+// parts advance when the READ is answered, so a READ cut short needs
+// READ_RETRY. Requests are carried out at once, in `on_twis`, so the
+// response is there for the next READ.
+
+use {
+    crate::{error::AppError, ipmi, lock, smbus},
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// The BMC address, 0x20 as an 8-bit one.
+pub const ADDRESS: u8 = 0x10;
+/// Longest request and response.
+pub const MSG_LEN: usize = 64;
+const BLOCK_LEN: usize = 32;
+
+const SINGLE_WRITE: u8 = 0x02;
+const READ_START: u8 = 0x03;
+const START_WRITE: u8 = 0x06;
+const MIDDLE_WRITE: u8 = 0x07;
+const END_WRITE: u8 = 0x08;
+const READ_MIDDLE: u8 = 0x09;
+const READ_RETRY: u8 = 0x0a;
+
+// Block number of the last part of a response.
+const LAST_BLOCK: u8 = 0xff;
+
+struct Ssif {
+    request: [u8; MSG_LEN],
+    request_len: usize,
+    response: [u8; MSG_LEN],
+    response_len: usize,
+    // The command a following READ answers.
+    command: Option<u8>,
+    // Where the next part of the response starts, and its block number.
+    next: usize,
+    block: u8,
+    // The last part sent, for READ_RETRY.
+    last: [u8; BLOCK_LEN + 1],
+    last_len: usize,
+}
+
+static SSIF: Mutex<RefCell<Ssif>> = Mutex::new(RefCell::new(Ssif {
+    request: [0; MSG_LEN],
+    request_len: 0,
+    response: [0; MSG_LEN],
+    response_len: 0,
+    command: None,
+    next: 0,
+    block: 0,
+    last: [0; BLOCK_LEN + 1],
+    last_len: 0,
+}));
+
+/// True with the feature.
+pub fn is_active() -> bool {
+    cfg!(feature = "ipmi-ssif")
+}
+
+/// Takes a WRITE at `ADDRESS`.
+pub fn write(data: &[u8]) {
+    let Some(&command) = data.first() else {
+        return;
+    };
+    lock::free(|cs| {
+        let mut ssif = SSIF.borrow(cs).borrow_mut();
+        ssif.command = None;
+        if let [READ_START | READ_MIDDLE | READ_RETRY] = data {
+            ssif.command = Some(command);
+            return;
+        }
+        let count = data.get(1).map_or(0, |&count| count as usize);
+        let bytes = match smbus::strip_pec(ADDRESS, data, 2 + count) {
+            Ok([_, _, bytes @ ..]) => bytes,
+            Ok(_) => return,
+            Err(error) => {
+                warn!("SSIF write {:#04x} dropped: {}", command, error);
+                AppError::from(error).record();
+                return;
+            }
+        };
+        let start = match command {
+            SINGLE_WRITE | START_WRITE => 0,
+            MIDDLE_WRITE | END_WRITE => ssif.request_len,
+            _ => return,
+        };
+        if bytes.len() > BLOCK_LEN || start + bytes.len() > MSG_LEN {
+            warn!("SSIF request too long, dropped");
+            ssif.request_len = 0;
+            return;
+        }
+        ssif.request[start..][..bytes.len()].copy_from_slice(bytes);
+        ssif.request_len = start + bytes.len();
+        if matches!(command, SINGLE_WRITE | END_WRITE) {
+            let Ssif {
+                request,
+                request_len,
+                response,
+                response_len,
+                ..
+            } = &mut *ssif;
+            *response_len = ipmi::handle(&request[..*request_len], response);
+            ssif.next = 0;
+            ssif.block = 0;
+        }
+    });
+}
+
+/// Fills `buf` for a READ at `ADDRESS`: the next part of the response as a
+/// block and its PEC, 0xff after it or with nothing to read.
+pub fn read(buf: &mut [u8]) {
+    buf.fill(0xff);
+    let mut block = [0; BLOCK_LEN + 2];
+    let answer = lock::free(|cs| {
+        let mut ssif = SSIF.borrow(cs).borrow_mut();
+        let command = ssif.command?;
+        let ssif = &mut *ssif;
+        let response = &ssif.response[..ssif.response_len];
+        let part = match command {
+            READ_RETRY => return Some((command, ssif.last, ssif.last_len)),
+            READ_START if response.is_empty() => return None,
+            READ_START if response.len() <= BLOCK_LEN => {
+                ssif.next = response.len();
+                let mut part = [0; BLOCK_LEN + 1];
+                part[0] = response.len() as u8;
+                part[1..][..response.len()].copy_from_slice(response);
+                (part, 1 + response.len())
+            }
+            READ_START => {
+                ssif.next = BLOCK_LEN - 2;
+                ssif.block = 0;
+                let mut part = [0; BLOCK_LEN + 1];
+                part[..3].copy_from_slice(&[BLOCK_LEN as u8, 0x00, 0x01]);
+                part[3..].copy_from_slice(&response[..BLOCK_LEN - 2]);
+                (part, BLOCK_LEN + 1)
+            }
+            _ => {
+                let rest = response.get(ssif.next..).filter(|rest| !rest.is_empty())?;
+                let len = rest.len().min(BLOCK_LEN - 1);
+                let number = if len == rest.len() {
+                    LAST_BLOCK
+                } else {
+                    ssif.block
+                };
+                ssif.block = ssif.block.wrapping_add(1);
+                ssif.next += len;
+                let mut part = [0; BLOCK_LEN + 1];
+                part[..2].copy_from_slice(&[len as u8 + 1, number]);
+                part[2..][..len].copy_from_slice(&rest[..len]);
+                (part, len + 2)
+            }
+        };
+        (ssif.last, ssif.last_len) = part;
+        Some((command, part.0, part.1))
+    });
+    let Some((command, part, len)) = answer else {
+        return;
+    };
+    block[..len].copy_from_slice(&part[..len]);
+    block[len] = smbus::read_pec(ADDRESS, command, &part[..len]);
+    let len = (len + 1).min(buf.len());
+    buf[..len].copy_from_slice(&block[..len]);
+}
@@ -0,0 +1,148 @@
+// Transaction statistics.
+//
+// Counters are plain atomics so any task can bump them without taking a
+// lock. They only ever grow (wrapping at u32::MAX); compare two summaries to
+// get rates.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU32::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u32) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Overwrites the count, for state restored by `retain`.
+    pub fn set(&self, n: u32) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+}
+
+pub struct Stats {
+    /// Heartbeats since boot.
+    pub alive: Counter,
+    /// Controller READs served by TWIS.
+    pub twis_reads: Counter,
+    /// Controller WRITEs accepted by TWIS.
+    pub twis_writes: Counter,
+    pub twis_bytes_rx: Counter,
+    pub twis_bytes_tx: Counter,
+    /// Reads issued by TWIM.
+    pub twim_reads: Counter,
+    /// Writes issued by TWIM.
+    pub twim_writes: Counter,
+    pub twim_bytes: Counter,
+    /// Address or data NACKs seen by TWIM.
+    pub nacks: Counter,
+    /// TWIM overruns plus TWIS RX overflows.
+    pub overruns: Counter,
+    /// TWIM transactions repeated after a failure.
+    pub retries: Counter,
+    /// Errors not covered by the counters above.
+    pub errors: Counter,
+    /// `on_twis` invocations with no TWIS event pending.
+    pub spurious: Counter,
+    /// Interrupts that reached the default handler.
+    pub unexpected_irqs: Counter,
+    /// Failed `soft_assert!`/`debug_check!` checks.
+    pub assertions: Counter,
+    /// Log lines and data records dropped because an RTT buffer was full.
+    pub rtt_drops: Counter,
+    /// Request frames refused by `auth`. Not in the register map, which has
+    /// room for `COUNT`.
+    pub auth_failures: Counter,
+}
+
+pub static STATS: Stats = Stats {
+    alive: Counter::new(),
+    twis_reads: Counter::new(),
+    twis_writes: Counter::new(),
+    twis_bytes_rx: Counter::new(),
+    twis_bytes_tx: Counter::new(),
+    twim_reads: Counter::new(),
+    twim_writes: Counter::new(),
+    twim_bytes: Counter::new(),
+    nacks: Counter::new(),
+    overruns: Counter::new(),
+    retries: Counter::new(),
+    errors: Counter::new(),
+    spurious: Counter::new(),
+    unexpected_irqs: Counter::new(),
+    assertions: Counter::new(),
+    rtt_drops: Counter::new(),
+    auth_failures: Counter::new(),
+};
+
+/// Number of counters reachable through `counter`.
+pub const COUNT: usize = 16;
+
+/// Counters in a fixed order, for binary encodings (register map, telemetry).
+pub fn counter(index: usize) -> Option<&'static Counter> {
+    let s = &STATS;
+    let counter = match index {
+        0 => &s.alive,
+        1 => &s.twis_reads,
+        2 => &s.twis_writes,
+        3 => &s.twis_bytes_rx,
+        4 => &s.twis_bytes_tx,
+        5 => &s.twim_reads,
+        6 => &s.twim_writes,
+        7 => &s.twim_bytes,
+        8 => &s.nacks,
+        9 => &s.overruns,
+        10 => &s.retries,
+        11 => &s.errors,
+        12 => &s.spurious,
+        13 => &s.unexpected_irqs,
+        14 => &s.assertions,
+        15 => &s.rtt_drops,
+        _ => return None,
+    };
+    Some(counter)
+}
+
+/// One-line summary of all counters.
+pub struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &STATS;
+        write!(
+            f,
+            "stats: alive={} | twis r={} w={} rx={}B tx={}B | twim r={} w={} {}B | nack={} ovr={} retry={} err={} | spurious={} irq={} assert={} | rtt drops={} | auth fail={}",
+            s.alive.get(),
+            s.twis_reads.get(),
+            s.twis_writes.get(),
+            s.twis_bytes_rx.get(),
+            s.twis_bytes_tx.get(),
+            s.twim_reads.get(),
+            s.twim_writes.get(),
+            s.twim_bytes.get(),
+            s.nacks.get(),
+            s.overruns.get(),
+            s.retries.get(),
+            s.errors.get(),
+            s.spurious.get(),
+            s.unexpected_irqs.get(),
+            s.assertions.get(),
+            s.rtt_drops.get(),
+            s.auth_failures.get(),
+        )
+    }
+}
@@ -0,0 +1,57 @@
+// Sticky status flags, served as the STATUS register.
+//
+// The firmware sets a bit when a condition occurs; it stays set until the
+// controller writes a 1 to it, so a short event is not missed between two
+// polls. The top bits are live instead: they follow a state and ignore
+// writes.
+
+use {
+    crate::events::{self, Kind},
+    core::sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The supply dropped below the power-fail threshold, see `power`.
+pub const BROWNOUT: u8 = 1 << 0;
+
+/// The analog input crossed the LPCOMP threshold, see `lpcomp`.
+pub const ANALOG: u8 = 1 << 1;
+
+/// The rotary encoder moved, see `qdec`.
+pub const ENCODER: u8 = 1 << 2;
+
+/// Live: the thermal throttle is engaged, see `thermal`.
+pub const THERMAL: u8 = 1 << 6;
+
+/// Live: VBUS (USB power) is present, see `power`.
+pub const VBUS: u8 = 1 << 7;
+
+static FLAGS: AtomicU8 = AtomicU8::new(0);
+static LIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Sets sticky bits and queues them as an event.
+pub fn set(bits: u8) {
+    FLAGS.fetch_or(bits, Ordering::Relaxed);
+    events::push(Kind::Status, bits, 0);
+}
+
+/// Clears the bits set in `bits`, as written by the controller.
+pub fn clear(bits: u8) {
+    FLAGS.fetch_and(!bits, Ordering::Relaxed);
+}
+
+/// Updates live bits.
+pub fn set_live(bits: u8, on: bool) {
+    if on {
+        LIVE.fetch_or(bits, Ordering::Relaxed);
+    } else {
+        LIVE.fetch_and(!bits, Ordering::Relaxed);
+    }
+}
+
+pub fn get() -> u8 {
+    FLAGS.load(Ordering::Relaxed) | LIVE.load(Ordering::Relaxed)
+}
+
+pub fn is_set(bits: u8) -> bool {
+    get() & bits != 0
+}
@@ -0,0 +1,169 @@
+// Stream mode at the STREAM register: typed messages framed with COBS.
+//
+// MESSAGE takes one message per WRITE. STREAM is a FIFO instead: the bytes
+// of every WRITE starting there are appended to a byte stream in which each
+// message is COBS-encoded (see `cobs`) and ends with a 0x00 delimiter, so a
+// frame may be split across any number of WRITEs and several may share one.
+// Every complete frame goes to `message::receive`, and its reply, framed the
+// same way, is queued for READs starting at STREAM; once the queue is empty
+// they read 0x00, empty frames to skip.
+//
+// A frame that overruns `FRAME_LEN` or does not decode is dropped up to the
+// next delimiter and refused, so after a lost or corrupted byte the stream
+// resynchronizes at the next 0x00. A controller writes one before its first
+// frame to drop a partial frame left over from an earlier one.
+//
+// With the `ccm` feature the frames are encrypted, see `session`.
+
+use {
+    crate::{
+        cobs,
+        error::ProtocolError,
+        lock,
+        message::{self, REPLY_LEN},
+        request::Request,
+        session::{self, MIC_LEN},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// Longest frame, COBS-encoded and without its delimiter.
+pub const FRAME_LEN: usize = 64;
+// A reply sealed by `session`, then framed.
+const SEALED_LEN: usize = REPLY_LEN + MIC_LEN;
+const REPLY_FRAME_LEN: usize = SEALED_LEN + 2;
+// Replies waiting to be read, framed.
+const TX_LEN: usize = 8 * REPLY_FRAME_LEN;
+// Decoded frames `apply_write` has not taken yet.
+const QUEUE_LEN: usize = 4;
+
+struct Stream {
+    rx: [u8; FRAME_LEN],
+    rx_len: usize,
+    // The frame in `rx` has overrun, skip to the delimiter.
+    overrun: bool,
+    tx: [u8; TX_LEN],
+    tx_len: usize,
+    queue: [Option<Result<Request, ProtocolError>>; QUEUE_LEN],
+    queued: usize,
+}
+
+static STREAM: Mutex<RefCell<Stream>> = Mutex::new(RefCell::new(Stream {
+    rx: [0; FRAME_LEN],
+    rx_len: 0,
+    overrun: false,
+    tx: [0; TX_LEN],
+    tx_len: 0,
+    queue: [None; QUEUE_LEN],
+    queued: 0,
+}));
+
+impl Stream {
+    fn push(&mut self, byte: u8) {
+        if byte != 0 {
+            if self.rx_len == FRAME_LEN {
+                self.overrun = true;
+            } else if !self.overrun {
+                self.rx[self.rx_len] = byte;
+                self.rx_len += 1;
+            }
+            return;
+        }
+        let len = core::mem::take(&mut self.rx_len);
+        if core::mem::take(&mut self.overrun) {
+            self.refuse(ProtocolError::BadLength {
+                len: len as u32 + 1,
+                max: FRAME_LEN,
+            });
+        } else if len != 0 {
+            let mut data = [0; FRAME_LEN];
+            let mut message = [0; FRAME_LEN];
+            match cobs::decode(&self.rx[..len], &mut data)
+                .map(|data_len| session::open(&data[..data_len], &mut message))
+            {
+                Some(Ok(message)) => {
+                    let res = message::receive(message);
+                    self.reply(res);
+                }
+                Some(Err(error)) => self.refuse(error),
+                None => self.refuse(ProtocolError::Malformed),
+            }
+        }
+    }
+
+    fn refuse(&mut self, error: ProtocolError) {
+        message::refuse(error);
+        self.reply(Err(error));
+    }
+
+    // Queues the result for `apply_write` and the answer for the controller.
+    fn reply(&mut self, res: Result<Request, ProtocolError>) {
+        if self.queued < QUEUE_LEN {
+            self.queue[self.queued] = Some(res);
+            self.queued += 1;
+        } else {
+            warn!("stream: request queue full, frame dropped");
+        }
+        let (answer, len) = message::last_answer();
+        let mut sealed = [0; SEALED_LEN];
+        let mut frame = [0; REPLY_FRAME_LEN];
+        let Some(frame_len) = session::seal(&answer[..len], &mut sealed)
+            .and_then(|sealed_len| cobs::encode(&sealed[..sealed_len], &mut frame))
+        else {
+            return;
+        };
+        let end = self.tx_len + frame_len + 1;
+        if end > TX_LEN {
+            warn!("stream: reply queue full, reply dropped");
+            return;
+        }
+        self.tx[self.tx_len..end - 1].copy_from_slice(&frame[..frame_len]);
+        self.tx[end - 1] = 0;
+        self.tx_len = end;
+    }
+}
+
+/// Appends the bytes of a WRITE to the stream.
+pub fn feed(bytes: &[u8]) {
+    lock::free(|cs| {
+        let mut stream = STREAM.borrow(cs).borrow_mut();
+        bytes.iter().for_each(|&byte| stream.push(byte));
+    });
+}
+
+/// The next request or refusal decoded from the stream, oldest first.
+pub fn take() -> Option<Result<Request, ProtocolError>> {
+    lock::free(|cs| {
+        let mut stream = STREAM.borrow(cs).borrow_mut();
+        if stream.queued == 0 {
+            return None;
+        }
+        let res = stream.queue[0].take();
+        stream.queue.rotate_left(1);
+        stream.queued -= 1;
+        res
+    })
+}
+
+/// Fills `buf` with the queued replies for a READ, 0x00 after them. They
+/// stay queued until `consume`.
+pub fn peek(buf: &mut [u8]) {
+    lock::free(|cs| {
+        let stream = STREAM.borrow(cs).borrow();
+        let len = stream.tx_len.min(buf.len());
+        buf[..len].copy_from_slice(&stream.tx[..len]);
+        buf[len..].fill(0);
+    });
+}
+
+/// Drops the `count` reply bytes the controller has read.
+pub fn consume(count: usize) {
+    lock::free(|cs| {
+        let mut stream = STREAM.borrow(cs).borrow_mut();
+        let count = count.min(stream.tx_len);
+        let len = stream.tx_len;
+        stream.tx.copy_within(count..len, 0);
+        stream.tx_len -= count;
+    });
+}
@@ -0,0 +1,126 @@
+// Task and interrupt instrumentation through the `rtos-trace` hooks.
+//
+// Every RTIC task opens a `Span` on entry. The span feeds the per-task
+// execution time `profile` and the `markers` activity pin; with the
+// `rtos-trace` feature it also emits task enter/exit (and ISR enter/exit for
+// hardware tasks) markers.
+//
+// The hooks are the ones `systemview-target` implements, so the markers can
+// be fed to SEGGER SystemView by swapping the `global_trace!` backend below.
+// The default backend records them into the `tracebuf` ring instead: the
+// SystemView target library ships its own C RTT implementation, which cannot
+// coexist with `rtt-target`.
+
+#[cfg(feature = "rtos-trace")]
+use rtos_trace::trace;
+
+use crate::{markers, profile, tracebuf::TaskId};
+
+/// Marks the execution of one task; the task ends when the span is dropped.
+/// The span's duration is also charged to the task in `profile`.
+pub struct Span {
+    task: TaskId,
+    start: u32,
+    #[cfg(feature = "rtos-trace")]
+    isr: bool,
+}
+
+impl Span {
+    /// Enters a hardware task (interrupt handler).
+    #[inline(always)]
+    pub fn isr(task: TaskId) -> Self {
+        #[cfg(feature = "rtos-trace")]
+        trace::isr_enter();
+        Self::enter(task, true)
+    }
+
+    /// Enters a software task.
+    #[inline(always)]
+    pub fn task(task: TaskId) -> Self {
+        Self::enter(task, false)
+    }
+
+    #[inline(always)]
+    fn enter(task: TaskId, _isr: bool) -> Self {
+        #[cfg(feature = "rtos-trace")]
+        trace::task_exec_begin(task as u32);
+        markers::enter();
+        Span {
+            task,
+            start: profile::now(),
+            #[cfg(feature = "rtos-trace")]
+            isr: _isr,
+        }
+    }
+}
+
+impl Drop for Span {
+    #[inline(always)]
+    fn drop(&mut self) {
+        profile::record(self.task, self.start);
+        markers::exit();
+        #[cfg(feature = "rtos-trace")]
+        {
+            trace::task_exec_end();
+            if self.isr {
+                trace::isr_exit();
+            }
+        }
+    }
+}
+
+/// Marks a pass through the idle loop.
+#[inline(always)]
+pub fn idle() {
+    #[cfg(feature = "rtos-trace")]
+    trace::system_idle();
+}
+
+#[cfg(feature = "rtos-trace")]
+mod backend {
+    use {
+        crate::tracebuf::{self, Event},
+        rtos_trace::{RtosTrace, TaskInfo},
+    };
+
+    struct TraceRing;
+
+    rtos_trace::global_trace! {TraceRing}
+
+    impl RtosTrace for TraceRing {
+        fn task_new(_id: u32) {}
+        fn task_send_info(_id: u32, _info: TaskInfo) {}
+        fn task_terminate(_id: u32) {}
+
+        fn task_exec_begin(id: u32) {
+            tracebuf::record(Event::TaskEnter, id as u8, 0);
+        }
+
+        fn task_exec_end() {
+            tracebuf::record(Event::TaskExit, 0, 0);
+        }
+
+        fn task_ready_begin(_id: u32) {}
+        fn task_ready_end(_id: u32) {}
+
+        fn system_idle() {
+            tracebuf::record(Event::Idle, 0, 0);
+        }
+
+        fn isr_enter() {
+            tracebuf::record(Event::IsrEnter, 0, 0);
+        }
+
+        fn isr_exit() {
+            tracebuf::record(Event::IsrExit, 0, 0);
+        }
+
+        fn isr_exit_to_scheduler() {
+            tracebuf::record(Event::IsrExit, 0, 0);
+        }
+
+        fn marker(_id: u32) {}
+        fn marker_begin(_id: u32) {}
+        fn marker_end(_id: u32) {}
+    }
+}
@@ -0,0 +1,89 @@
+// Telemetry frames over UARTE (`telemetry` feature).
+//
+// Once a second a compact binary frame is written to UARTE0 (TXD P0.20,
+// 115200 8N1), so a board without a debug probe can still be monitored from
+// any serial port. Frame layout, multi-byte fields little-endian:
+//
+//   | 0xa5 | len: u8 | payload: [u8; len] | checksum: u8 |
+//
+//   payload: | seq: u8 | uptime_ms: u32 | counters: [u32; stats::COUNT] |
+//            | last_tag: u8 | last_len: u16 | last_ms: u32 |
+//
+// `counters` are in `stats::counter` order, the same as the register map.
+// `last_*` describe the most recent TWIS transfer (`logging::Tag`, bytes
+// moved, uptime when it finished); `last_tag` is 0 before the first one.
+// `checksum` is the wrapping sum of `len` and the payload, negated, so all
+// bytes after the sync byte sum to zero.
+
+use {
+    crate::{
+        hal::{pac::UARTE0, uarte::Uarte},
+        logging::Tag,
+        mono, stats,
+        stats::STATS,
+    },
+    core::sync::atomic::{AtomicU32, Ordering},
+};
+
+pub const SYNC: u8 = 0xa5;
+
+const PAYLOAD_LEN: usize = 1 + 4 + 4 * stats::COUNT + 1 + 2 + 4;
+const FRAME_LEN: usize = 2 + PAYLOAD_LEN + 1;
+
+// Most recent TWIS transfer: tag in the top byte, length in the low 16 bits.
+static LAST_TRANSFER: AtomicU32 = AtomicU32::new(0);
+static LAST_TRANSFER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Remembers a finished TWIS transfer for the next frame.
+pub fn record_transfer(tag: Tag, len: usize) {
+    LAST_TRANSFER_MS.store(now_ms(), Ordering::Relaxed);
+    LAST_TRANSFER.store((tag as u32) << 24 | len as u32 & 0xffff, Ordering::Relaxed);
+}
+
+pub struct Telemetry {
+    uarte: Uarte<UARTE0>,
+    seq: u8,
+}
+
+impl Telemetry {
+    pub fn new(uarte: Uarte<UARTE0>) -> Self {
+        Telemetry { uarte, seq: 0 }
+    }
+
+    /// Encodes and sends one frame. Blocks for the ~7 ms the UARTE needs.
+    pub fn send(&mut self) {
+        let mut frame = [0u8; FRAME_LEN];
+        frame[0] = SYNC;
+        frame[1] = PAYLOAD_LEN as u8;
+        let mut pos = 2;
+        let mut put = |bytes: &[u8]| {
+            frame[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+        };
+        put(&[self.seq]);
+        put(&now_ms().to_le_bytes());
+        for index in 0..stats::COUNT {
+            let value = stats::counter(index).map_or(0, |c| c.get());
+            put(&value.to_le_bytes());
+        }
+        let last = LAST_TRANSFER.load(Ordering::Relaxed);
+        put(&[(last >> 24) as u8]);
+        put(&(last as u16).to_le_bytes());
+        put(&LAST_TRANSFER_MS.load(Ordering::Relaxed).to_le_bytes());
+
+        let sum = frame[1..FRAME_LEN - 1]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b));
+        frame[FRAME_LEN - 1] = sum.wrapping_neg();
+
+        if self.uarte.write(&frame).is_err() {
+            STATS.errors.inc();
+        }
+        self.seq = self.seq.wrapping_add(1);
+    }
+}
+
+fn now_ms() -> u32 {
+    let ticks = crate::app::monotonics::now().ticks();
+    (ticks * 1000 / mono::TICK_HZ as u64) as u32
+}
@@ -0,0 +1,87 @@
+// Die temperature and the thermal throttle.
+//
+// `check` runs every `PERIOD_SECS` and measures the die temperature with
+// TEMP (~36 us). Above `THROTTLE_C` the throttle engages, sets the live
+// `status::THERMAL` bit and makes the load generators (`bench`) do less
+// work; it releases below `RELEASE_C`, so a temperature hovering at the
+// threshold does not toggle it. The last reading is served in whole degrees
+// as the TEMPERATURE register and at full resolution, 0.25 degrees C, as
+// TEMPERATURE_RAW. With the SoftDevice of `ble` running, TEMP is read
+// through it.
+
+#[cfg(feature = "ble")]
+use crate::softdevice;
+use {
+    crate::{
+        hal::pac::TEMP,
+        status,
+        tracebuf::{self, Event},
+    },
+    core::sync::atomic::{AtomicI32, Ordering},
+};
+
+pub const PERIOD_SECS: u64 = 5;
+
+const THROTTLE_C: i32 = 60;
+const RELEASE_C: i32 = 55;
+
+// Last reading in the TEMP unit, 0.25 degrees C.
+static QUARTERS: AtomicI32 = AtomicI32::new(0);
+
+/// Takes `TEMP` so nothing else uses it, and takes a first reading.
+pub fn init(_temp: TEMP) {
+    check();
+}
+
+/// Measures the temperature and updates the throttle.
+pub fn check() {
+    let quarters = measure();
+    QUARTERS.store(quarters, Ordering::Relaxed);
+
+    let celsius = quarters / 4;
+    let was = throttled();
+    let now = if was {
+        celsius >= RELEASE_C
+    } else {
+        celsius > THROTTLE_C
+    };
+    if now != was {
+        status::set_live(status::THERMAL, now);
+        tracebuf::record(Event::Thermal, now as u8, celsius as u16);
+        if now {
+            warn!("die at {} C, throttling", celsius);
+        } else {
+            info!("die at {} C, throttle released", celsius);
+        }
+    }
+}
+
+fn measure() -> i32 {
+    #[cfg(feature = "ble")]
+    if softdevice::enabled() {
+        return softdevice::temperature();
+    }
+    // SAFETY: TEMP is only used here, `init` took ownership of it.
+    let temp = unsafe { &*TEMP::ptr() };
+    temp.events_datardy.reset();
+    temp.tasks_start.write(|w| unsafe { w.bits(1) });
+    while temp.events_datardy.read().bits() == 0 {}
+    temp.events_datardy.reset();
+    temp.temp.read().bits() as i32
+}
+
+/// Last reading in whole degrees C.
+pub fn celsius() -> i8 {
+    (QUARTERS.load(Ordering::Relaxed) / 4).clamp(i8::MIN as i32, i8::MAX as i32) as i8
+}
+
+/// Last reading in the TEMP unit, 0.25 degrees C.
+pub fn quarters() -> i16 {
+    QUARTERS
+        .load(Ordering::Relaxed)
+        .clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+pub fn throttled() -> bool {
+    status::is_set(status::THERMAL)
+}
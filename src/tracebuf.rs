@@ -0,0 +1,380 @@
+// Timestamped binary trace of significant events.
+//
+// Events are appended to a RAM ring buffer as fixed-size records; once the
+// ring is full the oldest record is overwritten. The `trace` console command
+// dumps the ring, oldest first, to the RTT data channel for offline timeline
+// reconstruction.
+//
+// Record layout (8 bytes, little-endian):
+//
+//   | timestamp: u32 | event: u8 | arg: u8 | value: u16 |
+//
+// `timestamp` is the RTC monotonic in 32.768 kHz ticks (wraps after ~36 h).
+// The meaning of `arg` and `value` depends on `event`, see `Event`.
+
+use {
+    crate::{
+        lock,
+        logging::{self, Tag, MAX_RECORD_PAYLOAD},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// Number of records kept in the ring.
+pub const CAPACITY: usize = 256;
+
+pub const RECORD_LEN: usize = 8;
+
+/// Event codes, stored in the `event` byte of a record.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Event {
+    /// TWIS WRITE event (address matched for a controller write).
+    TwisWrite = 0x01,
+    /// TWIS READ event (address matched for a controller read).
+    TwisRead = 0x02,
+    /// TWIS STOPPED event. `value`: bytes moved by the transfer.
+    TwisStopped = 0x03,
+    /// TWIS RX DMA armed. `value`: buffer length.
+    DmaRxStart = 0x04,
+    /// TWIS TX DMA armed. `value`: buffer length.
+    DmaTxStart = 0x05,
+    /// TWIS DMA transfer finished.
+    DmaDone = 0x06,
+    /// Software task spawned. `arg`: `TaskId`.
+    TaskSpawn = 0x07,
+    /// Error. `arg`: `ErrorSource`, `value`: source specific error code.
+    Error = 0x08,
+    /// Button pressed, TWIS buffer cleared.
+    ButtonReset = 0x09,
+    /// TWIM read finished. `value`: bytes read.
+    TwimRead = 0x0a,
+    /// TWIM write finished. `value`: bytes written.
+    TwimWrite = 0x0b,
+    /// Task started running (`rtos-trace` feature). `arg`: `TaskId`.
+    #[cfg(feature = "rtos-trace")]
+    TaskEnter = 0x0c,
+    /// The most recently entered task finished (`rtos-trace` feature).
+    #[cfg(feature = "rtos-trace")]
+    TaskExit = 0x0d,
+    /// Interrupt handler entered (`rtos-trace` feature).
+    #[cfg(feature = "rtos-trace")]
+    IsrEnter = 0x0e,
+    /// Interrupt handler left (`rtos-trace` feature).
+    #[cfg(feature = "rtos-trace")]
+    IsrExit = 0x0f,
+    /// Idle loop woke up and is about to sleep again (`rtos-trace` feature).
+    #[cfg(feature = "rtos-trace")]
+    Idle = 0x10,
+    /// Interrupt that should not have happened. `arg`: `anomaly::Kind`.
+    Anomaly = 0x11,
+    /// `soft_assert!` or `debug_check!` failed. `value`: source line.
+    Assert = 0x12,
+    /// Armed trigger pattern seen in a TWIS payload. `value`: match offset.
+    Trigger = 0x13,
+    /// Address match woke the chip, see `burst`.
+    BurstStart = 0x14,
+    /// Bus quiet again, back to sleep. `value`: burst length in ms.
+    BurstEnd = 0x15,
+    /// Power-fail comparator warning, see `power`.
+    Brownout = 0x16,
+    /// TWIS disabled for a sleep window. `value`: window length in ms.
+    SleepWindow = 0x17,
+    /// TWIS re-enabled after a sleep window.
+    SleepWindowEnd = 0x18,
+    /// VBUS appeared (`value` 1) or went away (0).
+    Vbus = 0x19,
+    /// LPCOMP upward crossing. `value`: threshold in sixteenths of VDD.
+    Analog = 0x1a,
+    /// Thermal throttle engaged (`arg` 1) or released (0). `value`: die
+    /// temperature in degrees C.
+    Thermal = 0x1b,
+    /// SPIS transaction ended. `arg`: SPIS STATUS (bit 0 overread, bit 1
+    /// overflow), `value`: bytes received.
+    SpisEnd = 0x1c,
+    /// SAADC run finished. `arg`: input, `value`: samples taken.
+    Sampled = 0x1d,
+    /// QSPI flash operation finished. `arg`: 1 read, 2 program, 3 erase,
+    /// `value`: 4 KB sector of the address.
+    #[cfg_attr(not(feature = "nrf52840"), allow(dead_code))]
+    FlashDone = 0x1e,
+    /// TWIS transaction timing, see `bustiming`. `arg`: SCL clocks
+    /// (saturated), `value`: average SCL rate in kHz.
+    BusTiming = 0x1f,
+    /// ENABLE input changed, see `busgate`. `value`: 1 high, 0 low.
+    Gate = 0x20,
+    /// PDM capture finished. `value`: samples captured.
+    Captured = 0x21,
+    /// An NFC reader selected the tag, see `nfctag`.
+    NfcSelected = 0x22,
+    /// Flash journal opened at boot, see `journal`. `value`: sectors in
+    /// use.
+    JournalOpen = 0x23,
+    /// The rotary encoder moved, see `qdec`. `value`: position, low 16
+    /// bits.
+    EncoderMoved = 0x24,
+    /// The time was set, see `wallclock`: four records, `arg` 0 to 3 and
+    /// `value` the bits 16 * `arg` and up of the new time in ms.
+    TimeSync = 0x25,
+}
+
+/// Task identifiers for `Event::TaskSpawn` and `Event::TaskEnter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskId {
+    SendTwiCmds = 0x01,
+    OnTwis = 0x02,
+    OnGpiote = 0x03,
+    PollConsole = 0x04,
+    ReportStats = 0x05,
+    BlinkLed = 0x06,
+    Heartbeat = 0x07,
+    SendTelemetry = 0x08,
+    EndBurst = 0x09,
+    SystemOff = 0x0a,
+    TwimIdle = 0x0b,
+    OnPower = 0x0c,
+    StoreConfig = 0x0d,
+    ResumeTwis = 0x0e,
+    CalibrateLfclk = 0x0f,
+    RunBench = 0x10,
+    OnLpcomp = 0x11,
+    CheckTemp = 0x12,
+    OnBridgeRx = 0x13,
+    BridgeCmd = 0x14,
+    OnUsbd = 0x15,
+    OnSpis = 0x16,
+    DriveLed = 0x17,
+    OnSaadc = 0x18,
+    OnRng = 0x19,
+    StoreBank = 0x1a,
+    OnQspi = 0x1b,
+    Forward = 0x1c,
+    OnPdm = 0x1d,
+    OnPwm1 = 0x1e,
+    OnNfct = 0x1f,
+    ActivateNfc = 0x20,
+    RefreshOled = 0x21,
+    FlushJournal = 0x22,
+    OnTwim = 0x23,
+    OnQdec = 0x24,
+    OnSignal = 0x25,
+    SendRequest = 0x26,
+    RunDfu = 0x27,
+    EnterBootloader = 0x28,
+    SendMctp = 0x29,
+    TickDs3231 = 0x2a,
+    SampleIna219 = 0x2b,
+}
+
+impl TaskId {
+    pub const ALL: [TaskId; 43] = [
+        TaskId::SendTwiCmds,
+        TaskId::OnTwis,
+        TaskId::OnGpiote,
+        TaskId::PollConsole,
+        TaskId::ReportStats,
+        TaskId::BlinkLed,
+        TaskId::Heartbeat,
+        TaskId::SendTelemetry,
+        TaskId::EndBurst,
+        TaskId::SystemOff,
+        TaskId::TwimIdle,
+        TaskId::OnPower,
+        TaskId::StoreConfig,
+        TaskId::ResumeTwis,
+        TaskId::CalibrateLfclk,
+        TaskId::RunBench,
+        TaskId::OnLpcomp,
+        TaskId::CheckTemp,
+        TaskId::OnBridgeRx,
+        TaskId::BridgeCmd,
+        TaskId::OnUsbd,
+        TaskId::OnSpis,
+        TaskId::DriveLed,
+        TaskId::OnSaadc,
+        TaskId::OnRng,
+        TaskId::StoreBank,
+        TaskId::OnQspi,
+        TaskId::Forward,
+        TaskId::OnPdm,
+        TaskId::OnPwm1,
+        TaskId::OnNfct,
+        TaskId::ActivateNfc,
+        TaskId::RefreshOled,
+        TaskId::FlushJournal,
+        TaskId::OnTwim,
+        TaskId::OnQdec,
+        TaskId::OnSignal,
+        TaskId::SendRequest,
+        TaskId::RunDfu,
+        TaskId::EnterBootloader,
+        TaskId::SendMctp,
+        TaskId::TickDs3231,
+        TaskId::SampleIna219,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskId::SendTwiCmds => "send_twi_cmds",
+            TaskId::OnTwis => "on_twis",
+            TaskId::OnGpiote => "on_gpiote",
+            TaskId::PollConsole => "poll_console",
+            TaskId::ReportStats => "report_stats",
+            TaskId::BlinkLed => "blink_led",
+            TaskId::Heartbeat => "heartbeat",
+            TaskId::SendTelemetry => "send_telemetry",
+            TaskId::EndBurst => "end_burst",
+            TaskId::SystemOff => "system_off",
+            TaskId::TwimIdle => "twim_idle",
+            TaskId::OnPower => "on_power",
+            TaskId::StoreConfig => "store_config",
+            TaskId::ResumeTwis => "resume_twis",
+            TaskId::CalibrateLfclk => "calibrate_lfclk",
+            TaskId::RunBench => "run_bench",
+            TaskId::OnLpcomp => "on_lpcomp",
+            TaskId::CheckTemp => "check_temp",
+            TaskId::OnBridgeRx => "on_bridge_rx",
+            TaskId::BridgeCmd => "bridge_cmd",
+            TaskId::OnUsbd => "on_usbd",
+            TaskId::OnSpis => "on_spis",
+            TaskId::DriveLed => "drive_led",
+            TaskId::OnSaadc => "on_saadc",
+            TaskId::OnRng => "on_rng",
+            TaskId::StoreBank => "store_bank",
+            TaskId::OnQspi => "on_qspi",
+            TaskId::Forward => "forward",
+            TaskId::OnPdm => "on_pdm",
+            TaskId::OnPwm1 => "on_pwm1",
+            TaskId::OnNfct => "on_nfct",
+            TaskId::ActivateNfc => "activate_nfc",
+            TaskId::RefreshOled => "refresh_oled",
+            TaskId::FlushJournal => "flush_journal",
+            TaskId::OnTwim => "on_twim",
+            TaskId::OnQdec => "on_qdec",
+            TaskId::OnSignal => "on_signal",
+            TaskId::SendRequest => "send_request",
+            TaskId::RunDfu => "run_dfu",
+            TaskId::EnterBootloader => "enter_bootloader",
+            TaskId::SendMctp => "send_mctp",
+            TaskId::TickDs3231 => "tick_ds3231",
+            TaskId::SampleIna219 => "sample_ina219",
+        }
+    }
+}
+
+/// Error sources for `Event::Error`.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum ErrorSource {
+    /// TWIM read failed, `value` is the `twim::Error` discriminant.
+    TwimRead = 0x01,
+    /// TWIM write failed, `value` is the `twim::Error` discriminant.
+    TwimWrite = 0x02,
+    /// Arming a TWIS DMA transfer failed, `value` is the `twis::Error` discriminant.
+    TwisDma = 0x03,
+    /// A task could not be spawned, `value` is the `TaskId`.
+    Spawn = 0x04,
+    /// Protocol error, `value` is the kind (high byte) and detail (low byte)
+    /// of an `error::ProtocolError`.
+    Protocol = 0x05,
+    /// Request refused while the supply is low, `value` is 0.
+    Brownout = 0x06,
+    /// Persisting the config failed, `value` is the `config::Error` discriminant.
+    Config = 0x07,
+    /// Arming an SPIS DMA transfer failed, `value` is the `spis::Error` discriminant.
+    SpisDma = 0x08,
+    /// Persisting the scratch registers failed, `value` is the
+    /// `nvstore::Error` discriminant.
+    Store = 0x09,
+}
+
+struct Ring {
+    records: [[u8; RECORD_LEN]; CAPACITY],
+    // Index of the next slot to write.
+    head: usize,
+    len: usize,
+    // Records ever appended, wrapping; the sequence number of the next.
+    total: u32,
+}
+
+static RING: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring {
+    records: [[0; RECORD_LEN]; CAPACITY],
+    head: 0,
+    len: 0,
+    total: 0,
+}));
+
+/// Appends an event to the ring.
+pub fn record(event: Event, arg: u8, value: u16) {
+    let timestamp = crate::app::monotonics::now().ticks() as u32;
+    let mut entry = [0u8; RECORD_LEN];
+    entry[0..4].copy_from_slice(&timestamp.to_le_bytes());
+    entry[4] = event as u8;
+    entry[5] = arg;
+    entry[6..8].copy_from_slice(&value.to_le_bytes());
+
+    lock::free(|cs| {
+        let mut ring = RING.borrow(cs).borrow_mut();
+        let head = ring.head;
+        ring.records[head] = entry;
+        ring.head = (head + 1) % CAPACITY;
+        ring.len = (ring.len + 1).min(CAPACITY);
+        ring.total = ring.total.wrapping_add(1);
+    });
+}
+
+/// Writes all buffered records, oldest first, to the RTT data channel as
+/// `Tag::Trace` records and returns how many were dumped.
+pub fn dump() -> usize {
+    let mut snapshot = [[0u8; RECORD_LEN]; CAPACITY];
+    let len = lock::free(|cs| {
+        let ring = RING.borrow(cs).borrow();
+        let start = (ring.head + CAPACITY - ring.len) % CAPACITY;
+        for (i, slot) in snapshot.iter_mut().take(ring.len).enumerate() {
+            *slot = ring.records[(start + i) % CAPACITY];
+        }
+        ring.len
+    });
+
+    const PER_CHUNK: usize = MAX_RECORD_PAYLOAD / RECORD_LEN;
+    let mut chunk = [0u8; PER_CHUNK * RECORD_LEN];
+    for records in snapshot[..len].chunks(PER_CHUNK) {
+        for (dst, src) in chunk.chunks_mut(RECORD_LEN).zip(records) {
+            dst.copy_from_slice(src);
+        }
+        logging::dump(Tag::Trace, &chunk[..records.len() * RECORD_LEN]);
+    }
+    len
+}
+
+/// Copies the records from sequence number `seq` on, oldest first, into
+/// `out`. Returns the sequence number of the first one copied, later than
+/// `seq` if the ring has overwritten records since, and how many.
+pub fn copy_since(seq: u32, out: &mut [[u8; RECORD_LEN]]) -> (u32, usize) {
+    lock::free(|cs| {
+        let ring = RING.borrow(cs).borrow();
+        let oldest = ring.total.wrapping_sub(ring.len as u32);
+        let first = if ring.total.wrapping_sub(seq) > ring.len as u32 {
+            oldest
+        } else {
+            seq
+        };
+        let left = ring.total.wrapping_sub(first) as usize;
+        let count = left.min(out.len());
+        for (i, slot) in out[..count].iter_mut().enumerate() {
+            *slot = ring.records[(ring.head + CAPACITY - left + i) % CAPACITY];
+        }
+        (first, count)
+    })
+}
+
+/// Discards all buffered records.
+pub fn clear() {
+    lock::free(|cs| {
+        let mut ring = RING.borrow(cs).borrow_mut();
+        ring.head = 0;
+        ring.len = 0;
+    });
+}
@@ -0,0 +1,97 @@
+// Logic-analyzer trigger on a payload pattern.
+//
+// With a pattern armed (`trigger de ad be ef`), every payload received by
+// TWIS is searched for it, and a match pulses P0.03 high for ~1 us. Point
+// the scope or analyzer trigger at that pin to capture exactly the
+// transaction carrying the bytes of interest. The pin stays low otherwise.
+
+use {
+    crate::{
+        hal::{
+            gpio::{Output, Pin, PushPull},
+            prelude::*,
+        },
+        lock,
+        tracebuf::{self, Event},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+/// Longest pattern that can be armed.
+pub const MAX_PATTERN: usize = 8;
+
+// Pulse width in CPU cycles at 64 MHz.
+const PULSE_CYCLES: u32 = 64;
+
+#[derive(Clone, Copy)]
+pub struct Pattern {
+    bytes: [u8; MAX_PATTERN],
+    len: usize,
+}
+
+impl Pattern {
+    /// Parses whitespace-separated hex bytes, e.g. `["de", "ad"]`.
+    pub fn parse<'a>(words: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let mut pattern = Pattern {
+            bytes: [0; MAX_PATTERN],
+            len: 0,
+        };
+        for word in words {
+            if pattern.len == MAX_PATTERN {
+                return None;
+            }
+            pattern.bytes[pattern.len] = u8::from_str_radix(word, 16).ok()?;
+            pattern.len += 1;
+        }
+        (pattern.len > 0).then_some(pattern)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+struct Trigger {
+    pin: Option<Pin<Output<PushPull>>>,
+    pattern: Option<Pattern>,
+}
+
+static TRIGGER: Mutex<RefCell<Trigger>> = Mutex::new(RefCell::new(Trigger {
+    pin: None,
+    pattern: None,
+}));
+
+/// Hands over the trigger output, which must be low.
+pub fn init(pin: Pin<Output<PushPull>>) {
+    lock::free(|cs| TRIGGER.borrow(cs).borrow_mut().pin = Some(pin));
+}
+
+/// Arms `pattern`, or disarms the trigger with `None`.
+pub fn set_pattern(pattern: Option<Pattern>) {
+    lock::free(|cs| TRIGGER.borrow(cs).borrow_mut().pattern = pattern);
+}
+
+pub fn pattern() -> Option<Pattern> {
+    lock::free(|cs| TRIGGER.borrow(cs).borrow().pattern)
+}
+
+/// Pulses the trigger pin if `payload` contains the armed pattern.
+pub fn check(payload: &[u8]) {
+    lock::free(|cs| {
+        let mut trigger = TRIGGER.borrow(cs).borrow_mut();
+        let Some(pattern) = trigger.pattern else {
+            return;
+        };
+        let needle = pattern.bytes();
+        let Some(offset) = payload.windows(needle.len()).position(|w| w == needle) else {
+            return;
+        };
+        if let Some(pin) = trigger.pin.as_mut() {
+            pin.set_high().ok();
+            cortex_m::asm::delay(PULSE_CYCLES);
+            pin.set_low().ok();
+        }
+        tracebuf::record(Event::Trigger, 0, offset as u16);
+    });
+}
@@ -0,0 +1,268 @@
+// Hardware-timed TWIM polling (`twim-ppi` feature).
+//
+// TIMER3 compares every period and PPI routes the COMPARE event to TWIM
+// STARTTX; the shorts then carry the transaction through on their own:
+//
+//   PPI 4  TIMER3 COMPARE[0]  TWIM1 STARTTX
+//          TIMER3 COMPARE0_CLEAR, TWIM1 LASTTX_STARTRX and LASTRX_STOP
+//
+// Each poll writes the ALIVE register number and reads the 4-byte alive
+// counter back with a repeated start, so the TWIS register pointer does not
+// walk. The CPU only wakes at STOPPED, in `on_twim`, to take the value. The
+// polls bypass the retries and frequency adaptation of `controller`, but
+// count in the statistics.
+//
+// The HAL `Twim` does not know about any of this. `controller` pauses the
+// polling around its own transactions, see `pause`; they run at priority 1
+// like the console that starts and stops it, so a transaction never spans
+// a start or stop.
+
+use {
+    crate::{
+        clock::Hfxo,
+        controller,
+        error::{AppError, Op},
+        hal::{
+            pac::{PPI, TIMER3, TWIM1},
+            twim::Error,
+        },
+        lock, regmap,
+        stats::STATS,
+        status,
+        tracebuf::{self, Event},
+    },
+    core::{
+        cell::RefCell,
+        fmt,
+        ptr::{addr_of, addr_of_mut},
+        sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// Shortest and longest period in ms.
+pub const MIN_PERIOD_MS: u32 = 1;
+pub const MAX_PERIOD_MS: u32 = 60_000;
+
+// PPI channel used here, `bustiming` has 0-3.
+const CHANNEL: usize = 4;
+// TIMER3 at 1 MHz.
+const PRESCALER: u8 = 4;
+
+// EasyDMA buffers of the polls.
+static mut POINTER: [u8; 1] = [regmap::ALIVE];
+static mut RX: [u8; 4] = [0; 4];
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static ADDRESS: AtomicU8 = AtomicU8::new(0);
+static PERIOD_MS: AtomicU32 = AtomicU32::new(0);
+// Polls finished, failed, and the last counter value read.
+static POLLS: AtomicU32 = AtomicU32::new(0);
+static FAILED: AtomicU32 = AtomicU32::new(0);
+static LAST: AtomicU32 = AtomicU32::new(0);
+// The HFXO held while polling, for the TWIM timing.
+static HFXO: Mutex<RefCell<Option<Hfxo>>> = Mutex::new(RefCell::new(None));
+
+fn timer() -> &'static crate::hal::pac::timer3::RegisterBlock {
+    // SAFETY: TIMER3 is only used here, `init` took ownership of it.
+    unsafe { &*TIMER3::ptr() }
+}
+
+fn twim() -> &'static crate::hal::pac::twim0::RegisterBlock {
+    // SAFETY: only touched while the HAL `Twim` is between transactions,
+    // see the header.
+    unsafe { &*TWIM1::ptr() }
+}
+
+/// Takes TIMER3 and connects it to TWIM through PPI. Polling starts with
+/// `start`.
+pub fn init(timer: TIMER3) {
+    timer.mode.write(|w| w.mode().timer());
+    timer.bitmode.write(|w| w.bitmode()._32bit());
+    timer
+        .prescaler
+        .write(|w| unsafe { w.prescaler().bits(PRESCALER) });
+    timer.shorts.write(|w| w.compare0_clear().enabled());
+    // SAFETY: channel `CHANNEL` is only configured here.
+    let ppi = unsafe { &*PPI::ptr() };
+    let compare = &timer.events_compare[0] as *const _ as u32;
+    let start = &twim().tasks_starttx as *const _ as u32;
+    ppi.ch[CHANNEL].eep.write(|w| unsafe { w.bits(compare) });
+    ppi.ch[CHANNEL].tep.write(|w| unsafe { w.bits(start) });
+    ppi.chenset.write(|w| unsafe { w.bits(1 << CHANNEL) });
+}
+
+/// Polls the alive counter of `address` every `period_ms`, restarting if
+/// already polling. From priority 1, between TWIM transactions.
+pub fn start(address: u8, period_ms: u32) {
+    stop();
+    let hfxo = Hfxo::request();
+    lock::free(|cs| HFXO.borrow(cs).replace(Some(hfxo)));
+    ADDRESS.store(address, Ordering::Relaxed);
+    PERIOD_MS.store(period_ms, Ordering::Relaxed);
+    timer().cc[0].write(|w| unsafe { w.bits(period_ms * 1000) });
+    controller::power_up();
+    RUNNING.store(true, Ordering::Relaxed);
+    resume();
+    info!(
+        "polling {:#04x} every {} ms from TIMER3",
+        address, period_ms
+    );
+}
+
+/// Stops polling, after the running poll if any.
+pub fn stop() {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    halt();
+    RUNNING.store(false, Ordering::Relaxed);
+    lock::free(|cs| HFXO.borrow(cs).replace(None));
+    info!("TWIM polling stopped");
+}
+
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Keeps the polling off while alive, for a transaction of the HAL `Twim`.
+pub struct Pause(bool);
+
+impl Drop for Pause {
+    fn drop(&mut self) {
+        if self.0 {
+            resume();
+        }
+    }
+}
+
+/// Pauses the polling, if running, until the returned guard is dropped.
+pub fn pause() -> Pause {
+    let running = running();
+    if running {
+        halt();
+    }
+    Pause(running)
+}
+
+// Stops the timer and waits for a poll it has started.
+fn halt() {
+    let timer = timer();
+    timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+    // The read makes sure the stop has taken effect, a COMPARE before it
+    // has set TXSTARTED by the time the loop looks.
+    timer.cc[0].read();
+    let twim = twim();
+    // `on_twim` clears TXSTARTED at the end of the poll.
+    while twim.events_txstarted.read().bits() != 0 {}
+    twim.intenclr.write(|w| w.stopped().clear().error().clear());
+}
+
+// Sets TWIM up for the polls again and restarts the timer.
+fn resume() {
+    let twim = twim();
+    twim.address
+        .write(|w| unsafe { w.address().bits(ADDRESS.load(Ordering::Relaxed)) });
+    twim.txd
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(addr_of!(POINTER) as u32) });
+    twim.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+    twim.rxd
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(addr_of_mut!(RX) as u32) });
+    twim.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(4) });
+    twim.shorts
+        .write(|w| w.lasttx_startrx().enabled().lastrx_stop().enabled());
+    twim.events_txstarted.reset();
+    twim.events_stopped.reset();
+    twim.events_error.reset();
+    twim.errorsrc.write(|w| unsafe { w.bits(0x7) });
+    twim.intenset.write(|w| w.stopped().set().error().set());
+    let timer = timer();
+    timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+    timer.tasks_start.write(|w| unsafe { w.bits(1) });
+}
+
+/// Handles the end of a poll.
+pub fn on_interrupt() {
+    let twim = twim();
+    if twim.events_error.read().bits() != 0 {
+        twim.events_error.reset();
+        // No short for this, STOPPED follows.
+        twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+        return;
+    }
+    if twim.events_stopped.read().bits() == 0 {
+        return;
+    }
+    twim.events_stopped.reset();
+    let source = twim.errorsrc.read();
+    let error = if source.anack().bit_is_set() {
+        Some(Error::AddressNack)
+    } else if source.dnack().bit_is_set() {
+        Some(Error::DataNack)
+    } else if source.overrun().bit_is_set() {
+        Some(Error::Overrun)
+    } else {
+        None
+    };
+    twim.errorsrc.write(|w| unsafe { w.bits(0x7) });
+    twim.events_txstarted.reset();
+    STATS.twim_writes.inc();
+    STATS.twim_reads.inc();
+    match error {
+        Some(error) => {
+            match error {
+                Error::Overrun => STATS.overruns.inc(),
+                _ => STATS.nacks.inc(),
+            }
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            AppError::Twim {
+                op: Op::Read,
+                address: ADDRESS.load(Ordering::Relaxed),
+                error,
+            }
+            .record();
+        }
+        None => {
+            // SAFETY: the poll is over and the next one cannot start before
+            // the timer compares again, a period from now.
+            let value = u32::from_le_bytes(unsafe { *addr_of!(RX) });
+            LAST.store(value, Ordering::Relaxed);
+            POLLS.fetch_add(1, Ordering::Relaxed);
+            STATS.twim_bytes.add(5);
+            tracebuf::record(Event::TwimRead, 0, 4);
+        }
+    }
+    if status::is_set(status::BROWNOUT) {
+        // No new DMA after a power-fail warning. TXSTARTED is clear already,
+        // so this does not wait.
+        stop();
+        warn!("TWIM polling stopped at low supply");
+    }
+}
+
+/// The console report.
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if running() {
+            write!(
+                f,
+                "polling {:#04x} every {} ms: ",
+                ADDRESS.load(Ordering::Relaxed),
+                PERIOD_MS.load(Ordering::Relaxed)
+            )?;
+        } else {
+            f.write_str("polling off: ")?;
+        }
+        write!(
+            f,
+            "{} polls, {} failed, alive counter {}",
+            POLLS.load(Ordering::Relaxed),
+            FAILED.load(Ordering::Relaxed),
+            LAST.load(Ordering::Relaxed)
+        )
+    }
+}
@@ -0,0 +1,134 @@
+// Verbose protocol-level log of TWIS events.
+//
+// When enabled (`twislog on`), every TWIS event is logged with the RXD/TXD
+// AMOUNT registers and the time since the previous event, turning the
+// peripheral side into a simple bus analyzer:
+//
+//   twis Write     rxd=0 txd=8 +1220us
+//   twis RxStarted rxd=0 txd=8 +30us
+//   twis Stopped   rxd=8 txd=8 +854us
+//
+// RXSTARTED, TXSTARTED and ERROR only raise interrupts while the log is on.
+// Timing comes from the RTC monotonic, so deltas have a resolution of ~31 us.
+
+use {
+    crate::{
+        hal::{pac::TWIS0, twis::TwiEvent},
+        mono,
+    },
+    core::sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Low 32 bits of the RTC tick count at the previous logged event.
+static LAST_TICKS: AtomicU32 = AtomicU32::new(0);
+
+fn regs() -> &'static crate::hal::pac::twis0::RegisterBlock {
+    // SAFETY: only event flags and interrupt enables are touched, which the
+    // HAL does not cache anywhere.
+    unsafe { &*TWIS0::ptr() }
+}
+
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Turns the event log on or off, enabling the extra event interrupts it needs.
+pub fn set_verbose(on: bool) {
+    let twis = regs();
+    if on {
+        LAST_TICKS.store(now_ticks(), Ordering::Relaxed);
+        twis.events_rxstarted.reset();
+        twis.events_txstarted.reset();
+        twis.events_error.reset();
+        twis.intenset.write(|w| {
+            w.rxstarted()
+                .set_bit()
+                .txstarted()
+                .set_bit()
+                .error()
+                .set_bit()
+        });
+    } else {
+        twis.intenclr.write(|w| {
+            w.rxstarted()
+                .set_bit()
+                .txstarted()
+                .set_bit()
+                .error()
+                .set_bit()
+        });
+    }
+    VERBOSE.store(on, Ordering::Relaxed);
+}
+
+/// Logs and clears the events that only the verbose log is interested in.
+/// Returns true if there were any.
+pub fn drain_extra_events() -> bool {
+    if !verbose() {
+        return false;
+    }
+    let twis = regs();
+    let mut drained = false;
+    if twis.events_error.read().bits() != 0 {
+        twis.events_error.reset();
+        log(TwiEvent::Error);
+        drained = true;
+    }
+    if twis.events_rxstarted.read().bits() != 0 {
+        twis.events_rxstarted.reset();
+        log(TwiEvent::RxStarted);
+        drained = true;
+    }
+    if twis.events_txstarted.read().bits() != 0 {
+        twis.events_txstarted.reset();
+        log(TwiEvent::TxStarted);
+        drained = true;
+    }
+    drained
+}
+
+/// Logs `event` if the verbose log is on.
+pub fn log(event: TwiEvent) {
+    if !verbose() {
+        return;
+    }
+    let now = now_ticks();
+    let delta = now.wrapping_sub(LAST_TICKS.swap(now, Ordering::Relaxed));
+    let delta_us = delta as u64 * 1_000_000 / mono::TICK_HZ as u64;
+    let twis = regs();
+    let rxd = twis.rxd.amount.read().bits();
+    let txd = twis.txd.amount.read().bits();
+    let name = name(event);
+    if event == TwiEvent::Error {
+        let errorsrc = twis.errorsrc.read();
+        info!(
+            "twis {:<9} rxd={} txd={} +{}us overflow={} dnack={} overread={}",
+            name,
+            rxd,
+            txd,
+            delta_us,
+            errorsrc.overflow().bit(),
+            errorsrc.dnack().bit(),
+            errorsrc.overread().bit()
+        );
+    } else {
+        info!("twis {:<9} rxd={} txd={} +{}us", name, rxd, txd, delta_us);
+    }
+}
+
+fn name(event: TwiEvent) -> &'static str {
+    match event {
+        TwiEvent::Stopped => "Stopped",
+        TwiEvent::Error => "Error",
+        TwiEvent::RxStarted => "RxStarted",
+        TwiEvent::TxStarted => "TxStarted",
+        TwiEvent::Write => "Write",
+        TwiEvent::Read => "Read",
+    }
+}
+
+fn now_ticks() -> u32 {
+    crate::app::monotonics::now().ticks() as u32
+}
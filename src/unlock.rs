@@ -0,0 +1,128 @@
+// Challenge-response unlock for privileged requests (`unlock` feature).
+//
+// SLEEP, WRITE_CONFIG, FACTORY_RESET, FLASH_ERASE, DFU_ACTIVATE and
+// BOOTLOADER can take the board off the bus or destroy data, so with the
+// feature a controller has to prove it knows the device key first:
+//
+//   1. read the 8-byte challenge from NONCE
+//   2. write the response to UNLOCK: the first 8 bytes of
+//      AES-128(key, challenge followed by 8 zero bytes)
+//   3. send the privileged request within `WINDOW_MS`
+//
+// A right response unlocks one privileged request, whichever register it
+// arrives through; any other is refused with `ProtocolError::Locked`.
+// Every response written, right or wrong, uses up the challenge, and the
+// next READ of NONCE draws a new one, so a recorded exchange cannot be
+// replayed and each guess costs a READ.
+//
+// The key is the one from `key`, shared or this chip's own. A challenge
+// is 4 random bytes and the number of challenges since boot, so it never
+// repeats before a reset even if the entropy pool has run dry; it is drawn
+// at the READ rather than at boot, when the pool is still empty.
+
+use {
+    crate::{
+        ecb::{self, BLOCK_LEN},
+        entropy,
+        error::ProtocolError,
+        key, lock, mono,
+        request::{self, Request},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const LEN: usize = 8;
+/// How long an unlock waits for its privileged request.
+pub const WINDOW_MS: u64 = 10_000;
+
+struct Lock {
+    challenge: Option<[u8; LEN]>,
+    challenges: u32,
+    staged: [u8; LEN],
+    // Uptime in ms when an unlock expires, if unlocked.
+    until: Option<u64>,
+}
+
+static LOCK: Mutex<RefCell<Lock>> = Mutex::new(RefCell::new(Lock {
+    challenge: None,
+    challenges: 0,
+    staged: [0; LEN],
+    until: None,
+}));
+
+fn now_ms() -> u64 {
+    crate::app::monotonics::now().ticks() * 1000 / mono::TICK_HZ as u64
+}
+
+impl Lock {
+    fn challenge(&mut self) -> [u8; LEN] {
+        *self.challenge.get_or_insert_with(|| {
+            self.challenges = self.challenges.wrapping_add(1);
+            let mut challenge = [0; LEN];
+            entropy::take(&mut challenge[..4]);
+            challenge[4..].copy_from_slice(&self.challenges.to_le_bytes());
+            challenge
+        })
+    }
+}
+
+/// True for the requests that need an unlock.
+pub fn is_privileged(request: &Request) -> bool {
+    matches!(
+        request.opcode(),
+        request::SLEEP
+            | request::WRITE_CONFIG
+            | request::FACTORY_RESET
+            | request::FLASH_ERASE
+            | request::DFU_ACTIVATE
+            | request::BOOTLOADER
+    )
+}
+
+/// Byte `index` of NONCE, drawing a challenge if there is none.
+pub fn challenge(index: usize) -> u8 {
+    lock::free(|cs| LOCK.borrow(cs).borrow_mut().challenge()[index])
+}
+
+/// Stages byte `index` of UNLOCK; the last one checks the response.
+pub fn write(index: usize, value: u8) -> bool {
+    let (challenge, response) = lock::free(|cs| {
+        let mut lock = LOCK.borrow(cs).borrow_mut();
+        lock.staged[index] = value;
+        if index < LEN - 1 {
+            return (None, lock.staged);
+        }
+        (lock.challenge.take(), lock.staged)
+    });
+    if index < LEN - 1 {
+        return true;
+    }
+    // A response to no challenge is wrong.
+    let right = challenge.is_some_and(|challenge| {
+        let mut block = [0; BLOCK_LEN];
+        block[..LEN].copy_from_slice(&challenge);
+        ecb::encrypt(&key::get(), &block).is_some_and(|cipher| cipher[..LEN] == response)
+    });
+    let until = now_ms() + WINDOW_MS;
+    lock::free(|cs| LOCK.borrow(cs).borrow_mut().until = right.then_some(until));
+    if right {
+        info!("unlocked for one privileged request");
+    } else {
+        warn!("wrong unlock response");
+    }
+    true
+}
+
+/// Lets `request` through if it needs no unlock or takes the pending one.
+pub fn admit(request: &Request) -> Result<(), ProtocolError> {
+    if !cfg!(feature = "unlock") || !is_privileged(request) {
+        return Ok(());
+    }
+    let now = now_ms();
+    let until = lock::free(|cs| LOCK.borrow(cs).borrow_mut().until.take());
+    match until {
+        Some(until) if now <= until => Ok(()),
+        _ => Err(ProtocolError::Locked(request.opcode())),
+    }
+}
@@ -0,0 +1,194 @@
+// Command console on a USB CDC-ACM port (`usb-console` feature).
+//
+// The nRF52840's own USB device enumerates as a serial port carrying the
+// same console as RTT: typed lines go to `console` next to the RTT input,
+// and every line written by `logging` (log output and command replies) is
+// mirrored to the port while a terminal has it open (DTR set). So the demo
+// can be driven from any PC, no debug probe needed.
+//
+// USBD can only be enabled once its supply is up, so the device is built on
+// the first USBPWRRDY (or in `init` if VBUS is already there) and then kept
+// for good, together with an `Hfxo`: USB needs the crystal. The USBD
+// interrupt fires on bus events and on every start of frame, ~1 ms, which is
+// when the stack is polled.
+//
+// Output that does not fit the write buffer is dropped. At the `trace` log
+// level that happens on every TWIS burst; lower the level when it matters.
+//
+// With `usb-msc` the device is composite: the serial port and a read-only
+// disk holding the journal, see `usbmsc`.
+
+#[cfg(feature = "usb-console")]
+use {
+    crate::{
+        build_info,
+        clock::Hfxo,
+        hal::pac::{POWER, USBD},
+        lock,
+    },
+    core::{
+        cell::RefCell,
+        fmt::{self, Write},
+    },
+    cortex_m::interrupt::Mutex,
+    nrf_usbd::Usbd,
+    usb_device::{
+        bus::UsbBusAllocator,
+        device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    },
+    usbd_serial::{SerialPort, USB_CLASS_CDC},
+};
+
+// The shared test VID/PID for CDC-ACM devices.
+#[cfg(feature = "usb-console")]
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+
+#[cfg(feature = "usb-console")]
+const READ_LEN: usize = 64;
+// Enough for the `help` text.
+#[cfg(feature = "usb-console")]
+const WRITE_LEN: usize = 2048;
+
+#[cfg(feature = "usb-console")]
+struct Peripheral;
+
+// SAFETY: USBD is only used by `nrf_usbd` through this type; `init` leaves
+// it enabled instead of parking it.
+#[cfg(feature = "usb-console")]
+unsafe impl nrf_usbd::UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = USBD::ptr() as *const ();
+}
+
+#[cfg(feature = "usb-console")]
+type Bus = Usbd<Peripheral>;
+
+#[cfg(feature = "usb-console")]
+struct Port {
+    device: UsbDevice<'static, Bus>,
+    serial: SerialPort<'static, Bus, [u8; READ_LEN], [u8; WRITE_LEN]>,
+    #[cfg(feature = "usb-msc")]
+    disk: crate::usbmsc::MassStorage<'static, Bus>,
+    _hfxo: Hfxo,
+}
+
+#[cfg(feature = "usb-console")]
+static PORT: Mutex<RefCell<Option<Port>>> = Mutex::new(RefCell::new(None));
+
+/// Builds the device once the USB supply is ready, and polls it. From the
+/// USBD interrupt.
+#[cfg(feature = "usb-console")]
+pub fn on_interrupt() {
+    // Outside the critical section: the HFXO start must not delay the TWIS
+    // interrupt, and the log line goes through `write_line`.
+    if lock::free(|cs| PORT.borrow(cs).borrow().is_none()) {
+        let port = start();
+        lock::free(|cs| PORT.borrow(cs).replace(port));
+    }
+    lock::free(|cs| {
+        if let Some(port) = PORT.borrow(cs).borrow_mut().as_mut() {
+            #[cfg(not(feature = "usb-msc"))]
+            port.device.poll(&mut [&mut port.serial]);
+            #[cfg(feature = "usb-msc")]
+            port.device.poll(&mut [&mut port.serial, &mut port.disk]);
+        }
+    });
+}
+
+#[cfg(not(feature = "usb-console"))]
+#[cfg_attr(feature = "nrf52832", allow(dead_code))]
+pub fn on_interrupt() {}
+
+#[cfg(feature = "usb-console")]
+fn start() -> Option<Port> {
+    // SAFETY: read-only, the USB events themselves belong to `power`.
+    let power = unsafe { &*POWER::ptr() };
+    if !power.usbregstatus.read().outputrdy().is_ready() {
+        return None;
+    }
+    let hfxo = Hfxo::request();
+    let bus =
+        cortex_m::singleton!(: UsbBusAllocator<Bus> = UsbBusAllocator::new(Usbd::new(Peripheral)))?;
+    let serial = SerialPort::new_with_store(bus, [0; READ_LEN], [0; WRITE_LEN]);
+    #[cfg(feature = "usb-msc")]
+    let disk = crate::usbmsc::MassStorage::new(bus);
+    let builder = UsbDeviceBuilder::new(bus, VID_PID)
+        .product(build_info::NAME)
+        .serial_number(build_info::GIT_HASH);
+    let device = if cfg!(feature = "usb-msc") {
+        // Miscellaneous, interface association: the CDC interfaces come
+        // with an association descriptor.
+        builder
+            .device_class(0xef)
+            .device_sub_class(0x02)
+            .device_protocol(0x01)
+    } else {
+        builder.device_class(USB_CLASS_CDC)
+    }
+    .build();
+    // SAFETY: USBD belongs to this module. Bus events and the start of
+    // frame cover everything the stack has to react to.
+    unsafe {
+        (*USBD::ptr())
+            .intenset
+            .write(|w| w.usbreset().set().usbevent().set().sof().set());
+    }
+    info!("USB console up");
+    Some(Port {
+        device,
+        serial,
+        #[cfg(feature = "usb-msc")]
+        disk,
+        _hfxo: hfxo,
+    })
+}
+
+/// Reads console input into `buf`, returns the number of bytes.
+#[cfg(feature = "usb-console")]
+pub fn read(buf: &mut [u8]) -> usize {
+    lock::free(|cs| match PORT.borrow(cs).borrow_mut().as_mut() {
+        Some(port) => port.serial.read(buf).unwrap_or(0),
+        None => 0,
+    })
+}
+
+#[cfg(not(feature = "usb-console"))]
+pub fn read(_buf: &mut [u8]) -> usize {
+    0
+}
+
+// Writes to the serial port, turning `\n` into `\r\n`.
+#[cfg(feature = "usb-console")]
+struct Writer<'a>(&'a mut SerialPort<'static, Bus, [u8; READ_LEN], [u8; WRITE_LEN]>);
+
+#[cfg(feature = "usb-console")]
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write(b"\r\n").ok();
+            }
+            self.0.write(part.as_bytes()).ok();
+        }
+        Ok(())
+    }
+}
+
+/// Writes `args` and a newline to the port, if a terminal has it open.
+/// Called by `logging::write_line`.
+#[cfg(feature = "usb-console")]
+pub fn write_line(args: fmt::Arguments) {
+    lock::free(|cs| {
+        if let Some(port) = PORT.borrow(cs).borrow_mut().as_mut() {
+            if !port.serial.dtr() {
+                return;
+            }
+            let mut writer = Writer(&mut port.serial);
+            writer.write_fmt(args).ok();
+            writer.write_str("\n").ok();
+            port.serial.flush().ok();
+        }
+    });
+}
+
+#[cfg(not(feature = "usb-console"))]
+pub fn write_line(_args: core::fmt::Arguments) {}
@@ -0,0 +1,115 @@
+// Read-only FAT12 volume holding the journal (`usb-msc` feature).
+//
+// `usbmsc` serves these blocks to the host. Nothing but the file data is
+// stored: the boot sector, FATs and root directory are made up on every
+// read from the current journal length, and the file's clusters map
+// straight onto `journal::read`:
+//
+//   block 0      boot sector, label TWIS-LOG
+//   blocks 1, 2  FATs, one cluster per block, chained in order
+//   block 3      root directory, the label and JOURNAL.BIN
+//   blocks 4..   JOURNAL.BIN, records in the `tracebuf` layout
+//
+// The host reads the directory once when mounting, so the file shows the
+// length of that moment; plug the board in again for the newer records.
+
+use crate::journal;
+
+pub const BLOCK_LEN: usize = 512;
+
+const FATS: u32 = 2;
+const FAT_START: u32 = 1;
+const ROOT_START: u32 = FAT_START + FATS;
+const ROOT_ENTRIES: u16 = (BLOCK_LEN / 32) as u16;
+const DATA_START: u32 = ROOT_START + 1;
+const CLUSTERS: u32 = journal::CAPACITY.div_ceil(BLOCK_LEN as u32);
+/// Blocks on the volume.
+pub const BLOCKS: u32 = DATA_START + CLUSTERS;
+
+const LABEL: &[u8; 11] = b"TWIS-LOG   ";
+const FILE_NAME: &[u8; 11] = b"JOURNAL BIN";
+// No calendar date is kept, every entry carries 2024-01-01.
+const DATE: u16 = (2024 - 1980) << 9 | 1 << 5 | 1;
+
+/// Fills `buf` with the bytes of block `lba` from `offset` on. Returns
+/// false if the journal cannot be read right now.
+pub fn read(lba: u32, offset: usize, buf: &mut [u8]) -> bool {
+    if lba >= DATA_START {
+        let at = (lba - DATA_START) * BLOCK_LEN as u32 + offset as u32;
+        return journal::read(at, buf);
+    }
+    let mut block = [0; BLOCK_LEN];
+    match lba {
+        0 => boot_sector(&mut block),
+        ROOT_START => root_directory(&mut block),
+        _ => fat(&mut block),
+    }
+    buf.copy_from_slice(&block[offset..offset + buf.len()]);
+    true
+}
+
+fn boot_sector(block: &mut [u8; BLOCK_LEN]) {
+    let mut put = |at: usize, bytes: &[u8]| block[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0, &[0xeb, 0x3c, 0x90]);
+    put(3, b"MSWIN4.1");
+    put(11, &(BLOCK_LEN as u16).to_le_bytes());
+    // One block per cluster, one reserved block.
+    put(13, &[1]);
+    put(14, &1u16.to_le_bytes());
+    put(16, &[FATS as u8]);
+    put(17, &ROOT_ENTRIES.to_le_bytes());
+    put(19, &(BLOCKS as u16).to_le_bytes());
+    // Fixed disk, one block per FAT, one sector per track and one head.
+    put(21, &[0xf8]);
+    put(22, &1u16.to_le_bytes());
+    put(24, &1u16.to_le_bytes());
+    put(26, &1u16.to_le_bytes());
+    put(36, &[0x80, 0, 0x29]);
+    put(39, &crate::identity::device_id()[..4]);
+    put(43, LABEL);
+    put(54, b"FAT12   ");
+    put(510, &[0x55, 0xaa]);
+}
+
+// Clusters of the file, numbered from 2.
+fn file_clusters() -> u32 {
+    journal::len().div_ceil(BLOCK_LEN as u32)
+}
+
+fn fat(block: &mut [u8; BLOCK_LEN]) {
+    let clusters = file_clusters();
+    let entry = |cluster: u32| -> u16 {
+        match cluster {
+            0 => 0xff8,
+            1 => 0xfff,
+            c if c < clusters + 1 => c as u16 + 1,
+            c if c == clusters + 1 => 0xfff,
+            _ => 0,
+        }
+    };
+    // 12-bit entries, two in three bytes.
+    for pair in 0..(CLUSTERS + 2).div_ceil(2) {
+        let (low, high) = (entry(2 * pair), entry(2 * pair + 1));
+        let at = 3 * pair as usize;
+        block[at] = low as u8;
+        block[at + 1] = (low >> 8) as u8 | (high << 4) as u8;
+        block[at + 2] = (high >> 4) as u8;
+    }
+}
+
+fn root_directory(block: &mut [u8; BLOCK_LEN]) {
+    block[..11].copy_from_slice(LABEL);
+    // Volume label.
+    block[11] = 0x08;
+    let file = &mut block[32..64];
+    file[..11].copy_from_slice(FILE_NAME);
+    // Read-only.
+    file[11] = 0x01;
+    for at in [16, 18, 24] {
+        file[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    let len = journal::len();
+    let cluster: u16 = if len == 0 { 0 } else { 2 };
+    file[26..28].copy_from_slice(&cluster.to_le_bytes());
+    file[28..32].copy_from_slice(&len.to_le_bytes());
+}
@@ -0,0 +1,376 @@
+// USB mass storage class serving `usbdisk` (`usb-msc` feature).
+//
+// Bulk-only transport with the SCSI commands a host needs to mount a
+// read-only disk: a 31-byte command block wrapper on the OUT endpoint, then
+// the data, then a 13-byte status wrapper on the IN endpoint. Data goes out
+// one packet per IN completion, so a READ(10) only ever holds a packet; a
+// block that cannot be read yet, while the QSPI is busy, is retried on the
+// next poll. Writes are refused as write-protected, after taking their
+// data.
+
+use {
+    crate::usbdisk::{self, BLOCK_LEN},
+    usb_device::{
+        class_prelude::*,
+        control::{Recipient, RequestType},
+    },
+};
+
+const PACKET_LEN: usize = 64;
+
+const CLASS_MSC: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+const GET_MAX_LUN: u8 = 0xfe;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+// SCSI opcodes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const PREVENT_ALLOW_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const VERIFY_10: u8 = 0x2f;
+
+// Sense keys and additional sense codes.
+const ILLEGAL_REQUEST: (u8, u8) = (0x05, 0x20);
+const OUT_OF_RANGE: (u8, u8) = (0x05, 0x21);
+const WRITE_PROTECTED: (u8, u8) = (0x07, 0x27);
+
+const INQUIRY_REPLY: [u8; 36] = *b"\x00\x80\x04\x02\x1f\x00\x00\x00\
+nRF52840TWIS journal    0.1 ";
+
+// Longest reply that is not a block.
+const REPLY_LEN: usize = 36;
+
+#[derive(Clone, Copy)]
+enum Data {
+    Reply {
+        buf: [u8; REPLY_LEN],
+        len: usize,
+        sent: usize,
+    },
+    Blocks {
+        lba: u32,
+        end: u32,
+        offset: usize,
+    },
+    // The data phase has ended, with a short packet or the length the host
+    // expected.
+    End,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    // Waiting for a command block wrapper.
+    Command,
+    DataIn(Data),
+    // Taking the data of a refused write.
+    DataOut { left: u32 },
+    // Sending the status wrapper.
+    Status,
+}
+
+pub struct MassStorage<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    out_ep: EndpointOut<'a, B>,
+    in_ep: EndpointIn<'a, B>,
+    state: State,
+    // Tag and expected length of the current command, bytes moved, and
+    // whether it passed.
+    tag: u32,
+    expected: u32,
+    moved: u32,
+    passed: bool,
+    // A packet is being sent.
+    busy: bool,
+    sense: (u8, u8),
+}
+
+impl<'a, B: UsbBus> MassStorage<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MassStorage {
+            interface: alloc.interface(),
+            out_ep: alloc.bulk(PACKET_LEN as u16),
+            in_ep: alloc.bulk(PACKET_LEN as u16),
+            state: State::Command,
+            tag: 0,
+            expected: 0,
+            moved: 0,
+            passed: true,
+            busy: false,
+            sense: (0, 0),
+        }
+    }
+
+    fn on_command(&mut self, cbw: &[u8]) {
+        let word = |at: usize| u32::from_le_bytes([cbw[at], cbw[at + 1], cbw[at + 2], cbw[at + 3]]);
+        if cbw.len() != CBW_LEN || word(0) != CBW_SIGNATURE {
+            // Not a wrapper: only a reset gets the host out of this.
+            self.in_ep.stall();
+            self.out_ep.stall();
+            return;
+        }
+        self.tag = word(4);
+        self.expected = word(8);
+        self.moved = 0;
+        self.passed = true;
+        let to_host = cbw[12] & 0x80 != 0;
+        let cb = &cbw[15..31];
+        let data = self.execute(cb);
+        if self.passed {
+            self.sense = (0, 0);
+        }
+        self.state = match data {
+            _ if self.expected == 0 => State::Status,
+            Some(data) if to_host => State::DataIn(data),
+            // Nothing to send: a zero length packet ends the data phase.
+            None if to_host => State::DataIn(Data::Reply {
+                buf: [0; REPLY_LEN],
+                len: 0,
+                sent: 0,
+            }),
+            _ => State::DataOut {
+                left: self.expected,
+            },
+        };
+        match self.state {
+            State::Status => self.send_status(),
+            State::DataIn(_) => self.send_data(),
+            _ => {}
+        }
+    }
+
+    // Runs a SCSI command: the data to send, if any.
+    fn execute(&mut self, cb: &[u8]) -> Option<Data> {
+        let be32 = |at: usize| u32::from_be_bytes([cb[at], cb[at + 1], cb[at + 2], cb[at + 3]]);
+        let reply = |bytes: &[u8]| {
+            let mut buf = [0; REPLY_LEN];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            Some(Data::Reply {
+                buf,
+                len: bytes.len(),
+                sent: 0,
+            })
+        };
+        let last = (usbdisk::BLOCKS - 1).to_be_bytes();
+        let block_len = (BLOCK_LEN as u32).to_be_bytes();
+        match cb[0] {
+            TEST_UNIT_READY | PREVENT_ALLOW_REMOVAL | VERIFY_10 => None,
+            REQUEST_SENSE => {
+                let (key, asc) = self.sense;
+                self.sense = (0, 0);
+                reply(&[0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, 0, 0, 0, 0, 0])
+            }
+            INQUIRY => reply(&INQUIRY_REPLY),
+            // Write protected, no mode pages.
+            MODE_SENSE_6 => reply(&[3, 0, 0x80, 0]),
+            READ_CAPACITY_10 => reply(&[
+                last[0],
+                last[1],
+                last[2],
+                last[3],
+                block_len[0],
+                block_len[1],
+                block_len[2],
+                block_len[3],
+            ]),
+            READ_FORMAT_CAPACITIES => {
+                let blocks = usbdisk::BLOCKS.to_be_bytes();
+                // One descriptor, formatted media.
+                reply(&[
+                    0,
+                    0,
+                    0,
+                    8,
+                    blocks[0],
+                    blocks[1],
+                    blocks[2],
+                    blocks[3],
+                    0x02,
+                    block_len[1],
+                    block_len[2],
+                    block_len[3],
+                ])
+            }
+            READ_10 => {
+                let lba = be32(2);
+                let count = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+                if lba > usbdisk::BLOCKS || count > usbdisk::BLOCKS - lba {
+                    self.fail(OUT_OF_RANGE);
+                    return None;
+                }
+                Some(Data::Blocks {
+                    lba,
+                    end: lba + count,
+                    offset: 0,
+                })
+            }
+            op if is_write(op) => {
+                self.fail(WRITE_PROTECTED);
+                None
+            }
+            _ => {
+                self.fail(ILLEGAL_REQUEST);
+                None
+            }
+        }
+    }
+
+    fn fail(&mut self, sense: (u8, u8)) {
+        self.passed = false;
+        self.sense = sense;
+    }
+
+    // Sends the next packet of the data phase, or the status once done.
+    fn send_data(&mut self) {
+        let State::DataIn(mut data) = self.state else {
+            return;
+        };
+        if self.busy {
+            return;
+        }
+        let room = (self.expected - self.moved) as usize;
+        let mut packet = [0; PACKET_LEN];
+        let len = match &mut data {
+            Data::End => return self.send_status(),
+            _ if room == 0 => return self.send_status(),
+            Data::Reply { buf, len, sent } => {
+                let chunk = (*len - *sent).min(PACKET_LEN).min(room);
+                packet[..chunk].copy_from_slice(&buf[*sent..*sent + chunk]);
+                *sent += chunk;
+                chunk
+            }
+            Data::Blocks { lba, end, offset } if *lba < *end => {
+                let chunk = PACKET_LEN.min(room);
+                if !usbdisk::read(*lba, *offset, &mut packet[..chunk]) {
+                    // Not now, `poll` comes back.
+                    return;
+                }
+                *offset += chunk;
+                if *offset == BLOCK_LEN {
+                    *lba += 1;
+                    *offset = 0;
+                }
+                chunk
+            }
+            Data::Blocks { .. } => 0,
+        };
+        if self.in_ep.write(&packet[..len]).is_ok() {
+            self.busy = true;
+            self.moved += len as u32;
+            let ended = len < PACKET_LEN || self.moved == self.expected;
+            self.state = State::DataIn(if ended { Data::End } else { data });
+        }
+    }
+
+    fn send_status(&mut self) {
+        let mut csw = [0; CSW_LEN];
+        csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&(self.expected - self.moved).to_le_bytes());
+        csw[12] = if self.passed { 0 } else { 1 };
+        self.state = State::Status;
+        if !self.busy && self.in_ep.write(&csw).is_ok() {
+            self.busy = true;
+        }
+    }
+}
+
+// WRITE(6), WRITE(10), WRITE(12) and WRITE AND VERIFY(10).
+fn is_write(op: u8) -> bool {
+    matches!(op, 0x0a | 0x2a | 0xaa | 0x2e)
+}
+
+impl<B: UsbBus> UsbClass<B> for MassStorage<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.interface, CLASS_MSC, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY)?;
+        writer.endpoint(&self.out_ep)?;
+        writer.endpoint(&self.in_ep)
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Command;
+        self.busy = false;
+    }
+
+    fn poll(&mut self) {
+        // A block that could not be read before, or a status wrapper that
+        // did not fit.
+        match self.state {
+            State::DataIn(_) => self.send_data(),
+            State::Status if !self.busy => self.send_status(),
+            _ => {}
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == GET_MAX_LUN
+        {
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == BULK_ONLY_RESET
+        {
+            self.reset();
+            xfer.accept().ok();
+        }
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.out_ep.address() {
+            return;
+        }
+        let mut packet = [0; PACKET_LEN];
+        let Ok(len) = self.out_ep.read(&mut packet) else {
+            return;
+        };
+        match self.state {
+            State::Command => self.on_command(&packet[..len]),
+            State::DataOut { left } => {
+                let left = left.saturating_sub(len as u32);
+                self.moved = self.expected - left;
+                if left == 0 {
+                    self.send_status();
+                } else {
+                    self.state = State::DataOut { left };
+                }
+            }
+            // The host should not send now.
+            _ => {}
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr != self.in_ep.address() {
+            return;
+        }
+        self.busy = false;
+        match self.state {
+            State::Status => self.state = State::Command,
+            State::DataIn(_) => self.send_data(),
+            _ => {}
+        }
+    }
+}
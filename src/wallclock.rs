@@ -0,0 +1,124 @@
+// Time of day for the TIME registers, so a controller can timestamp the
+// data it pulls.
+//
+// The time is kept as an offset to the RTC monotonic, which runs anyway and
+// costs nothing between reads. TIME counts from boot until the controller
+// sets it, then from the time it set; the controller picks the epoch, e.g.
+// Unix time.
+//
+// TIME is `LEN` bytes: seconds as u32 LE, then milliseconds as u16 LE.
+// Reading byte 0 latches all of them, so bytes read after it, in the same
+// READ or in later ones, belong to the same instant, as on an RTC chip.
+// Writes are staged the same way: writing the last byte commits all of
+// them, so a sync is one WRITE of all `LEN` bytes.
+//
+// A SYNC_TIME request sets the clock the same way from its arguments. Each
+// setting keeps the step it made, the new time minus the old one, for the
+// SYNC_OFFSET register, and is recorded in the event trace as `TimeSync`
+// records, so the RTC timestamps of the trace and the journal before and
+// after it can be put on the controller's time scale. `events` stamps its
+// records with this clock.
+
+use {
+    crate::{
+        lock, mono,
+        tracebuf::{self, Event},
+    },
+    core::cell::RefCell,
+    cortex_m::interrupt::Mutex,
+};
+
+pub const LEN: usize = 6;
+
+struct Clock {
+    // Added to the ms since boot.
+    offset_ms: i64,
+    // The step the last setting made.
+    adjustment_ms: i64,
+    latched: [u8; LEN],
+    staged: [u8; LEN],
+}
+
+static CLOCK: Mutex<RefCell<Clock>> = Mutex::new(RefCell::new(Clock {
+    offset_ms: 0,
+    adjustment_ms: 0,
+    latched: [0; LEN],
+    staged: [0; LEN],
+}));
+
+fn uptime_ms() -> i64 {
+    (crate::app::monotonics::now().ticks() * 1000 / mono::TICK_HZ as u64) as i64
+}
+
+/// The time in ms.
+pub fn now_ms() -> i64 {
+    lock::free(|cs| uptime_ms() + CLOCK.borrow(cs).borrow().offset_ms)
+}
+
+/// The SYNC_OFFSET register.
+pub fn adjustment() -> i64 {
+    lock::free(|cs| CLOCK.borrow(cs).borrow().adjustment_ms)
+}
+
+// Sets the clock to `ms`.
+fn set(clock: &mut Clock, ms: i64) {
+    let offset_ms = ms - uptime_ms();
+    clock.adjustment_ms = offset_ms - clock.offset_ms;
+    clock.offset_ms = offset_ms;
+    for (i, bits) in (0..4).zip(ms.to_le_bytes().chunks(2)) {
+        tracebuf::record(Event::TimeSync, i, u16::from_le_bytes([bits[0], bits[1]]));
+    }
+}
+
+/// Sets the clock for SYNC_TIME.
+pub fn sync(seconds: u32, millis: u16) {
+    let adjustment = lock::free(|cs| {
+        let mut clock = CLOCK.borrow(cs).borrow_mut();
+        set(&mut clock, seconds as i64 * 1000 + millis as i64);
+        clock.adjustment_ms
+    });
+    info!(
+        "time synced to {}.{:03} s, {} ms step",
+        seconds, millis, adjustment
+    );
+}
+
+fn encode(ms: i64) -> [u8; LEN] {
+    let seconds = (ms / 1000) as u32;
+    let millis = (ms % 1000) as u16;
+    let mut bytes = [0; LEN];
+    bytes[..4].copy_from_slice(&seconds.to_le_bytes());
+    bytes[4..].copy_from_slice(&millis.to_le_bytes());
+    bytes
+}
+
+/// Byte `index` of TIME; byte 0 latches the current time.
+pub fn read(index: usize) -> u8 {
+    lock::free(|cs| {
+        let mut clock = CLOCK.borrow(cs).borrow_mut();
+        if index == 0 {
+            clock.latched = encode(uptime_ms() + clock.offset_ms);
+        }
+        clock.latched[index]
+    })
+}
+
+/// Stages byte `index` of TIME; the last byte sets the clock.
+pub fn write(index: usize, value: u8) -> bool {
+    let set = lock::free(|cs| {
+        let mut clock = CLOCK.borrow(cs).borrow_mut();
+        clock.staged[index] = value;
+        if index != LEN - 1 {
+            return None;
+        }
+        let staged = clock.staged;
+        let seconds = u32::from_le_bytes([staged[0], staged[1], staged[2], staged[3]]);
+        let millis = u16::from_le_bytes([staged[4], staged[5]]).min(999);
+        set(&mut clock, seconds as i64 * 1000 + millis as i64);
+        Some((seconds, millis))
+    });
+    if let Some((seconds, millis)) = set {
+        info!("time set to {}.{:03} s", seconds, millis);
+    }
+    true
+}
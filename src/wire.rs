@@ -0,0 +1,159 @@
+// Encodings of the `message` types.
+//
+// A `Wire` type describes itself to an `Encoder` and is rebuilt from a
+// `Decoder`, so each message is written down once for every encoding: the
+// postcard encoding here, CBOR in `cbor` and Protocol Buffers in `protobuf`
+// with their features. A message is a version, the variant index of an
+// enum, then the variant's fields.
+//
+// postcard and serde are not dependencies; `Writer` and `Reader` are the
+// postcard encoding written out for the few kinds of values the messages
+// use, so a host that serializes the types listed in `message` with
+// postcard sends and reads the same bytes:
+//
+//   u8             one byte, the version too
+//   u16, u32       LEB128 varint: 7 bits per byte, least significant first,
+//                  bit 7 set on every byte but the last
+//   enum           variant index as a varint, then the fields
+//   struct, tuple  the fields in order, nothing in between
+//   [u8] (a Vec)   varint length, then the bytes
+//
+// Decoding fails on a truncated buffer and on a varint that is too long or
+// out of range for its type, like postcard does.
+
+use crate::error::ProtocolError;
+
+/// A message with an encoding for every `Encoder`.
+pub trait Wire: Sized {
+    fn encode(&self, encoder: &mut impl Encoder) -> Result<(), ProtocolError>;
+    fn decode(decoder: &mut impl Decoder) -> Result<Self, ProtocolError>;
+}
+
+pub trait Encoder {
+    /// Starts a message of variant `variant` with `fields` fields.
+    fn message(&mut self, version: u8, variant: u32, fields: usize) -> Result<(), ProtocolError>;
+    fn u8(&mut self, value: u8) -> Result<(), ProtocolError>;
+    fn uint(&mut self, value: u32) -> Result<(), ProtocolError>;
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError>;
+    /// Completes the message, returns its length.
+    fn finish(&mut self) -> Result<usize, ProtocolError>;
+}
+
+pub trait Decoder {
+    /// Reads the version and the variant of a message.
+    fn message(&mut self) -> Result<(u8, u32), ProtocolError>;
+    fn u8(&mut self) -> Result<u8, ProtocolError>;
+    fn uint(&mut self) -> Result<u32, ProtocolError>;
+    fn bytes(&mut self) -> Result<&[u8], ProtocolError>;
+    /// Fails unless the message has been read to its end.
+    fn finish(&self) -> Result<(), ProtocolError>;
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        u16::try_from(self.uint()?).map_err(|_| ProtocolError::Malformed)
+    }
+}
+
+/// postcard encoder.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, len: 0 }
+    }
+}
+
+impl Encoder for Writer<'_> {
+    fn message(&mut self, version: u8, variant: u32, _: usize) -> Result<(), ProtocolError> {
+        self.u8(version)?;
+        self.uint(variant)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), ProtocolError> {
+        let Some(byte) = self.buf.get_mut(self.len) else {
+            return Err(ProtocolError::BadLength {
+                len: self.len as u32 + 1,
+                max: self.buf.len(),
+            });
+        };
+        *byte = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn uint(&mut self, mut value: u32) -> Result<(), ProtocolError> {
+        while value >= 0x80 {
+            self.u8(value as u8 | 0x80)?;
+            value >>= 7;
+        }
+        self.u8(value as u8)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        self.uint(bytes.len() as u32)?;
+        bytes.iter().try_for_each(|&byte| self.u8(byte))
+    }
+
+    fn finish(&mut self) -> Result<usize, ProtocolError> {
+        Ok(self.len)
+    }
+}
+
+/// postcard decoder.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+}
+
+impl Decoder for Reader<'_> {
+    fn message(&mut self) -> Result<(u8, u32), ProtocolError> {
+        Ok((self.u8()?, self.uint()?))
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        let (&byte, rest) = self.data.split_first().ok_or(ProtocolError::Malformed)?;
+        self.data = rest;
+        Ok(byte)
+    }
+
+    fn uint(&mut self) -> Result<u32, ProtocolError> {
+        let mut value = 0;
+        // Five bytes at most, the last one with the top 4 bits of a u32.
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            if shift == 28 && byte > 0x0f {
+                return Err(ProtocolError::Malformed);
+            }
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ProtocolError::Malformed)
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], ProtocolError> {
+        let len = self.uint()? as usize;
+        if len > self.data.len() {
+            return Err(ProtocolError::Malformed);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtocolError::Malformed)
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// WS2812 LED strip driven from the LEDS registers (`ws2812` feature).
+//
+// LEDS holds `COUNT` RGB triples. A WRITE to them is shown on the strip as
+// soon as it has ended: `show` encodes the colours into `SEQUENCE` and
+// PWM1 clocks it out by EasyDMA on DATA (P0.17), one PWM period per bit, so
+// the CPU never bit-bangs the 800 kHz protocol. A WRITE while the previous
+// frame is still going out is shown right after it, from `on_interrupt`.
+//
+// At 16 MHz and a COUNTERTOP of 20 a period is 1.25 us; a 0 bit is high
+// for 6 ticks (375 ns), a 1 bit for 13 (812 ns). `RESET_LEN` low periods
+// after the last LED latch the frame. The strip takes bytes in GRB order.
+
+use {
+    crate::{board, hal::pac::PWM1, lock},
+    core::{
+        cell::RefCell,
+        ptr::addr_of_mut,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    cortex_m::interrupt::Mutex,
+};
+
+/// LEDs on the strip.
+pub const COUNT: usize = 8;
+/// Bytes of the LEDS registers.
+pub const LEN: usize = 3 * COUNT;
+
+const COUNTERTOP: u16 = 20;
+// Bit 15 set: high from the start of the period until the compare value.
+const ZERO: u16 = 0x8000 | 6;
+const ONE: u16 = 0x8000 | 13;
+const LOW: u16 = 0x8000;
+// 50 us.
+const RESET_LEN: usize = 40;
+const SEQUENCE_LEN: usize = 24 * COUNT + RESET_LEN;
+
+// Target of the PWM DMA while a frame goes out.
+static mut SEQUENCE: [u16; SEQUENCE_LEN] = [LOW; SEQUENCE_LEN];
+
+static COLOURS: Mutex<RefCell<[u8; LEN]>> = Mutex::new(RefCell::new([0; LEN]));
+static CHANGED: AtomicBool = AtomicBool::new(false);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Changed while a frame was going out.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+fn regs() -> &'static crate::hal::pac::pwm0::RegisterBlock {
+    // SAFETY: PWM1 is only used here and from its interrupt, `init` took
+    // ownership of it.
+    unsafe { &*PWM1::ptr() }
+}
+
+/// Takes `PWM1`, drives DATA and blanks the strip.
+pub fn init(pwm: PWM1) {
+    // SAFETY: DATA is owned by this module via `board`.
+    let p0 = unsafe { &*crate::hal::pac::P0::ptr() };
+    p0.outclr
+        .write(|w| unsafe { w.bits(1 << board::WS2812_DATA) });
+    p0.pin_cnf[board::WS2812_DATA].write(|w| w.dir().output());
+    pwm.psel.out[0].write(|w| unsafe { w.bits(board::WS2812_DATA as u32) });
+    pwm.mode.write(|w| w.updown().up());
+    pwm.prescaler.write(|w| w.prescaler().div_1());
+    pwm.countertop
+        .write(|w| unsafe { w.countertop().bits(COUNTERTOP) });
+    pwm.decoder
+        .write(|w| w.load().common().mode().refresh_count());
+    pwm.loop_.write(|w| w.cnt().disabled());
+    pwm.seq0.refresh.write(|w| unsafe { w.bits(0) });
+    pwm.seq0.enddelay.write(|w| unsafe { w.bits(0) });
+    pwm.seq0
+        .ptr
+        .write(|w| unsafe { w.bits(addr_of_mut!(SEQUENCE) as u32) });
+    pwm.seq0
+        .cnt
+        .write(|w| unsafe { w.bits(SEQUENCE_LEN as u32) });
+    pwm.shorts.write(|w| w.seqend0_stop().enabled());
+    pwm.intenset.write(|w| w.stopped().set());
+    pwm.enable.write(|w| w.enable().enabled());
+    ENABLED.store(true, Ordering::Relaxed);
+    CHANGED.store(true, Ordering::Relaxed);
+    show();
+    info!(
+        "WS2812 strip of {} LEDs on P0.{:02}",
+        COUNT,
+        board::WS2812_DATA
+    );
+}
+
+/// Byte `index` of LEDS.
+pub fn read(index: usize) -> u8 {
+    lock::free(|cs| COLOURS.borrow(cs).borrow()[index])
+}
+
+/// Sets byte `index` of LEDS, shown once the WRITE has ended.
+pub fn write(index: usize, value: u8) -> bool {
+    lock::free(|cs| COLOURS.borrow(cs).borrow_mut()[index] = value);
+    CHANGED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Sends the LEDS registers to the strip if they changed. From the bus
+/// handlers, after a WRITE.
+pub fn show() {
+    if !ENABLED.load(Ordering::Relaxed) || !CHANGED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        PENDING.store(true, Ordering::Relaxed);
+        return;
+    }
+    let colours = lock::free(|cs| *COLOURS.borrow(cs).borrow());
+    // SAFETY: the PWM is stopped, its DMA does not read it.
+    let sequence = unsafe { &mut *addr_of_mut!(SEQUENCE) };
+    for (led, rgb) in colours.chunks_exact(3).enumerate() {
+        let grb = [rgb[1], rgb[2], rgb[0]];
+        for (i, byte) in grb.into_iter().enumerate() {
+            for bit in 0..8 {
+                let one = byte & (0x80 >> bit) != 0;
+                sequence[24 * led + 8 * i + bit] = if one { ONE } else { ZERO };
+            }
+        }
+    }
+    regs().tasks_seqstart[0].write(|w| unsafe { w.bits(1) });
+}
+
+/// Handles STOPPED: the frame is out, the next one can go.
+pub fn on_interrupt() {
+    let pwm = regs();
+    if pwm.events_stopped.read().bits() == 0 {
+        return;
+    }
+    pwm.events_stopped.reset();
+    RUNNING.store(false, Ordering::Relaxed);
+    if PENDING.swap(false, Ordering::Relaxed) {
+        CHANGED.store(true, Ordering::Relaxed);
+        show();
+    }
+}